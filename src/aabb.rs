@@ -74,6 +74,13 @@ impl Aabb {
 }
 
 pub fn hit_aabb(bounding_box: &Aabb, r: &Ray, tmin: f64, tmax: f64) -> bool {
+    hit_aabb_interval(bounding_box, r, tmin, tmax).is_some()
+}
+
+/// Finds the interval of t values within [tmin, tmax] for which the ray is inside of the bounding box.
+///
+/// Returns None if the ray misses the bounding box or Some((t_enter, t_exit)).
+pub fn hit_aabb_interval(bounding_box: &Aabb, r: &Ray, tmin: f64, tmax: f64) -> Option<(f64, f64)> {
     let origin = r.origin;
     let direction = r.direction;
 
@@ -107,7 +114,7 @@ pub fn hit_aabb(bounding_box: &Aabb, r: &Ray, tmin: f64, tmax: f64) -> bool {
         }
 
         if tmax <= tmin {
-            return false;
+            return None;
         }
     }
 
@@ -138,7 +145,7 @@ pub fn hit_aabb(bounding_box: &Aabb, r: &Ray, tmin: f64, tmax: f64) -> bool {
         }
 
         if tmax <= tmin {
-            return false;
+            return None;
         }
     }
 
@@ -169,9 +176,9 @@ pub fn hit_aabb(bounding_box: &Aabb, r: &Ray, tmin: f64, tmax: f64) -> bool {
         }
 
         if tmax <= tmin {
-            return false;
+            return None;
         }
     }
 
-    true
+    Some((tmin, tmax))
 }
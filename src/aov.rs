@@ -0,0 +1,410 @@
+use crate::{
+    checkpoint::{CheckpointReader, CheckpointWriter},
+    image_encoding::{ImageChannel, write_exr, write_pfm},
+    vector::{Vector2, Vector3},
+};
+
+/// Arbitrary output variables, the buffers of what camera rays hit first that can be written alongside the image
+#[derive(Clone, Copy, PartialEq)]
+pub enum Aov {
+    Albedo,   // The color of the first surface hit, or of the background
+    Normal,   // The shading normal in world space
+    Depth,    // The distance from the camera
+    Position, // The world space position
+    Uv,       // The surface parameterization
+    Material, // The handle of the material
+    Object,   // The handle of the object
+}
+
+/// Get an output variable by the name used on the command line
+pub fn parse_aov(name: &str) -> Option<Aov> {
+    match name {
+        "albedo" => Some(Aov::Albedo),
+        "normal" => Some(Aov::Normal),
+        "depth" => Some(Aov::Depth),
+        "position" => Some(Aov::Position),
+        "uv" => Some(Aov::Uv),
+        "material" => Some(Aov::Material),
+        "object" => Some(Aov::Object),
+        _ => None,
+    }
+}
+
+/// The layer name and channel names that an output variable is written with
+fn get_aov_channel_names(aov: Aov) -> (&'static str, &'static [&'static str]) {
+    match aov {
+        Aov::Albedo => ("albedo", &["R", "G", "B"]),
+        Aov::Normal => ("normal", &["X", "Y", "Z"]),
+        Aov::Depth => ("depth", &["Z"]),
+        Aov::Position => ("position", &["X", "Y", "Z"]),
+        Aov::Uv => ("uv", &["U", "V"]),
+        Aov::Material => ("material", &["id"]),
+        Aov::Object => ("object", &["id"]),
+    }
+}
+
+/// The first surface that a camera ray hit
+pub struct FirstHit {
+    pub normal: Vector3,   // The shading normal, including bumps and normal maps
+    pub depth: f64,        // The distance from the ray's origin
+    pub position: Vector3, // The point that was hit
+    pub uv: Vector2,
+    pub material: usize, // The handle of the material that was hit
+    pub object: usize,   // The handle of the object that was hit
+}
+
+/// The output variables of every pixel, averaged over the samples taken through it. The handles can't be averaged, so
+/// they come from a pixel's first sample that hit something.
+pub struct AovBuffers {
+    width: i32,
+    height: i32,
+    albedo_sums: Vec<Vector3>,
+    normal_sums: Vec<Vector3>,
+    depth_sums: Vec<f64>,
+    position_sums: Vec<Vector3>,
+    uv_sums: Vec<Vector2>,
+    materials: Vec<Option<usize>>,
+    objects: Vec<Option<usize>>,
+    sample_counts: Vec<i32>, // The number of samples taken through each pixel
+    hit_counts: Vec<i32>,    // The number of samples through each pixel that hit something
+}
+
+impl AovBuffers {
+    pub fn new(width: i32, height: i32) -> Self {
+        let pixel_count = (width * height) as usize;
+        Self {
+            width,
+            height,
+            albedo_sums: vec![Vector3::default(); pixel_count],
+            normal_sums: vec![Vector3::default(); pixel_count],
+            depth_sums: vec![0.0; pixel_count],
+            position_sums: vec![Vector3::default(); pixel_count],
+            uv_sums: vec![Vector2::default(); pixel_count],
+            materials: vec![None; pixel_count],
+            objects: vec![None; pixel_count],
+            sample_counts: vec![0; pixel_count],
+            hit_counts: vec![0; pixel_count],
+        }
+    }
+
+    /// Add a sample taken through pixel x, y. first_hit is None if the sample didn't hit anything, and then the albedo
+    /// is the background's color.
+    pub fn add_sample(&mut self, x: i32, y: i32, albedo: &Vector3, first_hit: Option<&FirstHit>) {
+        let index = (y * self.width + x) as usize;
+        self.albedo_sums[index] = self.albedo_sums[index] + *albedo;
+        self.sample_counts[index] += 1;
+
+        if let Some(first_hit) = first_hit {
+            self.normal_sums[index] = self.normal_sums[index] + first_hit.normal;
+            self.depth_sums[index] += first_hit.depth;
+            self.position_sums[index] = self.position_sums[index] + first_hit.position;
+            self.uv_sums[index] = self.uv_sums[index] + first_hit.uv;
+            self.materials[index].get_or_insert(first_hit.material);
+            self.objects[index].get_or_insert(first_hit.object);
+            self.hit_counts[index] += 1;
+        }
+    }
+
+    /// Get the average albedo of the first surfaces hit through a pixel
+    pub fn get_albedo(&self, x: i32, y: i32) -> Vector3 {
+        let index = (y * self.width + x) as usize;
+        if self.sample_counts[index] == 0 {
+            return Vector3::default();
+        }
+        (1.0 / self.sample_counts[index] as f64) * self.albedo_sums[index]
+    }
+
+    /// Get the average shading normal of the first surfaces hit through a pixel, or zero if nothing was hit
+    pub fn get_normal(&self, x: i32, y: i32) -> Vector3 {
+        let index = (y * self.width + x) as usize;
+        if self.normal_sums[index].magnitude_squared() < 1e-12 {
+            return Vector3::default();
+        }
+        Vector3::calc_normalized_vector(&self.normal_sums[index])
+    }
+
+    /// Get the average distance to the first surfaces hit through a pixel. Pixels where most samples didn't hit
+    /// anything are infinitely far away.
+    pub fn get_depth(&self, x: i32, y: i32) -> f64 {
+        match self.get_hit_scale(x, y) {
+            Some(scale) => scale * self.depth_sums[(y * self.width + x) as usize],
+            None => f64::INFINITY,
+        }
+    }
+
+    /// Get the average position of the first surfaces hit through a pixel, or zero if most samples didn't hit anything
+    pub fn get_position(&self, x: i32, y: i32) -> Vector3 {
+        match self.get_hit_scale(x, y) {
+            Some(scale) => scale * self.position_sums[(y * self.width + x) as usize],
+            None => Vector3::default(),
+        }
+    }
+
+    /// Get the average uv of the first surfaces hit through a pixel, or zero if most samples didn't hit anything
+    pub fn get_uv(&self, x: i32, y: i32) -> Vector2 {
+        match self.get_hit_scale(x, y) {
+            Some(scale) => scale * self.uv_sums[(y * self.width + x) as usize],
+            None => Vector2::default(),
+        }
+    }
+
+    /// The factor that averages the sums over the samples that hit something, or None if most of them didn't
+    fn get_hit_scale(&self, x: i32, y: i32) -> Option<f64> {
+        let index = (y * self.width + x) as usize;
+        if self.hit_counts[index] == 0 || 2 * self.hit_counts[index] < self.sample_counts[index] {
+            return None;
+        }
+        Some(1.0 / self.hit_counts[index] as f64)
+    }
+
+    /// Save the sums to a checkpoint
+    pub fn write_checkpoint(&self, writer: &mut CheckpointWriter) {
+        for index in 0..self.sample_counts.len() {
+            writer.write_vector3(&self.albedo_sums[index]);
+            writer.write_vector3(&self.normal_sums[index]);
+            writer.write_f64(self.depth_sums[index]);
+            writer.write_vector3(&self.position_sums[index]);
+            writer.write_f64(self.uv_sums[index].x);
+            writer.write_f64(self.uv_sums[index].y);
+            writer.write_u64(self.materials[index].map_or(u64::MAX, |handle| handle as u64));
+            writer.write_u64(self.objects[index].map_or(u64::MAX, |handle| handle as u64));
+            writer.write_i32(self.sample_counts[index]);
+            writer.write_i32(self.hit_counts[index]);
+        }
+    }
+
+    /// Restore the sums from a checkpoint
+    pub fn read_checkpoint(&mut self, reader: &mut CheckpointReader) {
+        let read_handle = |reader: &mut CheckpointReader| match reader.read_u64() {
+            u64::MAX => None,
+            handle => Some(handle as usize),
+        };
+        for index in 0..self.sample_counts.len() {
+            self.albedo_sums[index] = reader.read_vector3();
+            self.normal_sums[index] = reader.read_vector3();
+            self.depth_sums[index] = reader.read_f64();
+            self.position_sums[index] = reader.read_vector3();
+            self.uv_sums[index] = Vector2 {
+                x: reader.read_f64(),
+                y: reader.read_f64(),
+            };
+            self.materials[index] = read_handle(reader);
+            self.objects[index] = read_handle(reader);
+            self.sample_counts[index] = reader.read_i32();
+            self.hit_counts[index] = reader.read_i32();
+        }
+    }
+
+    /// Get the channels of an output variable for every pixel. Handles are -1 where nothing was hit.
+    pub fn get_channels(&self, aov: Aov) -> Vec<ImageChannel> {
+        let (layer, channel_names) = get_aov_channel_names(aov);
+        let mut channels: Vec<ImageChannel> = channel_names
+            .iter()
+            .map(|channel_name| ImageChannel {
+                name: format!("{}.{}", layer, channel_name),
+                values: Vec::with_capacity((self.width * self.height) as usize),
+            })
+            .collect();
+
+        let handle_value = |handle: Option<usize>| handle.map_or(-1.0, |handle| handle as f64);
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let index = (y * self.width + x) as usize;
+                let values = match aov {
+                    Aov::Albedo => {
+                        let albedo = self.get_albedo(x, y);
+                        vec![albedo.x, albedo.y, albedo.z]
+                    }
+                    Aov::Normal => {
+                        let normal = self.get_normal(x, y);
+                        vec![normal.x, normal.y, normal.z]
+                    }
+                    Aov::Depth => vec![self.get_depth(x, y)],
+                    Aov::Position => {
+                        let position = self.get_position(x, y);
+                        vec![position.x, position.y, position.z]
+                    }
+                    Aov::Uv => {
+                        let uv = self.get_uv(x, y);
+                        vec![uv.x, uv.y]
+                    }
+                    Aov::Material => vec![handle_value(self.materials[index])],
+                    Aov::Object => vec![handle_value(self.objects[index])],
+                };
+                for (channel, value) in channels.iter_mut().zip(values) {
+                    channel.values.push(value as f32);
+                }
+            }
+        }
+
+        channels
+    }
+}
+
+/// Radiance split up by how the light reached the camera. Paths are sorted by the first surface the camera ray hit,
+/// so a diffuse floor seen in a mirror counts as specular. The passes add up to the image.
+#[derive(Clone, Copy, PartialEq)]
+pub enum RenderPass {
+    DirectDiffuse,   // Light that scattered diffusely once on its way to the camera
+    IndirectDiffuse, // Light that scattered diffusely at the first surface and somewhere else before that
+    Specular,        // Light that reflected like a mirror at the first surface
+    Transmission,    // Light that refracted through the first surface
+    Emission,        // Lights seen directly
+    Background,      // The background seen directly
+}
+
+pub const RENDER_PASS_COUNT: usize = 6;
+
+/// Get a render pass by the name used on the command line
+pub fn parse_render_pass(name: &str) -> Option<RenderPass> {
+    match name {
+        "diffuse_direct" => Some(RenderPass::DirectDiffuse),
+        "diffuse_indirect" => Some(RenderPass::IndirectDiffuse),
+        "specular" => Some(RenderPass::Specular),
+        "transmission" => Some(RenderPass::Transmission),
+        "emission" => Some(RenderPass::Emission),
+        "background" => Some(RenderPass::Background),
+        _ => None,
+    }
+}
+
+fn get_render_pass_name(pass: RenderPass) -> &'static str {
+    match pass {
+        RenderPass::DirectDiffuse => "diffuse_direct",
+        RenderPass::IndirectDiffuse => "diffuse_indirect",
+        RenderPass::Specular => "specular",
+        RenderPass::Transmission => "transmission",
+        RenderPass::Emission => "emission",
+        RenderPass::Background => "background",
+    }
+}
+
+/// Split colors into red, green, and blue channels, named with layer in front if there is one
+fn get_color_channels(layer: Option<&str>, colors: &[Vector3]) -> Vec<ImageChannel> {
+    ["R", "G", "B"]
+        .iter()
+        .enumerate()
+        .map(|(component, name)| ImageChannel {
+            name: match layer {
+                Some(layer) => format!("{}.{}", layer, name),
+                None => name.to_string(),
+            },
+            values: colors
+                .iter()
+                .map(|color| [color.x, color.y, color.z][component] as f32)
+                .collect(),
+        })
+        .collect()
+}
+
+/// Write output variables and render passes next to the image. If file_path ends in .exr, the image, the output
+/// variables, and the passes are written as layers of one file. Otherwise each one is written to its own PFM file,
+/// named by adding its name and .pfm to file_path.
+pub fn write_aovs(
+    file_path: &str,
+    aov_buffers: &AovBuffers,
+    aovs: &[Aov],
+    passes: &[(RenderPass, Vec<Vector3>)],
+    colors: &[Vector3],
+) {
+    let width = aov_buffers.width as usize;
+    let height = aov_buffers.height as usize;
+
+    if file_path.to_ascii_lowercase().ends_with(".exr") {
+        let mut channels = get_color_channels(None, colors);
+        for aov in aovs {
+            channels.extend(aov_buffers.get_channels(*aov));
+        }
+        for (pass, pass_colors) in passes {
+            channels.extend(get_color_channels(
+                Some(get_render_pass_name(*pass)),
+                pass_colors,
+            ));
+        }
+        write_exr(file_path, width, height, &channels);
+        return;
+    }
+
+    for aov in aovs {
+        let mut channels = aov_buffers.get_channels(*aov);
+        // PFM files have either 1 or 3 channels
+        if channels.len() == 2 {
+            channels.push(ImageChannel {
+                name: String::new(),
+                values: vec![0.0; width * height],
+            });
+        }
+        let (layer, _) = get_aov_channel_names(*aov);
+        write_pfm(
+            &format!("{}.{}.pfm", file_path, layer),
+            width,
+            height,
+            &channels,
+        );
+    }
+    for (pass, pass_colors) in passes {
+        write_pfm(
+            &format!("{}.{}.pfm", file_path, get_render_pass_name(*pass)),
+            width,
+            height,
+            &get_color_channels(None, pass_colors),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn first_hit(depth: f64, material: usize) -> FirstHit {
+        FirstHit {
+            normal: Vector3 {
+                x: 0.0,
+                y: 1.0,
+                z: 0.0,
+            },
+            depth,
+            position: Vector3 {
+                x: depth,
+                y: 0.0,
+                z: 0.0,
+            },
+            uv: Vector2 { x: 0.5, y: 0.25 },
+            material,
+            object: 7,
+        }
+    }
+
+    #[test]
+    fn features_average_over_the_samples_that_hit() {
+        let mut aov_buffers = AovBuffers::new(2, 1);
+        let white = Vector3 {
+            x: 1.0,
+            y: 1.0,
+            z: 1.0,
+        };
+        aov_buffers.add_sample(1, 0, &white, Some(&first_hit(2.0, 3)));
+        aov_buffers.add_sample(1, 0, &Vector3::default(), None);
+        aov_buffers.add_sample(1, 0, &white, Some(&first_hit(4.0, 5)));
+
+        assert_eq!(aov_buffers.get_depth(1, 0), 3.0);
+        assert_eq!(aov_buffers.get_position(1, 0).x, 3.0);
+        assert!((aov_buffers.get_albedo(1, 0).y - 2.0 / 3.0).abs() < 1e-12);
+
+        // The handles come from the first hit
+        let materials = aov_buffers.get_channels(Aov::Material);
+        assert_eq!(materials[0].name, "material.id");
+        assert_eq!(materials[0].values, vec![-1.0, 3.0]);
+    }
+
+    #[test]
+    fn pixels_that_mostly_missed_are_background() {
+        let mut aov_buffers = AovBuffers::new(1, 1);
+        aov_buffers.add_sample(0, 0, &Vector3::default(), Some(&first_hit(2.0, 0)));
+        aov_buffers.add_sample(0, 0, &Vector3::default(), None);
+        aov_buffers.add_sample(0, 0, &Vector3::default(), None);
+
+        assert_eq!(aov_buffers.get_depth(0, 0), f64::INFINITY);
+        assert_eq!(aov_buffers.get_uv(0, 0).x, 0.0);
+    }
+}
@@ -0,0 +1,628 @@
+use std::f64::consts::PI;
+
+use crate::{
+    map::{FilterMode, ImageData, TextureSampler, WrapMode},
+    raytrace_vector::orthonormal_basis,
+    vector::{Vector2, Vector3, calc_component_product, rotate_around_y},
+};
+
+/// The light arriving from infinitely far away along rays that don't hit anything
+pub enum Background {
+    Gradient(Vector3, Vector3), // color looking straight down, color looking straight up
+    Environment(EnvironmentMap),
+    Sky(PhysicalSky),
+}
+
+/// An equirectangular image surrounding the scene.
+///
+/// The center of the image is straight down -z, the top row is straight up, and u increases turning towards +x.
+pub struct EnvironmentMap {
+    image: ImageData,
+    sampler: TextureSampler,
+    rotation: f64,  // Rotation in radians around the y-axis
+    intensity: f64, // Scale applied to the image's values
+
+    // The distribution used for importance sampling. Each pixel is weighted by its luminance times the solid angle it
+    // covers, so rows are picked from the marginal cdf and then columns from that row's conditional cdf.
+    pixel_weights: Vec<f64>,
+    total_weight: f64,
+    row_cdf: Vec<f64>,          // height + 1 entries from 0.0 to 1.0
+    column_cdfs: Vec<Vec<f64>>, // One cdf of width + 1 entries for each row
+}
+
+impl EnvironmentMap {
+    /// Create an environment map from an image. rotation is in degrees around the y-axis.
+    pub fn new(image: ImageData, rotation: f64, intensity: f64) -> Self {
+        let width = image.width();
+        let height = image.height();
+        assert!(width > 0 && height > 0, "Environment map image is empty");
+
+        let mut pixel_weights: Vec<f64> = Vec::with_capacity(width * height);
+        for j in 0..height {
+            // Rows near the poles cover less solid angle
+            let sin_theta = (PI * (j as f64 + 0.5) / height as f64).sin();
+            for i in 0..width {
+                let (color, _) = image.get_pixel(0, i, j);
+                pixel_weights.push(f64::max(luminance(&color), 0.0) * sin_theta);
+            }
+        }
+
+        let mut column_cdfs: Vec<Vec<f64>> = Vec::with_capacity(height);
+        let mut row_weights: Vec<f64> = Vec::with_capacity(height);
+        for row in pixel_weights.chunks_exact(width) {
+            let (cdf, row_weight) = build_cdf(row);
+            column_cdfs.push(cdf);
+            row_weights.push(row_weight);
+        }
+        let (row_cdf, total_weight) = build_cdf(&row_weights);
+
+        Self {
+            image,
+            sampler: TextureSampler::new(WrapMode::Repeat, FilterMode::Bilinear),
+            rotation: rotation.to_radians(),
+            intensity,
+            pixel_weights,
+            total_weight,
+            row_cdf,
+            column_cdfs,
+        }
+    }
+
+    /// Convert a world space direction to image coordinates in [0, 1], with the first row at the top
+    fn direction_to_image(&self, direction: &Vector3) -> (f64, f64) {
+        let local = rotate_around_y(&Vector3::calc_normalized_vector(direction), -self.rotation);
+        let theta = local.y.clamp(-1.0, 1.0).acos();
+        let phi = local.x.atan2(-local.z);
+
+        (0.5 + phi / (2.0 * PI), theta / PI)
+    }
+
+    /// Convert image coordinates in [0, 1], with the first row at the top, to a world space unit direction
+    fn image_to_direction(&self, x: f64, y: f64) -> Vector3 {
+        let theta = PI * y;
+        let phi = 2.0 * PI * (x - 0.5);
+        let local = Vector3 {
+            x: theta.sin() * phi.sin(),
+            y: theta.cos(),
+            z: -theta.sin() * phi.cos(),
+        };
+
+        rotate_around_y(&local, self.rotation)
+    }
+
+    fn radiance(&self, direction: &Vector3) -> Vector3 {
+        let (x, y) = self.direction_to_image(direction);
+        let (color, _) = self
+            .image
+            .sample(x, 1.0 - y, &self.sampler, Vector2::default());
+        self.intensity * color
+    }
+
+    /// The probability density in solid angle of sample picking a direction
+    fn pdf(&self, direction: &Vector3) -> f64 {
+        if self.total_weight <= 0.0 {
+            return 0.0;
+        }
+
+        let width = self.image.width();
+        let height = self.image.height();
+        let (x, y) = self.direction_to_image(direction);
+        let i = usize::min((x * width as f64) as usize, width - 1);
+        let j = usize::min((y * height as f64) as usize, height - 1);
+
+        let sin_theta = (PI * y).sin();
+        if sin_theta <= 0.0 {
+            return 0.0;
+        }
+
+        // The density over the image is constant within each pixel. The image spans 2 * pi by pi radians, and
+        // equirectangular pixels shrink by sin(theta) in solid angle.
+        let image_pdf =
+            self.pixel_weights[j * width + i] / self.total_weight * (width * height) as f64;
+        image_pdf / (2.0 * PI * PI * sin_theta)
+    }
+
+    /// Pick a direction with probability proportional to how much light comes from it.
+    /// Returns the direction, the radiance from it, and the probability density in solid angle.
+    fn sample(&self, u: &Vector2) -> Option<(Vector3, Vector3, f64)> {
+        if self.total_weight <= 0.0 {
+            return None;
+        }
+
+        let (j, y_offset) = sample_cdf(&self.row_cdf, u.y);
+        let (i, x_offset) = sample_cdf(&self.column_cdfs[j], u.x);
+
+        let x = (i as f64 + x_offset) / self.image.width() as f64;
+        let y = (j as f64 + y_offset) / self.image.height() as f64;
+        let direction = self.image_to_direction(x, y);
+
+        let pdf = self.pdf(&direction);
+        if pdf <= 0.0 {
+            return None;
+        }
+
+        Some((direction, self.radiance(&direction), pdf))
+    }
+}
+
+/// Converts luminance in cd/m^2 to the units used for rendering, so that a clear daytime sky is around 0.5
+const LUMINANCE_SCALE: f64 = 1.0 / 20000.0;
+
+/// The luminance of the sun before it passes through the atmosphere in cd/m^2
+const SUN_LUMINANCE: f64 = 1.6e9;
+
+/// The angular radius of the sun in radians
+const SUN_ANGULAR_RADIUS: f64 = 0.00465;
+
+/// A procedural daylight sky using the Preetham et al. analytic model, "A Practical Analytic Model for Daylight".
+///
+/// The sky's brightness and color come from the Perez distribution fitted for each of luminance and the two CIE
+/// chromaticity coordinates. The sun is a small disk whose color is attenuated by the atmosphere, and everything below
+/// the horizon is a diffuse ground lit by the sun and the sky.
+pub struct PhysicalSky {
+    sun_direction: Vector3, // Unit vector pointing towards the sun
+    zenith: [f64; 3], // Luminance in cd/m^2 and the x and y chromaticity of the sky straight up
+    perez: [[f64; 5]; 3], // Perez coefficients A to E for luminance, x, and y
+    perez_zenith: [f64; 3], // The Perez function at the zenith, used to normalize the distribution
+    sun_radiance: Vector3,
+    sun_cos_angle: f64, // The cosine of the sun's angular radius
+    ground_radiance: Vector3,
+}
+
+impl PhysicalSky {
+    /// Create a sky for a sun in the given direction. The sun must be above the horizon.
+    ///
+    /// turbidity: The haziness of the atmosphere. 2 is a very clear sky and 10 is hazy. The fit is valid in [2, 10].
+    /// ground_albedo: The reflectance of the ground seen below the horizon
+    pub fn new(sun_direction: Vector3, turbidity: f64, ground_albedo: Vector3) -> Self {
+        let sun_direction = Vector3::calc_normalized_vector(&sun_direction);
+        assert!(sun_direction.y > 0.0, "The sun must be above the horizon");
+        let t = turbidity.clamp(2.0, 10.0);
+        let theta_sun = sun_direction.y.acos();
+
+        // Zenith luminance in kcd/m^2 and chromaticity
+        let chi = (4.0 / 9.0 - t / 120.0) * (PI - 2.0 * theta_sun);
+        let zenith_luminance = 1000.0 * ((4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192);
+        let zenith_chromaticity = |coefficients: [[f64; 4]; 3]| -> f64 {
+            let thetas = [theta_sun.powi(3), theta_sun.powi(2), theta_sun, 1.0];
+            let turbidities = [t * t, t, 1.0];
+            let mut result = 0.0;
+            for (row, turbidity_term) in coefficients.iter().zip(turbidities) {
+                for (coefficient, theta_term) in row.iter().zip(thetas) {
+                    result += turbidity_term * coefficient * theta_term;
+                }
+            }
+            result
+        };
+        let zenith_x = zenith_chromaticity([
+            [0.00166, -0.00375, 0.00209, 0.0],
+            [-0.02903, 0.06377, -0.03202, 0.00394],
+            [0.11693, -0.21196, 0.06052, 0.25886],
+        ]);
+        let zenith_y = zenith_chromaticity([
+            [0.00275, -0.00610, 0.00317, 0.0],
+            [-0.04214, 0.08970, -0.04153, 0.00516],
+            [0.15346, -0.26756, 0.06670, 0.26688],
+        ]);
+
+        let perez = [
+            [
+                0.1787 * t - 1.4630,
+                -0.3554 * t + 0.4275,
+                -0.0227 * t + 5.3251,
+                0.1206 * t - 2.5771,
+                -0.0670 * t + 0.3703,
+            ],
+            [
+                -0.0193 * t - 0.2592,
+                -0.0665 * t + 0.0008,
+                -0.0004 * t + 0.2125,
+                -0.0641 * t - 0.8989,
+                -0.0033 * t + 0.0452,
+            ],
+            [
+                -0.0167 * t - 0.2608,
+                -0.0950 * t + 0.0092,
+                -0.0079 * t + 0.2102,
+                -0.0441 * t - 1.6537,
+                -0.0109 * t + 0.0529,
+            ],
+        ];
+        let perez_zenith = [
+            perez_function(&perez[0], 1.0, theta_sun),
+            perez_function(&perez[1], 1.0, theta_sun),
+            perez_function(&perez[2], 1.0, theta_sun),
+        ];
+
+        // Attenuate sunlight by Rayleigh and aerosol scattering along its path through the atmosphere. The color
+        // channels use representative wavelengths in micrometers.
+        let theta_sun_degrees = theta_sun.to_degrees();
+        let air_mass = 1.0 / (sun_direction.y + 0.15 * (93.885 - theta_sun_degrees).powf(-1.253));
+        let beta = 0.04608 * t - 0.04586;
+        let transmittance = |wavelength: f64| -> f64 {
+            let rayleigh = (-0.008735 * wavelength.powf(-4.08) * air_mass).exp();
+            let aerosol = (-beta * wavelength.powf(-1.3) * air_mass).exp();
+            rayleigh * aerosol
+        };
+        let sun_radiance = (SUN_LUMINANCE * LUMINANCE_SCALE)
+            * Vector3 {
+                x: transmittance(0.65),
+                y: transmittance(0.55),
+                z: transmittance(0.45),
+            };
+
+        let mut sky = Self {
+            sun_direction,
+            zenith: [zenith_luminance, zenith_x, zenith_y],
+            perez,
+            perez_zenith,
+            sun_radiance,
+            sun_cos_angle: SUN_ANGULAR_RADIUS.cos(),
+            ground_radiance: Vector3 {
+                x: 0.0,
+                y: 0.0,
+                z: 0.0,
+            },
+        };
+
+        // The ground is lit by the sun and by the sky, which we integrate numerically over the upper hemisphere
+        let theta_steps = 32;
+        let phi_steps = 64;
+        let d_theta = 0.5 * PI / theta_steps as f64;
+        let d_phi = 2.0 * PI / phi_steps as f64;
+        let mut irradiance =
+            (sun_direction.y * 2.0 * PI * (1.0 - sky.sun_cos_angle)) * sun_radiance;
+        for i in 0..theta_steps {
+            let theta = (i as f64 + 0.5) * d_theta;
+            for j in 0..phi_steps {
+                let phi = (j as f64 + 0.5) * d_phi;
+                let direction = Vector3 {
+                    x: theta.sin() * phi.cos(),
+                    y: theta.cos(),
+                    z: theta.sin() * phi.sin(),
+                };
+                let solid_angle = theta.sin() * d_theta * d_phi;
+                irradiance =
+                    irradiance + (theta.cos() * solid_angle) * sky.sky_radiance(&direction);
+            }
+        }
+        sky.ground_radiance = (1.0 / PI) * calc_component_product(&ground_albedo, &irradiance);
+
+        sky
+    }
+
+    /// The light scattered by the atmosphere towards a unit direction above the horizon, not including the sun disk
+    fn sky_radiance(&self, direction: &Vector3) -> Vector3 {
+        // The model misbehaves right at the horizon
+        let cos_theta = f64::max(direction.y, 0.01);
+        let gamma = Vector3::dot_product(direction, &self.sun_direction)
+            .clamp(-1.0, 1.0)
+            .acos();
+
+        let distribution = |index: usize| -> f64 {
+            self.zenith[index] * perez_function(&self.perez[index], cos_theta, gamma)
+                / self.perez_zenith[index]
+        };
+        let luminance = distribution(0) * LUMINANCE_SCALE;
+        let x = distribution(1);
+        let y = distribution(2);
+
+        xyy_to_linear_srgb(x, y, luminance)
+    }
+
+    fn radiance(&self, direction: &Vector3) -> Vector3 {
+        let unit_direction = Vector3::calc_normalized_vector(direction);
+        if unit_direction.y <= 0.0 {
+            return self.ground_radiance;
+        }
+
+        let sky = self.sky_radiance(&unit_direction);
+        if Vector3::dot_product(&unit_direction, &self.sun_direction) >= self.sun_cos_angle {
+            sky + self.sun_radiance
+        } else {
+            sky
+        }
+    }
+
+    /// The probability density in solid angle of sample picking a direction
+    fn pdf(&self, direction: &Vector3) -> f64 {
+        let unit_direction = Vector3::calc_normalized_vector(direction);
+        if Vector3::dot_product(&unit_direction, &self.sun_direction) >= self.sun_cos_angle {
+            1.0 / (2.0 * PI * (1.0 - self.sun_cos_angle))
+        } else {
+            0.0
+        }
+    }
+
+    /// Pick a direction uniformly within the sun disk, which is where almost all of the direct light comes from. The
+    /// rest of the sky is bright enough to be found by scattered rays.
+    /// Returns the direction, the radiance from it, and the probability density in solid angle.
+    fn sample(&self, u: &Vector2) -> Option<(Vector3, Vector3, f64)> {
+        let cos_theta = 1.0 - u.x * (1.0 - self.sun_cos_angle);
+        let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
+        let phi = 2.0 * PI * u.y;
+
+        let (a, b) = orthonormal_basis(&self.sun_direction);
+        let direction = (sin_theta * phi.cos()) * a
+            + (sin_theta * phi.sin()) * b
+            + cos_theta * self.sun_direction;
+
+        Some((
+            direction,
+            self.radiance(&direction),
+            1.0 / (2.0 * PI * (1.0 - self.sun_cos_angle)),
+        ))
+    }
+}
+
+/// The Perez sky distribution for coefficients A to E, where theta is the angle from the zenith and gamma is the angle
+/// from the sun
+fn perez_function(coefficients: &[f64; 5], cos_theta: f64, gamma: f64) -> f64 {
+    let [a, b, c, d, e] = *coefficients;
+    (1.0 + a * (b / cos_theta).exp()) * (1.0 + c * (d * gamma).exp() + e * gamma.cos().powi(2))
+}
+
+/// Convert a CIE xyY color to linear Rec. 709 RGB. Colors outside of the Rec. 709 gamut are clamped.
+fn xyy_to_linear_srgb(x: f64, y: f64, luminance: f64) -> Vector3 {
+    if y <= 0.0 {
+        return Vector3 {
+            x: 0.0,
+            y: 0.0,
+            z: 0.0,
+        };
+    }
+
+    let cie_x = x / y * luminance;
+    let cie_z = (1.0 - x - y) / y * luminance;
+    Vector3 {
+        x: f64::max(3.2406 * cie_x - 1.5372 * luminance - 0.4986 * cie_z, 0.0),
+        y: f64::max(-0.9689 * cie_x + 1.8758 * luminance + 0.0415 * cie_z, 0.0),
+        z: f64::max(0.0557 * cie_x - 0.2040 * luminance + 1.0570 * cie_z, 0.0),
+    }
+}
+
+/// Get the light arriving along a ray that escaped the scene
+pub fn get_background_color(background: &Background, direction: &Vector3) -> Vector3 {
+    match background {
+        Background::Gradient(down, up) => {
+            let unit_vector = Vector3::calc_normalized_vector(direction);
+            let lerp_value = (unit_vector.y + 1.0) / 2.0; // Y value has a range of -1.0 to 1.0, and we map that to 0.0 to 1.0
+            (1.0 - lerp_value) * down + lerp_value * up
+        }
+        Background::Environment(environment_map) => environment_map.radiance(direction),
+        Background::Sky(sky) => sky.radiance(direction),
+    }
+}
+
+/// Sample a direction towards the background for direct lighting from two uniform values in [0, 1).
+/// Returns None if the background can't be importance sampled, otherwise the direction, the radiance from that
+/// direction, and the probability density of picking it in solid angle.
+pub fn sample_background(background: &Background, u: &Vector2) -> Option<(Vector3, Vector3, f64)> {
+    match background {
+        Background::Gradient(_, _) => None,
+        Background::Environment(environment_map) => environment_map.sample(u),
+        Background::Sky(sky) => sky.sample(u),
+    }
+}
+
+/// The probability density in solid angle of sample_background picking a direction
+pub fn background_pdf(background: &Background, direction: &Vector3) -> f64 {
+    match background {
+        Background::Gradient(_, _) => 0.0,
+        Background::Environment(environment_map) => environment_map.pdf(direction),
+        Background::Sky(sky) => sky.pdf(direction),
+    }
+}
+
+/// The luminance of a linear Rec. 709 color
+pub fn luminance(color: &Vector3) -> f64 {
+    0.2126 * color.x + 0.7152 * color.y + 0.0722 * color.z
+}
+
+/// Build a normalized cumulative distribution from non-negative weights.
+/// Returns the cdf, which has one more entry than weights, and the sum of the weights.
+pub fn build_cdf(weights: &[f64]) -> (Vec<f64>, f64) {
+    let mut cdf: Vec<f64> = Vec::with_capacity(weights.len() + 1);
+    cdf.push(0.0);
+    for weight in weights {
+        cdf.push(cdf[cdf.len() - 1] + weight);
+    }
+
+    let total = cdf[cdf.len() - 1];
+    if total > 0.0 {
+        for value in cdf.iter_mut() {
+            *value /= total;
+        }
+    } else {
+        // Fall back to a uniform distribution so rows without any light can still be indexed
+        let count = weights.len() as f64;
+        for (index, value) in cdf.iter_mut().enumerate() {
+            *value = index as f64 / count;
+        }
+    }
+
+    (cdf, total)
+}
+
+/// Find the bin of a cdf that contains xi in [0, 1).
+/// Returns the bin index and how far xi is through the bin in [0, 1).
+pub fn sample_cdf(cdf: &[f64], xi: f64) -> (usize, f64) {
+    // The last entry that is less than or equal to xi, skipping empty bins
+    let index = cdf.partition_point(|value| *value <= xi).saturating_sub(1);
+    let index = usize::min(index, cdf.len() - 2);
+
+    let bin_width = cdf[index + 1] - cdf[index];
+    let offset = if bin_width > 0.0 {
+        ((xi - cdf[index]) / bin_width).clamp(0.0, 1.0 - f64::EPSILON)
+    } else {
+        0.0
+    };
+
+    (index, offset)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A small environment map with a different value in every pixel and one much brighter pixel
+    fn make_environment_map() -> EnvironmentMap {
+        let (width, height) = (8, 4);
+        let mut pixels = Vec::with_capacity(width * height * 3);
+        for j in 0..height {
+            for i in 0..width {
+                let value = if (i, j) == (5, 2) {
+                    40.0
+                } else {
+                    0.1 + ((i * 3 + j * 5) % 7) as f32
+                };
+                pixels.extend([value, 0.5 * value, 0.25 * value]);
+            }
+        }
+        EnvironmentMap::new(ImageData::from_pixels(width, height, 3, pixels), 30.0, 1.0)
+    }
+
+    #[test]
+    fn environment_samples_match_their_pdf() {
+        let map = make_environment_map();
+        let (width, height) = (map.image.width(), map.image.height());
+        for a in 0..32 {
+            for b in 0..32 {
+                let u = Vector2 {
+                    x: (a as f64 + 0.5) / 32.0,
+                    y: (b as f64 + 0.5) / 32.0,
+                };
+                let (direction, _, pdf) = map.sample(&u).unwrap();
+                assert!((pdf - map.pdf(&direction)).abs() < 1e-9 * pdf);
+
+                // The density of the picked pixel, spread over the solid angle it covers
+                let (j, _) = sample_cdf(&map.row_cdf, u.y);
+                let (i, _) = sample_cdf(&map.column_cdfs[j], u.x);
+                let (_, y) = map.direction_to_image(&direction);
+                let expected = map.pixel_weights[j * width + i] / map.total_weight
+                    * (width * height) as f64
+                    / (2.0 * PI * PI * (PI * y).sin());
+                assert!((pdf - expected).abs() < 1e-9 * expected);
+            }
+        }
+    }
+
+    #[test]
+    fn environment_pdf_integrates_to_one() {
+        let map = make_environment_map();
+        let (theta_count, phi_count) = (400, 800);
+        let mut integral = 0.0;
+        for a in 0..theta_count {
+            let theta = PI * (a as f64 + 0.5) / theta_count as f64;
+            for b in 0..phi_count {
+                let phi = 2.0 * PI * (b as f64 + 0.5) / phi_count as f64;
+                let direction = Vector3 {
+                    x: theta.sin() * phi.cos(),
+                    y: theta.cos(),
+                    z: theta.sin() * phi.sin(),
+                };
+                let solid_angle =
+                    theta.sin() * (PI / theta_count as f64) * (2.0 * PI / phi_count as f64);
+                integral += map.pdf(&direction) * solid_angle;
+            }
+        }
+        assert!((integral - 1.0).abs() < 0.01, "{}", integral);
+    }
+
+    #[test]
+    fn brightest_pixel_is_sampled_most_often() {
+        let map = make_environment_map();
+        let (width, height) = (map.image.width(), map.image.height());
+        let mut counts = vec![0; width * height];
+        for a in 0..64 {
+            for b in 0..64 {
+                let u = Vector2 {
+                    x: (a as f64 + 0.5) / 64.0,
+                    y: (b as f64 + 0.5) / 64.0,
+                };
+                let (direction, _, _) = map.sample(&u).unwrap();
+                let (x, y) = map.direction_to_image(&direction);
+                let i = usize::min((x * width as f64) as usize, width - 1);
+                let j = usize::min((y * height as f64) as usize, height - 1);
+                counts[j * width + i] += 1;
+            }
+        }
+
+        let brightest = 2 * width + 5;
+        assert!(
+            (0..counts.len()).all(|index| index == brightest || counts[index] < counts[brightest])
+        );
+    }
+
+    /// A clear sky with the sun 60 degrees from the zenith
+    fn make_sky() -> PhysicalSky {
+        let theta_sun = 60.0_f64.to_radians();
+        PhysicalSky::new(
+            Vector3 {
+                x: theta_sun.sin(),
+                y: theta_sun.cos(),
+                z: 0.0,
+            },
+            3.0,
+            Vector3 {
+                x: 0.2,
+                y: 0.2,
+                z: 0.2,
+            },
+        )
+    }
+
+    #[test]
+    fn sky_zenith_matches_the_preetham_model() {
+        // The zenith luminance in cd/m^2 and chromaticity from the formulas in the paper's appendix, for a turbidity
+        // of 3 and the sun 60 degrees from the zenith
+        let (luminance, x, y) = (5139.16, 0.24493, 0.25257);
+        let sky = make_sky();
+        assert!((sky.zenith[0] - luminance).abs() < 0.01);
+        assert!((sky.zenith[1] - x).abs() < 1e-5);
+        assert!((sky.zenith[2] - y).abs() < 1e-5);
+
+        // The Perez distribution is normalized so that the sky straight up has the zenith's color, which is bluer
+        // than white
+        let up = Vector3 {
+            x: 0.0,
+            y: 1.0,
+            z: 0.0,
+        };
+        let expected = xyy_to_linear_srgb(
+            sky.zenith[1],
+            sky.zenith[2],
+            sky.zenith[0] * LUMINANCE_SCALE,
+        );
+        let zenith = sky.radiance(&up);
+        assert!((zenith - expected).magnitude() < 1e-5 * expected.magnitude());
+        assert!(zenith.z > zenith.y && zenith.y > zenith.x);
+    }
+
+    #[test]
+    fn sun_samples_match_their_pdf() {
+        let sky = make_sky();
+        for a in 0..16 {
+            for b in 0..16 {
+                let u = Vector2 {
+                    x: (a as f64 + 0.5) / 16.0,
+                    y: (b as f64 + 0.5) / 16.0,
+                };
+                let (direction, radiance, pdf) = sky.sample(&u).unwrap();
+                assert!(Vector3::dot_product(&direction, &sky.sun_direction) >= sky.sun_cos_angle);
+                assert!((pdf - sky.pdf(&direction)).abs() < 1e-9 * pdf);
+                assert!(radiance.x > sky.sun_radiance.x);
+            }
+        }
+
+        // The pdf covers the sun disk's solid angle, and nothing outside of it
+        let solid_angle = 2.0 * PI * (1.0 - sky.sun_cos_angle);
+        assert!((sky.pdf(&sky.sun_direction) * solid_angle - 1.0).abs() < 1e-9);
+        let up = Vector3 {
+            x: 0.0,
+            y: 1.0,
+            z: 0.0,
+        };
+        assert_eq!(sky.pdf(&up), 0.0);
+    }
+}
@@ -1,32 +1,86 @@
-use rand::{Rng, rngs::ThreadRng};
+use std::{f64::consts::PI, fs, time::Instant};
+
+use learn_raycasting::denoise::{AuxiliaryBuffers, DenoiseSettings, denoise};
 
 use crate::{
+    aov::{Aov, AovBuffers, FirstHit, RENDER_PASS_COUNT, RenderPass, write_aovs},
+    background::{Background, background_pdf, get_background_color, luminance, sample_background},
+    checkpoint::{CHECKPOINT_MAGIC, CheckpointReader, CheckpointWriter},
+    film::Film,
+    filter::{Filter, get_filter_radius},
+    hit_record::{HitRecord, compute_uv_footprint},
     hittables::Hittables,
-    material::{Material, scatter_ray},
+    lens::{Aperture, LensSystem, sample_aperture},
+    light::{Lights, area_light_pdf, sample_light},
+    material::{
+        Lobe, Material, ScatterValues, evaluate_scatter, get_albedo, get_emitted, get_lobe,
+        get_shading_normal, scatter_ray,
+    },
     math::degrees_to_radians,
-    ray::Ray,
-    raytrace_vector::random_vector_in_unit_disk,
-    vector::{Vector3, calc_cross_product},
+    ray::{Ray, RayDifferential},
+    sampler::{SamplePattern, Sampler, hash},
+    tile::{PixelRegion, encode_tile, write_tile},
+    vector::{Vector2, Vector3, calc_component_product, calc_cross_product},
 };
 
+/// How directions in the scene are mapped onto the image
+#[derive(Clone, Copy)]
+pub enum Projection {
+    Perspective,             // A pinhole or thin lens with the camera's vfov
+    Orthographic(f64),       // Parallel rays over a view this wide in world units
+    EquidistantFisheye(f64), // Field of view in degrees. Angles grow evenly from the center.
+    EquisolidFisheye(f64),   // Field of view in degrees. Areas are in proportion to solid angles.
+    Equirectangular,         // The full sphere of directions, laid out like the environment maps
+}
+
+/// Where the images of the two eyes go in a stereo image
+#[derive(Clone, Copy)]
+pub enum StereoLayout {
+    SideBySide, // Left eye on the left
+    TopBottom,  // Left eye on the top
+}
+
 pub struct Camera {
     image_width: i32,  // The height of the image in pixels
     image_height: i32, // The width of the image in pixels
+    view_width: i32,   // The width of one eye's image, or image_width without stereo
+    view_height: i32,  // The height of one eye's image, or image_height without stereo
     vfov: f64,         // Vertical field of view
     top_left_pixel: Vector3,
     pixel_spacing_u: Vector3, // The vector to add to a pixel to get the next horizontal pixel
     pixel_spacing_v: Vector3, // The vector to add to a pixel to get the next vertical pixel
     center: Vector3,          // The camera's center
+    u: Vector3,               // Points to the camera's right
+    v: Vector3,               // Points up from the camera
+    w: Vector3,               // Points backwards, away from what the camera is looking at
+    projection: Projection,
+    stereo: Option<Stereo>,
+    aperture: Aperture, // The shape of the lens that defocused rays start from
+    lens_camera: Option<LensCamera>, // Traces rays through a real lens in place of the projection
+    focal_length: Option<f64>, // In millimeters, when the field of view comes from a sensor size
+    f_number: Option<f64>, // The focal length over the diameter of the aperture
+    shutter_open: f64,  // The time in seconds that the shutter opens at
+    shutter_close: f64, // The time in seconds that the shutter closes at
+    exposure: Option<Exposure>,
     look_at: Vector3,
     vup: Vector3,
-    pixel_sample_count: i32, // The number of points around a pixel to sample from
-    one_over_pixel_sample_count: f64,
+    pixel_sample_count: i32, // The number of points around a pixel to sample from, on average with adaptive sampling
     defocus_angle: f64, // Variation angle in degrees of rays through each pixel. Determines the size of the defocus blur disk
     defocus_disk_u: Vector3,
     defocus_disk_v: Vector3,
     focus_distance: f64, // Distance from the camera center to the plane of perfect focus
+    russian_roulette_depth: i32, // The number of reflections before paths can be randomly terminated
+    adaptive_sampling: Option<AdaptiveSampling>,
+    filter: Filter, // How samples are weighted onto the pixels around them
+    denoise: Option<DenoiseSettings>, // Denoise the finished image, guided by the first surfaces hit
+    aov_output: Option<AovOutput>,
+    checkpoint: Option<CheckpointSettings>,
+    resume_path: Option<String>, // A checkpoint to continue rendering from
+    crop_window: Option<PixelRegion>, // The only pixels to take samples through
+    tile_path: Option<String>,   // Where to write the rendered pixels as a tile for merging
 
-    rng: ThreadRng,
+    seed: u64,        // Every random choice of the render follows from this
+    sampler: Sampler, // Supplies the values that each pixel sample uses for its random choices
 }
 
 impl Camera {
@@ -47,16 +101,6 @@ impl Camera {
             if image_height > 0 { image_height } else { 1 }
         };
 
-        // Calculate the height of the viewport
-        let theta = degrees_to_radians(vfov);
-        let h = (theta / 2.0).tan();
-
-        // h is the opposite, focal length is the adjacent, hence the multiplication by focal length.
-        // The multiplication by 2.0 is because we want the height of the whole viewport, not half of it.
-        let viewport_height = 2.0 * h * focus_distance;
-        // We don't reuse the aspect_ratio for calculating the viewport_width b/c that is the idealized ratio (not the actual ratio)
-        let viewport_width = viewport_height * (image_width as f64 / image_height as f64);
-
         // Calculate the vectors for our camera's coordinate system
         let (u, v, w) = {
             // Our coordinate system is right-handed, so vector w goes from the viewport towards the center
@@ -67,212 +111,1705 @@ impl Camera {
             (u, v, w)
         };
 
-        let viewport_u = viewport_width * u;
-        let viewport_v = -1.0 * viewport_height * v;
+        let mut camera = Self {
+            image_width,
+            image_height,
+            view_width: image_width,
+            view_height: image_height,
+            vfov,
+            top_left_pixel: Vector3::default(),
+            pixel_spacing_u: Vector3::default(),
+            pixel_spacing_v: Vector3::default(),
+            center,
+            u,
+            v,
+            w,
+            projection: Projection::Perspective,
+            stereo: None,
+            aperture: Aperture::Disk,
+            lens_camera: None,
+            focal_length: None,
+            f_number: None,
+            shutter_open: 0.0,
+            shutter_close: 1.0,
+            exposure: None,
+            look_at,
+            vup,
+            pixel_sample_count,
+            sampler: Sampler::new(SamplePattern::Sobol, pixel_sample_count),
+            seed: 0,
+            defocus_angle,
+            defocus_disk_u: Vector3::default(),
+            defocus_disk_v: Vector3::default(),
+            focus_distance,
+            russian_roulette_depth: 3,
+            adaptive_sampling: None,
+            filter: Filter::Box(0.5),
+            denoise: None,
+            aov_output: None,
+            checkpoint: None,
+            resume_path: None,
+            crop_window: None,
+            tile_path: None,
+        };
+        camera.update_viewport();
+        camera
+    }
+
+    /// Place the viewport and the defocus disk for the field of view and defocus angle
+    fn update_viewport(&mut self) {
+        // Calculate the height of the viewport
+        let theta = degrees_to_radians(self.vfov);
+        let h = (theta / 2.0).tan();
+
+        // h is the opposite, focal length is the adjacent, hence the multiplication by focal length.
+        // The multiplication by 2.0 is because we want the height of the whole viewport, not half of it.
+        let viewport_height = 2.0 * h * self.focus_distance;
+        // We don't reuse the aspect_ratio for calculating the viewport_width b/c that is the idealized ratio (not the actual ratio)
+        let viewport_width = viewport_height * (self.view_width as f64 / self.view_height as f64);
+
+        let viewport_u = viewport_width * self.u;
+        let viewport_v = -1.0 * viewport_height * self.v;
 
         // Pixels are inset by half the pixel-to-pixel distance so that the viewport area is evenly divided into width x height regions
-        let pixel_spacing_u = (1.0 / (image_width as f64)) * viewport_u;
-        let pixel_spacing_v = (1.0 / (image_height as f64)) * viewport_v; // Negative since we go "down" the viewport
+        self.pixel_spacing_u = (1.0 / (self.view_width as f64)) * viewport_u;
+        self.pixel_spacing_v = (1.0 / (self.view_height as f64)) * viewport_v; // Negative since we go "down" the viewport
 
-        let top_left_pixel = {
+        self.top_left_pixel = {
             // Term 1 is subtracted because w points away from the viewport
             // Term 2 is subtracted because we want the "leftmost" column, and u points to the "right"
             // Term 3 is subtracted because we want the "top" row, and v points "down"
-            let viewport_upper_left =
-                center - (focus_distance * w) - (0.5 * viewport_u) - (0.5 * viewport_v);
+            let viewport_upper_left = self.center
+                - (self.focus_distance * self.w)
+                - (0.5 * viewport_u)
+                - (0.5 * viewport_v);
 
             // We need to inset the top-left pixel
-            viewport_upper_left + 0.5 * pixel_spacing_u + 0.5 * pixel_spacing_v
+            viewport_upper_left + 0.5 * self.pixel_spacing_u + 0.5 * self.pixel_spacing_v
         };
 
         // Calculate the camera defocus disk basis vectors
-        let defocus_radius = focus_distance * degrees_to_radians(defocus_angle / 2.0).tan();
-        let defocus_disk_u = defocus_radius * u;
-        let defocus_disk_v = defocus_radius * v;
+        let defocus_radius =
+            self.focus_distance * degrees_to_radians(self.defocus_angle / 2.0).tan();
+        self.defocus_disk_u = defocus_radius * self.u;
+        self.defocus_disk_v = defocus_radius * self.v;
+    }
+
+    /// Set how the values for the samples of each pixel are spread out
+    pub fn set_sample_pattern(&mut self, pattern: SamplePattern) {
+        self.sampler = Sampler::new(pattern, self.max_pixel_sample_count());
+        self.sampler.set_seed(self.seed as u32);
+    }
+
+    /// Set the seed that every random choice of the render follows from. Renders with the same seed and settings come
+    /// out the same, and renders with different seeds differ only in their noise.
+    pub fn set_seed(&mut self, seed: u64) {
+        self.seed = seed;
+        self.sampler.set_seed(seed as u32);
+    }
+
+    /// Save the progress of the render to a checkpoint file every interval_seconds, so that it can be resumed if the
+    /// process stops. Checkpoints are made between passes over the image.
+    pub fn set_checkpoint(&mut self, file_path: String, interval_seconds: f64) {
+        self.checkpoint = Some(CheckpointSettings {
+            file_path,
+            interval_seconds,
+        });
+    }
+
+    /// Continue the render from a checkpoint. The render must have the same settings as the one that made the
+    /// checkpoint, and the result is the same as if it had never stopped.
+    pub fn set_resume(&mut self, file_path: String) {
+        self.resume_path = Some(file_path);
+    }
+
+    /// The settings that decide which samples a render takes and how they're added up, which a resumed render must
+    /// share with the render that made its checkpoint. Floats keep all of their bits so that they're compared exactly.
+    fn get_sampling_settings(&self, max_depth: i32) -> [u64; 9] {
+        let (filter_kind, filter_parameters) = match self.filter {
+            Filter::Box(radius) => (0, [radius, 0.0, 0.0]),
+            Filter::Tent(radius) => (1, [radius, 0.0, 0.0]),
+            Filter::Gaussian(radius, sigma) => (2, [radius, sigma, 0.0]),
+            Filter::MitchellNetravali(radius, b, c) => (3, [radius, b, c]),
+            Filter::BlackmanHarris(radius) => (4, [radius, 0.0, 0.0]),
+        };
+        let noise_threshold = match &self.adaptive_sampling {
+            Some(adaptive) => adaptive.noise_threshold.to_bits(),
+            None => u64::MAX,
+        };
+
+        [
+            self.sampler.get_pattern() as u64,
+            filter_kind,
+            filter_parameters[0].to_bits(),
+            filter_parameters[1].to_bits(),
+            filter_parameters[2].to_bits(),
+            noise_threshold,
+            self.seed,
+            max_depth as u64,
+            self.russian_roulette_depth as u64,
+        ]
+    }
+
+    /// Only take samples through the pixels from x, y to x + width, y + height. The camera's framing doesn't change, so
+    /// the rest of the image is left black and the cropped part matches the same part of a full render.
+    pub fn set_crop_window(&mut self, x: i32, y: i32, width: i32, height: i32) {
+        if width <= 0
+            || height <= 0
+            || x < 0
+            || y < 0
+            || x + width > self.image_width
+            || y + height > self.image_height
+        {
+            panic!(
+                "The crop window {}x{} at {}, {} isn't inside of the {}x{} image",
+                width, height, x, y, self.image_width, self.image_height
+            );
+        }
+
+        self.crop_window = Some(PixelRegion {
+            x0: x,
+            y0: y,
+            x1: x + width,
+            y1: y + height,
+        });
+    }
+
+    /// Write the rendered pixels to a tile file as well as the image, along with where they are in the image. Tiles
+    /// rendered with different crop windows, or of the same pixels with different seeds, can be merged with
+    /// merge_tiles.
+    pub fn set_tile_output(&mut self, file_path: String) {
+        self.tile_path = Some(file_path);
+    }
+
+    /// The pixels that samples are taken through
+    pub fn render_region(&self) -> PixelRegion {
+        self.crop_window.unwrap_or(PixelRegion {
+            x0: 0,
+            y0: 0,
+            x1: self.image_width,
+            y1: self.image_height,
+        })
+    }
+
+    /// The pixels that the samples taken through a region reach, which is a little more than the region since samples
+    /// near its edge are splatted onto the pixels just outside of it too
+    pub fn get_tile_region(&self, region: &PixelRegion) -> PixelRegion {
+        region.expand(
+            get_filter_radius(&self.filter),
+            self.image_width,
+            self.image_height,
+        )
+    }
+
+    /// Set how directions in the scene are mapped onto the image. Only the perspective projection is blurred by the
+    /// defocus angle and focus distance, apart from the orthographic one focusing at the focus distance in the same way.
+    pub fn set_projection(&mut self, projection: Projection) {
+        self.projection = projection;
+    }
+
+    /// Set the shape of the aperture, which out of focus highlights take on. It's used by the thin lens of the
+    /// perspective and orthographic projections, and as the aperture stop of a lens system.
+    pub fn set_aperture(&mut self, aperture: Aperture) {
+        self.aperture = aperture;
+    }
+
+    /// Trace the camera rays through a lens system, focused at the focus distance, in place of the projection. The film
+    /// has the lens system's diagonal and the image's aspect ratio, and sits at the camera's center. Scenes are taken
+    /// to be in meters. The lens's distortion and vignetting come out of the tracing, with the image's brightness
+    /// scaled so that the center of the image is as bright as with the other projections when the aperture is round.
+    pub fn set_lens_system(&mut self, mut lens_system: LensSystem) {
+        lens_system.focus(self.focus_distance * MILLIMETERS_PER_UNIT);
+
+        let aspect_ratio = self.view_width as f64 / self.view_height as f64;
+        let film_height = lens_system.film_diagonal() / (1.0 + aspect_ratio * aspect_ratio).sqrt();
+        let mut lens_camera = LensCamera {
+            system: lens_system,
+            film_width: aspect_ratio * film_height,
+            film_height,
+            exposure_scale: 1.0,
+        };
+
+        // Find how much of the light through the back of the lens reaches the scene from the center of the film
+        let grid_size = 64;
+        let mut transmitted = 0.0;
+        for i in 0..grid_size {
+            for j in 0..grid_size {
+                let lens_offset = Vector2 {
+                    x: (i as f64 + 0.5) / grid_size as f64,
+                    y: (j as f64 + 0.5) / grid_size as f64,
+                };
+                let center = 0.5 * self.view_width as f64;
+                let middle = 0.5 * self.view_height as f64;
+                if let Some((_, weight)) = self.get_lens_ray(
+                    &lens_camera,
+                    &Aperture::Disk,
+                    0.0,
+                    center,
+                    middle,
+                    &lens_offset,
+                ) {
+                    transmitted += weight;
+                }
+            }
+        }
+        if transmitted <= 0.0 {
+            panic!("No light makes it through the lens system");
+        }
+        lens_camera.exposure_scale = (grid_size * grid_size) as f64 / transmitted;
+        self.lens_camera = Some(lens_camera);
+    }
+
+    /// Get the ray through a point in an eye's image, traced through the lens system from the film. Returns None if
+    /// the lens blocks the ray, and otherwise the ray along with how much to scale the light it finds by.
+    fn get_lens_ray(
+        &self,
+        lens_camera: &LensCamera,
+        aperture: &Aperture,
+        eye: f64,
+        film_x: f64,
+        film_y: f64,
+        lens_offset: &Vector2,
+    ) -> Option<(Ray, f64)> {
+        // The lens flips the image, so the film is flipped back
+        let film_point = Vector3 {
+            x: -(film_x / self.view_width as f64 - 0.5) * lens_camera.film_width,
+            y: (film_y / self.view_height as f64 - 0.5) * lens_camera.film_height,
+            z: 0.0,
+        };
+        let (rear_z, rear_radius) = lens_camera.system.get_rear_element();
+        let rear_point = Vector3 {
+            x: 0.0,
+            y: 0.0,
+            z: rear_z,
+        } + rear_radius * sample_aperture(&Aperture::Disk, lens_offset);
+        let direction = Vector3::calc_normalized_vector(&(rear_point - film_point));
+        let (origin, direction, transmission) =
+            lens_camera.system.trace(film_point, direction, aperture)?;
+
+        // Light reaching the film at an angle is spread over more of it, falling off with the fourth power of the
+        // angle's cosine
+        let cos_theta = (rear_point - film_point).z / (rear_point - film_point).magnitude();
+        let to_world = |lens_vector: &Vector3| {
+            lens_vector.x * self.u + lens_vector.y * self.v - lens_vector.z * self.w
+        };
+        let ray = Ray {
+            origin: self.center
+                + self.get_eye_offset(eye, self.u)
+                + (1.0 / MILLIMETERS_PER_UNIT) * to_world(&origin),
+            direction: to_world(&direction),
+            time: 0.0,
+        };
+        Some((
+            ray,
+            lens_camera.exposure_scale * transmission * cos_theta.powi(4),
+        ))
+    }
+
+    /// Set the field of view from the focal length of a lens and the width and height of the sensor behind it, all in
+    /// millimeters. The image is the largest part of the sensor with the image's aspect ratio, centered on it.
+    pub fn set_sensor(&mut self, focal_length: f64, sensor_width: f64, sensor_height: f64) {
+        if focal_length <= 0.0 || sensor_width <= 0.0 || sensor_height <= 0.0 {
+            panic!(
+                "The focal length {} and sensor size {}x{} must be positive",
+                focal_length, sensor_width, sensor_height
+            );
+        }
+
+        let aspect_ratio = self.view_width as f64 / self.view_height as f64;
+        let film_height = f64::min(sensor_height, sensor_width / aspect_ratio);
+        self.vfov = 2.0 * (film_height / (2.0 * focal_length)).atan().to_degrees();
+        self.focal_length = Some(focal_length);
+        self.update_viewport();
+    }
+
+    /// Set the size of the aperture from an f-number, which is the focal length over the aperture's diameter. The
+    /// defocus angle follows from the aperture and the focus distance, with the scene taken to be in meters. The
+    /// sensor has to be set first.
+    pub fn set_f_number(&mut self, f_number: f64) {
+        let focal_length = self
+            .focal_length
+            .expect("The f-number needs the focal length of a sensor");
+        if f_number <= 0.0 {
+            panic!("The f-number {} must be positive", f_number);
+        }
+
+        let aperture_radius = focal_length / (2.0 * f_number) / MILLIMETERS_PER_UNIT;
+        self.defocus_angle = 2.0 * (aperture_radius / self.focus_distance).atan().to_degrees();
+        self.f_number = Some(f_number);
+        self.update_viewport();
+    }
+
+    /// Set the times in seconds that the shutter opens and closes at. Moving objects move over the first second, so
+    /// the times must be between 0.0 and 1.0, and the shutter is open from 0.0 to 1.0 if it isn't set.
+    pub fn set_shutter(&mut self, open: f64, close: f64) {
+        if !(0.0 <= open && open <= close && close <= 1.0) {
+            panic!(
+                "The shutter must open and close between 0 and 1 seconds, not at {} and {}",
+                open, close
+            );
+        }
+
+        self.shutter_open = open;
+        self.shutter_close = close;
+    }
+
+    /// Expose the image the way a camera with an ISO sensitivity, the shutter's time, and the f-number would, scaling
+    /// the colors before they're tone mapped. compensation adds stops to the exposure. The scenes' radiance is in units
+    /// where daylight is around 1, so the exposure is relative to the "sunny 16" settings of f/16, a 1/100 second
+    /// shutter, and ISO 100, which leave the image unchanged. The f-number has to be set first.
+    pub fn set_iso(&mut self, iso: f64, compensation: f64) {
+        if self.f_number.is_none() {
+            panic!("The ISO exposure needs an f-number");
+        }
+        if iso <= 0.0 {
+            panic!("The ISO {} must be positive", iso);
+        }
+
+        self.exposure = Some(Exposure { iso, compensation });
+    }
+
+    /// The amount that the colors of the image are scaled by before they're tone mapped
+    pub fn get_exposure(&self) -> f64 {
+        match (&self.exposure, self.f_number) {
+            (Some(exposure), Some(f_number)) => {
+                let shutter_time = self.shutter_close - self.shutter_open;
+                shutter_time / (f_number * f_number)
+                    * (exposure.iso / 100.0)
+                    * 2.0_f64.powf(exposure.compensation)
+                    / REFERENCE_EXPOSURE
+            }
+            _ => 1.0,
+        }
+    }
+
+    /// Render an image for each eye, offset from the camera's center by half of interocular_distance to either side,
+    /// and lay them out next to each other in one image. The image gets twice as wide or tall as it was. The eyes'
+    /// rays cross at convergence_distance, which is the focus distance if it isn't given, so that things at that
+    /// distance appear at the depth of the screen.
+    ///
+    /// With the equirectangular projection, the eyes go around a circle as the direction changes, the way that a
+    /// viewer's eyes would as they turn their head, which is known as omni-directional stereo.
+    pub fn set_stereo(
+        &mut self,
+        interocular_distance: f64,
+        convergence_distance: Option<f64>,
+        layout: StereoLayout,
+    ) {
+        self.stereo = Some(Stereo {
+            interocular_distance,
+            convergence_distance: convergence_distance.unwrap_or(self.focus_distance),
+            layout,
+        });
+        (self.image_width, self.image_height) = match layout {
+            StereoLayout::SideBySide => (2 * self.view_width, self.view_height),
+            StereoLayout::TopBottom => (self.view_width, 2 * self.view_height),
+        };
+    }
+
+    /// Find which eye a pixel of the image belongs to. Returns -1.0 for the left eye, 1.0 for the right eye, or 0.0
+    /// without stereo, along with the pixel's coordinates in the eye's image.
+    fn get_eye(&self, x: i32, y: i32) -> (f64, i32, i32) {
+        match self.stereo.map(|stereo| stereo.layout) {
+            None => (0.0, x, y),
+            Some(StereoLayout::SideBySide) if x < self.view_width => (-1.0, x, y),
+            Some(StereoLayout::SideBySide) => (1.0, x - self.view_width, y),
+            Some(StereoLayout::TopBottom) if y < self.view_height => (-1.0, x, y),
+            Some(StereoLayout::TopBottom) => (1.0, x, y - self.view_height),
+        }
+    }
+
+    /// The offset of an eye from the center of the camera, given the direction to the eye's right
+    fn get_eye_offset(&self, eye: f64, right: Vector3) -> Vector3 {
+        match &self.stereo {
+            Some(stereo) => (0.5 * eye * stereo.interocular_distance) * right,
+            None => Vector3::default(),
+        }
+    }
+
+    /// Get the ray through a point in an eye's image, for the projections other than the perspective one. film_x and
+    /// film_y are in pixels from the top left corner of the eye's image. Returns None outside of a fisheye's image
+    /// circle.
+    fn get_projected_ray(
+        &self,
+        eye: f64,
+        film_x: f64,
+        film_y: f64,
+        lens_offset: &Vector2,
+    ) -> Option<Ray> {
+        let forward = -1.0 * self.w;
+        let (origin, direction) = match self.projection {
+            Projection::Perspective => unreachable!("Perspective rays go through the viewport"),
+            Projection::Orthographic(view_width) => {
+                // Pixels are square, so the view's height follows from the image's
+                let pixel_size = view_width / self.view_width as f64;
+                let origin = self.center
+                    + ((film_x - 0.5 * self.view_width as f64) * pixel_size) * self.u
+                    - ((film_y - 0.5 * self.view_height as f64) * pixel_size) * self.v;
+
+                // With a lens, the rays from the lens converge on the point in focus
+                if self.defocus_angle <= 0.0 {
+                    (origin, forward)
+                } else {
+                    let focus_point = origin + self.focus_distance * forward;
+                    let uv = sample_aperture(&self.aperture, lens_offset);
+                    let lens_point =
+                        origin + uv.x * self.defocus_disk_u + uv.y * self.defocus_disk_v;
+                    (lens_point, focus_point - lens_point)
+                }
+            }
+            Projection::EquidistantFisheye(fov) | Projection::EquisolidFisheye(fov) => {
+                // The image circle fits in the shorter side of the image
+                let circle_radius = 0.5 * f64::min(self.view_width as f64, self.view_height as f64);
+                let dx = (film_x - 0.5 * self.view_width as f64) / circle_radius;
+                let dy = (film_y - 0.5 * self.view_height as f64) / circle_radius;
+                let r = (dx * dx + dy * dy).sqrt();
+                if r > 1.0 {
+                    return None;
+                }
+
+                let half_fov = degrees_to_radians(fov / 2.0);
+                let theta = match self.projection {
+                    Projection::EquidistantFisheye(_) => r * half_fov,
+                    _ => 2.0 * (r * (half_fov / 2.0).sin()).clamp(-1.0, 1.0).asin(),
+                };
+                let (sin_phi, cos_phi) = if r > 0.0 {
+                    (-dy / r, dx / r)
+                } else {
+                    (0.0, 0.0)
+                };
+                let direction = theta.cos() * forward
+                    + (theta.sin() * cos_phi) * self.u
+                    + (theta.sin() * sin_phi) * self.v;
+                (self.center, direction)
+            }
+            Projection::Equirectangular => {
+                // The same mapping as EnvironmentMap, with the camera's forward direction at the center of the image
+                let theta = PI * film_y / self.view_height as f64;
+                let phi = 2.0 * PI * (film_x / self.view_width as f64 - 0.5);
+                let direction = (theta.sin() * phi.sin()) * self.u
+                    + theta.cos() * self.v
+                    + (theta.sin() * phi.cos()) * forward;
+                (self.center, direction)
+            }
+        };
+
+        let Some(stereo) = &self.stereo else {
+            return Some(Ray {
+                origin,
+                direction,
+                time: 0.0,
+            });
+        };
+
+        // For omni-directional stereo, the eyes are to either side of the direction in the horizontal plane
+        let direction = Vector3::calc_normalized_vector(&direction);
+        let right = match self.projection {
+            Projection::Equirectangular => {
+                let horizontal = direction - Vector3::dot_product(&direction, &self.v) * self.v;
+                if horizontal.magnitude() < 1e-12 {
+                    // Looking straight up or down, both eyes are at the center
+                    Vector3::default()
+                } else {
+                    Vector3::calc_normalized_vector(&calc_cross_product(&horizontal, &self.v))
+                }
+            }
+            _ => self.u,
+        };
+
+        // The eye's ray goes through the point that the center's ray reaches at the convergence distance
+        let eye_offset = self.get_eye_offset(eye, right);
+        Some(Ray {
+            origin: origin + eye_offset,
+            direction: stereo.convergence_distance * direction - eye_offset,
+            time: 0.0,
+        })
+    }
+
+    /// Get the ray for a sample of pixel x, y along with the rays through the neighboring pixels, used to estimate the
+    /// texture footprint of the first hit, and how much to scale the light it finds by. Returns None if the sample is
+    /// outside of the projected image or blocked by the lens.
+    fn get_camera_ray(
+        &self,
+        x: i32,
+        y: i32,
+        pixel_offset: &Vector2,
+        lens_offset: &Vector2,
+        time: f64,
+    ) -> Option<(Ray, RayDifferential, f64)> {
+        let (eye, x, y) = self.get_eye(x, y);
+
+        if self.lens_camera.is_none()
+            && let Projection::Perspective = self.projection
+        {
+            // Note that we subtract the y values because we are going from the top down
+            let current_pixel = self.top_left_pixel
+                + (x as f64) * self.pixel_spacing_u
+                + (y as f64) * self.pixel_spacing_v;
+
+            // Pick a point in the unit square around the current pixel to send the ray through
+            let sample_pixel = current_pixel
+                + (pixel_offset.x - 0.5) * self.pixel_spacing_u
+                + (pixel_offset.y - 0.5) * self.pixel_spacing_v;
+
+            // Determine the ray origin based on the defocus angle
+            let ray_origin = if self.defocus_angle <= 0.0 {
+                // Aperture has infinitesimal radius
+                self.center
+            } else {
+                let uv = sample_aperture(&self.aperture, lens_offset);
+                let lens_point =
+                    self.center + uv.x * self.defocus_disk_u + uv.y * self.defocus_disk_v;
+                lens_point
+            };
+
+            // Each eye looks through the viewport shifted towards it, so that the eyes' rays cross at the convergence
+            // distance while the viewport stays in the plane of focus
+            let (ray_origin, sample_pixel) = match &self.stereo {
+                Some(stereo) => {
+                    let eye_offset = self.get_eye_offset(eye, self.u);
+                    let viewport_shift = 1.0 - self.focus_distance / stereo.convergence_distance;
+                    (
+                        ray_origin + eye_offset,
+                        sample_pixel + viewport_shift * eye_offset,
+                    )
+                }
+                None => (ray_origin, sample_pixel),
+            };
+
+            let ray = Ray {
+                origin: ray_origin,
+                direction: sample_pixel - ray_origin,
+                time, // Between 0.0 and 1.0
+            };
+            let differential = RayDifferential {
+                rx_origin: ray_origin,
+                rx_direction: sample_pixel + self.pixel_spacing_u - ray_origin,
+                ry_origin: ray_origin,
+                ry_direction: sample_pixel + self.pixel_spacing_v - ray_origin,
+            };
+            return Some((ray, differential, 1.0));
+        }
+
+        let film_x = x as f64 + pixel_offset.x;
+        let film_y = y as f64 + pixel_offset.y;
+        let get_ray = |film_x: f64, film_y: f64| match &self.lens_camera {
+            Some(lens_camera) => self.get_lens_ray(
+                lens_camera,
+                &self.aperture,
+                eye,
+                film_x,
+                film_y,
+                lens_offset,
+            ),
+            None => self
+                .get_projected_ray(eye, film_x, film_y, lens_offset)
+                .map(|ray| (ray, 1.0)),
+        };
+        let (mut ray, weight) = get_ray(film_x, film_y)?;
+        ray.time = time;
+
+        // Neighbors that fall outside of the image circle, or are blocked by the lens, fall back on the ray itself
+        let rx = get_ray(film_x + 1.0, film_y).map_or(ray.clone(), |(ray, _)| ray);
+        let ry = get_ray(film_x, film_y + 1.0).map_or(ray.clone(), |(ray, _)| ray);
+        let differential = RayDifferential {
+            rx_origin: rx.origin,
+            rx_direction: rx.direction,
+            ry_origin: ry.origin,
+            ry_direction: ry.direction,
+        };
+        Some((ray, differential, weight))
+    }
+
+    /// Set the filter that reconstructs the pixels from the samples around them
+    pub fn set_filter(&mut self, filter: Filter) {
+        self.filter = filter;
+    }
+
+    /// Denoise the finished image. The albedo, normal, and depth of the first surfaces hit are recorded for every
+    /// pixel and guide the denoiser, so that it smooths out the noise without blurring across edges.
+    pub fn set_denoise(&mut self, settings: DenoiseSettings) {
+        self.denoise = Some(settings);
+    }
+
+    /// Write buffers of what the camera rays hit first, and the image split up by how light reached the camera,
+    /// alongside the image. The image in the output variables isn't denoised, so that the passes add up to it. The
+    /// image and the passes are scaled by the exposure like the PPM is. See write_aovs for how file_path is used.
+    pub fn set_aov_output(&mut self, aovs: Vec<Aov>, passes: Vec<RenderPass>, file_path: String) {
+        self.aov_output = Some(AovOutput {
+            aovs,
+            passes,
+            file_path,
+        });
+    }
+
+    /// Spend the samples where the image is noisy instead of giving every pixel the same number. Pixels stop being
+    /// sampled once their estimated relative error is below noise_threshold, and the samples they didn't use go to
+    /// the noisiest pixels. The total number of samples stays at most pixel_sample_count times the number of pixels.
+    /// If heatmap_path is given, an image of the number of samples that each pixel got is written there.
+    pub fn set_adaptive_sampling(&mut self, noise_threshold: f64, heatmap_path: Option<String>) {
+        self.adaptive_sampling = Some(AdaptiveSampling {
+            noise_threshold,
+            heatmap_path,
+        });
+        self.sampler.set_sample_count(self.max_pixel_sample_count());
+    }
+
+    /// The number of samples a pixel gets in the first pass over the image
+    fn initial_pixel_sample_count(&self) -> i32 {
+        match self.adaptive_sampling {
+            // Enough samples to estimate the error, leaving most of the budget for the noisy pixels
+            Some(_) => i32::min(
+                i32::max(self.pixel_sample_count / 4, 4),
+                self.pixel_sample_count,
+            ),
+            None => self.pixel_sample_count,
+        }
+    }
+
+    /// The most samples that any pixel can get
+    fn max_pixel_sample_count(&self) -> i32 {
+        match self.adaptive_sampling {
+            Some(_) => 8 * self.pixel_sample_count,
+            None => self.pixel_sample_count,
+        }
+    }
+
+    /// The number of samples that pixels get in each pass over the image, until every pixel has had
+    /// initial_pixel_sample_count samples
+    fn progressive_pass_sample_count(&self) -> i32 {
+        match self.adaptive_sampling {
+            Some(_) => self.initial_pixel_sample_count(),
+            None => i32::max(self.pixel_sample_count / PROGRESSIVE_PASS_COUNT, 1),
+        }
+    }
+
+    /// Set the number of reflections that every path makes before it can be randomly terminated. Paths that carry
+    /// little light are ended early after this depth, which saves time without changing the image on average.
+    pub fn set_russian_roulette_depth(&mut self, russian_roulette_depth: i32) {
+        self.russian_roulette_depth = russian_roulette_depth;
+    }
+}
+
+// The number of passes over the image that the samples are split into when rendering without adaptive sampling
+const PROGRESSIVE_PASS_COUNT: i32 = 8;
+
+// The shutter time over the f-number squared for the "sunny 16" settings, f/16 at 1/100 of a second and ISO 100, which
+// expose daylit scenes correctly
+const REFERENCE_EXPOSURE: f64 = (1.0 / 100.0) / (16.0 * 16.0);
+
+// The length of a unit of the scene in the millimeters that lens systems are measured in
+const MILLIMETERS_PER_UNIT: f64 = 1000.0;
+
+/// A lens system along with the film behind it
+struct LensCamera {
+    system: LensSystem,
+    film_width: f64,     // In millimeters
+    film_height: f64,    // In millimeters
+    exposure_scale: f64, // Makes the center of the image as bright as it would be without the lens
+}
+
+/// The sensitivity that the image is exposed with
+struct Exposure {
+    iso: f64,
+    compensation: f64, // In stops, added to the exposure that the camera's settings give
+}
+
+/// The settings for rendering an image for each eye
+#[derive(Clone, Copy)]
+struct Stereo {
+    interocular_distance: f64, // The distance between the eyes
+    convergence_distance: f64, // The distance at which the eyes' rays cross
+    layout: StereoLayout,
+}
+
+/// Where and how often to save the progress of a render
+struct CheckpointSettings {
+    file_path: String,
+    interval_seconds: f64,
+}
+
+/// The output variables to write and where to write them
+struct AovOutput {
+    aovs: Vec<Aov>,
+    passes: Vec<RenderPass>,
+    file_path: String,
+}
+
+/// The settings for spending samples where the image is noisy
+struct AdaptiveSampling {
+    noise_threshold: f64,         // The relative error that a pixel is converged at
+    heatmap_path: Option<String>, // Where to write an image of the samples per pixel
+}
+
+/// The number of samples taken through a pixel, along with the mean and variance of their luminance
+#[derive(Clone, Default)]
+struct PixelEstimate {
+    sample_count: i32,
+    luminance_mean: f64,
+    luminance_squared_deviations: f64, // The sum of squared differences from the mean, updated with Welford's method
+}
+
+impl PixelEstimate {
+    fn write_checkpoint(&self, writer: &mut CheckpointWriter) {
+        writer.write_i32(self.sample_count);
+        writer.write_f64(self.luminance_mean);
+        writer.write_f64(self.luminance_squared_deviations);
+    }
+
+    fn read_checkpoint(reader: &mut CheckpointReader) -> Self {
+        Self {
+            sample_count: reader.read_i32(),
+            luminance_mean: reader.read_f64(),
+            luminance_squared_deviations: reader.read_f64(),
+        }
+    }
+
+    fn add_sample(&mut self, color: &Vector3) {
+        self.sample_count += 1;
+
+        let sample_luminance = luminance(color);
+        let delta = sample_luminance - self.luminance_mean;
+        self.luminance_mean += delta / self.sample_count as f64;
+        self.luminance_squared_deviations += delta * (sample_luminance - self.luminance_mean);
+    }
 
-        let camera_rng = ThreadRng::default();
+    /// The standard error of the mean luminance relative to the mean. Dark pixels are compared against a small
+    /// floor instead, since any absolute error is huge compared to a mean near zero but isn't visible.
+    fn relative_error(&self) -> f64 {
+        if self.sample_count < 2 {
+            return f64::INFINITY;
+        }
+
+        let variance = self.luminance_squared_deviations / (self.sample_count - 1) as f64;
+        let standard_error = (variance / self.sample_count as f64).sqrt();
+        standard_error / f64::max(self.luminance_mean, 0.05)
+    }
+}
+
+/// How a ray was scattered, which decides how light it finds is shared between scattering and light sampling
+struct ScatterEvent {
+    pdf: f64,                // The probability density of scattering in the ray's direction
+    normal: Option<Vector3>, // The normal used to pick lights at the scattering point. None inside of volumes.
+}
+
+/// The parts of the scene that paths scatter through
+struct Scene<'a> {
+    hittables: &'a mut Hittables,
+    lights: &'a mut Lights,
+    background: &'a Background,
+    materials: &'a [Material],
+}
 
+/// The light that a camera ray brought back, along with what it hit first
+struct PathSample {
+    radiance: Vector3,
+    passes: [Vector3; RENDER_PASS_COUNT], // The radiance split up by RenderPass, adding up to radiance
+    albedo: Vector3, // The albedo of the first surface hit, or the background's color if nothing was hit
+    first_hit: Option<FirstHit>, // None if nothing was hit
+}
+
+/// Everything that the samples have added up so far
+struct RenderState {
+    pixels: Vec<PixelEstimate>,
+    film: Film,
+    aov_buffers: AovBuffers,
+    pass_films: Vec<Film>, // A film for each RenderPass, or none if the passes aren't written
+}
+
+impl RenderState {
+    fn new(camera: &Camera, has_passes: bool) -> Self {
+        let pass_film_count = if has_passes { RENDER_PASS_COUNT } else { 0 };
         Self {
-            image_width,
-            image_height,
-            vfov,
-            top_left_pixel,
-            pixel_spacing_u,
-            pixel_spacing_v,
-            center,
-            look_at,
-            vup,
-            pixel_sample_count,
-            one_over_pixel_sample_count: 1.0 / (pixel_sample_count as f64),
-            rng: camera_rng,
-            defocus_angle,
-            defocus_disk_u,
-            defocus_disk_v,
-            focus_distance,
+            pixels: vec![
+                PixelEstimate::default();
+                (camera.image_width * camera.image_height) as usize
+            ],
+            film: Film::new(camera.image_width, camera.image_height, camera.filter)
+                .with_view_size(camera.view_width, camera.view_height),
+            aov_buffers: AovBuffers::new(camera.image_width, camera.image_height),
+            pass_films: (0..pass_film_count)
+                .map(|_| {
+                    Film::new(camera.image_width, camera.image_height, camera.filter)
+                        .with_view_size(camera.view_width, camera.view_height)
+                })
+                .collect(),
         }
     }
+
+    /// Save the state after a number of passes, along with the settings needed to check that it's resumed correctly
+    fn write_checkpoint(&self, camera: &Camera, max_depth: i32, pass: i32, file_path: &str) {
+        let mut writer = CheckpointWriter::new(CHECKPOINT_MAGIC);
+        writer.write_i32(camera.image_width);
+        writer.write_i32(camera.image_height);
+        writer.write_i32(camera.pixel_sample_count);
+        writer.write_i32(self.pass_films.len() as i32);
+        let region = camera.render_region();
+        for bound in [region.x0, region.y0, region.x1, region.y1] {
+            writer.write_i32(bound);
+        }
+        for setting in camera.get_sampling_settings(max_depth) {
+            writer.write_u64(setting);
+        }
+        writer.write_i32(pass);
+
+        for pixel in &self.pixels {
+            pixel.write_checkpoint(&mut writer);
+        }
+        self.film.write_checkpoint(&mut writer);
+        self.aov_buffers.write_checkpoint(&mut writer);
+        for pass_film in &self.pass_films {
+            pass_film.write_checkpoint(&mut writer);
+        }
+
+        writer.save(file_path);
+    }
+
+    /// Restore the state from a checkpoint. Returns the number of passes that were done.
+    fn read_checkpoint(&mut self, camera: &Camera, max_depth: i32, file_path: &str) -> i32 {
+        let mut reader = CheckpointReader::load(file_path, CHECKPOINT_MAGIC);
+        let settings: Vec<i32> = (0..8).map(|_| reader.read_i32()).collect();
+        let region = camera.render_region();
+        if settings
+            != [
+                camera.image_width,
+                camera.image_height,
+                camera.pixel_sample_count,
+                self.pass_films.len() as i32,
+                region.x0,
+                region.y0,
+                region.x1,
+                region.y1,
+            ]
+        {
+            panic!(
+                "The checkpoint at {} was made with a different image size, sample count, render passes, or crop window",
+                file_path
+            );
+        }
+        let sampling_settings = camera.get_sampling_settings(max_depth);
+        if sampling_settings
+            .iter()
+            .any(|setting| *setting != reader.read_u64())
+        {
+            panic!(
+                "The checkpoint at {} was made with a different sampler, filter, adaptive sampling threshold, seed, maximum depth, or Russian roulette depth",
+                file_path
+            );
+        }
+        let pass = reader.read_i32();
+
+        for pixel in self.pixels.iter_mut() {
+            *pixel = PixelEstimate::read_checkpoint(&mut reader);
+        }
+        self.film.read_checkpoint(&mut reader);
+        self.aov_buffers.read_checkpoint(&mut reader);
+        for pass_film in self.pass_films.iter_mut() {
+            pass_film.read_checkpoint(&mut reader);
+        }
+
+        pass
+    }
+}
+
+/// Decide which pixels get samples in the next pass over the image, and how many. The plan only depends on the
+/// samples taken so far, so a resumed render makes the same plans as one that never stopped.
+///
+/// Every pixel gets samples until it has initial_pixel_sample_count of them. After that, with adaptive sampling, the
+/// rest of the budget goes to the pixels that haven't converged yet, a batch at a time, so that the error estimates can
+/// catch up with the new samples between batches. Pixels outside of the crop window never get samples. Returns no pixels
+/// when the render is done.
+fn plan_pass(camera: &Camera, pixels: &[PixelEstimate]) -> Vec<(usize, i32)> {
+    let region = camera.render_region();
+    let in_region = |index: usize| {
+        region.contains(
+            index as i32 % camera.image_width,
+            index as i32 / camera.image_width,
+        )
+    };
+
+    let initial_sample_count = camera.initial_pixel_sample_count();
+    let pass_sample_count = camera.progressive_pass_sample_count();
+    let pass_samples: Vec<(usize, i32)> = pixels
+        .iter()
+        .enumerate()
+        .filter(|(index, pixel)| in_region(*index) && pixel.sample_count < initial_sample_count)
+        .map(|(index, pixel)| {
+            (
+                index,
+                i32::min(pass_sample_count, initial_sample_count - pixel.sample_count),
+            )
+        })
+        .collect();
+    if !pass_samples.is_empty() {
+        return pass_samples;
+    }
+
+    let noise_threshold = match &camera.adaptive_sampling {
+        Some(adaptive) => adaptive.noise_threshold,
+        None => return vec![],
+    };
+    let max_sample_count = camera.max_pixel_sample_count();
+    let taken_sample_count: i64 = pixels.iter().map(|pixel| pixel.sample_count as i64).sum();
+    let region_pixel_count = ((region.x1 - region.x0) * (region.y1 - region.y0)) as i64;
+    let remaining_budget =
+        camera.pixel_sample_count as i64 * region_pixel_count - taken_sample_count;
+    let batch_size = initial_sample_count;
+
+    let mut noisy_pixels: Vec<(usize, f64)> = pixels
+        .iter()
+        .enumerate()
+        .filter(|(index, pixel)| in_region(*index) && pixel.sample_count < max_sample_count)
+        .map(|(index, pixel)| (index, pixel.relative_error()))
+        .filter(|(_, error)| *error > noise_threshold)
+        .collect();
+
+    // Noisiest first, in case the budget runs out partway through
+    noisy_pixels.sort_by(|a, b| b.1.total_cmp(&a.1));
+    noisy_pixels.truncate(i64::max(remaining_budget / batch_size as i64, 0) as usize);
+
+    noisy_pixels
+        .iter()
+        .map(|(index, _)| {
+            (
+                *index,
+                i32::min(batch_size, max_sample_count - pixels[*index].sample_count),
+            )
+        })
+        .collect()
+}
+
+/// The seed for the random choices of one sample of a pixel
+fn get_sample_seed(seed: u64, x: i32, y: i32, sample_index: i32) -> u64 {
+    let seed_half = |half: u32| {
+        hash(&[
+            seed as u32,
+            (seed >> 32) as u32,
+            x as u32,
+            y as u32,
+            sample_index as u32,
+            half,
+        ])
+    };
+    ((seed_half(0) as u64) << 32) | seed_half(1) as u64
 }
 
 /// Render the scene in the ppm format
 ///
 /// camera: The camera data structure
 /// hittables: The world geometries
+/// lights: The lights that are sampled explicitly
+/// background: The light arriving from outside of the scene
 /// materials: A reference to the materials data
 /// max_depth: The maximum number of reflections for each ray
 pub fn render(
     camera: &mut Camera,
     hittables: &mut Hittables,
-    materials: &Vec<Material>,
+    lights: &mut Lights,
+    background: &Background,
+    materials: &[Material],
     max_depth: i32,
 ) {
-    // ppm format preamble
-    println!("P3");
-    println!("{} {}", camera.image_width, camera.image_height);
-    println!("255");
+    let state = take_samples(camera, hittables, lights, background, materials, max_depth);
+
+    if let Some(heatmap_path) = camera
+        .adaptive_sampling
+        .as_ref()
+        .and_then(|adaptive| adaptive.heatmap_path.as_ref())
+    {
+        write_sample_heatmap(
+            heatmap_path,
+            &state.pixels,
+            camera.image_width,
+            camera.image_height,
+        );
+    }
 
+    if let Some(tile_path) = &camera.tile_path {
+        write_tile(
+            tile_path,
+            &state.film,
+            &camera.get_tile_region(&camera.render_region()),
+            camera.image_width,
+            camera.image_height,
+            camera.get_exposure(),
+        );
+    }
+
+    let film = &state.film;
+    let aov_buffers = &state.aov_buffers;
+    let pass_films = &state.pass_films;
+    let mut colors = vec![];
     for y in 0..camera.image_height {
-        eprintln!("Scanlines remaining: {}", camera.image_height - y);
         for x in 0..camera.image_width {
-            // Note that we subtract the y values because we are going from the top down
-            let current_pixel = camera.top_left_pixel
-                + (x as f64) * camera.pixel_spacing_u
-                + (y as f64) * camera.pixel_spacing_v;
-
-            // find the color of the current pixel
-            let pixel_color = {
-                let mut average_color = Vector3 {
-                    x: 0.0,
-                    y: 0.0,
-                    z: 0.0,
-                };
-                for _ in 0..camera.pixel_sample_count {
-                    // Pick a random point in the unit square around the current pixel to send the ray through
-                    let sample_pixel = current_pixel
-                        + camera.rng.random_range(-0.5..0.5) * camera.pixel_spacing_u
-                        + camera.rng.random_range(-0.5..0.5) * camera.pixel_spacing_v;
-
-                    // Determine the ray origin based on the defocus angle
-                    let ray_origin = if camera.defocus_angle <= 0.0 {
-                        // Aperture has infinitesimal radius
-                        camera.center
-                    } else {
-                        let uv = random_vector_in_unit_disk(&mut camera.rng);
-                        let lens_point = camera.center
-                            + uv.x * camera.defocus_disk_u
-                            + uv.y * camera.defocus_disk_v;
-                        lens_point
-                    };
-
-                    let ray = Ray {
-                        origin: ray_origin,
-                        direction: sample_pixel - ray_origin,
-                        time: camera.rng.random_range(0.0..1.0), // Random time between 0.0 and 1.0
-                    };
-
-                    average_color = average_color
-                        + ray_color(&ray, hittables, &mut camera.rng, materials, max_depth);
+            colors.push(film.get_color(x, y));
+        }
+    }
+
+    if let Some(aov_output) = &camera.aov_output {
+        // The image and its passes are exposed like the PPM so that they match it, but the features aren't colors
+        let exposure = camera.get_exposure();
+        let passes: Vec<(RenderPass, Vec<Vector3>)> = aov_output
+            .passes
+            .iter()
+            .map(|pass| {
+                let pass_film = &pass_films[*pass as usize];
+                let mut pass_colors = vec![];
+                for y in 0..camera.image_height {
+                    for x in 0..camera.image_width {
+                        pass_colors.push(exposure * pass_film.get_color(x, y));
+                    }
                 }
+                (*pass, pass_colors)
+            })
+            .collect();
+        let exposed_colors: Vec<Vector3> = colors.iter().map(|color| exposure * *color).collect();
+        write_aovs(
+            &aov_output.file_path,
+            aov_buffers,
+            &aov_output.aovs,
+            &passes,
+            &exposed_colors,
+        );
+    }
 
-                average_color = camera.one_over_pixel_sample_count * average_color;
+    // The output variables keep the noisy image, which the passes add up to
+    if let Some(settings) = &camera.denoise {
+        eprintln!("Denoising");
+        colors = denoise_film(
+            aov_buffers,
+            &colors,
+            settings,
+            camera.image_width,
+            camera.image_height,
+        );
+    }
 
-                average_color
-            };
+    write_ppm(
+        &colors,
+        camera.image_width,
+        camera.image_height,
+        camera.get_exposure(),
+    );
+}
 
-            write_color(&pixel_color);
+/// Render the pixels in a region of the image, and encode them as a tile. The camera's crop window is replaced by the
+/// region. See render for the arguments.
+pub fn render_tile(
+    camera: &mut Camera,
+    hittables: &mut Hittables,
+    lights: &mut Lights,
+    background: &Background,
+    materials: &[Material],
+    max_depth: i32,
+    region: PixelRegion,
+) -> Vec<u8> {
+    camera.crop_window = Some(region);
+    let state = take_samples(camera, hittables, lights, background, materials, max_depth);
+    encode_tile(
+        &state.film,
+        &camera.get_tile_region(&camera.render_region()),
+        camera.image_width,
+        camera.image_height,
+        camera.get_exposure(),
+    )
+    .into_bytes()
+}
+
+/// Take every sample of the render, resuming from and saving checkpoints along the way. See render for the arguments.
+fn take_samples(
+    camera: &mut Camera,
+    hittables: &mut Hittables,
+    lights: &mut Lights,
+    background: &Background,
+    materials: &[Material],
+    max_depth: i32,
+) -> RenderState {
+    let has_passes = camera
+        .aov_output
+        .as_ref()
+        .is_some_and(|aov_output| !aov_output.passes.is_empty());
+    let mut state = RenderState::new(camera, has_passes);
+
+    let mut pass = 0;
+    if let Some(resume_path) = camera.resume_path.clone() {
+        pass = state.read_checkpoint(camera, max_depth, &resume_path);
+        eprintln!("Resuming after pass {}", pass);
+    }
+
+    let mut scene = Scene {
+        hittables,
+        lights,
+        background,
+        materials,
+    };
+    let mut last_checkpoint = Instant::now();
+    while take_pass(camera, &mut scene, &mut state, max_depth, pass + 1) {
+        pass += 1;
+        if let Some(checkpoint) = &camera.checkpoint
+            && last_checkpoint.elapsed().as_secs_f64() >= checkpoint.interval_seconds
+        {
+            state.write_checkpoint(camera, max_depth, pass, &checkpoint.file_path);
+            last_checkpoint = Instant::now();
         }
     }
+
+    state
 }
 
-/// Get the color of the scene for a ray
-///
-/// ray_in: The ray to determine the reflection of
-/// hittables: The world geometries that can interact with rays
-/// rng: An RNG for generating randomness in our reflections
-/// materials: A reference to the materials data
-/// max_depth: The maximum number of remaining reflections to calculate
-fn ray_color(
-    ray_in: &Ray,
-    hittables: &mut Hittables,
-    rng: &mut ThreadRng,
-    materials: &Vec<Material>,
+/// Take the samples of the next pass over the image, which is numbered pass. Returns false if the render was already
+/// done.
+fn take_pass(
+    camera: &mut Camera,
+    scene: &mut Scene,
+    state: &mut RenderState,
     max_depth: i32,
-) -> Vector3 {
-    if max_depth <= 0 {
-        return Vector3 {
+    pass: i32,
+) -> bool {
+    let pass_samples = plan_pass(camera, &state.pixels);
+    if pass_samples.is_empty() {
+        return false;
+    }
+    eprintln!("Pass {}: {} pixels", pass, pass_samples.len());
+
+    for (index, count) in pass_samples {
+        sample_pixel(camera, scene, state, max_depth, index, count);
+    }
+
+    true
+}
+
+/// Take a number of samples through the pixel at an index
+fn sample_pixel(
+    camera: &mut Camera,
+    scene: &mut Scene,
+    state: &mut RenderState,
+    max_depth: i32,
+    index: usize,
+    count: i32,
+) {
+    let x = index as i32 % camera.image_width;
+    let y = index as i32 / camera.image_width;
+
+    for _ in 0..count {
+        let pixel = &mut state.pixels[index];
+        camera.sampler.start_pixel_sample(x, y, pixel.sample_count);
+        // The other random choices are seeded the same way, so that any sample can be reproduced on its own
+        scene
+            .hittables
+            .seed_rng(get_sample_seed(camera.seed, x, y, pixel.sample_count));
+        let pixel_offset = camera.sampler.get_2d();
+        let lens_offset = camera.sampler.get_2d();
+        let time = camera.shutter_open
+            + camera.sampler.get_1d() * (camera.shutter_close - camera.shutter_open);
+
+        // Samples outside of the projected image stay black
+        let path = match camera.get_camera_ray(x, y, &pixel_offset, &lens_offset, time) {
+            Some((ray, differential, weight)) => {
+                let mut path = ray_color(
+                    &ray,
+                    &differential,
+                    scene,
+                    &mut camera.sampler,
+                    max_depth,
+                    camera.russian_roulette_depth,
+                );
+                path.radiance = weight * path.radiance;
+                for pass in path.passes.iter_mut() {
+                    *pass = weight * *pass;
+                }
+                path
+            }
+            None => PathSample {
+                radiance: Vector3::default(),
+                passes: [Vector3::default(); RENDER_PASS_COUNT],
+                albedo: Vector3::default(),
+                first_hit: None,
+            },
+        };
+        pixel.add_sample(&path.radiance);
+        state.film.add_sample(x, y, &pixel_offset, &path.radiance);
+        state
+            .aov_buffers
+            .add_sample(x, y, &path.albedo, path.first_hit.as_ref());
+        for (pass_film, pass_radiance) in state.pass_films.iter_mut().zip(&path.passes) {
+            pass_film.add_sample(x, y, &pixel_offset, pass_radiance);
+        }
+    }
+}
+
+/// Print an image in the ppm format, with its colors in row-major order from the top left. The colors are scaled by
+/// the exposure before they're tone mapped.
+pub fn write_ppm(colors: &[Vector3], image_width: i32, image_height: i32, exposure: f64) {
+    // ppm format preamble
+    println!("P3");
+    println!("{} {}", image_width, image_height);
+    println!("255");
+
+    for color in colors {
+        write_color(&(exposure * *color));
+    }
+}
+
+/// Denoise the colors of the film, guided by the features of the first surfaces hit through each pixel
+fn denoise_film(
+    aov_buffers: &AovBuffers,
+    colors: &[Vector3],
+    settings: &DenoiseSettings,
+    image_width: i32,
+    image_height: i32,
+) -> Vec<Vector3> {
+    let mut color = vec![];
+    let mut albedo = vec![];
+    let mut normal = vec![];
+    let mut depth = vec![];
+    for y in 0..image_height {
+        for x in 0..image_width {
+            let pixel_color = colors[(y * image_width + x) as usize];
+            let pixel_albedo = aov_buffers.get_albedo(x, y);
+            let pixel_normal = aov_buffers.get_normal(x, y);
+            color.extend([pixel_color.x, pixel_color.y, pixel_color.z]);
+            albedo.extend([pixel_albedo.x, pixel_albedo.y, pixel_albedo.z]);
+            normal.extend([pixel_normal.x, pixel_normal.y, pixel_normal.z]);
+            depth.push(aov_buffers.get_depth(x, y));
+        }
+    }
+
+    let auxiliary = AuxiliaryBuffers {
+        albedo: Some(&albedo),
+        normal: Some(&normal),
+        depth: Some(&depth),
+    };
+    let denoised = denoise(
+        image_width as usize,
+        image_height as usize,
+        &color,
+        &auxiliary,
+        settings,
+    )
+    .unwrap_or_else(|error| panic!("Unable to denoise the image: {}", error));
+
+    denoised
+        .chunks_exact(3)
+        .map(|rgb| Vector3 {
+            x: rgb[0],
+            y: rgb[1],
+            z: rgb[2],
+        })
+        .collect()
+}
+
+/// Write an image in the ppm format of how many samples each pixel got, going from dark blue for the fewest samples
+/// through cyan and yellow to red for the most
+fn write_sample_heatmap(
+    file_path: &str,
+    pixels: &[PixelEstimate],
+    image_width: i32,
+    image_height: i32,
+) {
+    let fewest = pixels
+        .iter()
+        .map(|pixel| pixel.sample_count)
+        .min()
+        .unwrap_or(0);
+    let most = pixels
+        .iter()
+        .map(|pixel| pixel.sample_count)
+        .max()
+        .unwrap_or(0);
+    eprintln!("Samples per pixel ranged from {} to {}", fewest, most);
+
+    let stops = [
+        Vector3 {
+            x: 0.0,
+            y: 0.0,
+            z: 0.3,
+        },
+        Vector3 {
             x: 0.0,
+            y: 0.8,
+            z: 1.0,
+        },
+        Vector3 {
+            x: 1.0,
+            y: 1.0,
+            z: 0.0,
+        },
+        Vector3 {
+            x: 1.0,
             y: 0.0,
             z: 0.0,
+        },
+    ];
+
+    let mut contents = format!("P3\n{} {}\n255\n", image_width, image_height);
+    for pixel in pixels {
+        let t = if most > fewest {
+            (pixel.sample_count - fewest) as f64 / (most - fewest) as f64
+        } else {
+            0.0
         };
+
+        let position = t * (stops.len() - 1) as f64;
+        let index = usize::min(position as usize, stops.len() - 2);
+        let fraction = position - index as f64;
+        let color = (1.0 - fraction) * stops[index] + fraction * stops[index + 1];
+
+        contents += &format!(
+            "{} {} {}\n",
+            (color.x * 255.99) as i32,
+            (color.y * 255.99) as i32,
+            (color.z * 255.99) as i32
+        );
     }
 
-    // Due to floating-point imprecision, occasionally the intersection point is not
-    // exactly flush with the surface of the geometry. This can cause a ray to reflect
-    // off of the surface that it is reflecting off of. We set tmin to some small value
-    // greater than 0.0 to avoid this.
-    let closest_record = hittables.get_hit_record(ray_in, 0.001, std::f64::INFINITY);
+    fs::write(file_path, contents)
+        .unwrap_or_else(|_| panic!("Unable to write file path at {}", file_path));
+}
 
-    match closest_record {
-        Some(closest_record) => {
-            let material = &materials[closest_record.material];
-            match scatter_ray(
-                material,
-                ray_in,
-                closest_record.point,
-                closest_record.normal,
-                closest_record.front_face,
-                closest_record.u,
-                closest_record.v,
-                rng,
-            ) {
-                Some((attenuation, reflected_ray)) => {
-                    // Recursively look up color of the reflected ray
-                    let recursive_result =
-                        ray_color(&reflected_ray, hittables, rng, materials, max_depth - 1);
-                    Vector3 {
-                        x: recursive_result.x * attenuation.x,
-                        y: recursive_result.y * attenuation.y,
-                        z: recursive_result.z * attenuation.z,
-                    }
+/// Get the color of the scene for a camera ray by following its path as it scatters through the scene
+///
+/// camera_ray: The ray leaving the camera
+/// differential: The ray differentials of camera_ray
+/// scene: The geometry, lights, background, and materials that the path scatters through
+/// sampler: Supplies the values for the choices at each bounce, already started for this pixel sample
+/// max_depth: The maximum number of reflections to calculate
+/// russian_roulette_depth: The number of reflections after which paths are randomly terminated
+fn ray_color(
+    camera_ray: &Ray,
+    differential: &RayDifferential,
+    scene: &mut Scene,
+    sampler: &mut Sampler,
+    max_depth: i32,
+    russian_roulette_depth: i32,
+) -> PathSample {
+    let mut passes = [Vector3::default(); RENDER_PASS_COUNT];
+    // The fraction of light arriving along ray_in that makes it back to the camera
+    let mut throughput = Vector3 {
+        x: 1.0,
+        y: 1.0,
+        z: 1.0,
+    };
+
+    let mut ray_in = camera_ray.clone();
+    // How the previous bounce scattered in ray_in's direction. None if the direction could not have been picked by
+    // light sampling, like for camera rays and mirror reflections.
+    let mut previous: Option<ScatterEvent> = None;
+    // How the camera ray scattered at the first surface it hit. None until then.
+    let mut first_lobe: Option<Lobe> = None;
+
+    // What the camera ray hit first, for the denoiser
+    let mut first_albedo = Vector3::default();
+    let mut first_hit = None;
+
+    for depth in 0..max_depth {
+        // Tracking rays through volumes takes any number of random choices, so the choices made while finding hits
+        // are seeded by a value of the sampler instead, the way that pbrt does
+        scene.hittables.seed_rng(sampler.get_1d().to_bits());
+
+        // Due to floating-point imprecision, occasionally the intersection point is not
+        // exactly flush with the surface of the geometry. This can cause a ray to reflect
+        // off of the surface that it is reflecting off of. We set tmin to some small value
+        // greater than 0.0 to avoid this.
+        let closest_record =
+            scene
+                .hittables
+                .get_hit_record(&ray_in, 0.001, f64::INFINITY, scene.materials);
+
+        let mut closest_record = match closest_record {
+            Some(closest_record) => closest_record,
+            None => {
+                let background_color = get_background_color(scene.background, &ray_in.direction);
+                if depth == 0 {
+                    first_albedo = background_color;
                 }
-                None => {
-                    // Ray was absorbed
-                    Vector3 {
-                        x: 0.0,
-                        y: 0.0,
-                        z: 0.0,
+
+                // If the background could also have been reached by light sampling at the previous bounce, both
+                // strategies share its contribution
+                let background_color = match &previous {
+                    Some(previous) => {
+                        let light_pdf = background_pdf(scene.background, &ray_in.direction);
+                        power_heuristic(previous.pdf, light_pdf) * background_color
                     }
-                }
+                    None => background_color,
+                };
+                let pass = match first_lobe {
+                    Some(lobe) => get_render_pass(lobe, depth),
+                    None => RenderPass::Background,
+                };
+                passes[pass as usize] =
+                    passes[pass as usize] + calc_component_product(&throughput, &background_color);
+                break;
             }
+        };
+
+        // Only camera rays carry differentials
+        let material = &scene.materials[closest_record.material];
+        if depth == 0 {
+            compute_uv_footprint(&mut closest_record, differential);
+            first_albedo = get_albedo(material, &closest_record);
+            first_hit = Some(FirstHit {
+                normal: get_shading_normal(material, &closest_record),
+                depth: closest_record.t * ray_in.direction.magnitude(),
+                position: closest_record.point,
+                uv: Vector2 {
+                    x: closest_record.u,
+                    y: closest_record.v,
+                },
+                material: closest_record.material,
+                object: closest_record.object,
+            });
         }
-        None => {
-            // Create a background color
-            let unit_vector = Vector3::calc_normalized_vector(&ray_in.direction);
 
-            let white = Vector3 {
-                x: 1.0,
-                y: 1.0,
-                z: 1.0,
-            };
-            let blue = Vector3 {
-                x: 0.5,
-                y: 0.7,
-                z: 1.0,
+        // Light emitted by the surface itself
+        let emitted = {
+            let emitted = get_emitted(material, &closest_record);
+            match (
+                &previous,
+                scene.lights.get_object_light(closest_record.object),
+            ) {
+                (Some(previous), Some(light_handle)) if emitted.magnitude_squared() > 0.0 => {
+                    // Light sampling at the previous bounce could also have found this light
+                    let light_pdf = scene.lights.light_handle_probability(
+                        &ray_in.origin,
+                        previous.normal.as_ref(),
+                        light_handle,
+                    ) * area_light_pdf(
+                        &scene.lights.lights()[light_handle],
+                        &ray_in.origin,
+                        &closest_record.point,
+                    );
+                    power_heuristic(previous.pdf, light_pdf) * emitted
+                }
+                _ => emitted,
+            }
+        };
+
+        // Light that reaches the hit point directly from the background and the lights
+        let direct_light = sample_direct_background(
+            &ray_in,
+            &closest_record,
+            material,
+            scene.hittables,
+            scene.background,
+            sampler,
+            scene.materials,
+        ) + sample_direct_lights(
+            &ray_in,
+            &closest_record,
+            material,
+            scene.hittables,
+            scene.lights,
+            sampler,
+            scene.materials,
+        );
+        let roulette_value = sampler.get_1d();
+        let scatter_values = ScatterValues {
+            choice: sampler.get_1d(),
+            channel: sampler.get_1d(),
+            distance: sampler.get_1d(),
+            direction: sampler.get_2d(),
+        };
+
+        let emission_pass = match first_lobe {
+            Some(lobe) => get_render_pass(lobe, depth),
+            None => RenderPass::Emission,
+        };
+        passes[emission_pass as usize] =
+            passes[emission_pass as usize] + calc_component_product(&throughput, &emitted);
+        // Light samples only line up with diffuse scattering, so that's what the first surface did if it found light
+        let direct_pass = get_render_pass(first_lobe.unwrap_or(Lobe::Diffuse), depth + 1);
+        passes[direct_pass as usize] =
+            passes[direct_pass as usize] + calc_component_product(&throughput, &direct_light);
+
+        let (attenuation, reflected_ray) =
+            match scatter_ray(material, &ray_in, &closest_record, &scatter_values) {
+                Some(scattered) => scattered,
+                None => break, // Ray was absorbed
             };
 
-            let lerp_value = (unit_vector.y + 1.0) / 2.0; // Y value has a range of -1.0 to 1.0, and we map that to 0.0 to 1.0 
-            let blended = (1.0 - lerp_value) * white + lerp_value * blue;
+        previous = evaluate_scatter(material, &ray_in, &closest_record, &reflected_ray.direction)
+            .map(|(_, pdf)| ScatterEvent {
+                pdf,
+                normal: light_sampling_normal(material, &closest_record),
+            });
+        if depth == 0 {
+            first_lobe = Some(get_lobe(
+                material,
+                &ray_in,
+                &closest_record,
+                &reflected_ray.direction,
+            ));
+        }
+        throughput = calc_component_product(&throughput, &attenuation);
+        ray_in = reflected_ray;
 
-            blended
+        // Randomly end paths that carry little light. The paths that survive carry more light to make up for the
+        // ones that ended, so the result is unchanged on average.
+        if depth + 1 >= russian_roulette_depth {
+            let survival_probability = f64::min(
+                f64::max(throughput.x, f64::max(throughput.y, throughput.z)),
+                1.0,
+            );
+            if roulette_value >= survival_probability {
+                break;
+            }
+            throughput = (1.0 / survival_probability) * throughput;
         }
     }
+
+    PathSample {
+        radiance: passes
+            .iter()
+            .fold(Vector3::default(), |radiance, pass| radiance + *pass),
+        passes,
+        albedo: first_albedo,
+        first_hit,
+    }
+}
+
+/// Get the render pass for light that scattered at scatter_count surfaces on its way to the camera, where the first
+/// surface that the camera ray hit scattered it with first_lobe
+fn get_render_pass(first_lobe: Lobe, scatter_count: i32) -> RenderPass {
+    match first_lobe {
+        Lobe::Diffuse if scatter_count <= 1 => RenderPass::DirectDiffuse,
+        Lobe::Diffuse => RenderPass::IndirectDiffuse,
+        Lobe::Specular => RenderPass::Specular,
+        Lobe::Transmission => RenderPass::Transmission,
+    }
+}
+
+/// Estimate the light arriving at a hit point straight from the background by sampling a direction towards it and
+/// tracing a shadow ray. The estimate is weighted with multiple importance sampling against scattered rays that escape.
+fn sample_direct_background(
+    ray_in: &Ray,
+    hit_record: &HitRecord,
+    material: &Material,
+    hittables: &mut Hittables,
+    background: &Background,
+    sampler: &mut Sampler,
+    materials: &[Material],
+) -> Vector3 {
+    let u = sampler.get_2d();
+    let zero = Vector3 {
+        x: 0.0,
+        y: 0.0,
+        z: 0.0,
+    };
+
+    let (direction, radiance, light_pdf) = match sample_background(background, &u) {
+        Some(sample) => sample,
+        None => return zero,
+    };
+    let (scattered, scatter_pdf) = match evaluate_scatter(material, ray_in, hit_record, &direction)
+    {
+        Some(evaluation) => evaluation,
+        None => return zero,
+    };
+    if scatter_pdf <= 0.0 {
+        return zero;
+    }
+
+    let shadow_ray = Ray {
+        origin: hit_record.point,
+        direction,
+        time: ray_in.time,
+    };
+    let transmittance = hittables.transmittance(&shadow_ray, 0.001, f64::INFINITY, materials);
+    if transmittance <= 0.0 {
+        return zero;
+    }
+
+    let weight = transmittance * power_heuristic(light_pdf, scatter_pdf) / light_pdf;
+    weight * calc_component_product(&scattered, &radiance)
+}
+
+/// Estimate the light that reaches a hit point from the lights by picking one light, sampling it, and tracing a shadow
+/// ray. Point, spot, and directional lights can never be hit by scattered rays, so this is the only way their light is
+/// found. Area lights can be hit, so their estimate is weighted with multiple importance sampling.
+fn sample_direct_lights(
+    ray_in: &Ray,
+    hit_record: &HitRecord,
+    material: &Material,
+    hittables: &mut Hittables,
+    lights: &mut Lights,
+    sampler: &mut Sampler,
+    materials: &[Material],
+) -> Vector3 {
+    let u_light_handle = sampler.get_1d();
+    let u_light = sampler.get_2d();
+    let zero = Vector3 {
+        x: 0.0,
+        y: 0.0,
+        z: 0.0,
+    };
+
+    let normal = light_sampling_normal(material, hit_record);
+    let (light_handle, light_probability) =
+        match lights.sample_light_handle(&hit_record.point, normal.as_ref(), u_light_handle) {
+            Some(picked) => picked,
+            None => return zero,
+        };
+    let sample = match sample_light(
+        &lights.lights()[light_handle],
+        &hit_record.point,
+        materials,
+        &u_light,
+    ) {
+        Some(sample) => sample,
+        None => return zero,
+    };
+    let (scattered, scatter_pdf) =
+        match evaluate_scatter(material, ray_in, hit_record, &sample.direction) {
+            Some(evaluation) => evaluation,
+            None => return zero, // The material only scatters in specific directions
+        };
+    if scattered.magnitude_squared() <= 0.0 {
+        return zero;
+    }
+
+    let shadow_ray = Ray {
+        origin: hit_record.point,
+        direction: sample.direction,
+        time: ray_in.time,
+    };
+    let transmittance =
+        hittables.transmittance(&shadow_ray, 0.001, sample.distance - 0.001, materials);
+    if transmittance <= 0.0 {
+        return zero;
+    }
+
+    let weight = match sample.pdf {
+        Some(pdf) => {
+            let light_pdf = light_probability * pdf;
+            power_heuristic(light_pdf, scatter_pdf) / light_pdf
+        }
+        None => 1.0 / light_probability,
+    };
+    (transmittance * weight) * calc_component_product(&scattered, &sample.incident)
+}
+
+/// The normal that lights are picked with at a hit point. Volumes scatter in every direction, so they have none.
+fn light_sampling_normal(material: &Material, hit_record: &HitRecord) -> Option<Vector3> {
+    match material {
+        Material::HenyeyGreenstein(_, _) => None,
+        _ => Some(hit_record.normal),
+    }
+}
+
+/// The power heuristic weight for a sample picked with pdf a when it could also have been picked with pdf b
+fn power_heuristic(a: f64, b: f64) -> f64 {
+    let a2 = a * a;
+    let b2 = b * b;
+    if a2 + b2 <= 0.0 { 0.0 } else { a2 / (a2 + b2) }
 }
 
 /// Write a color out to stdout for the ppm format
 fn write_color(color: &Vector3) {
-    // Gamma correct color first
-    let gamma_corrected_r = color.x.sqrt();
-    let gamma_corrected_g = color.y.sqrt();
-    let gamma_corrected_b = color.z.sqrt();
+    // Gamma correct color first. High dynamic range lighting can go above 1.0, so we clamp to the displayable range.
+    let gamma_corrected_r = color.x.clamp(0.0, 1.0).sqrt();
+    let gamma_corrected_g = color.y.clamp(0.0, 1.0).sqrt();
+    let gamma_corrected_b = color.z.clamp(0.0, 1.0).sqrt();
 
     let r = (gamma_corrected_r * 255.99) as i32;
     let g = (gamma_corrected_g * 255.99) as i32;
@@ -280,3 +1817,551 @@ fn write_color(color: &Vector3) {
 
     println!("{} {} {}", r, g, b);
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{env, panic, process};
+
+    use super::*;
+    use crate::{
+        hittables::Hittable,
+        light::{AreaLight, Light},
+        map::Map,
+        quad::Quad,
+        sphere::Sphere,
+    };
+
+    fn make_camera(projection: Projection) -> Camera {
+        let mut camera = make_sampled_camera(1);
+        camera.set_projection(projection);
+        camera
+    }
+
+    fn make_sampled_camera(pixel_sample_count: i32) -> Camera {
+        Camera::new(
+            Vector3::default(),
+            Vector3 {
+                x: 0.0,
+                y: 0.0,
+                z: -1.0,
+            },
+            Vector3 {
+                x: 0.0,
+                y: 1.0,
+                z: 0.0,
+            },
+            0.0,
+            1.0,
+            2.0,
+            200,
+            90.0,
+            pixel_sample_count,
+        )
+    }
+
+    fn get_direction(camera: &Camera, film_x: f64, film_y: f64) -> Option<Vector3> {
+        camera
+            .get_projected_ray(0.0, film_x, film_y, &Vector2::default())
+            .map(|ray| Vector3::calc_normalized_vector(&ray.direction))
+    }
+
+    #[test]
+    fn equirectangular_matches_the_environment_map_layout() {
+        let camera = make_camera(Projection::Equirectangular);
+        let forward = get_direction(&camera, 100.0, 50.0).unwrap();
+        assert!((forward.z + 1.0).abs() < 1e-12);
+        let up = get_direction(&camera, 100.0, 0.0).unwrap();
+        assert!((up.y - 1.0).abs() < 1e-12);
+        let right = get_direction(&camera, 150.0, 50.0).unwrap();
+        assert!((right.x - 1.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn fisheyes_reach_half_of_their_fov_at_the_image_circle() {
+        for projection in [
+            Projection::EquidistantFisheye(180.0),
+            Projection::EquisolidFisheye(180.0),
+        ] {
+            let camera = make_camera(projection);
+            // The image circle has a radius of 50 pixels around the center at 100, 50
+            let edge = get_direction(&camera, 150.0, 50.0).unwrap();
+            assert!((edge.x - 1.0).abs() < 1e-12);
+            let top = get_direction(&camera, 100.0, 0.0).unwrap();
+            assert!((top.y - 1.0).abs() < 1e-12);
+            assert!(get_direction(&camera, 160.0, 50.0).is_none());
+        }
+    }
+
+    #[test]
+    fn stereo_eyes_cross_at_the_convergence_distance() {
+        for projection in [Projection::Perspective, Projection::Equirectangular] {
+            let mut camera = make_camera(projection);
+            camera.set_stereo(0.064, Some(5.0), StereoLayout::SideBySide);
+            assert_eq!((camera.image_width, camera.image_height), (400, 100));
+
+            // The centers of the left and right eyes' images
+            let rays: Vec<Ray> = [100, 300]
+                .iter()
+                .map(|x| {
+                    camera
+                        .get_camera_ray(*x, 50, &Vector2::default(), &Vector2::default(), 0.0)
+                        .unwrap()
+                        .0
+                })
+                .collect();
+            assert!((rays[0].origin.x + 0.032).abs() < 1e-12);
+            assert!((rays[1].origin.x - 0.032).abs() < 1e-12);
+            for ray in &rays {
+                // Follow the ray to 5 units in front of the camera
+                let t = -5.0 / ray.direction.z;
+                let point = ray.origin + t * ray.direction;
+                assert!(point.x.abs() < 1e-9 && point.y.abs() < 1e-9);
+            }
+        }
+    }
+
+    #[test]
+    fn physical_settings_give_the_view_aperture_and_exposure() {
+        let mut camera = make_camera(Projection::Perspective);
+        // The 2:1 image is as wide as the sensor, so it uses 18mm of the sensor's height
+        camera.set_sensor(18.0, 36.0, 24.0);
+        assert!((camera.vfov - 2.0 * 0.5_f64.atan().to_degrees()).abs() < 1e-12);
+
+        // An 18mm lens at f/4 has an aperture 4.5mm across, so the defocus disk's radius is 2.25mm
+        camera.set_f_number(4.0);
+        assert!((camera.defocus_disk_u.magnitude() - 0.00225).abs() < 1e-12);
+
+        // Compared to sunny 16, half a second at f/4 and ISO 800 is 50 times as long, lets in 16 times the light, and
+        // is 8 times as sensitive. The compensation takes away one stop.
+        camera.set_shutter(0.25, 0.75);
+        camera.set_iso(800.0, -1.0);
+        assert!((camera.get_exposure() - 50.0 * 16.0 * 8.0 / 2.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn sunny_16_settings_leave_the_image_unchanged() {
+        let mut camera = make_camera(Projection::Perspective);
+        camera.set_sensor(50.0, 36.0, 24.0);
+        assert_eq!(camera.get_exposure(), 1.0);
+
+        // Trading aperture, shutter time, and ISO against each other keeps the exposure
+        for (f_number, shutter_time, iso) in [
+            (16.0, 0.01, 100.0),
+            (8.0, 0.0025, 100.0),
+            (16.0, 0.005, 200.0),
+        ] {
+            camera.set_f_number(f_number);
+            camera.set_shutter(0.0, shutter_time);
+            camera.set_iso(iso, 0.0);
+            assert!((camera.get_exposure() - 1.0).abs() < 1e-12);
+        }
+
+        // Each stop of compensation doubles it
+        camera.set_shutter(0.0, 0.01);
+        camera.set_iso(100.0, 2.0);
+        assert!((camera.get_exposure() - 4.0).abs() < 1e-12);
+    }
+
+    fn gray(value: f64) -> Vector3 {
+        Vector3 {
+            x: value,
+            y: value,
+            z: value,
+        }
+    }
+
+    /// A diffuse floor with a metal and a glass sphere on it, lit by an area light and the sky
+    fn make_scene() -> (Vec<Material>, Hittables, Lights, Background) {
+        let materials = vec![
+            Material::Diffuse(Map::Color(gray(0.5))),
+            Material::Metal(gray(0.8), 0.1),
+            Material::Dielectric(1.5),
+            Material::Emissive(Map::Color(gray(4.0))),
+        ];
+        let mut hittables = Hittables::new();
+        let spheres = [
+            (0.0, -100.5, -1.5, 100.0, 0),
+            (-0.6, 0.0, -1.5, 0.5, 1),
+            (0.6, 0.0, -1.5, 0.5, 2),
+        ];
+        for (x, y, z, radius, material) in spheres {
+            hittables.add_object(Hittable::Sphere(Sphere::new(
+                Vector3 { x, y, z },
+                radius,
+                material,
+            )));
+        }
+
+        // The light faces down
+        let quad = Quad::new(
+            Vector3 {
+                x: -0.5,
+                y: 1.2,
+                z: -2.0,
+            },
+            Vector3 {
+                x: 1.0,
+                y: 0.0,
+                z: 0.0,
+            },
+            Vector3 {
+                x: 0.0,
+                y: 0.0,
+                z: 1.0,
+            },
+            3,
+        );
+        let object = hittables.add_object(Hittable::Quad(quad.clone()));
+        let mut lights = Lights::new();
+        lights.add_light(Light::Area(AreaLight::new(quad, object, &materials)));
+
+        let background = Background::Gradient(
+            gray(1.0),
+            Vector3 {
+                x: 0.5,
+                y: 0.7,
+                z: 1.0,
+            },
+        );
+        (materials, hittables, lights, background)
+    }
+
+    #[test]
+    fn passes_add_up_to_the_radiance() {
+        let mut camera = make_camera(Projection::Perspective);
+        let (materials, mut hittables, mut lights, background) = make_scene();
+        let mut scene = Scene {
+            hittables: &mut hittables,
+            lights: &mut lights,
+            background: &background,
+            materials: &materials,
+        };
+
+        let mut pass_totals = [Vector3::default(); RENDER_PASS_COUNT];
+        for y in (0..camera.image_height).step_by(2) {
+            for x in (0..camera.image_width).step_by(2) {
+                camera.sampler.start_pixel_sample(x, y, 0);
+                let pixel_offset = camera.sampler.get_2d();
+                let (ray, differential, _) = camera
+                    .get_camera_ray(x, y, &pixel_offset, &Vector2::default(), 0.0)
+                    .unwrap();
+                let path = ray_color(&ray, &differential, &mut scene, &mut camera.sampler, 10, 3);
+
+                let pass_sum = path
+                    .passes
+                    .iter()
+                    .fold(Vector3::default(), |sum, pass| sum + *pass);
+                assert!((pass_sum - path.radiance).magnitude() < 1e-12);
+                for (total, pass) in pass_totals.iter_mut().zip(path.passes) {
+                    *total = *total + pass;
+                }
+            }
+        }
+
+        // Every kind of light reaches the camera somewhere in the scene
+        for total in pass_totals {
+            assert!(total.magnitude() > 0.0);
+        }
+    }
+
+    #[test]
+    fn russian_roulette_keeps_the_mean_radiance() {
+        let mut camera = make_camera(Projection::Perspective);
+        let (materials, mut hittables, mut lights, background) = make_scene();
+        let mut scene = Scene {
+            hittables: &mut hittables,
+            lights: &mut lights,
+            background: &background,
+            materials: &materials,
+        };
+        let max_depth = 10;
+
+        // The luminance of paths through every other pixel, along with their mean and the variance of the mean
+        let mut estimate = |russian_roulette_depth: i32| {
+            let mut luminances: Vec<f64> = vec![];
+            for y in (0..camera.image_height).step_by(2) {
+                for x in (0..camera.image_width).step_by(2) {
+                    for sample_index in 0..4 {
+                        camera.sampler.start_pixel_sample(x, y, sample_index);
+                        let pixel_offset = camera.sampler.get_2d();
+                        let (ray, differential, _) = camera
+                            .get_camera_ray(x, y, &pixel_offset, &Vector2::default(), 0.0)
+                            .unwrap();
+                        let path = ray_color(
+                            &ray,
+                            &differential,
+                            &mut scene,
+                            &mut camera.sampler,
+                            max_depth,
+                            russian_roulette_depth,
+                        );
+                        luminances.push(luminance(&path.radiance));
+                    }
+                }
+            }
+
+            let count = luminances.len() as f64;
+            let mean = luminances.iter().sum::<f64>() / count;
+            let variance = luminances
+                .iter()
+                .map(|value| (value - mean) * (value - mean))
+                .sum::<f64>()
+                / (count - 1.0);
+            (luminances, mean, variance / count)
+        };
+
+        // Paths never reach the roulette when it starts at the maximum depth
+        let (without_roulette, mean, mean_variance) = estimate(max_depth);
+        let (with_roulette, roulette_mean, roulette_mean_variance) = estimate(1);
+        assert_ne!(without_roulette, with_roulette);
+        let standard_error = (mean_variance + roulette_mean_variance).sqrt();
+        assert!(
+            (mean - roulette_mean).abs() < 4.0 * standard_error,
+            "{} without Russian roulette, {} with it, standard error {}",
+            mean,
+            roulette_mean,
+            standard_error
+        );
+    }
+
+    #[test]
+    fn pass_films_add_up_to_the_film() {
+        let mut camera = make_camera(Projection::Perspective);
+        let (materials, mut hittables, mut lights, background) = make_scene();
+        camera.set_filter(Filter::Tent(1.5));
+        camera.set_crop_window(80, 30, 40, 20);
+        let passes = vec![
+            RenderPass::DirectDiffuse,
+            RenderPass::IndirectDiffuse,
+            RenderPass::Specular,
+            RenderPass::Transmission,
+            RenderPass::Emission,
+            RenderPass::Background,
+        ];
+        camera.set_aov_output(vec![], passes, String::new());
+
+        let state = take_samples(
+            &mut camera,
+            &mut hittables,
+            &mut lights,
+            &background,
+            &materials,
+            10,
+        );
+        let region = camera.get_tile_region(&camera.render_region());
+        for y in region.y0..region.y1 {
+            for x in region.x0..region.x1 {
+                let pass_sum = state
+                    .pass_films
+                    .iter()
+                    .fold(Vector3::default(), |sum, film| sum + film.get_color(x, y));
+                assert!((pass_sum - state.film.get_color(x, y)).magnitude() < 1e-9);
+            }
+        }
+    }
+
+    #[test]
+    fn pixel_estimates_match_direct_mean_and_variance() {
+        let values = [0.3, 1.7, 0.0, 2.5, 0.9, 0.9, 4.2];
+        let mut pixel = PixelEstimate::default();
+        for value in values {
+            pixel.add_sample(&gray(value));
+        }
+
+        let count = values.len() as f64;
+        let mean = values.iter().sum::<f64>() / count;
+        let variance = values
+            .iter()
+            .map(|value| (value - mean) * (value - mean))
+            .sum::<f64>()
+            / (count - 1.0);
+        assert_eq!(pixel.sample_count, values.len() as i32);
+        assert!((pixel.luminance_mean - mean).abs() < 1e-12);
+        assert!((pixel.luminance_squared_deviations / (count - 1.0) - variance).abs() < 1e-12);
+        assert!((pixel.relative_error() - (variance / count).sqrt() / mean).abs() < 1e-12);
+    }
+
+    #[test]
+    fn dark_pixels_are_compared_against_a_floor() {
+        // The variance can't be estimated from fewer than 2 samples
+        let mut pixel = PixelEstimate::default();
+        assert_eq!(pixel.relative_error(), f64::INFINITY);
+        pixel.add_sample(&gray(0.0));
+        assert_eq!(pixel.relative_error(), f64::INFINITY);
+        pixel.add_sample(&gray(0.0));
+        assert_eq!(pixel.relative_error(), 0.0);
+
+        // A mean of 0.01 with a standard error of 0.01 is divided by the floor of 0.05 rather than the mean
+        let mut pixel = PixelEstimate::default();
+        pixel.add_sample(&gray(0.0));
+        pixel.add_sample(&gray(0.02));
+        assert!((pixel.relative_error() - 0.01 / 0.05).abs() < 1e-12);
+    }
+
+    #[test]
+    fn adaptive_sampling_stays_within_the_budget() {
+        let pixel_sample_count = 16;
+        let (materials, mut hittables, mut lights, background) = make_scene();
+        for (noise_threshold, expected_initial_only) in [(0.001, false), (1e9, true)] {
+            let mut camera = make_sampled_camera(pixel_sample_count);
+            camera.set_crop_window(80, 30, 40, 20);
+            camera.set_adaptive_sampling(noise_threshold, None);
+            let state = take_samples(
+                &mut camera,
+                &mut hittables,
+                &mut lights,
+                &background,
+                &materials,
+                10,
+            );
+
+            let region = camera.render_region();
+            let mut total_sample_count = 0;
+            let mut most_samples = 0;
+            for (index, pixel) in state.pixels.iter().enumerate() {
+                let x = index as i32 % camera.image_width;
+                let y = index as i32 / camera.image_width;
+                if !region.contains(x, y) {
+                    assert_eq!(pixel.sample_count, 0);
+                    continue;
+                }
+                assert!(pixel.sample_count >= camera.initial_pixel_sample_count());
+                assert!(pixel.sample_count <= camera.max_pixel_sample_count());
+                total_sample_count += pixel.sample_count;
+                most_samples = i32::max(most_samples, pixel.sample_count);
+            }
+
+            let pixel_count = (region.x1 - region.x0) * (region.y1 - region.y0);
+            assert!(total_sample_count <= pixel_sample_count * pixel_count);
+            if expected_initial_only {
+                // Every pixel converged after the first pass
+                assert_eq!(
+                    total_sample_count,
+                    camera.initial_pixel_sample_count() * pixel_count
+                );
+            } else {
+                // The noisy pixels got more than their share
+                assert!(most_samples > pixel_sample_count);
+                assert!(total_sample_count > pixel_sample_count * pixel_count * 3 / 4);
+            }
+        }
+    }
+
+    /// Everything that a render has added up, in the order that checkpoints store it
+    fn get_state_bytes(state: &RenderState) -> Vec<u8> {
+        let mut writer = CheckpointWriter::new(CHECKPOINT_MAGIC);
+        for pixel in &state.pixels {
+            pixel.write_checkpoint(&mut writer);
+        }
+        state.film.write_checkpoint(&mut writer);
+        state.aov_buffers.write_checkpoint(&mut writer);
+        for pass_film in &state.pass_films {
+            pass_film.write_checkpoint(&mut writer);
+        }
+        writer.into_bytes()
+    }
+
+    #[test]
+    fn resumed_renders_match_uninterrupted_ones() {
+        let (materials, mut hittables, mut lights, background) = make_scene();
+        let max_depth = 10;
+        let checkpoint_path = env::temp_dir().join(format!("resume_test_{}.ckpt", process::id()));
+        let checkpoint_path = checkpoint_path.to_str().unwrap().to_string();
+
+        // Adaptive sampling plans its passes from the pixel estimates, so it checks that they're restored too
+        for adaptive in [false, true] {
+            let make_render_camera = || {
+                let mut camera = make_sampled_camera(16);
+                camera.set_crop_window(80, 30, 24, 16);
+                camera.set_filter(Filter::Tent(1.5));
+                camera.set_aov_output(
+                    vec![],
+                    vec![RenderPass::DirectDiffuse, RenderPass::Specular],
+                    String::new(),
+                );
+                if adaptive {
+                    camera.set_adaptive_sampling(0.05, None);
+                }
+                camera
+            };
+
+            let mut camera = make_render_camera();
+            let uninterrupted = take_samples(
+                &mut camera,
+                &mut hittables,
+                &mut lights,
+                &background,
+                &materials,
+                max_depth,
+            );
+
+            // Stop after the third pass
+            let mut camera = make_render_camera();
+            let mut state = RenderState::new(&camera, true);
+            let mut scene = Scene {
+                hittables: &mut hittables,
+                lights: &mut lights,
+                background: &background,
+                materials: &materials,
+            };
+            for pass in 1..=3 {
+                assert!(take_pass(
+                    &mut camera,
+                    &mut scene,
+                    &mut state,
+                    max_depth,
+                    pass
+                ));
+            }
+            state.write_checkpoint(&camera, max_depth, 3, &checkpoint_path);
+
+            let mut camera = make_render_camera();
+            camera.set_resume(checkpoint_path.clone());
+            let resumed = take_samples(
+                &mut camera,
+                &mut hittables,
+                &mut lights,
+                &background,
+                &materials,
+                max_depth,
+            );
+            assert!(get_state_bytes(&resumed) == get_state_bytes(&uninterrupted));
+            assert_ne!(
+                get_state_bytes(&state),
+                get_state_bytes(&uninterrupted),
+                "The render finished before it was stopped"
+            );
+        }
+        fs::remove_file(&checkpoint_path).unwrap();
+    }
+
+    #[test]
+    fn resuming_with_different_settings_panics() {
+        let (materials, mut hittables, mut lights, background) = make_scene();
+        let checkpoint_path =
+            env::temp_dir().join(format!("resume_settings_test_{}.ckpt", process::id()));
+        let checkpoint_path = checkpoint_path.to_str().unwrap().to_string();
+
+        let mut camera = make_sampled_camera(4);
+        camera.set_crop_window(0, 0, 4, 4);
+        let state = RenderState::new(&camera, false);
+        state.write_checkpoint(&camera, 10, 0, &checkpoint_path);
+
+        // The Russian roulette depth differs
+        camera.set_russian_roulette_depth(5);
+        camera.set_resume(checkpoint_path.clone());
+        let result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            take_samples(
+                &mut camera,
+                &mut hittables,
+                &mut lights,
+                &background,
+                &materials,
+                10,
+            )
+        }));
+        fs::remove_file(&checkpoint_path).unwrap();
+        let message = result.err().unwrap().downcast::<String>().unwrap();
+        assert!(message.contains("different sampler"), "{}", message);
+    }
+}
@@ -0,0 +1,149 @@
+use std::fs;
+
+use crate::vector::Vector3;
+
+// Identifies checkpoint files, with a version number at the end
+pub const CHECKPOINT_MAGIC: &[u8; 8] = b"RTCKPT02";
+
+/// Builds the contents of a checkpoint file. Everything is stored little endian and floats keep all of their bits, so
+/// that a resumed render continues from exactly the same sums. The file starts with a magic string saying what kind of
+/// file it is.
+pub struct CheckpointWriter {
+    bytes: Vec<u8>,
+}
+
+impl CheckpointWriter {
+    pub fn new(magic: &[u8; 8]) -> Self {
+        Self {
+            bytes: magic.to_vec(),
+        }
+    }
+
+    pub fn write_u64(&mut self, value: u64) {
+        self.bytes.extend(value.to_le_bytes());
+    }
+
+    pub fn write_i32(&mut self, value: i32) {
+        self.bytes.extend(value.to_le_bytes());
+    }
+
+    pub fn write_f64(&mut self, value: f64) {
+        self.bytes.extend(value.to_le_bytes());
+    }
+
+    pub fn write_vector3(&mut self, value: &Vector3) {
+        self.write_f64(value.x);
+        self.write_f64(value.y);
+        self.write_f64(value.z);
+    }
+
+    /// Take the contents, to send them somewhere other than a file
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+
+    /// Write the checkpoint to a file. The file is written next to its final path and then renamed over it, so that an
+    /// interrupted write can't leave a broken checkpoint behind.
+    pub fn save(&self, file_path: &str) {
+        let temporary_path = format!("{}.tmp", file_path);
+        fs::write(&temporary_path, &self.bytes)
+            .unwrap_or_else(|_| panic!("Unable to write file path at {}", temporary_path));
+        fs::rename(&temporary_path, file_path)
+            .unwrap_or_else(|_| panic!("Unable to write file path at {}", file_path));
+    }
+}
+
+/// Reads back the contents of a checkpoint file in the order that they were written
+pub struct CheckpointReader {
+    bytes: Vec<u8>,
+    position: usize,
+}
+
+impl CheckpointReader {
+    pub fn load(file_path: &str, magic: &[u8; 8]) -> Self {
+        let bytes = fs::read(file_path)
+            .unwrap_or_else(|_| panic!("Unable to read file path at {}", file_path));
+        Self::from_bytes(bytes, magic, file_path).unwrap_or_else(|error| panic!("{}", error))
+    }
+
+    /// Read contents that didn't come from a file. The name says where they came from in error messages.
+    pub fn from_bytes(bytes: Vec<u8>, magic: &[u8; 8], name: &str) -> Result<Self, String> {
+        if !bytes.starts_with(magic) {
+            return Err(format!(
+                "{} is not a {} file",
+                name,
+                String::from_utf8_lossy(magic)
+            ));
+        }
+
+        Ok(Self {
+            bytes,
+            position: magic.len(),
+        })
+    }
+
+    /// The number of bytes that haven't been read yet
+    pub fn remaining_len(&self) -> usize {
+        self.bytes.len() - self.position
+    }
+
+    fn read_bytes<const N: usize>(&mut self) -> [u8; N] {
+        let bytes = self
+            .bytes
+            .get(self.position..self.position + N)
+            .expect("Checkpoint ended early");
+        self.position += N;
+        bytes.try_into().unwrap()
+    }
+
+    pub fn read_u64(&mut self) -> u64 {
+        u64::from_le_bytes(self.read_bytes())
+    }
+
+    pub fn read_i32(&mut self) -> i32 {
+        i32::from_le_bytes(self.read_bytes())
+    }
+
+    pub fn read_f64(&mut self) -> f64 {
+        f64::from_le_bytes(self.read_bytes())
+    }
+
+    pub fn read_vector3(&mut self) -> Vector3 {
+        Vector3 {
+            x: self.read_f64(),
+            y: self.read_f64(),
+            z: self.read_f64(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn values_read_back_exactly() {
+        let file_path = std::env::temp_dir().join("checkpoint_test.ckpt");
+        let file_path = file_path.to_str().unwrap();
+
+        let mut writer = CheckpointWriter::new(CHECKPOINT_MAGIC);
+        writer.write_u64(u64::MAX - 1);
+        writer.write_i32(-7);
+        writer.write_f64(0.1 + 0.2);
+        writer.write_vector3(&Vector3 {
+            x: f64::INFINITY,
+            y: -0.0,
+            z: 1e-300,
+        });
+        writer.save(file_path);
+
+        let mut reader = CheckpointReader::load(file_path, CHECKPOINT_MAGIC);
+        assert_eq!(reader.read_u64(), u64::MAX - 1);
+        assert_eq!(reader.read_i32(), -7);
+        assert_eq!(reader.read_f64().to_bits(), (0.1_f64 + 0.2).to_bits());
+        let vector = reader.read_vector3();
+        assert_eq!(vector.x, f64::INFINITY);
+        assert!(vector.y.is_sign_negative());
+        assert_eq!(vector.z, 1e-300);
+    }
+}
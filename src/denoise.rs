@@ -0,0 +1,346 @@
+/// Buffers that guide the denoiser alongside the noisy colors. Each one has a value for every pixel in the same row-major
+/// order as the colors, and any of them can be left out.
+pub struct AuxiliaryBuffers<'a> {
+    pub albedo: Option<&'a [f64]>, // RGB triples of the surface color at the first hit
+    pub normal: Option<&'a [f64]>, // XYZ triples of the unit shading normal at the first hit, zero where nothing was hit
+    pub depth: Option<&'a [f64]>,  // The distance to the first hit, infinite where nothing was hit
+}
+
+/// How strongly the denoiser smooths and how much each buffer holds it back at edges
+pub struct DenoiseSettings {
+    pub iterations: usize, // The number of filter passes, each reaching twice as far as the last
+    pub color_sigma: f64, // Larger values allow smoothing across bigger differences relative to the noise level
+    pub albedo_sigma: f64, // The albedo difference that stops smoothing
+    pub normal_power: f64, // Larger values stop smoothing at smaller changes of the normal
+    pub depth_sigma: f64, // The relative change of depth per pixel that stops smoothing
+}
+
+impl Default for DenoiseSettings {
+    fn default() -> Self {
+        Self {
+            iterations: 5,
+            color_sigma: 4.0,
+            albedo_sigma: 0.1,
+            normal_power: 64.0,
+            depth_sigma: 0.05,
+        }
+    }
+}
+
+// Albedo channels below this aren't divided out, since the colors there carry no texture to preserve
+const MIN_ALBEDO: f64 = 1e-3;
+
+// The B3 spline weights of the a-trous wavelet transform
+const KERNEL: [f64; 5] = [1.0 / 16.0, 1.0 / 4.0, 3.0 / 8.0, 1.0 / 4.0, 1.0 / 16.0];
+
+/// Denoise an image of RGB triples in row-major order, returning the denoised RGB triples.
+///
+/// This is an edge-avoiding a-trous wavelet filter (Dammertz et al. 2010) with the variance guidance of SVGF
+/// (Schied et al. 2017). Each pass is a joint bilateral filter over a sparse 5x5 footprint, where neighbors are
+/// weighted down when their colors differ by more than the noise, or when the auxiliary buffers show an edge. Texture
+/// detail is kept by dividing out the albedo before filtering and multiplying it back in after.
+///
+/// Returns an error if a buffer doesn't have a value for every pixel.
+pub fn denoise(
+    width: usize,
+    height: usize,
+    color: &[f64],
+    auxiliary: &AuxiliaryBuffers,
+    settings: &DenoiseSettings,
+) -> Result<Vec<f64>, String> {
+    let pixel_count = width * height;
+    check_length("color", color.len(), 3 * pixel_count)?;
+    if let Some(albedo) = auxiliary.albedo {
+        check_length("albedo", albedo.len(), 3 * pixel_count)?;
+    }
+    if let Some(normal) = auxiliary.normal {
+        check_length("normal", normal.len(), 3 * pixel_count)?;
+    }
+    if let Some(depth) = auxiliary.depth {
+        check_length("depth", depth.len(), pixel_count)?;
+    }
+
+    // Filter the lighting rather than the final color
+    let demodulation = |index: usize| -> [f64; 3] {
+        match auxiliary.albedo {
+            Some(albedo) => {
+                let channel = |c: usize| {
+                    let value = albedo[3 * index + c];
+                    if value > MIN_ALBEDO { value } else { 1.0 }
+                };
+                [channel(0), channel(1), channel(2)]
+            }
+            None => [1.0, 1.0, 1.0],
+        }
+    };
+    let mut lighting: Vec<[f64; 3]> = (0..pixel_count)
+        .map(|index| {
+            let factor = demodulation(index);
+            [
+                color[3 * index] / factor[0],
+                color[3 * index + 1] / factor[1],
+                color[3 * index + 2] / factor[2],
+            ]
+        })
+        .collect();
+
+    let mut variance = estimate_variance(width, height, &lighting);
+
+    for iteration in 0..settings.iterations {
+        let step = 1_i64 << iteration;
+        let mut filtered_lighting = lighting.clone();
+        let mut filtered_variance = variance.clone();
+
+        for y in 0..height as i64 {
+            for x in 0..width as i64 {
+                let center = (y as usize) * width + x as usize;
+                let center_luminance = luminance(&lighting[center]);
+                let color_scale = settings.color_sigma * variance[center].sqrt() + 1e-6;
+
+                let mut weighted_lighting = [0.0; 3];
+                let mut weighted_variance = 0.0;
+                let mut weight_sum = 0.0;
+                for dy in -2..=2_i64 {
+                    let neighbor_y = y + dy * step;
+                    if neighbor_y < 0 || neighbor_y >= height as i64 {
+                        continue;
+                    }
+                    for dx in -2..=2_i64 {
+                        let neighbor_x = x + dx * step;
+                        if neighbor_x < 0 || neighbor_x >= width as i64 {
+                            continue;
+                        }
+                        let neighbor = (neighbor_y as usize) * width + neighbor_x as usize;
+
+                        let luminance_difference =
+                            (center_luminance - luminance(&lighting[neighbor])).abs();
+                        let pixel_distance = ((dx * dx + dy * dy) as f64).sqrt() * step as f64;
+                        let weight = KERNEL[(dx + 2) as usize]
+                            * KERNEL[(dy + 2) as usize]
+                            * (-luminance_difference / color_scale).exp()
+                            * edge_weight(auxiliary, settings, center, neighbor, pixel_distance);
+                        if weight <= 0.0 {
+                            continue;
+                        }
+
+                        for c in 0..3 {
+                            weighted_lighting[c] += weight * lighting[neighbor][c];
+                        }
+                        weighted_variance += weight * weight * variance[neighbor];
+                        weight_sum += weight;
+                    }
+                }
+
+                // The center always has a positive weight, so weight_sum is too
+                filtered_lighting[center] = [
+                    weighted_lighting[0] / weight_sum,
+                    weighted_lighting[1] / weight_sum,
+                    weighted_lighting[2] / weight_sum,
+                ];
+                filtered_variance[center] = weighted_variance / (weight_sum * weight_sum);
+            }
+        }
+
+        lighting = filtered_lighting;
+        variance = filtered_variance;
+    }
+
+    let mut result = Vec::with_capacity(3 * pixel_count);
+    for (index, value) in lighting.iter().enumerate() {
+        let factor = demodulation(index);
+        result.push(value[0] * factor[0]);
+        result.push(value[1] * factor[1]);
+        result.push(value[2] * factor[2]);
+    }
+
+    Ok(result)
+}
+
+fn check_length(name: &str, length: usize, expected: usize) -> Result<(), String> {
+    if length != expected {
+        return Err(format!(
+            "The {} buffer has {} values, expected {}",
+            name, length, expected
+        ));
+    }
+    Ok(())
+}
+
+/// How much the auxiliary buffers allow smoothing between two pixels, from 0.0 at an edge to 1.0
+fn edge_weight(
+    auxiliary: &AuxiliaryBuffers,
+    settings: &DenoiseSettings,
+    center: usize,
+    neighbor: usize,
+    pixel_distance: f64,
+) -> f64 {
+    let mut weight = 1.0;
+
+    if let Some(albedo) = auxiliary.albedo {
+        let difference_squared: f64 = (0..3)
+            .map(|c| (albedo[3 * center + c] - albedo[3 * neighbor + c]).powi(2))
+            .sum();
+        weight *= (-difference_squared / (settings.albedo_sigma * settings.albedo_sigma)).exp();
+    }
+
+    if let Some(normal) = auxiliary.normal {
+        let cos_angle: f64 = (0..3)
+            .map(|c| normal[3 * center + c] * normal[3 * neighbor + c])
+            .sum();
+        let center_length: f64 = (0..3).map(|c| normal[3 * center + c].powi(2)).sum();
+        let neighbor_length: f64 = (0..3).map(|c| normal[3 * neighbor + c].powi(2)).sum();
+
+        // Pixels where nothing was hit only match each other
+        weight *= if center_length == 0.0 || neighbor_length == 0.0 {
+            if center_length == neighbor_length {
+                1.0
+            } else {
+                0.0
+            }
+        } else {
+            let cos_angle = cos_angle / (center_length * neighbor_length).sqrt();
+            f64::max(cos_angle, 0.0).powf(settings.normal_power)
+        };
+    }
+
+    if let Some(depth) = auxiliary.depth {
+        let (a, b) = (depth[center], depth[neighbor]);
+        weight *= if !a.is_finite() || !b.is_finite() {
+            if a == b { 1.0 } else { 0.0 }
+        } else {
+            // Depth changes faster farther away and farther across the image, so compare the change relative to both
+            let scale = settings.depth_sigma * f64::max(a, 1e-6) * pixel_distance + 1e-6;
+            (-(a - b).abs() / scale).exp()
+        };
+    }
+
+    weight
+}
+
+/// Estimate the variance of each pixel's luminance from its 3x3 neighborhood, since a single image doesn't record
+/// the spread of its samples
+fn estimate_variance(width: usize, height: usize, lighting: &[[f64; 3]]) -> Vec<f64> {
+    let mut variance = vec![0.0; width * height];
+    for y in 0..height {
+        for x in 0..width {
+            let mut sum = 0.0;
+            let mut sum_squared = 0.0;
+            let mut count = 0.0;
+            for neighbor_y in y.saturating_sub(1)..=usize::min(y + 1, height - 1) {
+                for neighbor_x in x.saturating_sub(1)..=usize::min(x + 1, width - 1) {
+                    let value = luminance(&lighting[neighbor_y * width + neighbor_x]);
+                    sum += value;
+                    sum_squared += value * value;
+                    count += 1.0;
+                }
+            }
+
+            let mean = sum / count;
+            variance[y * width + x] = f64::max(sum_squared / count - mean * mean, 0.0);
+        }
+    }
+
+    variance
+}
+
+fn luminance(color: &[f64; 3]) -> f64 {
+    0.2126 * color[0] + 0.7152 * color[1] + 0.0722 * color[2]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn no_auxiliary() -> AuxiliaryBuffers<'static> {
+        AuxiliaryBuffers {
+            albedo: None,
+            normal: None,
+            depth: None,
+        }
+    }
+
+    #[test]
+    fn flat_image_is_unchanged() {
+        let color = vec![0.25; 3 * 8 * 6];
+        let result = denoise(8, 6, &color, &no_auxiliary(), &DenoiseSettings::default()).unwrap();
+        assert!(result.iter().all(|value| (value - 0.25).abs() < 1e-12));
+    }
+
+    #[test]
+    fn noise_is_reduced() {
+        // A flat gray image with a fixed pattern of noise added
+        let (width, height) = (32, 32);
+        let mut color = vec![];
+        for index in 0..width * height {
+            let noise = ((index * 7919) % 101) as f64 / 100.0 - 0.5;
+            color.extend([0.5 + 0.2 * noise; 3]);
+        }
+
+        let result = denoise(
+            width,
+            height,
+            &color,
+            &no_auxiliary(),
+            &DenoiseSettings::default(),
+        )
+        .unwrap();
+        let error = |values: &[f64]| -> f64 {
+            values
+                .iter()
+                .map(|value| (value - 0.5).powi(2))
+                .sum::<f64>()
+                / values.len() as f64
+        };
+        assert!(error(&result) < 0.1 * error(&color));
+    }
+
+    #[test]
+    fn normal_edges_are_kept() {
+        // The left half faces up and is dark, the right half faces sideways and is bright
+        let (width, height) = (16, 8);
+        let mut color = vec![];
+        let mut normal = vec![];
+        for _ in 0..height {
+            for x in 0..width {
+                if x < width / 2 {
+                    color.extend([0.1; 3]);
+                    normal.extend([0.0, 1.0, 0.0]);
+                } else {
+                    color.extend([0.9; 3]);
+                    normal.extend([1.0, 0.0, 0.0]);
+                }
+            }
+        }
+
+        let auxiliary = AuxiliaryBuffers {
+            albedo: None,
+            normal: Some(&normal),
+            depth: None,
+        };
+        let result = denoise(
+            width,
+            height,
+            &color,
+            &auxiliary,
+            &DenoiseSettings::default(),
+        )
+        .unwrap();
+        let left_of_edge = 3 * (width / 2 - 1);
+        let right_of_edge = 3 * (width / 2);
+        assert!((result[left_of_edge] - 0.1).abs() < 1e-6);
+        assert!((result[right_of_edge] - 0.9).abs() < 1e-6);
+    }
+
+    #[test]
+    fn rejects_mismatched_buffers() {
+        let color = vec![0.0; 3 * 4 * 4];
+        let depth = vec![1.0; 5];
+        let auxiliary = AuxiliaryBuffers {
+            albedo: None,
+            normal: None,
+            depth: Some(&depth),
+        };
+        assert!(denoise(4, 4, &color, &auxiliary, &DenoiseSettings::default()).is_err());
+        assert!(denoise(4, 5, &color, &no_auxiliary(), &DenoiseSettings::default()).is_err());
+    }
+}
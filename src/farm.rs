@@ -0,0 +1,650 @@
+use std::{
+    env, fs,
+    io::{self, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    path::Path,
+    sync::{Condvar, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::tile::{PixelRegion, TileMerger, decode_tile};
+
+// How often the coordinator says that it's still waiting while no workers are connected
+const WAITING_MESSAGE_INTERVAL: Duration = Duration::from_secs(10);
+
+// The kinds of messages. Every message is its kind, the length of its contents as a u64, and then the contents.
+const JOB_MESSAGE: u8 = 1; // Coordinator to worker: the render to make tiles of
+const TILE_MESSAGE: u8 = 2; // Coordinator to worker: the region of the next tile to render
+const DONE_MESSAGE: u8 = 3; // Coordinator to worker: there are no more tiles
+const RESULT_MESSAGE: u8 = 4; // Worker to coordinator: the encoded tile
+
+/// A file that the render is built from, sent to the workers so that they don't need their own copy
+pub struct JobFile {
+    name: String, // The file's name, without its directory
+    contents: Vec<u8>,
+}
+
+impl JobFile {
+    fn load(file_path: &str) -> Self {
+        Self {
+            name: Path::new(file_path)
+                .file_name()
+                .unwrap()
+                .to_string_lossy()
+                .to_string(),
+            contents: fs::read(file_path)
+                .unwrap_or_else(|_| panic!("Unable to read file path at {}", file_path)),
+        }
+    }
+
+    /// Write the file to the temporary directory, with a prefix to keep it apart from the job's other files. Returns
+    /// the path that it was written to. The name comes from the network, so only its last component is used, which
+    /// keeps the file in the temporary directory.
+    fn write_local(&self, prefix: &str) -> String {
+        let name = Path::new(&self.name)
+            .file_name()
+            .unwrap_or_else(|| panic!("Unable to use {} as a file name", self.name));
+        let file_path = env::temp_dir().join(format!(
+            "farm_worker_{}_{}{}",
+            std::process::id(),
+            prefix,
+            name.to_string_lossy()
+        ));
+        let file_path = file_path.to_string_lossy().to_string();
+        fs::write(&file_path, &self.contents)
+            .unwrap_or_else(|_| panic!("Unable to write file path at {}", file_path));
+        file_path
+    }
+}
+
+/// A job as it runs on this machine, with its files written to the temporary directory. The files are removed when
+/// it's dropped.
+pub struct LocalJob {
+    pub args: Vec<String>,
+    pub options: Vec<(String, String)>,
+    file_paths: Vec<String>,
+}
+
+impl Drop for LocalJob {
+    fn drop(&mut self) {
+        for file_path in &self.file_paths {
+            let _ = fs::remove_file(file_path);
+        }
+    }
+}
+
+/// What every worker needs to know to render tiles of the same image as the coordinator
+pub struct FarmJob {
+    pub args: Vec<String>, // The positional arguments, starting with the program name and scene number
+    pub options: Vec<(String, String)>,
+    scene_file: Option<JobFile>,
+    option_files: Vec<(String, JobFile)>, // The files named by options, along with the option's name
+}
+
+impl FarmJob {
+    /// Make a job from the command line. If the scene's argument is a file, its contents are sent along with the job,
+    /// as are the contents of the files named by the options in file_options.
+    pub fn new(args: Vec<String>, options: Vec<(String, String)>, file_options: &[&str]) -> Self {
+        let scene_file = args
+            .get(2)
+            .filter(|scene_arg| Path::new(scene_arg).is_file())
+            .map(|file_path| JobFile::load(file_path));
+        let option_files = options
+            .iter()
+            .filter(|(name, _)| file_options.contains(&name.as_str()))
+            .map(|(name, file_path)| (name.clone(), JobFile::load(file_path)))
+            .collect();
+
+        Self {
+            args,
+            options,
+            scene_file,
+            option_files,
+        }
+    }
+
+    /// Get the arguments and options to render with on this machine. The scene file and the files named by options are
+    /// written to the temporary directory and their paths take the place of the coordinator's.
+    pub fn write_local(&self) -> LocalJob {
+        let mut local_job = LocalJob {
+            args: self.args.clone(),
+            options: self.options.clone(),
+            file_paths: vec![],
+        };
+        if let Some(scene_file) = &self.scene_file {
+            let file_path = scene_file.write_local("");
+            local_job.args[2] = file_path.clone();
+            local_job.file_paths.push(file_path);
+        }
+        for (option_name, option_file) in &self.option_files {
+            let file_path = option_file.write_local(&format!("{}_", option_name));
+            for (name, value) in local_job.options.iter_mut() {
+                if name == option_name {
+                    *value = file_path.clone();
+                }
+            }
+            local_job.file_paths.push(file_path);
+        }
+        local_job
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+        let write_bytes = |bytes: &mut Vec<u8>, value: &[u8]| {
+            bytes.extend((value.len() as u64).to_le_bytes());
+            bytes.extend(value);
+        };
+
+        bytes.extend((self.args.len() as u64).to_le_bytes());
+        for arg in &self.args {
+            write_bytes(&mut bytes, arg.as_bytes());
+        }
+        bytes.extend((self.options.len() as u64).to_le_bytes());
+        for (name, value) in &self.options {
+            write_bytes(&mut bytes, name.as_bytes());
+            write_bytes(&mut bytes, value.as_bytes());
+        }
+        match &self.scene_file {
+            Some(scene_file) => {
+                bytes.push(1);
+                write_bytes(&mut bytes, scene_file.name.as_bytes());
+                write_bytes(&mut bytes, &scene_file.contents);
+            }
+            None => bytes.push(0),
+        }
+        bytes.extend((self.option_files.len() as u64).to_le_bytes());
+        for (name, option_file) in &self.option_files {
+            write_bytes(&mut bytes, name.as_bytes());
+            write_bytes(&mut bytes, option_file.name.as_bytes());
+            write_bytes(&mut bytes, &option_file.contents);
+        }
+
+        bytes
+    }
+
+    fn decode(bytes: &[u8]) -> Self {
+        let mut position = 0;
+        let read_u64 = |position: &mut usize| {
+            let value = u64::from_le_bytes(
+                bytes[*position..*position + 8]
+                    .try_into()
+                    .expect("The job ended early"),
+            );
+            *position += 8;
+            value as usize
+        };
+        let read_bytes = |position: &mut usize| {
+            let length = read_u64(position);
+            let value = bytes[*position..*position + length].to_vec();
+            *position += length;
+            value
+        };
+        let read_string = |position: &mut usize| {
+            String::from_utf8(read_bytes(position)).expect("Unable to parse job")
+        };
+
+        let arg_count = read_u64(&mut position);
+        let args = (0..arg_count).map(|_| read_string(&mut position)).collect();
+        let option_count = read_u64(&mut position);
+        let options = (0..option_count)
+            .map(|_| (read_string(&mut position), read_string(&mut position)))
+            .collect();
+        let has_scene_file = bytes[position] == 1;
+        position += 1;
+        let scene_file = has_scene_file.then(|| JobFile {
+            name: read_string(&mut position),
+            contents: read_bytes(&mut position),
+        });
+        let option_file_count = read_u64(&mut position);
+        let option_files = (0..option_file_count)
+            .map(|_| {
+                (
+                    read_string(&mut position),
+                    JobFile {
+                        name: read_string(&mut position),
+                        contents: read_bytes(&mut position),
+                    },
+                )
+            })
+            .collect();
+
+        Self {
+            args,
+            options,
+            scene_file,
+            option_files,
+        }
+    }
+}
+
+fn send_message(stream: &mut TcpStream, kind: u8, contents: &[u8]) -> io::Result<()> {
+    stream.write_all(&[kind])?;
+    stream.write_all(&(contents.len() as u64).to_le_bytes())?;
+    stream.write_all(contents)?;
+    stream.flush()
+}
+
+fn receive_message(stream: &mut TcpStream) -> io::Result<(u8, Vec<u8>)> {
+    let mut kind = [0; 1];
+    stream.read_exact(&mut kind)?;
+    let mut length = [0; 8];
+    stream.read_exact(&mut length)?;
+    // Read up to the length rather than making room for it first, in case the length is garbage
+    let length = u64::from_le_bytes(length);
+    let mut contents = vec![];
+    stream.take(length).read_to_end(&mut contents)?;
+    if contents.len() as u64 != length {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+    }
+    Ok((kind[0], contents))
+}
+
+fn encode_region(region: &PixelRegion) -> Vec<u8> {
+    [region.x0, region.y0, region.x1, region.y1]
+        .iter()
+        .flat_map(|bound| bound.to_le_bytes())
+        .collect()
+}
+
+fn decode_region(bytes: &[u8]) -> PixelRegion {
+    let bound =
+        |index: usize| i32::from_le_bytes(bytes[4 * index..4 * index + 4].try_into().unwrap());
+    PixelRegion {
+        x0: bound(0),
+        y0: bound(1),
+        x1: bound(2),
+        y1: bound(3),
+    }
+}
+
+/// A tile to render, and the region that the samples taken through it reach, which its encoding must cover
+#[derive(Clone, Copy)]
+pub struct FarmTile {
+    pub region: PixelRegion,
+    pub encoded_region: PixelRegion,
+}
+
+/// The tiles that are left, and the image that the finished ones add up to
+struct FarmState {
+    pending_tiles: Vec<FarmTile>, // Tiles that no worker has, taken from the end
+    unfinished_count: usize,      // Tiles that are pending or being rendered
+    worker_count: usize,          // Workers that are connected
+    merger: TileMerger,
+}
+
+/// Hand out the tiles of a job to the workers that connect to the listener, one at a time, until they have all been
+/// rendered. If a worker disconnects, sends back a broken tile, or takes longer than worker_timeout to reply, the
+/// tile goes back to the queue for the next worker that asks for one. Returns the merged tiles. Panics if no worker is
+/// connected for longer than worker_timeout.
+pub fn run_coordinator(
+    listener: TcpListener,
+    job: &FarmJob,
+    tiles: Vec<FarmTile>,
+    worker_timeout: Duration,
+) -> TileMerger {
+    let tile_count = tiles.len();
+    let state = Mutex::new(FarmState {
+        pending_tiles: tiles.into_iter().rev().collect(),
+        unfinished_count: tile_count,
+        worker_count: 0,
+        merger: TileMerger::default(),
+    });
+    let tile_finished = Condvar::new();
+    let job_bytes = job.encode();
+
+    // Accept workers until every tile is done. The listener is polled so that the loop can stop.
+    listener
+        .set_nonblocking(true)
+        .expect("Unable to poll for workers");
+    let address = listener.local_addr().unwrap();
+    let mut idle_since = Instant::now();
+    let mut last_waiting_message = idle_since;
+    thread::scope(|scope| {
+        loop {
+            {
+                let state = state.lock().unwrap();
+                if state.unfinished_count == 0 {
+                    break;
+                }
+                if state.worker_count > 0 {
+                    idle_since = Instant::now();
+                }
+            }
+            if idle_since.elapsed() > worker_timeout {
+                panic!(
+                    "No workers connected to {} for {} seconds",
+                    address,
+                    worker_timeout.as_secs_f64()
+                );
+            }
+            if last_waiting_message.elapsed() > WAITING_MESSAGE_INTERVAL
+                && idle_since.elapsed() > WAITING_MESSAGE_INTERVAL
+            {
+                eprintln!("Waiting for workers at {}", address);
+                last_waiting_message = Instant::now();
+            }
+
+            match listener.accept() {
+                Ok((stream, address)) => {
+                    eprintln!("Worker {} connected", address);
+                    state.lock().unwrap().worker_count += 1;
+                    let (state, tile_finished, job_bytes) = (&state, &tile_finished, &job_bytes);
+                    scope.spawn(move || {
+                        let result = serve_worker(
+                            stream,
+                            address,
+                            worker_timeout,
+                            job_bytes,
+                            state,
+                            tile_finished,
+                            tile_count,
+                        );
+                        if let Err(error) = result {
+                            eprintln!("Worker {} disconnected: {}", address, error);
+                        }
+                        state.lock().unwrap().worker_count -= 1;
+                    });
+                }
+                Err(error) if error.kind() == io::ErrorKind::WouldBlock => {
+                    thread::sleep(Duration::from_millis(10));
+                }
+                Err(error) => panic!("Unable to accept workers: {}", error),
+            }
+        }
+    });
+
+    state.into_inner().unwrap().merger
+}
+
+/// Send tiles to a worker until there are none left
+fn serve_worker(
+    mut stream: TcpStream,
+    address: SocketAddr,
+    worker_timeout: Duration,
+    job_bytes: &[u8],
+    state: &Mutex<FarmState>,
+    tile_finished: &Condvar,
+    tile_count: usize,
+) -> io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(worker_timeout))?;
+    stream.set_write_timeout(Some(worker_timeout))?;
+    send_message(&mut stream, JOB_MESSAGE, job_bytes)?;
+    let name = format!("worker {}", address);
+
+    loop {
+        // Wait for a tile while others are still being rendered, in case their workers disconnect
+        let farm_tile = {
+            let mut state = state.lock().unwrap();
+            loop {
+                if let Some(tile) = state.pending_tiles.pop() {
+                    break Some(tile);
+                }
+                if state.unfinished_count == 0 {
+                    break None;
+                }
+                state = tile_finished.wait(state).unwrap();
+            }
+        };
+        let Some(farm_tile) = farm_tile else {
+            return send_message(&mut stream, DONE_MESSAGE, &[]);
+        };
+
+        // Check the tile before taking the lock, so that a broken one only loses this worker
+        let result = send_message(&mut stream, TILE_MESSAGE, &encode_region(&farm_tile.region))
+            .and_then(|_| receive_message(&mut stream))
+            .and_then(|message| match message {
+                (RESULT_MESSAGE, tile) => decode_tile(tile, &name)
+                    .and_then(|tile| {
+                        if tile.region == farm_tile.encoded_region {
+                            Ok(tile)
+                        } else {
+                            Err(format!("The tile from {} covers the wrong region", name))
+                        }
+                    })
+                    .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error)),
+                (kind, _) => Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unexpected message {}", kind),
+                )),
+            });
+
+        let mut state = state.lock().unwrap();
+        let result = result.and_then(|tile| {
+            state
+                .merger
+                .add_tile(&tile, &name)
+                .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))
+        });
+        tile_finished.notify_all();
+        match result {
+            Ok(()) => {
+                state.unfinished_count -= 1;
+                eprintln!(
+                    "Tile {}/{} done",
+                    tile_count - state.unfinished_count,
+                    tile_count
+                );
+            }
+            Err(error) => {
+                state.pending_tiles.push(farm_tile);
+                return Err(error);
+            }
+        }
+    }
+}
+
+/// A worker's connection to the coordinator
+pub struct WorkerConnection {
+    stream: TcpStream,
+}
+
+impl WorkerConnection {
+    pub fn connect(address: &str) -> Self {
+        Self {
+            stream: TcpStream::connect(address)
+                .unwrap_or_else(|_| panic!("Unable to connect to the coordinator at {}", address)),
+        }
+    }
+
+    fn receive(&mut self, expected_kinds: &[u8]) -> (u8, Vec<u8>) {
+        let (kind, contents) =
+            receive_message(&mut self.stream).expect("Lost the connection to the coordinator");
+        if !expected_kinds.contains(&kind) {
+            panic!("Unexpected message {} from the coordinator", kind);
+        }
+        (kind, contents)
+    }
+
+    /// Get the job, which the coordinator sends as soon as the worker connects
+    pub fn receive_job(&mut self) -> FarmJob {
+        let (_, contents) = self.receive(&[JOB_MESSAGE]);
+        FarmJob::decode(&contents)
+    }
+
+    /// Get the region of the next tile to render, or None if the job is done
+    pub fn receive_tile(&mut self) -> Option<PixelRegion> {
+        match self.receive(&[TILE_MESSAGE, DONE_MESSAGE]) {
+            (TILE_MESSAGE, contents) => Some(decode_region(&contents)),
+            _ => None,
+        }
+    }
+
+    /// Send back the encoded tile for the last region received
+    pub fn send_tile(&mut self, tile: &[u8]) {
+        send_message(&mut self.stream, RESULT_MESSAGE, tile)
+            .expect("Lost the connection to the coordinator");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        film::Film,
+        filter::Filter,
+        tile::encode_tile,
+        vector::{Vector2, Vector3},
+    };
+
+    const WIDTH: i32 = 8;
+    const HEIGHT: i32 = 6;
+    const COLOR: Vector3 = Vector3 {
+        x: 0.25,
+        y: 0.5,
+        z: 1.0,
+    };
+
+    /// Coordinate a job of 4 tiles on another thread, returning its address and the thread
+    fn start_coordinator(worker_timeout: Duration) -> (String, thread::JoinHandle<TileMerger>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let job = FarmJob {
+            args: vec![
+                "learn_raycasting".to_string(),
+                "4".to_string(),
+                "/coordinator/scene.txt".to_string(),
+            ],
+            options: vec![
+                ("seed".to_string(), "3".to_string()),
+                ("lens".to_string(), "/coordinator/lens.txt".to_string()),
+            ],
+            scene_file: Some(JobFile {
+                name: "../scene.txt".to_string(),
+                contents: b"scene".to_vec(),
+            }),
+            option_files: vec![(
+                "lens".to_string(),
+                JobFile {
+                    name: "lens.txt".to_string(),
+                    contents: b"lens".to_vec(),
+                },
+            )],
+        };
+        let tiles = PixelRegion {
+            x0: 0,
+            y0: 0,
+            x1: WIDTH,
+            y1: HEIGHT,
+        }
+        .split(4)
+        .into_iter()
+        .map(|region| FarmTile {
+            region,
+            encoded_region: region,
+        })
+        .collect();
+        let coordinator =
+            thread::spawn(move || run_coordinator(listener, &job, tiles, worker_timeout));
+        (address, coordinator)
+    }
+
+    /// Render a tile the color of every pixel
+    fn render_tile(region: &PixelRegion) -> Vec<u8> {
+        let mut film = Film::new(WIDTH, HEIGHT, Filter::Box(0.5));
+        for y in region.y0..region.y1 {
+            for x in region.x0..region.x1 {
+                film.add_sample(x, y, &Vector2 { x: 0.5, y: 0.5 }, &COLOR);
+            }
+        }
+        encode_tile(&film, region, WIDTH, HEIGHT, 1.0).into_bytes()
+    }
+
+    /// Render every tile that's left, then check the merged image
+    fn finish_job(address: &str, coordinator: thread::JoinHandle<TileMerger>) -> i32 {
+        let mut worker = WorkerConnection::connect(address);
+        worker.receive_job();
+        let mut tile_count = 0;
+        while let Some(region) = worker.receive_tile() {
+            worker.send_tile(&render_tile(&region));
+            tile_count += 1;
+        }
+
+        let (merged_width, merged_height, colors) = coordinator.join().unwrap().get_image();
+        assert_eq!((merged_width, merged_height), (WIDTH, HEIGHT));
+        assert!(
+            colors
+                .iter()
+                .all(|merged| (*merged - COLOR).magnitude() < 1e-12)
+        );
+        tile_count
+    }
+
+    #[test]
+    fn tiles_of_disconnected_workers_are_reassigned() {
+        let (address, coordinator) = start_coordinator(Duration::from_secs(60));
+
+        // The first worker takes a tile and leaves without rendering it
+        let mut quitter = WorkerConnection::connect(&address);
+        let job = quitter.receive_job();
+        assert_eq!(job.args[1], "4");
+        assert_eq!(job.options[0], ("seed".to_string(), "3".to_string()));
+        assert_eq!(job.scene_file.as_ref().unwrap().contents, b"scene");
+
+        // The job's files are written locally in place of the coordinator's paths, and removed afterwards
+        let local_job = job.write_local();
+        let options = &local_job.options;
+        assert_eq!(options[0], ("seed".to_string(), "3".to_string()));
+        assert_eq!(options[1].0, "lens");
+        assert_ne!(options[1].1, "/coordinator/lens.txt");
+        assert_eq!(fs::read(&options[1].1).unwrap(), b"lens");
+        assert_eq!(fs::read(&local_job.args[2]).unwrap(), b"scene");
+        assert_eq!(
+            Path::new(&local_job.args[2]).parent(),
+            Some(env::temp_dir().as_path())
+        );
+        let file_paths = [local_job.args[2].clone(), options[1].1.clone()];
+        drop(local_job);
+        assert!(
+            file_paths
+                .iter()
+                .all(|file_path| !Path::new(file_path).exists())
+        );
+        assert!(quitter.receive_tile().is_some());
+        drop(quitter);
+
+        assert_eq!(finish_job(&address, coordinator), 4);
+    }
+
+    #[test]
+    fn broken_tiles_and_silent_workers_are_reassigned() {
+        let (address, coordinator) = start_coordinator(Duration::from_millis(200));
+
+        // One worker sends back a cut off tile, another one of the wrong region, and another nothing at all
+        let mut truncated = WorkerConnection::connect(&address);
+        truncated.receive_job();
+        let region = truncated.receive_tile().unwrap();
+        let tile = render_tile(&region);
+        truncated.send_tile(&tile[..tile.len() - 5]);
+
+        let mut misplaced = WorkerConnection::connect(&address);
+        misplaced.receive_job();
+        let region = misplaced.receive_tile().unwrap();
+        misplaced.send_tile(&render_tile(&PixelRegion {
+            x0: region.x0,
+            y0: region.y0,
+            x1: region.x0 + 1,
+            y1: region.y0 + 1,
+        }));
+
+        let mut silent = WorkerConnection::connect(&address);
+        silent.receive_job();
+        assert!(silent.receive_tile().is_some());
+        // Long enough for the silent worker to time out, but not for the coordinator to give up without workers
+        thread::sleep(Duration::from_millis(300));
+
+        assert_eq!(finish_job(&address, coordinator), 4);
+        drop((truncated, misplaced, silent));
+    }
+
+    #[test]
+    fn coordinator_without_workers_gives_up() {
+        let (_, coordinator) = start_coordinator(Duration::from_millis(200));
+        let Err(error) = coordinator.join() else {
+            panic!("The coordinator finished without workers");
+        };
+        let message = error.downcast_ref::<String>().unwrap();
+        assert!(message.contains("No workers connected"), "{}", message);
+    }
+}
@@ -0,0 +1,112 @@
+use crate::{
+    checkpoint::{CheckpointReader, CheckpointWriter},
+    filter::{Filter, evaluate_filter, get_filter_radius},
+    vector::{Vector2, Vector3},
+};
+
+/// The image being rendered. Each sample is splatted onto every pixel within the filter's radius, weighted by the
+/// filter, and a pixel's color is the weighted average of the samples around it.
+pub struct Film {
+    width: i32,
+    view_width: i32,  // The width of the views that samples stay within
+    view_height: i32, // The height of the views that samples stay within
+    filter: Filter,
+    weighted_colors: Vec<Vector3>, // The sum of each sample's color times its filter weight, for each pixel
+    weights: Vec<f64>,             // The sum of the filter weights, for each pixel
+}
+
+impl Film {
+    pub fn new(width: i32, height: i32, filter: Filter) -> Self {
+        let pixel_count = (width * height) as usize;
+        Self {
+            width,
+            view_width: width,
+            view_height: height,
+            filter,
+            weighted_colors: vec![Vector3::default(); pixel_count],
+            weights: vec![0.0; pixel_count],
+        }
+    }
+
+    /// Split the image into a grid of views of a size, so that the samples of one view aren't splatted onto the pixels
+    /// of the views next to it
+    pub fn with_view_size(mut self, view_width: i32, view_height: i32) -> Self {
+        self.view_width = view_width;
+        self.view_height = view_height;
+        self
+    }
+
+    /// Add a sample taken through pixel x, y at an offset in [0, 1) from the pixel's top left corner
+    pub fn add_sample(&mut self, x: i32, y: i32, offset: &Vector2, color: &Vector3) {
+        // Pixel centers are at integer coordinates
+        let sample_x = x as f64 + offset.x - 0.5;
+        let sample_y = y as f64 + offset.y - 0.5;
+        let radius = get_filter_radius(&self.filter);
+
+        let view_x = x - x % self.view_width;
+        let view_y = y - y % self.view_height;
+        let x0 = i32::max((sample_x - radius).ceil() as i32, view_x);
+        let x1 = i32::min(
+            (sample_x + radius).floor() as i32,
+            view_x + self.view_width - 1,
+        );
+        let y0 = i32::max((sample_y - radius).ceil() as i32, view_y);
+        let y1 = i32::min(
+            (sample_y + radius).floor() as i32,
+            view_y + self.view_height - 1,
+        );
+        for pixel_y in y0..=y1 {
+            for pixel_x in x0..=x1 {
+                let weight = evaluate_filter(
+                    &self.filter,
+                    sample_x - pixel_x as f64,
+                    sample_y - pixel_y as f64,
+                );
+                if weight == 0.0 {
+                    continue;
+                }
+
+                let index = (pixel_y * self.width + pixel_x) as usize;
+                self.weighted_colors[index] = self.weighted_colors[index] + weight * *color;
+                self.weights[index] += weight;
+            }
+        }
+    }
+
+    /// Get the color of a pixel
+    pub fn get_color(&self, x: i32, y: i32) -> Vector3 {
+        let index = (y * self.width + x) as usize;
+
+        // Filters with negative lobes can leave a pixel with almost no total weight
+        if self.weights[index].abs() < 1e-12 {
+            return Vector3::default();
+        }
+        (1.0 / self.weights[index]) * self.weighted_colors[index]
+    }
+
+    /// Get a pixel's weighted color sum and weight sum, for merging with other films
+    pub fn get_sums(&self, x: i32, y: i32) -> (Vector3, f64) {
+        let index = (y * self.width + x) as usize;
+        (self.weighted_colors[index], self.weights[index])
+    }
+
+    /// Save the film's sums to a checkpoint
+    pub fn write_checkpoint(&self, writer: &mut CheckpointWriter) {
+        for weighted_color in &self.weighted_colors {
+            writer.write_vector3(weighted_color);
+        }
+        for weight in &self.weights {
+            writer.write_f64(*weight);
+        }
+    }
+
+    /// Restore the film's sums from a checkpoint
+    pub fn read_checkpoint(&mut self, reader: &mut CheckpointReader) {
+        for weighted_color in self.weighted_colors.iter_mut() {
+            *weighted_color = reader.read_vector3();
+        }
+        for weight in self.weights.iter_mut() {
+            *weight = reader.read_f64();
+        }
+    }
+}
@@ -0,0 +1,115 @@
+use std::f64::consts::PI;
+
+/// Reconstruction filters that decide how much each sample contributes to the pixels around it.
+/// Every filter is separable, and is zero past its radius in pixels.
+#[derive(Clone, Copy)]
+pub enum Filter {
+    Box(f64),                         // radius
+    Tent(f64),                        // radius
+    Gaussian(f64, f64),               // radius, standard deviation
+    MitchellNetravali(f64, f64, f64), // radius, B, C
+    BlackmanHarris(f64),              // radius
+}
+
+/// How far from a sample, in pixels, the filter reaches
+pub fn get_filter_radius(filter: &Filter) -> f64 {
+    match filter {
+        Filter::Box(radius)
+        | Filter::Tent(radius)
+        | Filter::Gaussian(radius, _)
+        | Filter::MitchellNetravali(radius, _, _)
+        | Filter::BlackmanHarris(radius) => *radius,
+    }
+}
+
+/// The weight of a sample at an offset in pixels from a pixel's center
+pub fn evaluate_filter(filter: &Filter, x: f64, y: f64) -> f64 {
+    evaluate_filter_1d(filter, x) * evaluate_filter_1d(filter, y)
+}
+
+fn evaluate_filter_1d(filter: &Filter, x: f64) -> f64 {
+    let x = x.abs();
+    if x > get_filter_radius(filter) {
+        return 0.0;
+    }
+
+    match filter {
+        Filter::Box(_) => 1.0,
+        Filter::Tent(radius) => radius - x,
+        Filter::Gaussian(radius, sigma) => {
+            // Shift down so that the filter goes to zero at the radius instead of being cut off
+            let gaussian = |x: f64| (-x * x / (2.0 * sigma * sigma)).exp();
+            f64::max(gaussian(x) - gaussian(*radius), 0.0)
+        }
+        Filter::MitchellNetravali(radius, b, c) => {
+            // The cubic is defined over [-2, 2], so stretch it over the radius
+            let t = 2.0 * x / radius;
+            if t > 1.0 {
+                ((-b - 6.0 * c) * t * t * t
+                    + (6.0 * b + 30.0 * c) * t * t
+                    + (-12.0 * b - 48.0 * c) * t
+                    + (8.0 * b + 24.0 * c))
+                    / 6.0
+            } else {
+                ((12.0 - 9.0 * b - 6.0 * c) * t * t * t
+                    + (-18.0 + 12.0 * b + 6.0 * c) * t * t
+                    + (6.0 - 2.0 * b))
+                    / 6.0
+            }
+        }
+        Filter::BlackmanHarris(radius) => {
+            // The window goes from 0.0 at -radius to 1.0 at 0 and back to 0.0 at radius
+            let n = 0.5 + 0.5 * x / radius;
+            0.35875 - 0.48829 * (2.0 * PI * n).cos() + 0.14128 * (4.0 * PI * n).cos()
+                - 0.01168 * (6.0 * PI * n).cos()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn all_filters() -> Vec<Filter> {
+        vec![
+            Filter::Box(0.5),
+            Filter::Tent(1.0),
+            Filter::Gaussian(1.5, 0.5),
+            Filter::MitchellNetravali(2.0, 1.0 / 3.0, 1.0 / 3.0),
+            Filter::BlackmanHarris(2.0),
+        ]
+    }
+
+    #[test]
+    fn filters_are_zero_past_their_radius() {
+        for filter in all_filters() {
+            let radius = get_filter_radius(&filter);
+            assert_eq!(evaluate_filter(&filter, radius + 1e-9, 0.0), 0.0);
+            assert_eq!(evaluate_filter(&filter, 0.0, -radius - 1e-9), 0.0);
+            assert!(evaluate_filter(&filter, 0.0, 0.0) > 0.0);
+        }
+    }
+
+    #[test]
+    fn filters_are_symmetric_and_peak_at_the_center() {
+        for filter in all_filters() {
+            let center = evaluate_filter(&filter, 0.0, 0.0);
+            for offset in [0.1, 0.3, 0.45] {
+                let weight = evaluate_filter(&filter, offset, 0.0);
+                assert!((weight - evaluate_filter(&filter, -offset, 0.0)).abs() < 1e-12);
+                assert!((weight - evaluate_filter(&filter, 0.0, offset)).abs() < 1e-12);
+                assert!(weight <= center);
+            }
+        }
+    }
+
+    #[test]
+    fn mitchell_netravali_matches_the_cubic() {
+        let filter = Filter::MitchellNetravali(2.0, 1.0 / 3.0, 1.0 / 3.0);
+        assert!((evaluate_filter(&filter, 0.0, 0.0) - (8.0 / 9.0) * (8.0 / 9.0)).abs() < 1e-12);
+        assert!((evaluate_filter_1d(&filter, 1.0) - 1.0 / 18.0).abs() < 1e-12);
+
+        // The negative lobe
+        assert!(evaluate_filter_1d(&filter, 1.5) < 0.0);
+    }
+}
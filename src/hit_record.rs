@@ -1,38 +1,126 @@
 use crate::{
-    ray::{self, Ray},
-    vector::Vector3,
+    ray::{self, Ray, RayDifferential},
+    raytrace_vector::orthonormal_basis,
+    vector::{Vector2, Vector3},
 };
 
+#[derive(Clone)]
 pub struct HitRecord {
     pub point: Vector3, // The point of intersection
 
-    // The normal at the point of intersection. Normals always point out and are always unit vectors.
+    // The geometric normal at the point of intersection. Normals always point out and are always unit vectors.
     pub normal: Vector3,
 
+    // The normal used for shading. This starts out as the geometric normal and can be perturbed by materials with
+    // normal or bump maps. It faces the same side as the geometric normal.
+    pub shading_normal: Vector3,
+
     pub t: f64,           // The t value for the ray at the point of intersection
     pub front_face: bool, // Whether or not the ray intersected from the front face or the back face
 
     pub material: usize, // Handle to the material that was hit
+    pub object: usize, // Handle to the object that was hit. Set by Hittables when it finds the closest hit.
 
     pub u: f64,
     pub v: f64,
+
+    // The partial derivatives of the point with respect to u and v. These are tangent to the surface and
+    // calc_cross_product(dpdu, dpdv) points in the direction of the outward normal.
+    pub dpdu: Vector3,
+    pub dpdv: Vector3,
+
+    // How far u and v change across the pixel that the ray was traced through. It is used to filter textures and is
+    // zero when unknown.
+    pub uv_footprint: Vector2,
 }
 
 impl HitRecord {
     /// Constructor for the hit record. 'normal' is assumed to have unit length.
+    /// The tangents default to an arbitrary basis around the normal. Geometries with a parameterization should set
+    /// dpdu and dpdv after construction.
     pub fn new(ray_in: &Ray, normal: Vector3, t: f64, material: usize, u: f64, v: f64) -> Self {
         let point = ray::at(&ray_in, t);
+        let (dpdu, dpdv) = orthonormal_basis(&normal);
         let front_face = Vector3::dot_product(&ray_in.direction, &normal) < 0.0;
         let normal = if front_face { normal } else { -1.0 * normal };
 
         Self {
             point,
             normal,
+            shading_normal: normal,
             t,
             front_face,
             material,
+            object: 0,
             u,
             v,
+            dpdu,
+            dpdv,
+            uv_footprint: Vector2::default(),
+        }
+    }
+}
+
+/// Estimate how far u and v change across a pixel from the ray differentials of the ray that made the hit record.
+///
+/// The offset rays are intersected with the tangent plane at the hit point, and the offsets between the intersections
+/// are projected onto dpdu and dpdv.
+pub fn compute_uv_footprint(record: &mut HitRecord, differential: &RayDifferential) {
+    let n = record.normal;
+    let intersect_tangent_plane = |origin: Vector3, direction: Vector3| -> Option<Vector3> {
+        let denominator = Vector3::dot_product(&n, &direction);
+        if denominator.abs() < 1e-12 {
+            return None;
         }
+        let t = Vector3::dot_product(&n, &(record.point - origin)) / denominator;
+        Some(origin + t * direction)
+    };
+
+    let (px, py) = match (
+        intersect_tangent_plane(differential.rx_origin, differential.rx_direction),
+        intersect_tangent_plane(differential.ry_origin, differential.ry_direction),
+    ) {
+        (Some(px), Some(py)) => (px, py),
+        _ => return,
+    };
+    let dpdx = px - record.point;
+    let dpdy = py - record.point;
+
+    // Solve dp = du * dpdu + dv * dpdv using the two axes that are least aligned with the normal. The third equation is
+    // redundant since dp lies in the tangent plane.
+    let (a0, a1) = if n.x.abs() > n.y.abs() && n.x.abs() > n.z.abs() {
+        (1, 2)
+    } else if n.y.abs() > n.z.abs() {
+        (0, 2)
+    } else {
+        (0, 1)
+    };
+    let component = |v: &Vector3, axis: usize| match axis {
+        0 => v.x,
+        1 => v.y,
+        _ => v.z,
+    };
+
+    let determinant = component(&record.dpdu, a0) * component(&record.dpdv, a1)
+        - component(&record.dpdv, a0) * component(&record.dpdu, a1);
+    if determinant.abs() < 1e-12 {
+        return;
     }
+    let solve = |dp: &Vector3| -> (f64, f64) {
+        let du = (component(&record.dpdv, a1) * component(dp, a0)
+            - component(&record.dpdv, a0) * component(dp, a1))
+            / determinant;
+        let dv = (component(&record.dpdu, a0) * component(dp, a1)
+            - component(&record.dpdu, a1) * component(dp, a0))
+            / determinant;
+        (du, dv)
+    };
+
+    let (dudx, dvdx) = solve(&dpdx);
+    let (dudy, dvdy) = solve(&dpdy);
+
+    record.uv_footprint = Vector2 {
+        x: f64::max(dudx.abs(), dudy.abs()),
+        y: f64::max(dvdx.abs(), dvdy.abs()),
+    };
 }
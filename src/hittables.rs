@@ -1,11 +1,13 @@
-use rand::{Rng, rngs::ThreadRng};
+use rand::{Rng, SeedableRng, rngs::SmallRng};
 
 use crate::{
     aabb::{Aabb, hit_aabb},
     hit_record::HitRecord,
+    material::{Material, get_opacity},
     quad::{Quad, hit_quad},
     ray::Ray,
     sphere::{Sphere, hit_sphere},
+    volume::{Volume, hit_volume, volume_transmittance},
 };
 
 #[derive(Clone)]
@@ -17,14 +19,14 @@ struct NodeData {
 
 enum BvhNode {
     Node(NodeData),
-    Object(Hittable),
+    Object(usize), // Handle to the object
 }
 
 pub struct Hittables {
     objects: Vec<Hittable>,
     bvh_nodes: Vec<BvhNode>,
     root: Option<usize>,
-    rng: ThreadRng,
+    rng: SmallRng, // Reseeded for every pixel sample, so that renders can be reproduced
 }
 
 impl Hittables {
@@ -33,10 +35,15 @@ impl Hittables {
             objects: vec![],
             bvh_nodes: vec![],
             root: None,
-            rng: ThreadRng::default(),
+            rng: SmallRng::seed_from_u64(0),
         }
     }
 
+    /// Restart the random choices made while finding hits, like where rays scatter inside of volumes
+    pub fn seed_rng(&mut self, seed: u64) {
+        self.rng = SmallRng::seed_from_u64(seed);
+    }
+
     pub fn add_object(&mut self, object: Hittable) -> usize {
         // self.bbox = Aabb::from_boxes(&self.bbox, &s.bounding_box);
         let handle = self.objects.len();
@@ -54,12 +61,9 @@ impl Hittables {
         handle
     }
 
-    /// Use a bounding volume hierarchy to find the closest hit record.
-    /// The function takes a mutable in order to allow us to construct a bvh just-in-time while
-    /// not allowing the function to be called on data in a transient state.
-    pub fn get_hit_record(&mut self, ray_in: &Ray, tmin: f64, tmax: f64) -> Option<HitRecord> {
-        // Get the bvh root
-        let bvh_root = match self.root {
+    /// Get the root of the bounding volume hierarchy, constructing it first if needed
+    fn get_bvh_root(&mut self) -> usize {
+        match self.root {
             Some(root) => root,
             None => {
                 // Construct BVH if it does not already exist
@@ -68,22 +72,15 @@ impl Hittables {
                 self.bvh_nodes.clear();
 
                 // Initialize stack
-                let all_contained_objects = {
-                    let mut all_contained_objects: Vec<Hittable> =
-                        Vec::<Hittable>::with_capacity(self.objects.len());
-                    for object in &self.objects {
-                        all_contained_objects.push(object.clone());
-                    }
-                    all_contained_objects
-                };
-                let all_bbox = bbox_from_objects(&all_contained_objects);
+                let all_contained_objects: Vec<usize> = (0..self.objects.len()).collect();
+                let all_bbox = bbox_from_objects(&self.objects, &all_contained_objects);
                 self.add_node(BvhNode::Node(NodeData {
                     left: 0,
                     right: 0,
                     bbox: all_bbox,
                 }));
 
-                let mut stack: Vec<(usize, Vec<Hittable>)> = vec![(0, all_contained_objects)];
+                let mut stack: Vec<(usize, Vec<usize>)> = vec![(0, all_contained_objects)];
 
                 loop {
                     match stack.pop() {
@@ -114,33 +111,37 @@ impl Hittables {
 
                             // Now add the nodes themselves
                             if contained_objects.len() == 1 {
-                                self.add_node(BvhNode::Object(contained_objects[0].clone()));
+                                self.add_node(BvhNode::Object(contained_objects[0]));
                             } else if contained_objects.len() == 2 {
-                                self.add_node(BvhNode::Object(contained_objects[0].clone()));
-                                self.add_node(BvhNode::Object(contained_objects[1].clone()));
+                                self.add_node(BvhNode::Object(contained_objects[0]));
+                                self.add_node(BvhNode::Object(contained_objects[1]));
                             } else {
                                 // Sort the objects by a random axis.
                                 // The longest axis method has an issue if most objects are on the same plane,
                                 // then no real sorting occurs and the bounding boxes don't decrease in size.
                                 {
                                     let choice = self.rng.random_range(0..3);
+                                    let objects = &self.objects;
                                     if choice == 0 {
                                         contained_objects.sort_by(|a, b| {
-                                            a.get_bounding_box()
+                                            objects[*a]
+                                                .get_bounding_box()
                                                 .x0
-                                                .total_cmp(&b.get_bounding_box().x0)
+                                                .total_cmp(&objects[*b].get_bounding_box().x0)
                                         })
                                     } else if choice == 1 {
                                         contained_objects.sort_by(|a, b| {
-                                            a.get_bounding_box()
+                                            objects[*a]
+                                                .get_bounding_box()
                                                 .y0
-                                                .total_cmp(&b.get_bounding_box().y0)
+                                                .total_cmp(&objects[*b].get_bounding_box().y0)
                                         })
                                     } else {
                                         contained_objects.sort_by(|a, b| {
-                                            a.get_bounding_box()
+                                            objects[*a]
+                                                .get_bounding_box()
                                                 .z0
-                                                .total_cmp(&b.get_bounding_box().z0)
+                                                .total_cmp(&objects[*b].get_bounding_box().z0)
                                         })
                                     }
                                 }
@@ -154,12 +155,12 @@ impl Hittables {
                                 self.add_node(BvhNode::Node(NodeData {
                                     left: 0,
                                     right: 0,
-                                    bbox: bbox_from_objects(&left_objects),
+                                    bbox: bbox_from_objects(&self.objects, &left_objects),
                                 }));
                                 self.add_node(BvhNode::Node(NodeData {
                                     left: 0,
                                     right: 0,
-                                    bbox: bbox_from_objects(&right_objects),
+                                    bbox: bbox_from_objects(&self.objects, &right_objects),
                                 }));
 
                                 // Add to the stack
@@ -174,7 +175,21 @@ impl Hittables {
                 self.root = Some(0); // Update so we don't reconstruct again
                 0 // return the root's handle
             }
-        };
+        }
+    }
+
+    /// Use a bounding volume hierarchy to find the closest hit record.
+    /// The function takes a mutable in order to allow us to construct a bvh just-in-time while
+    /// not allowing the function to be called on data in a transient state.
+    /// The materials are needed to skip intersections that a material's opacity mask cuts out.
+    pub fn get_hit_record(
+        &mut self,
+        ray_in: &Ray,
+        tmin: f64,
+        tmax: f64,
+        materials: &[Material],
+    ) -> Option<HitRecord> {
+        let bvh_root = self.get_bvh_root();
 
         let closest_record = {
             let mut stack: Vec<usize> = vec![bvh_root];
@@ -200,15 +215,24 @@ impl Hittables {
                             // Do not modify stack if bounding box was not hit
                         }
                     }
-                    BvhNode::Object(object_in) => {
-                        match object_in.hit(ray_in, tmin, closest) {
-                            Some(hit_record) => {
-                                if hit_record.t < closest {
-                                    closest = hit_record.t;
-                                    closest_record = Some(hit_record);
-                                }
+                    BvhNode::Object(object_handle) => {
+                        let object_in = &self.objects[*object_handle];
+
+                        // Partially opaque intersections are kept with probability equal to their opacity.
+                        // When an intersection is skipped, we keep looking for one farther along the ray.
+                        let mut search_tmin = tmin;
+                        while let Some(mut hit_record) =
+                            object_in.hit(ray_in, search_tmin, closest, &mut self.rng)
+                        {
+                            let opacity = get_opacity(&materials[hit_record.material], &hit_record);
+                            if opacity >= 1.0 || self.rng.random_range(0.0..1.0) < opacity {
+                                hit_record.object = *object_handle;
+                                closest = hit_record.t;
+                                closest_record = Some(hit_record);
+                                break;
                             }
-                            None => {} // Continue searching
+
+                            search_tmin = hit_record.t;
                         }
                     }
                 }
@@ -219,19 +243,69 @@ impl Hittables {
 
         closest_record
     }
+
+    /// Estimate the fraction of light that travels along the ray within [tmin, tmax] without being blocked.
+    /// This is used for shadow rays, so we don't need the closest hit. Volumes are estimated with ratio tracking and
+    /// partially opaque surfaces let through the light that their opacity doesn't block.
+    pub fn transmittance(
+        &mut self,
+        ray_in: &Ray,
+        tmin: f64,
+        tmax: f64,
+        materials: &[Material],
+    ) -> f64 {
+        let bvh_root = self.get_bvh_root();
+
+        let mut transmittance = 1.0;
+        let mut stack: Vec<usize> = vec![bvh_root];
+        while let Some(current_node_handle) = stack.pop() {
+            match &self.bvh_nodes[current_node_handle] {
+                BvhNode::Node(node_data) => {
+                    if hit_aabb(&node_data.bbox, ray_in, tmin, tmax) {
+                        stack.push(node_data.left);
+                        stack.push(node_data.right);
+                    }
+                }
+                BvhNode::Object(object_handle) => {
+                    let object_in = &self.objects[*object_handle];
+                    if let Hittable::Volume(volume) = object_in {
+                        transmittance *=
+                            volume_transmittance(ray_in, volume, tmin, tmax, &mut self.rng);
+                        continue;
+                    }
+
+                    // A surface can be crossed more than once, so keep looking past each intersection
+                    let mut search_tmin = tmin;
+                    while let Some(hit_record) =
+                        object_in.hit(ray_in, search_tmin, tmax, &mut self.rng)
+                    {
+                        let opacity = get_opacity(&materials[hit_record.material], &hit_record);
+                        transmittance *= 1.0 - opacity;
+                        if transmittance <= 0.0 {
+                            return 0.0;
+                        }
+
+                        search_tmin = hit_record.t;
+                    }
+                }
+            }
+        }
+
+        transmittance
+    }
 }
 
-/// Constructs an axis-aligned bounding box from a Vec of hittable objects
-fn bbox_from_objects(objects: &Vec<Hittable>) -> Aabb {
-    if objects.len() == 1 {
-        objects[0].get_bounding_box()
+/// Constructs an axis-aligned bounding box from handles to hittable objects
+fn bbox_from_objects(objects: &[Hittable], handles: &[usize]) -> Aabb {
+    if handles.len() == 1 {
+        objects[handles[0]].get_bounding_box()
     } else {
         let mut result = Aabb::from_boxes(
-            &objects[0].get_bounding_box(),
-            &objects[1].get_bounding_box(),
+            &objects[handles[0]].get_bounding_box(),
+            &objects[handles[1]].get_bounding_box(),
         );
-        for object in objects {
-            result = Aabb::from_boxes(&result, &object.get_bounding_box());
+        for handle in handles {
+            result = Aabb::from_boxes(&result, &objects[*handle].get_bounding_box());
         }
         result
     }
@@ -241,6 +315,7 @@ fn bbox_from_objects(objects: &Vec<Hittable>) -> Aabb {
 pub enum Hittable {
     Sphere(Sphere),
     Quad(Quad),
+    Volume(Volume),
 }
 
 impl Hittable {
@@ -248,13 +323,142 @@ impl Hittable {
         match self {
             Hittable::Sphere(sphere) => sphere.bounding_box.clone(),
             Hittable::Quad(quad) => quad.bounding_box.clone(),
+            Hittable::Volume(volume) => volume.bounding_box.clone(),
         }
     }
 
-    fn hit(&self, ray_in: &Ray, tmin: f64, tmax: f64) -> Option<HitRecord> {
+    /// Find the hit record for the object. The rng is needed by objects that are hit stochastically, like volumes.
+    fn hit(&self, ray_in: &Ray, tmin: f64, tmax: f64, rng: &mut SmallRng) -> Option<HitRecord> {
         match self {
             Hittable::Sphere(sphere) => hit_sphere(ray_in, sphere, tmin, tmax),
             Hittable::Quad(quad) => hit_quad(ray_in, quad, tmin, tmax),
+            Hittable::Volume(volume) => hit_volume(ray_in, volume, tmin, tmax, rng),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{map::Map, vector::Vector3};
+
+    /// A diffuse material with a constant opacity
+    fn make_cutout(opacity: f64) -> Material {
+        let gray = |value: f64| Vector3 {
+            x: value,
+            y: value,
+            z: value,
+        };
+        Material::Cutout(
+            Box::new(Material::Diffuse(Map::Color(gray(0.5)))),
+            Map::Color(gray(opacity)),
+        )
+    }
+
+    /// A square facing the ray from make_ray, at a height along z
+    fn make_square(z: f64, material: usize) -> Hittable {
+        Hittable::Quad(Quad::new(
+            Vector3 {
+                x: -1.0,
+                y: -1.0,
+                z,
+            },
+            Vector3 {
+                x: 2.0,
+                y: 0.0,
+                z: 0.0,
+            },
+            Vector3 {
+                x: 0.0,
+                y: 2.0,
+                z: 0.0,
+            },
+            material,
+        ))
+    }
+
+    fn make_ray() -> Ray {
+        Ray {
+            origin: Vector3 {
+                x: 0.1,
+                y: 0.2,
+                z: 5.0,
+            },
+            direction: Vector3 {
+                x: 0.0,
+                y: 0.0,
+                z: -1.0,
+            },
+            time: 0.0,
         }
     }
+
+    #[test]
+    fn cutouts_are_hit_in_proportion_to_their_opacity() {
+        let materials = vec![make_cutout(0.0), make_cutout(1.0), make_cutout(0.5)];
+        let ray = make_ray();
+
+        // A transparent square in front of an opaque one is never hit
+        let mut hittables = Hittables::new();
+        hittables.add_object(make_square(1.0, 0));
+        let opaque = hittables.add_object(make_square(0.0, 1));
+        for seed in 0..100 {
+            hittables.seed_rng(seed);
+            let record = hittables
+                .get_hit_record(&ray, 0.001, f64::INFINITY, &materials)
+                .unwrap();
+            assert_eq!(record.object, opaque);
+        }
+
+        // An opaque square in front of a half transparent one is always hit, and the half transparent one is hit
+        // about half of the time when it's in front
+        let mut hittables = Hittables::new();
+        hittables.add_object(make_square(0.0, 2));
+        let opaque = hittables.add_object(make_square(1.0, 1));
+        let mut behind = Hittables::new();
+        let front_half = behind.add_object(make_square(1.0, 2));
+        behind.add_object(make_square(0.0, 1));
+        let mut half_count = 0;
+        for seed in 0..1000 {
+            hittables.seed_rng(seed);
+            let record = hittables
+                .get_hit_record(&ray, 0.001, f64::INFINITY, &materials)
+                .unwrap();
+            assert_eq!(record.object, opaque);
+
+            behind.seed_rng(seed);
+            let record = behind
+                .get_hit_record(&ray, 0.001, f64::INFINITY, &materials)
+                .unwrap();
+            if record.object == front_half {
+                half_count += 1;
+            }
+        }
+        assert!(
+            (half_count as f64 / 1000.0 - 0.5).abs() < 0.05,
+            "{}",
+            half_count
+        );
+    }
+
+    #[test]
+    fn stacked_cutouts_multiply_their_transmittance() {
+        let materials = vec![make_cutout(0.5), make_cutout(1.0)];
+        let ray = make_ray();
+
+        let mut hittables = Hittables::new();
+        hittables.add_object(make_square(1.0, 0));
+        hittables.add_object(make_square(0.0, 0));
+        let transmittance = hittables.transmittance(&ray, 0.001, f64::INFINITY, &materials);
+        assert!((transmittance - 0.25).abs() < 1e-12);
+
+        // Only what's within the ray's interval blocks it, and opaque surfaces block all of it
+        let transmittance = hittables.transmittance(&ray, 0.001, 4.5, &materials);
+        assert!((transmittance - 0.5).abs() < 1e-12);
+        hittables.add_object(make_square(-1.0, 1));
+        assert_eq!(
+            hittables.transmittance(&ray, 0.001, f64::INFINITY, &materials),
+            0.0
+        );
+    }
 }
@@ -0,0 +1,319 @@
+use std::fs;
+
+/// The angular distribution of a light fixture's intensity from an IES LM-63 photometric file.
+///
+/// Only type C photometry is supported, which is what almost all architectural fixtures use. Vertical angles are
+/// measured from straight down (nadir) at 0 degrees to straight up at 180 degrees. Horizontal angles go around the
+/// vertical axis.
+pub struct IesProfile {
+    vertical_angles: Vec<f64>,   // In degrees, increasing
+    horizontal_angles: Vec<f64>, // In degrees, increasing
+    candela: Vec<Vec<f64>>,      // A row over the vertical angles for each horizontal angle
+    max_candela: f64,
+}
+
+impl IesProfile {
+    /// Load an IES file. Panics if the file can't be read or parsed.
+    pub fn load(file_path: &str) -> Self {
+        let file_contents = fs::read(file_path)
+            .unwrap_or_else(|_| panic!("Unable to read file path at {}", file_path));
+
+        Self::parse(&String::from_utf8_lossy(&file_contents))
+            .unwrap_or_else(|message| panic!("Unable to parse {}: {}", file_path, message))
+    }
+
+    /// Parse the contents of an IES file in the LM-63-1986, 1991, 1995, or 2002 formats
+    pub fn parse(contents: &str) -> Result<Self, String> {
+        // Everything up to and including the TILT line is free-form header text
+        let mut lines = contents.lines();
+        let tilt = loop {
+            match lines.next() {
+                Some(line) => {
+                    if let Some(tilt) = line.trim().strip_prefix("TILT=") {
+                        break tilt.trim().to_string();
+                    }
+                }
+                None => return Err("Missing TILT line".to_string()),
+            }
+        };
+
+        // The rest of the file is numbers separated by whitespace, and sometimes commas, that can wrap across lines
+        let remaining: Vec<&str> = lines.collect();
+        let mut tokens = remaining
+            .iter()
+            .flat_map(|line| line.split(|c: char| c.is_whitespace() || c == ','))
+            .filter(|token| !token.is_empty());
+        let mut next_number = |name: &str| -> Result<f64, String> {
+            let token = tokens
+                .next()
+                .ok_or_else(|| format!("File ended while reading {}", name))?;
+            token
+                .parse::<f64>()
+                .map_err(|_| format!("Invalid number {} for {}", token, name))
+        };
+
+        if tilt == "INCLUDE" {
+            // The tilt data describes how lamp output changes with the fixture's tilt. We render fixtures as
+            // measured, so it is skipped.
+            let _lamp_to_luminaire_geometry = next_number("lamp to luminaire geometry")?;
+            let tilt_angle_count = next_number("tilt angle count")? as usize;
+            for _ in 0..(2 * tilt_angle_count) {
+                next_number("tilt angles and factors")?;
+            }
+        }
+
+        let _lamp_count = next_number("lamp count")?;
+        let _lumens_per_lamp = next_number("lumens per lamp")?;
+        let candela_multiplier = next_number("candela multiplier")?;
+        let vertical_angle_count = next_number("vertical angle count")? as usize;
+        let horizontal_angle_count = next_number("horizontal angle count")? as usize;
+        let photometric_type = next_number("photometric type")? as i32;
+        let _units_type = next_number("units type")?;
+        let _width = next_number("width")?;
+        let _length = next_number("length")?;
+        let _height = next_number("height")?;
+        let ballast_factor = next_number("ballast factor")?;
+        let _ballast_lamp_factor = next_number("ballast lamp photometric factor")?;
+        let _input_watts = next_number("input watts")?;
+
+        if photometric_type != 1 {
+            return Err(format!(
+                "Unsupported photometric type {}, only type C (1) is supported",
+                photometric_type
+            ));
+        }
+        if vertical_angle_count == 0 || horizontal_angle_count == 0 {
+            return Err("There must be at least one vertical and one horizontal angle".to_string());
+        }
+
+        let vertical_angles = (0..vertical_angle_count)
+            .map(|_| next_number("vertical angles"))
+            .collect::<Result<Vec<f64>, String>>()?;
+        let horizontal_angles = (0..horizontal_angle_count)
+            .map(|_| next_number("horizontal angles"))
+            .collect::<Result<Vec<f64>, String>>()?;
+        if !vertical_angles.windows(2).all(|pair| pair[0] < pair[1])
+            || !horizontal_angles.windows(2).all(|pair| pair[0] < pair[1])
+        {
+            return Err("Angles must be increasing".to_string());
+        }
+
+        let scale = candela_multiplier * ballast_factor;
+        let mut candela: Vec<Vec<f64>> = Vec::with_capacity(horizontal_angle_count);
+        for _ in 0..horizontal_angle_count {
+            let row = (0..vertical_angle_count)
+                .map(|_| next_number("candela values").map(|value| scale * value))
+                .collect::<Result<Vec<f64>, String>>()?;
+            candela.push(row);
+        }
+
+        let max_candela = candela
+            .iter()
+            .flatten()
+            .fold(0.0, |max, value| f64::max(max, *value));
+
+        Ok(Self {
+            vertical_angles,
+            horizontal_angles,
+            candela,
+            max_candela,
+        })
+    }
+
+    /// The largest intensity in the profile in candela
+    pub fn max_candela(&self) -> f64 {
+        self.max_candela
+    }
+
+    /// Get the intensity in candela at a vertical and horizontal angle in degrees, interpolating between the measured
+    /// angles. Horizontal angles that weren't measured are filled in using the symmetry implied by the measured range.
+    pub fn candela(&self, vertical_angle: f64, horizontal_angle: f64) -> f64 {
+        let first_vertical = self.vertical_angles[0];
+        let last_vertical = self.vertical_angles[self.vertical_angles.len() - 1];
+        if vertical_angle < first_vertical || vertical_angle > last_vertical {
+            // The fixture emits no light outside of the measured vertical range
+            return 0.0;
+        }
+
+        let horizontal_angle = self.fold_horizontal_angle(horizontal_angle);
+        let (h0, h1, th) = find_interval(&self.horizontal_angles, horizontal_angle);
+        let (v0, v1, tv) = find_interval(&self.vertical_angles, vertical_angle);
+
+        let lerp = |a: f64, b: f64, t: f64| (1.0 - t) * a + t * b;
+        lerp(
+            lerp(self.candela[h0][v0], self.candela[h0][v1], tv),
+            lerp(self.candela[h1][v0], self.candela[h1][v1], tv),
+            th,
+        )
+    }
+
+    /// Map a horizontal angle onto the range of measured angles
+    fn fold_horizontal_angle(&self, horizontal_angle: f64) -> f64 {
+        let angle = horizontal_angle.rem_euclid(360.0);
+        let first_horizontal = self.horizontal_angles[0];
+        let last_horizontal = self.horizontal_angles[self.horizontal_angles.len() - 1];
+
+        if self.horizontal_angles.len() == 1 {
+            // Symmetric around the vertical axis
+            self.horizontal_angles[0]
+        } else if last_horizontal == 90.0 {
+            // Symmetric in each quadrant
+            let angle = if angle > 180.0 { 360.0 - angle } else { angle };
+            if angle > 90.0 { 180.0 - angle } else { angle }
+        } else if last_horizontal == 180.0 {
+            // Symmetric about the 0 to 180 degree plane
+            if angle > 180.0 { 360.0 - angle } else { angle }
+        } else if first_horizontal == 90.0 && last_horizontal == 270.0 {
+            // Symmetric about the 90 to 270 degree plane
+            if (90.0..=270.0).contains(&angle) {
+                angle
+            } else {
+                (180.0 - angle).rem_euclid(360.0)
+            }
+        } else {
+            angle
+        }
+    }
+}
+
+/// Find the entries of increasing values that surround x, and how far x is between them in [0, 1]. x is clamped to the
+/// range of values.
+fn find_interval(values: &[f64], x: f64) -> (usize, usize, f64) {
+    if values.len() == 1 || x <= values[0] {
+        return (0, 0, 0.0);
+    }
+    if x >= values[values.len() - 1] {
+        return (values.len() - 1, values.len() - 1, 0.0);
+    }
+
+    let upper = values.partition_point(|value| *value <= x);
+    let lower = upper - 1;
+    let t = (x - values[lower]) / (values[upper] - values[lower]);
+
+    (lower, upper, t)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_path(name: &str) -> String {
+        format!("{}/ies/{}", env!("CARGO_MANIFEST_DIR"), name)
+    }
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < 1e-9,
+            "expected {}, got {}",
+            expected,
+            actual
+        );
+    }
+
+    #[test]
+    fn parses_axially_symmetric_profile() {
+        let profile = IesProfile::load(&sample_path("downlight.ies"));
+
+        assert_eq!(profile.vertical_angles.len(), 10);
+        assert_eq!(profile.horizontal_angles, vec![0.0]);
+        assert_close(profile.max_candela(), 1200.0);
+
+        // Every horizontal angle uses the single measured plane
+        assert_close(profile.candela(0.0, 0.0), 1200.0);
+        assert_close(profile.candela(0.0, 217.0), 1200.0);
+        assert_close(profile.candela(30.0, 90.0), 750.0);
+    }
+
+    #[test]
+    fn interpolates_between_vertical_angles() {
+        let profile = IesProfile::load(&sample_path("downlight.ies"));
+
+        assert_close(profile.candela(15.0, 0.0), 1075.0);
+        assert_close(profile.candela(42.5, 0.0), 0.75 * 450.0 + 0.25 * 200.0);
+    }
+
+    #[test]
+    fn no_light_outside_of_vertical_range() {
+        let profile = IesProfile::load(&sample_path("downlight.ies"));
+
+        assert_close(profile.candela(90.0, 0.0), 0.0);
+        assert_close(profile.candela(135.0, 0.0), 0.0);
+        assert_close(profile.candela(-5.0, 0.0), 0.0);
+    }
+
+    #[test]
+    fn skips_included_tilt_data_and_applies_multiplier() {
+        let profile = IesProfile::load(&sample_path("wallwasher.ies"));
+
+        assert_eq!(profile.vertical_angles, vec![0.0, 22.5, 45.0, 67.5, 90.0]);
+        assert_eq!(profile.horizontal_angles, vec![0.0, 90.0, 180.0]);
+
+        // The file's candela multiplier is 0.5
+        assert_close(profile.max_candela(), 150.0);
+        assert_close(profile.candela(45.0, 0.0), 150.0);
+        assert_close(profile.candela(45.0, 90.0), 100.0);
+        assert_close(profile.candela(45.0, 180.0), 50.0);
+    }
+
+    #[test]
+    fn bilateral_symmetry_mirrors_horizontal_angles() {
+        let profile = IesProfile::load(&sample_path("wallwasher.ies"));
+
+        assert_close(profile.candela(45.0, 45.0), 125.0);
+        assert_close(profile.candela(45.0, 315.0), 125.0);
+        assert_close(profile.candela(45.0, 270.0), 100.0);
+        assert_close(profile.candela(22.5, -45.0), 0.5 * (100.0 + 75.0));
+    }
+
+    #[test]
+    fn bilateral_symmetry_about_the_90_to_270_degree_plane() {
+        let profile = IesProfile::parse(
+            "TILT=NONE\n1 1000 1.0 2 3 1 2 0 0 0\n1 1 1\n0 45\n90 180 270\n100 100\n200 200\n300 300\n",
+        )
+        .unwrap();
+
+        assert_close(profile.candela(0.0, 90.0), 100.0);
+        assert_close(profile.candela(0.0, 225.0), 250.0);
+        assert_close(profile.candela(0.0, 0.0), 200.0);
+        assert_close(profile.candela(0.0, 45.0), 150.0);
+        assert_close(profile.candela(0.0, 315.0), 250.0);
+        assert_close(profile.candela(0.0, 300.0), 200.0 + 100.0 * 60.0 / 90.0);
+        assert_close(profile.candela(0.0, -30.0), 200.0 + 100.0 * 30.0 / 90.0);
+    }
+
+    #[test]
+    fn quadrant_symmetry_and_wrapped_values() {
+        let profile = IesProfile::load(&sample_path("troffer.ies"));
+
+        assert_eq!(profile.vertical_angles, vec![0.0, 30.0, 60.0, 90.0]);
+        assert_eq!(profile.horizontal_angles, vec![0.0, 45.0, 90.0]);
+
+        // The candela values wrap across lines in the file
+        assert_close(profile.candela(60.0, 90.0), 150.0);
+
+        assert_close(profile.candela(30.0, 135.0), 380.0);
+        assert_close(profile.candela(30.0, 225.0), 380.0);
+        assert_close(profile.candela(30.0, 315.0), 380.0);
+        assert_close(profile.candela(45.0, 0.0), 300.0);
+    }
+
+    #[test]
+    fn rejects_missing_tilt() {
+        let result =
+            IesProfile::parse("IESNA:LM-63-2002\n1 1000 1.0 1 1 1 2 0 0 0\n1 1 1\n0\n0\n100\n");
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn rejects_truncated_candela_values() {
+        let result =
+            IesProfile::parse("TILT=NONE\n1 1000 1.0 3 1 1 2 0 0 0\n1 1 1\n0 45 90\n0\n100 50\n");
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn rejects_unsupported_photometric_type() {
+        let result = IesProfile::parse("TILT=NONE\n1 1000 1.0 1 1 2 2 0 0 0\n1 1 1\n0\n0\n100\n");
+        assert!(result.is_err());
+    }
+}
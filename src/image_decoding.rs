@@ -0,0 +1,512 @@
+use std::fs;
+
+use zune_jpeg::{
+    JpegDecoder,
+    zune_core::{colorspace::ColorSpace, options::DecoderOptions},
+};
+
+/// Pixels decoded from an image file. Channels are interleaved and there can be 1 (gray), 2 (gray, alpha),
+/// 3 (red, green, blue), or 4 (red, green, blue, alpha) of them.
+pub struct DecodedImage {
+    pub width: usize,
+    pub height: usize,
+    pub channels: usize,
+    pub pixels: Vec<f32>, // Low dynamic range formats are normalized to [0.0, 1.0]
+    pub is_hdr: bool,     // High dynamic range formats already store linear values
+}
+
+// Images wider or taller than this are taken to be corrupt, as stb_image does
+const MAX_HDR_DIMENSION: usize = 1 << 24;
+
+/// Decode an image file based on its extension. Supports JPEG, PNG, and Radiance HDR files.
+pub fn decode_image(file_path: &str) -> DecodedImage {
+    let file_contents =
+        fs::read(file_path).unwrap_or_else(|_| panic!("Unable to read file path at {}", file_path));
+
+    let extension = file_path
+        .rsplit('.')
+        .next()
+        .unwrap_or("")
+        .to_ascii_lowercase();
+
+    let decoded = if extension == "png" {
+        decode_png(&file_contents, file_path)
+    } else if extension == "hdr" || extension == "pic" {
+        decode_hdr(&file_contents, file_path)
+    } else {
+        decode_jpeg(&file_contents, file_path)
+    };
+    decoded.unwrap_or_else(|error| panic!("{}", error))
+}
+
+fn decode_jpeg(file_contents: &[u8], file_path: &str) -> Result<DecodedImage, String> {
+    // Ask for RGB output so that YCbCr, YCCK, and CMYK images are converted for us
+    let options = DecoderOptions::default().jpeg_set_out_colorspace(ColorSpace::RGB);
+    let mut decoder = JpegDecoder::new_with_options(file_contents, options);
+    let bytes = decoder
+        .decode()
+        .map_err(|_| format!("Unable to decode file contents of {}", file_path))?;
+    let info = decoder
+        .info()
+        .ok_or_else(|| format!("Unable to get image info of {}", file_path))?;
+    let colorspace = decoder
+        .get_output_colorspace()
+        .ok_or_else(|| format!("Unable to get output colorspace of {}", file_path))?;
+
+    let width = info.width as usize;
+    let height = info.height as usize;
+    let color_scale = 1.0 / 255.0; // For normalizing values to [0.0, 1.0]
+
+    let (channels, pixels) = match colorspace {
+        ColorSpace::Luma | ColorSpace::LumaA | ColorSpace::RGB | ColorSpace::RGBA => {
+            let pixels = bytes
+                .iter()
+                .map(|byte| color_scale * *byte as f32)
+                .collect();
+            (colorspace.num_components(), pixels)
+        }
+        ColorSpace::BGR | ColorSpace::BGRA => {
+            let channels = colorspace.num_components();
+            let mut pixels: Vec<f32> = bytes
+                .iter()
+                .map(|byte| color_scale * *byte as f32)
+                .collect();
+            for pixel in pixels.chunks_exact_mut(channels) {
+                pixel.swap(0, 2);
+            }
+            (channels, pixels)
+        }
+        ColorSpace::CMYK => {
+            let mut pixels: Vec<f32> = Vec::with_capacity(3 * width * height);
+            for cmyk in bytes.chunks_exact(4) {
+                let black = 1.0 - color_scale * cmyk[3] as f32;
+                pixels.push((1.0 - color_scale * cmyk[0] as f32) * black);
+                pixels.push((1.0 - color_scale * cmyk[1] as f32) * black);
+                pixels.push((1.0 - color_scale * cmyk[2] as f32) * black);
+            }
+            (3, pixels)
+        }
+        _ => {
+            return Err(format!(
+                "Unsupported JPEG output colorspace {:?} in {}",
+                colorspace, file_path
+            ));
+        }
+    };
+
+    if pixels.len() != width * height * channels {
+        return Err(format!("Unexpected pixel count in {}", file_path));
+    }
+
+    Ok(DecodedImage {
+        width,
+        height,
+        channels,
+        pixels,
+        is_hdr: false,
+    })
+}
+
+fn decode_png(file_contents: &[u8], file_path: &str) -> Result<DecodedImage, String> {
+    let mut decoder = png::Decoder::new(file_contents);
+    // Expand palettes to RGB, bit depths below 8 to 8 bits, and transparency chunks to an alpha channel
+    decoder.set_transformations(png::Transformations::EXPAND);
+    let mut reader = decoder
+        .read_info()
+        .map_err(|_| format!("Unable to read PNG header of {}", file_path))?;
+    let mut bytes = vec![0; reader.output_buffer_size()];
+    let info = reader
+        .next_frame(&mut bytes)
+        .map_err(|_| format!("Unable to decode file contents of {}", file_path))?;
+
+    let width = info.width as usize;
+    let height = info.height as usize;
+    let channels = match info.color_type {
+        png::ColorType::Grayscale => 1,
+        png::ColorType::GrayscaleAlpha => 2,
+        png::ColorType::Rgb => 3,
+        png::ColorType::Rgba => 4,
+        png::ColorType::Indexed => {
+            return Err(format!("PNG palette was not expanded for {}", file_path));
+        }
+    };
+
+    let mut pixels: Vec<f32> = Vec::with_capacity(width * height * channels);
+    for row in bytes.chunks_exact(info.line_size).take(height) {
+        match info.bit_depth {
+            png::BitDepth::Sixteen => {
+                // 16-bit samples are stored big-endian
+                for sample in row.chunks_exact(2).take(width * channels) {
+                    pixels.push(u16::from_be_bytes([sample[0], sample[1]]) as f32 / 65535.0);
+                }
+            }
+            _ => {
+                for sample in row.iter().take(width * channels) {
+                    pixels.push(*sample as f32 / 255.0);
+                }
+            }
+        }
+    }
+
+    Ok(DecodedImage {
+        width,
+        height,
+        channels,
+        pixels,
+        is_hdr: false,
+    })
+}
+
+/// Decode a Radiance RGBE image. Supports flat and run-length encoded scanlines in the standard -Y +X orientation.
+fn decode_hdr(file_contents: &[u8], file_path: &str) -> Result<DecodedImage, String> {
+    // Read header lines until the blank line that separates it from the resolution string
+    let mut position = 0;
+    let read_line = |position: &mut usize| -> String {
+        let start = (*position).min(file_contents.len());
+        *position = start;
+        while *position < file_contents.len() && file_contents[*position] != b'\n' {
+            *position += 1;
+        }
+        let line = String::from_utf8_lossy(&file_contents[start..*position]).to_string();
+        *position += 1; // Skip the newline
+        line
+    };
+
+    let magic = read_line(&mut position);
+    if !magic.starts_with("#?") {
+        return Err(format!("{} is not a Radiance HDR file", file_path));
+    }
+    loop {
+        let line = read_line(&mut position);
+        if line.is_empty() {
+            break;
+        }
+        if line.starts_with("FORMAT=") && line != "FORMAT=32-bit_rle_rgbe" {
+            return Err(format!(
+                "Unsupported HDR pixel format {} in {}",
+                line, file_path
+            ));
+        }
+        if position >= file_contents.len() {
+            return Err(format!("HDR header of {} is truncated", file_path));
+        }
+    }
+
+    let resolution = read_line(&mut position);
+    let tokens: Vec<&str> = resolution.split_whitespace().collect();
+    if tokens.len() != 4 || tokens[0] != "-Y" || tokens[2] != "+X" {
+        return Err(format!(
+            "Unsupported HDR resolution string {} in {}",
+            resolution, file_path
+        ));
+    }
+    let height: usize = tokens[1]
+        .parse()
+        .map_err(|_| format!("Invalid HDR height in {}", file_path))?;
+    let width: usize = tokens[3]
+        .parse()
+        .map_err(|_| format!("Invalid HDR width in {}", file_path))?;
+    // Every scanline takes at least 4 bytes
+    if width == 0
+        || width > MAX_HDR_DIMENSION
+        || height > MAX_HDR_DIMENSION
+        || 4 * height > file_contents.len().saturating_sub(position)
+    {
+        return Err(format!(
+            "Invalid HDR resolution {}x{} in {}",
+            width, height, file_path
+        ));
+    }
+
+    let truncated = || format!("HDR pixel data of {} is truncated", file_path);
+    let mut next_byte = || -> Result<u8, String> {
+        let byte = *file_contents.get(position).ok_or_else(truncated)?;
+        position += 1;
+        Ok(byte)
+    };
+
+    let mut pixels: Vec<f32> = vec![];
+    let mut scanline: Vec<[u8; 4]> = vec![[0; 4]; width];
+    for _ in 0..height {
+        let header = [next_byte()?, next_byte()?, next_byte()?, next_byte()?];
+        let is_rle = (8..0x8000).contains(&width)
+            && header[0] == 2
+            && header[1] == 2
+            && (header[2] & 0x80) == 0;
+
+        if is_rle {
+            if ((header[2] as usize) << 8 | header[3] as usize) != width {
+                return Err(format!("HDR scanline width mismatch in {}", file_path));
+            }
+
+            // Each of the four components is run-length encoded separately
+            for component in 0..4 {
+                let mut x = 0;
+                while x < width {
+                    let count = next_byte()? as usize;
+                    let length = if count > 128 { count - 128 } else { count };
+                    if length == 0 || x + length > width {
+                        return Err(format!("Invalid HDR run length in {}", file_path));
+                    }
+                    if count > 128 {
+                        // A run of the same value
+                        let count = length;
+                        let value = next_byte()?;
+                        for pixel in scanline.iter_mut().skip(x).take(count) {
+                            pixel[component] = value;
+                        }
+                        x += count;
+                    } else {
+                        // A literal sequence of values
+                        for pixel in scanline.iter_mut().skip(x).take(count) {
+                            pixel[component] = next_byte()?;
+                        }
+                        x += count;
+                    }
+                }
+            }
+        } else {
+            // Flat pixels, possibly using the older run-length encoding that repeats the previous pixel
+            let mut x = 0;
+            let mut pixel = header;
+            let mut shift = 0;
+            loop {
+                if pixel[0] == 1 && pixel[1] == 1 && pixel[2] == 1 && x > 0 {
+                    // Each repeat in a row shifts its count up by another byte
+                    if shift > 16 {
+                        return Err(format!("Invalid HDR run length in {}", file_path));
+                    }
+                    let count = (pixel[3] as usize) << shift;
+                    let previous = scanline[x - 1];
+                    for repeated in scanline.iter_mut().skip(x).take(count) {
+                        *repeated = previous;
+                    }
+                    x += count;
+                    shift += 8;
+                } else {
+                    scanline[x] = pixel;
+                    x += 1;
+                    shift = 0;
+                }
+
+                if x >= width {
+                    break;
+                }
+                pixel = [next_byte()?, next_byte()?, next_byte()?, next_byte()?];
+            }
+        }
+
+        for rgbe in &scanline {
+            if rgbe[3] == 0 {
+                pixels.extend_from_slice(&[0.0, 0.0, 0.0]);
+            } else {
+                // The shared exponent is biased by 128, and the mantissas are 8-bit fractions
+                let scale = 2.0_f32.powi(rgbe[3] as i32 - (128 + 8));
+                pixels.push(rgbe[0] as f32 * scale);
+                pixels.push(rgbe[1] as f32 * scale);
+                pixels.push(rgbe[2] as f32 * scale);
+            }
+        }
+    }
+
+    Ok(DecodedImage {
+        width,
+        height,
+        channels: 3,
+        pixels,
+        is_hdr: true,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode_png(
+        width: u32,
+        height: u32,
+        color_type: png::ColorType,
+        bit_depth: png::BitDepth,
+        palette: Option<Vec<u8>>,
+        data: &[u8],
+    ) -> Vec<u8> {
+        let mut bytes = vec![];
+        let mut encoder = png::Encoder::new(&mut bytes, width, height);
+        encoder.set_color(color_type);
+        encoder.set_depth(bit_depth);
+        if let Some(palette) = palette {
+            encoder.set_palette(palette);
+        }
+        let mut writer = encoder.write_header().unwrap();
+        writer.write_image_data(data).unwrap();
+        writer.finish().unwrap();
+        bytes
+    }
+
+    fn assert_pixels(decoded: &DecodedImage, channels: usize, expected: &[f32]) {
+        assert_eq!(decoded.channels, channels);
+        assert_eq!(decoded.pixels.len(), expected.len());
+        for (pixel, expected) in decoded.pixels.iter().zip(expected) {
+            assert!((pixel - expected).abs() < 1e-6, "{:?}", decoded.pixels);
+        }
+    }
+
+    fn hdr_file(width: usize, height: usize, pixel_data: &[u8]) -> Vec<u8> {
+        let mut bytes = format!(
+            "#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y {} +X {}\n",
+            height, width
+        )
+        .into_bytes();
+        bytes.extend(pixel_data);
+        bytes
+    }
+
+    #[test]
+    fn png_gray_and_alpha_channels_are_kept() {
+        let gray = encode_png(
+            2,
+            1,
+            png::ColorType::Grayscale,
+            png::BitDepth::Eight,
+            None,
+            &[0, 255],
+        );
+        assert_pixels(&decode_png(&gray, "gray").unwrap(), 1, &[0.0, 1.0]);
+
+        let gray_alpha = encode_png(
+            2,
+            1,
+            png::ColorType::GrayscaleAlpha,
+            png::BitDepth::Eight,
+            None,
+            &[51, 255, 255, 0],
+        );
+        assert_pixels(
+            &decode_png(&gray_alpha, "gray alpha").unwrap(),
+            2,
+            &[0.2, 1.0, 1.0, 0.0],
+        );
+
+        let rgba = encode_png(
+            1,
+            1,
+            png::ColorType::Rgba,
+            png::BitDepth::Eight,
+            None,
+            &[255, 0, 51, 102],
+        );
+        assert_pixels(
+            &decode_png(&rgba, "rgba").unwrap(),
+            4,
+            &[1.0, 0.0, 0.2, 0.4],
+        );
+    }
+
+    #[test]
+    fn png_16_bit_and_palette_samples_are_expanded() {
+        // 16-bit samples are big endian
+        let sixteen_bit = encode_png(
+            1,
+            1,
+            png::ColorType::Rgb,
+            png::BitDepth::Sixteen,
+            None,
+            &[0xff, 0xff, 0x80, 0x00, 0x00, 0x00],
+        );
+        assert_pixels(
+            &decode_png(&sixteen_bit, "16-bit").unwrap(),
+            3,
+            &[1.0, 32768.0 / 65535.0, 0.0],
+        );
+
+        let palette = encode_png(
+            2,
+            1,
+            png::ColorType::Indexed,
+            png::BitDepth::Eight,
+            Some(vec![255, 0, 0, 0, 51, 255]),
+            &[1, 0],
+        );
+        assert_pixels(
+            &decode_png(&palette, "palette").unwrap(),
+            3,
+            &[0.0, 0.2, 1.0, 1.0, 0.0, 0.0],
+        );
+
+        // Low bit depths are scaled up to the full range
+        let one_bit = encode_png(
+            8,
+            1,
+            png::ColorType::Grayscale,
+            png::BitDepth::One,
+            None,
+            &[0b1000_0001],
+        );
+        assert_pixels(
+            &decode_png(&one_bit, "1-bit").unwrap(),
+            1,
+            &[1.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 1.0],
+        );
+    }
+
+    #[test]
+    fn hdr_flat_scanlines_are_decoded() {
+        // Exponent 129 scales mantissas by 2 / 256; the repeat copies the previous pixel twice
+        let flat = hdr_file(4, 1, &[128, 64, 0, 129, 0, 0, 0, 0, 1, 1, 1, 2]);
+        let decoded = decode_hdr(&flat, "flat").unwrap();
+        assert!(decoded.is_hdr);
+        assert_pixels(
+            &decoded,
+            3,
+            &[1.0, 0.5, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0],
+        );
+    }
+
+    #[test]
+    fn hdr_run_length_encoded_scanlines_are_decoded() {
+        // Each component is a run of 6, then the literals value and 0
+        let mut pixel_data = vec![2, 2, 0, 8];
+        for value in [128, 64, 0, 129] {
+            pixel_data.extend([128 + 6, value, 2, value, 0]);
+        }
+        let decoded = decode_hdr(&hdr_file(8, 1, &pixel_data), "rle").unwrap();
+        let mut expected = [1.0, 0.5, 0.0].repeat(7);
+        expected.extend([0.0; 3]);
+        assert_pixels(&decoded, 3, &expected);
+    }
+
+    #[test]
+    fn broken_files_are_errors() {
+        let mut pixel_data = vec![2, 2, 0, 8];
+        for value in [128, 64, 0, 129] {
+            pixel_data.extend([128 + 8, value]);
+        }
+        let hdr = hdr_file(8, 1, &pixel_data);
+        assert!(decode_hdr(&hdr, "whole").is_ok());
+        assert!(decode_hdr(&hdr[..hdr.len() - 3], "truncated").is_err());
+        assert!(decode_hdr(b"#?RADIANCE\n", "no header end").is_err());
+        assert!(decode_hdr(b"not an hdr", "no magic").is_err());
+
+        // A run length of zero or past the end of the scanline
+        for count in [0, 128 + 9] {
+            let mut pixel_data = vec![2, 2, 0, 8, count, 1];
+            pixel_data.extend([0; 16]);
+            assert!(decode_hdr(&hdr_file(8, 1, &pixel_data), "bad run").is_err());
+        }
+        assert!(decode_hdr(&hdr_file(1 << 30, 1, &[0; 4]), "too wide").is_err());
+
+        let png = encode_png(
+            4,
+            4,
+            png::ColorType::Rgb,
+            png::BitDepth::Eight,
+            None,
+            &[7; 48],
+        );
+        assert!(decode_png(&png, "whole").is_ok());
+        assert!(decode_png(&png[..png.len() - 20], "truncated").is_err());
+        assert!(decode_png(&png[8..], "no signature").is_err());
+
+        assert!(decode_jpeg(&[0xff, 0xd8, 0xff, 0xe0, 0, 16, 1, 2], "truncated").is_err());
+    }
+}
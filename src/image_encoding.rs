@@ -0,0 +1,169 @@
+use std::fs;
+
+/// A named channel of an image with one float per pixel, in row-major order from the top left
+pub struct ImageChannel {
+    pub name: String,
+    pub values: Vec<f32>,
+}
+
+/// Write an image in the Portable Float Map format. There must be 1 (gray) or 3 (red, green, blue) channels.
+pub fn write_pfm(file_path: &str, width: usize, height: usize, channels: &[ImageChannel]) {
+    let header = match channels.len() {
+        1 => "Pf",
+        3 => "PF",
+        _ => panic!(
+            "Unable to write {} channels to a PFM file, expected 1 or 3",
+            channels.len()
+        ),
+    };
+
+    // A negative scale means little endian
+    let mut contents = format!("{}\n{} {}\n-1.0\n", header, width, height).into_bytes();
+
+    // Rows are stored from the bottom up
+    for y in (0..height).rev() {
+        for x in 0..width {
+            for channel in channels {
+                contents.extend(channel.values[y * width + x].to_le_bytes());
+            }
+        }
+    }
+
+    fs::write(file_path, contents)
+        .unwrap_or_else(|_| panic!("Unable to write file path at {}", file_path));
+}
+
+/// Write an image in the OpenEXR format, uncompressed with one scanline per chunk. Every channel is stored as 32-bit
+/// floats. Channels named like "layer.R" are grouped into layers by readers.
+pub fn write_exr(file_path: &str, width: usize, height: usize, channels: &[ImageChannel]) {
+    fs::write(file_path, encode_exr(width, height, channels))
+        .unwrap_or_else(|_| panic!("Unable to write file path at {}", file_path));
+}
+
+fn encode_exr(width: usize, height: usize, channels: &[ImageChannel]) -> Vec<u8> {
+    // The channel list must be sorted by name, and so are the channels within each scanline
+    let mut channels: Vec<&ImageChannel> = channels.iter().collect();
+    channels.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let mut contents = vec![];
+    contents.extend(20000630_u32.to_le_bytes()); // Magic number
+    contents.extend(2_u32.to_le_bytes()); // Version 2, single part scanline image
+
+    let mut channel_list = vec![];
+    for channel in &channels {
+        channel_list.extend(channel.name.as_bytes());
+        channel_list.push(0);
+        channel_list.extend(2_i32.to_le_bytes()); // 32-bit float
+        channel_list.extend([0, 0, 0, 0]); // Not perceptually linear, then reserved bytes
+        channel_list.extend(1_i32.to_le_bytes()); // Sampled at every pixel horizontally
+        channel_list.extend(1_i32.to_le_bytes()); // and vertically
+    }
+    channel_list.push(0);
+
+    let mut window = vec![];
+    for value in [0, 0, width as i32 - 1, height as i32 - 1] {
+        window.extend(value.to_le_bytes());
+    }
+
+    let mut add_attribute = |name: &str, attribute_type: &str, value: &[u8]| {
+        contents.extend(name.as_bytes());
+        contents.push(0);
+        contents.extend(attribute_type.as_bytes());
+        contents.push(0);
+        contents.extend((value.len() as i32).to_le_bytes());
+        contents.extend(value);
+    };
+    add_attribute("channels", "chlist", &channel_list);
+    add_attribute("compression", "compression", &[0]); // No compression
+    add_attribute("dataWindow", "box2i", &window);
+    add_attribute("displayWindow", "box2i", &window);
+    add_attribute("lineOrder", "lineOrder", &[0]); // Increasing y
+    add_attribute("pixelAspectRatio", "float", &1.0_f32.to_le_bytes());
+    add_attribute("screenWindowCenter", "v2f", &[0; 8]);
+    add_attribute("screenWindowWidth", "float", &1.0_f32.to_le_bytes());
+    contents.push(0); // End of the header
+
+    // The offset table points at each scanline, which holds its y, its size, and then each channel's row in turn
+    let scanline_size = channels.len() * width * 4;
+    let first_scanline = contents.len() + height * 8;
+    for y in 0..height {
+        let offset = first_scanline + y * (8 + scanline_size);
+        contents.extend((offset as u64).to_le_bytes());
+    }
+    for y in 0..height {
+        contents.extend((y as i32).to_le_bytes());
+        contents.extend((scanline_size as i32).to_le_bytes());
+        for channel in &channels {
+            for x in 0..width {
+                contents.extend(channel.values[y * width + x].to_le_bytes());
+            }
+        }
+    }
+
+    contents
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn exr_scanlines_are_where_the_offset_table_says() {
+        let (width, height) = (3, 2);
+        let channels = [
+            ImageChannel {
+                name: "depth.Z".to_string(),
+                values: vec![0.0, 1.0, 2.0, 3.0, 4.0, 5.0],
+            },
+            ImageChannel {
+                name: "albedo.R".to_string(),
+                values: vec![10.0, 11.0, 12.0, 13.0, 14.0, 15.0],
+            },
+        ];
+        let contents = encode_exr(width, height, &channels);
+        assert_eq!(read_u32(&contents, 0), 20000630);
+
+        // The offset table comes right before the first scanline
+        let scanline_size = 8 + 2 * width * 4;
+        let first_scanline = contents.len() - height * scanline_size;
+        let table = first_scanline - height * 8;
+        for y in 0..height {
+            let offset = u64::from_le_bytes(
+                contents[table + 8 * y..table + 8 * y + 8]
+                    .try_into()
+                    .unwrap(),
+            ) as usize;
+            assert_eq!(read_u32(&contents, offset), y as u32);
+            assert_eq!(read_u32(&contents, offset + 4) as usize, 2 * width * 4);
+
+            // Channels are sorted by name, so albedo comes first
+            let first_value =
+                f32::from_le_bytes(contents[offset + 8..offset + 12].try_into().unwrap());
+            assert_eq!(first_value, 10.0 + (y * width) as f32);
+        }
+    }
+
+    #[test]
+    fn pfm_rows_go_from_the_bottom_up() {
+        let file_path = std::env::temp_dir().join("image_encoding_test.pfm");
+        let file_path = file_path.to_str().unwrap();
+        let channels = [ImageChannel {
+            name: "Y".to_string(),
+            values: vec![1.0, 2.0, 3.0, 4.0],
+        }];
+        write_pfm(file_path, 2, 2, &channels);
+
+        let contents = fs::read(file_path).unwrap();
+        let header = b"Pf\n2 2\n-1.0\n";
+        assert_eq!(&contents[..header.len()], header);
+        let values: Vec<f32> = contents[header.len()..]
+            .chunks_exact(4)
+            .map(|bytes| f32::from_le_bytes(bytes.try_into().unwrap()))
+            .collect();
+        assert_eq!(values, vec![3.0, 4.0, 1.0, 2.0]);
+    }
+}
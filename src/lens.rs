@@ -0,0 +1,468 @@
+use std::{f64::consts::PI, fs};
+
+use crate::{
+    background::{build_cdf, luminance, sample_cdf},
+    map::ImageData,
+    raytrace_vector::{refract, sample_unit_disk},
+    vector::{Vector2, Vector3},
+};
+
+/// The shape of the opening that light passes through on its way into the camera, which is the shape that out of
+/// focus highlights take. Apertures fit in the square from -1 to 1, with disks and polygons touching its edges.
+pub enum Aperture {
+    Disk,
+    Polygon(i32, f64), // The number of blades, and their rotation in degrees
+    Mask(ApertureMask),
+}
+
+/// An aperture shaped like an image, letting light through in proportion to each pixel's luminance
+pub struct ApertureMask {
+    width: usize,
+    height: usize,
+    values: Vec<f64>,           // The luminance of each pixel, in rows from the top
+    max_value: f64, // The luminance of the brightest pixel, which lets all of the light through
+    row_cdf: Vec<f64>, // height + 1 entries from 0.0 to 1.0
+    column_cdfs: Vec<Vec<f64>>, // One cdf of width + 1 entries for each row
+}
+
+impl ApertureMask {
+    pub fn new(image: &ImageData) -> Self {
+        let width = image.width();
+        let height = image.height();
+        assert!(width > 0 && height > 0, "The aperture mask image is empty");
+        let values: Vec<f64> = (0..height)
+            .flat_map(|j| (0..width).map(move |i| (i, j)))
+            .map(|(i, j)| f64::max(luminance(&image.get_pixel(0, i, j).0), 0.0))
+            .collect();
+
+        let mut column_cdfs = Vec::with_capacity(height);
+        let mut row_weights = Vec::with_capacity(height);
+        for row in values.chunks_exact(width) {
+            let (cdf, row_weight) = build_cdf(row);
+            column_cdfs.push(cdf);
+            row_weights.push(row_weight);
+        }
+        let (row_cdf, total_weight) = build_cdf(&row_weights);
+        assert!(
+            total_weight > 0.0,
+            "The aperture mask doesn't let any light through"
+        );
+
+        let max_value = values.iter().copied().fold(0.0, f64::max);
+
+        Self {
+            width,
+            height,
+            values,
+            max_value,
+            row_cdf,
+            column_cdfs,
+        }
+    }
+
+    /// The size of a pixel, which keeps the mask's aspect ratio with its longer side spanning from -1 to 1
+    fn pixel_size(&self) -> f64 {
+        2.0 / usize::max(self.width, self.height) as f64
+    }
+}
+
+/// Map two uniform values in [0, 1) to a point in an aperture in the xy plane. Points are spread evenly over disks and
+/// polygons, and in proportion to the luminance of masks.
+pub fn sample_aperture(aperture: &Aperture, u: &Vector2) -> Vector3 {
+    match aperture {
+        Aperture::Disk => sample_unit_disk(u),
+        Aperture::Polygon(blade_count, rotation) => {
+            // Pick one of the triangles between the center and the edges, then a point in it
+            let blade_count = *blade_count as f64;
+            let scaled = u.x * blade_count;
+            let triangle = f64::min(scaled.floor(), blade_count - 1.0);
+            let v = scaled - triangle;
+
+            let angle = |corner: f64| rotation.to_radians() + 2.0 * PI * corner / blade_count;
+            let (a, b) = (angle(triangle), angle(triangle + 1.0));
+            let radius = v.sqrt();
+            let t = u.y;
+            Vector3 {
+                x: radius * ((1.0 - t) * a.cos() + t * b.cos()),
+                y: radius * ((1.0 - t) * a.sin() + t * b.sin()),
+                z: 0.0,
+            }
+        }
+        Aperture::Mask(mask) => {
+            let (j, row_offset) = sample_cdf(&mask.row_cdf, u.y);
+            let (i, column_offset) = sample_cdf(&mask.column_cdfs[j], u.x);
+            let pixel_size = mask.pixel_size();
+            Vector3 {
+                x: (i as f64 + column_offset - 0.5 * mask.width as f64) * pixel_size,
+                y: (0.5 * mask.height as f64 - j as f64 - row_offset) * pixel_size,
+                z: 0.0,
+            }
+        }
+    }
+}
+
+/// The fraction of light that an aperture lets through at a point in the xy plane. This is 1 inside of disks and
+/// polygons and 0 outside of them, and the luminance of masks relative to their brightest pixel, so that tracing rays
+/// evenly and weighting them by it spreads light the same way as sample_aperture does.
+pub fn aperture_transmission(aperture: &Aperture, point: &Vector2) -> f64 {
+    let inside = |contains: bool| if contains { 1.0 } else { 0.0 };
+    match aperture {
+        Aperture::Disk => inside(point.x * point.x + point.y * point.y <= 1.0),
+        Aperture::Polygon(blade_count, rotation) => {
+            // Within the edge of the triangle that the point's angle falls in
+            let blade_count = *blade_count as f64;
+            let edge_angle = 2.0 * PI / blade_count;
+            let angle = (point.y.atan2(point.x) - rotation.to_radians()).rem_euclid(2.0 * PI);
+            let offset = angle % edge_angle - 0.5 * edge_angle;
+            let radius = (point.x * point.x + point.y * point.y).sqrt();
+            inside(radius * offset.cos() <= (0.5 * edge_angle).cos())
+        }
+        Aperture::Mask(mask) => {
+            let pixel_size = mask.pixel_size();
+            let i = (point.x / pixel_size + 0.5 * mask.width as f64).floor();
+            let j = (0.5 * mask.height as f64 - point.y / pixel_size).floor();
+            if i < 0.0 || j < 0.0 || i >= mask.width as f64 || j >= mask.height as f64 {
+                return 0.0;
+            }
+            mask.values[j as usize * mask.width + i as usize] / mask.max_value
+        }
+    }
+}
+
+/// One surface of a lens prescription, with every length in millimeters
+#[derive(Clone)]
+pub struct LensElement {
+    pub curvature_radius: f64, // Positive if its center is towards the film. 0 for the aperture stop.
+    pub thickness: f64,        // The distance along the axis to the next surface towards the film
+    pub ior: f64,              // The index of refraction up to the next surface, or 0 for air
+    pub aperture_radius: f64,  // Rays that reach the surface farther from the axis are blocked
+}
+
+/// A lens made of spherical elements, traced ray by ray so that its focus, distortion, and vignetting are all those of
+/// the real lens. The lens sits on the z axis with the film at z = 0 and the scene towards positive z, and every length
+/// is in millimeters.
+pub struct LensSystem {
+    elements: Vec<LensElement>, // From the front of the lens towards the film
+    film_diagonal: f64,
+}
+
+impl LensSystem {
+    /// Make a lens system from its elements, from the front of the lens towards the film. The last element's
+    /// thickness is the distance to the film, which is replaced when the lens is focused.
+    pub fn new(elements: Vec<LensElement>, film_diagonal: f64) -> Self {
+        Self {
+            elements,
+            film_diagonal,
+        }
+    }
+
+    /// Load a lens prescription. Panics if the file can't be read or parsed.
+    pub fn load(file_path: &str, film_diagonal: f64) -> Self {
+        let file_contents = fs::read(file_path)
+            .unwrap_or_else(|_| panic!("Unable to read file path at {}", file_path));
+
+        Self::parse(&String::from_utf8_lossy(&file_contents), film_diagonal)
+            .unwrap_or_else(|message| panic!("Unable to parse {}: {}", file_path, message))
+    }
+
+    /// Parse a lens prescription with a line for each surface, from the front of the lens towards the film. Each line
+    /// has the curvature radius, the thickness, the index of refraction, and the aperture's diameter. Lines starting
+    /// with # are comments.
+    pub fn parse(contents: &str, film_diagonal: f64) -> Result<Self, String> {
+        let mut elements = vec![];
+        for line in contents.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let values = line
+                .split_whitespace()
+                .map(|value| {
+                    value
+                        .parse::<f64>()
+                        .map_err(|_| format!("Invalid number {}", value))
+                })
+                .collect::<Result<Vec<f64>, String>>()?;
+            if values.len() != 4 {
+                return Err(format!("Expected 4 numbers on the line {}", line));
+            }
+            elements.push(LensElement {
+                curvature_radius: values[0],
+                thickness: values[1],
+                ior: values[2],
+                aperture_radius: values[3] / 2.0,
+            });
+        }
+
+        if elements.is_empty() {
+            return Err("No lens elements".to_string());
+        }
+        Ok(Self::new(elements, film_diagonal))
+    }
+
+    pub fn film_diagonal(&self) -> f64 {
+        self.film_diagonal
+    }
+
+    /// The distance from the film to a surface's vertex on the axis
+    fn get_surface_z(&self, index: usize) -> f64 {
+        self.elements[index..]
+            .iter()
+            .map(|element| element.thickness)
+            .sum()
+    }
+
+    /// The distance from the film to the back of the lens, and the radius of the back element
+    pub fn get_rear_element(&self) -> (f64, f64) {
+        let rear = self.elements.len() - 1;
+        (
+            self.get_surface_z(rear),
+            self.elements[rear].aperture_radius,
+        )
+    }
+
+    /// Follow a ray through the lens, from the film to the scene if it's heading towards positive z and the other way
+    /// otherwise. The aperture stop has the shape of aperture. Returns the ray leaving the lens and the fraction of light
+    /// that the aperture lets through along it, or None if the ray is blocked or totally internally reflected.
+    pub fn trace(
+        &self,
+        origin: Vector3,
+        direction: Vector3,
+        aperture: &Aperture,
+    ) -> Option<(Vector3, Vector3, f64)> {
+        let towards_scene = direction.z > 0.0;
+        let indices: Vec<usize> = if towards_scene {
+            (0..self.elements.len()).rev().collect()
+        } else {
+            (0..self.elements.len()).collect()
+        };
+        // Air between the elements has an index of refraction of 0 in prescriptions
+        let get_ior = |index: Option<usize>| match index.map(|index| self.elements[index].ior) {
+            Some(ior) if ior != 0.0 => ior,
+            _ => 1.0,
+        };
+
+        let mut origin = origin;
+        let mut direction = Vector3::calc_normalized_vector(&direction);
+        let mut transmission = 1.0;
+        for index in indices {
+            let element = &self.elements[index];
+            let surface_z = self.get_surface_z(index);
+
+            if element.curvature_radius == 0.0 {
+                let t = (surface_z - origin.z) / direction.z;
+                if t < 0.0 {
+                    return None;
+                }
+                origin = origin + t * direction;
+                let stop_point = Vector2 {
+                    x: origin.x / element.aperture_radius,
+                    y: origin.y / element.aperture_radius,
+                };
+                transmission = aperture_transmission(aperture, &stop_point);
+                if transmission <= 0.0 {
+                    return None;
+                }
+                continue;
+            }
+
+            // The center of curvature is at -curvature_radius from the vertex along z
+            let radius = element.curvature_radius;
+            let center = Vector3 {
+                x: 0.0,
+                y: 0.0,
+                z: surface_z - radius,
+            };
+            let offset = origin - center;
+            let b = Vector3::dot_product(&offset, &direction);
+            let c = offset.magnitude_squared() - radius * radius;
+            let discriminant = b * b - c;
+            if discriminant < 0.0 {
+                return None;
+            }
+            // The surface is the half of the sphere around the vertex
+            let (t0, t1) = (-b - discriminant.sqrt(), -b + discriminant.sqrt());
+            let t = if (direction.z < 0.0) != (radius < 0.0) {
+                t0
+            } else {
+                t1
+            };
+            if t < 0.0 {
+                return None;
+            }
+            origin = origin + t * direction;
+            if origin.x * origin.x + origin.y * origin.y
+                > element.aperture_radius * element.aperture_radius
+            {
+                return None;
+            }
+
+            // The medium on the film side of a surface is the element's own
+            let (ior_from, ior_to) = if towards_scene {
+                (get_ior(Some(index)), get_ior(index.checked_sub(1)))
+            } else {
+                (get_ior(index.checked_sub(1)), get_ior(Some(index)))
+            };
+            let mut normal = Vector3::calc_normalized_vector(&(origin - center));
+            if Vector3::dot_product(&normal, &direction) > 0.0 {
+                normal = -1.0 * normal;
+            }
+            let ratio = ior_from / ior_to;
+            let cos_theta = -Vector3::dot_product(&direction, &normal);
+            if ratio * ratio * (1.0 - cos_theta * cos_theta) > 1.0 {
+                return None;
+            }
+            direction = Vector3::calc_normalized_vector(&refract(&direction, &normal, ratio));
+        }
+
+        Some((origin, direction, transmission))
+    }
+
+    /// Move the film so that points at a distance from it are in focus. Panics if the lens can't focus there.
+    pub fn focus(&mut self, distance: f64) {
+        let front_radius = self.elements[0].aperture_radius;
+        for _ in 0..20 {
+            // Follow a ray from the point on the axis, close to the axis, to where it crosses the axis behind the lens
+            let front_z = self.get_surface_z(0);
+            if distance <= front_z {
+                panic!(
+                    "Unable to focus the lens at {} mm, which is inside of the lens",
+                    distance
+                );
+            }
+            let origin = Vector3 {
+                x: 0.0,
+                y: 0.0,
+                z: distance,
+            };
+            let direction = Vector3 {
+                x: 0.01 * front_radius,
+                y: 0.0,
+                z: front_z - distance,
+            };
+            let image_z = match self.trace(origin, direction, &Aperture::Disk) {
+                Some((exit_origin, exit_direction, _)) if exit_direction.x != 0.0 => {
+                    exit_origin.z - exit_origin.x / exit_direction.x * exit_direction.z
+                }
+                _ => panic!("Unable to focus the lens at {} mm", distance),
+            };
+
+            let rear = self.elements.len() - 1;
+            self.elements[rear].thickness -= image_z;
+            if self.elements[rear].thickness <= 0.0 {
+                panic!("Unable to focus the lens at {} mm", distance);
+            }
+            if image_z.abs() < 1e-9 {
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn polygon_samples_stay_inside_of_the_polygon() {
+        let aperture = Aperture::Polygon(5, 18.0);
+        for i in 0..32 {
+            for j in 0..32 {
+                let u = Vector2 {
+                    x: (i as f64 + 0.5) / 32.0,
+                    y: (j as f64 + 0.5) / 32.0,
+                };
+                let point = sample_aperture(&aperture, &u);
+                let point = Vector2 {
+                    x: 0.999 * point.x,
+                    y: 0.999 * point.y,
+                };
+                assert_eq!(aperture_transmission(&aperture, &point), 1.0);
+            }
+        }
+
+        // A corner is on the unit circle, but the middle of an edge is inside of it
+        let edge_middle = Vector2 {
+            x: 0.99 * (54.0_f64).to_radians().cos(),
+            y: 0.99 * (54.0_f64).to_radians().sin(),
+        };
+        assert_eq!(aperture_transmission(&aperture, &edge_middle), 0.0);
+    }
+
+    #[test]
+    fn mask_transmission_matches_how_often_it_is_sampled() {
+        // A white pixel beside a gray one, which the thin lens samples a third of the time
+        let image = ImageData::from_pixels(2, 1, 3, vec![1.0, 1.0, 1.0, 0.5, 0.5, 0.5]);
+        let aperture = Aperture::Mask(ApertureMask::new(&image));
+        let left = Vector2 { x: -0.5, y: 0.0 };
+        let right = Vector2 { x: 0.5, y: 0.0 };
+        assert!((aperture_transmission(&aperture, &left) - 1.0).abs() < 1e-9);
+        assert!((aperture_transmission(&aperture, &right) - 0.5).abs() < 1e-9);
+        assert_eq!(
+            aperture_transmission(&aperture, &Vector2 { x: 0.0, y: 0.9 }),
+            0.0
+        );
+
+        let sample_count = 1000;
+        let right_count = (0..sample_count)
+            .filter(|i| {
+                let u = Vector2 {
+                    x: (*i as f64 + 0.5) / sample_count as f64,
+                    y: 0.5,
+                };
+                sample_aperture(&aperture, &u).x > 0.0
+            })
+            .count();
+        assert!((right_count as f64 / sample_count as f64 - 1.0 / 3.0).abs() < 0.01);
+    }
+
+    #[test]
+    #[should_panic(expected = "The aperture mask image is empty")]
+    fn empty_mask_panics() {
+        ApertureMask::new(&ImageData::from_pixels(0, 0, 3, Vec::new()));
+    }
+
+    #[test]
+    fn focused_lens_images_a_point_onto_the_film() {
+        // A single thin glass lens with a focal length of about 103 mm, focused at 1 m from the film
+        let elements = vec![
+            LensElement {
+                curvature_radius: 103.0,
+                thickness: 2.0,
+                ior: 1.5,
+                aperture_radius: 10.0,
+            },
+            LensElement {
+                curvature_radius: -103.0,
+                thickness: 100.0,
+                ior: 0.0,
+                aperture_radius: 10.0,
+            },
+        ];
+        let mut lens = LensSystem::new(elements, 43.0);
+        lens.focus(1000.0);
+
+        // The lens ends up about 880 mm from the focus distance, so by the thin lens equation the film is about
+        // 1 / (1 / 103 - 1 / 880) = 117 mm behind it
+        let (rear_z, _) = lens.get_rear_element();
+        assert!((rear_z - 117.0).abs() < 1.5, "{}", rear_z);
+
+        // Rays from the film's center through different parts of the lens meet again at the focus distance
+        for height in [-2.0, 1.0, 3.0] {
+            let (origin, direction, _) = lens
+                .trace(
+                    Vector3::default(),
+                    Vector3 {
+                        x: height,
+                        y: 0.0,
+                        z: rear_z,
+                    },
+                    &Aperture::Disk,
+                )
+                .unwrap();
+            let t = (1000.0 - origin.z) / direction.z;
+            assert!((origin.x + t * direction.x).abs() < 0.5);
+        }
+    }
+}
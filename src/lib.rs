@@ -0,0 +1,2 @@
+/// Parts of the renderer that are useful on their own
+pub mod denoise;
@@ -0,0 +1,1177 @@
+use std::{collections::HashMap, f64::consts::PI, rc::Rc};
+
+use crate::{
+    aabb::Aabb,
+    background::luminance,
+    hit_record::HitRecord,
+    ies::IesProfile,
+    material::{Material, get_emitted},
+    quad::Quad,
+    ray::Ray,
+    raytrace_vector::orthonormal_basis,
+    vector::{Vector2, Vector3, calc_cross_product},
+};
+
+const ONE_MINUS_EPSILON: f64 = 1.0 - f64::EPSILON;
+
+/// Lights that are sampled explicitly. Point, spot, and directional lights are infinitely small or infinitely far
+/// away, so rays can never hit them and light sampling is the only way they contribute. Area lights are emissive
+/// geometry that scattered rays can also hit.
+pub enum Light {
+    Point(PointLight),
+    Spot(SpotLight),
+    Directional(DirectionalLight),
+    Area(AreaLight),
+}
+
+/// A measured angular distribution attached to a light, oriented in world space
+pub struct LightProfile {
+    ies: Rc<IesProfile>,
+    nadir: Vector3,        // Unit vector for the profile's 0 degree vertical angle
+    zero_azimuth: Vector3, // Unit vector perpendicular to the nadir for the profile's 0 degree horizontal angle
+}
+
+impl LightProfile {
+    /// Orient a profile so that its nadir points along the given direction
+    fn new(ies: Rc<IesProfile>, nadir: Vector3) -> Self {
+        let nadir = Vector3::calc_normalized_vector(&nadir);
+        let (zero_azimuth, _) = orthonormal_basis(&nadir);
+        Self {
+            ies,
+            nadir,
+            zero_azimuth,
+        }
+    }
+
+    /// The profile's intensity leaving the light along a unit direction, relative to its brightest direction
+    fn relative_intensity(&self, direction: &Vector3) -> f64 {
+        if self.ies.max_candela() <= 0.0 {
+            return 0.0;
+        }
+
+        let vertical_angle = Vector3::dot_product(direction, &self.nadir)
+            .clamp(-1.0, 1.0)
+            .acos()
+            .to_degrees();
+        let ninety_azimuth = calc_cross_product(&self.nadir, &self.zero_azimuth);
+        let horizontal_angle = Vector3::dot_product(direction, &ninety_azimuth)
+            .atan2(Vector3::dot_product(direction, &self.zero_azimuth))
+            .to_degrees();
+
+        self.ies.candela(vertical_angle, horizontal_angle) / self.ies.max_candela()
+    }
+}
+
+/// A light that shines from a point, equally in all directions unless it has a profile
+pub struct PointLight {
+    position: Vector3,
+    intensity: Vector3, // Radiant intensity, the color times the strength
+    profile: Option<LightProfile>,
+}
+
+impl PointLight {
+    pub fn new(position: Vector3, color: Vector3, strength: f64) -> Self {
+        Self {
+            position,
+            intensity: strength * color,
+            profile: None,
+        }
+    }
+
+    /// Create a point light whose intensity follows an IES profile with its nadir pointing along the given direction.
+    /// The strength is the intensity in the profile's brightest direction.
+    pub fn new_ies(
+        position: Vector3,
+        nadir: Vector3,
+        color: Vector3,
+        strength: f64,
+        profile: Rc<IesProfile>,
+    ) -> Self {
+        Self {
+            position,
+            intensity: strength * color,
+            profile: Some(LightProfile::new(profile, nadir)),
+        }
+    }
+}
+
+/// A point light that only shines within a cone. The intensity falls off smoothly from the inner angle to the outer
+/// angle.
+pub struct SpotLight {
+    position: Vector3,
+    direction: Vector3, // Unit vector along the axis of the cone
+    intensity: Vector3, // Radiant intensity inside of the inner cone, the color times the strength
+    cos_inner: f64,     // The cosine of the angle where the falloff starts
+    cos_outer: f64,     // The cosine of the angle where the light stops
+    profile: Option<LightProfile>,
+}
+
+impl SpotLight {
+    /// Create a spotlight at position pointing towards look_at. The angles are in degrees from the cone's axis.
+    pub fn new(
+        position: Vector3,
+        look_at: Vector3,
+        color: Vector3,
+        strength: f64,
+        inner_angle: f64,
+        outer_angle: f64,
+    ) -> Self {
+        assert!(inner_angle <= outer_angle);
+        Self {
+            position,
+            direction: Vector3::calc_normalized_vector(&(look_at - position)),
+            intensity: strength * color,
+            cos_inner: inner_angle.to_radians().cos(),
+            cos_outer: outer_angle.to_radians().cos(),
+            profile: None,
+        }
+    }
+
+    /// Create a spotlight whose intensity inside of its cone follows an IES profile. The profile's nadir points along
+    /// the cone's axis and the strength is the intensity in the profile's brightest direction.
+    pub fn new_ies(
+        position: Vector3,
+        look_at: Vector3,
+        color: Vector3,
+        strength: f64,
+        inner_angle: f64,
+        outer_angle: f64,
+        profile: Rc<IesProfile>,
+    ) -> Self {
+        let mut spot_light =
+            Self::new(position, look_at, color, strength, inner_angle, outer_angle);
+        spot_light.profile = Some(LightProfile::new(profile, spot_light.direction));
+        spot_light
+    }
+}
+
+/// A light infinitely far away, like the sun, whose rays all arrive from the same direction
+pub struct DirectionalLight {
+    direction: Vector3,  // Unit vector pointing from the scene towards the light
+    irradiance: Vector3, // Power per area arriving on a surface facing the light, the color times the strength
+}
+
+impl DirectionalLight {
+    /// Create a light shining along direction
+    pub fn new(direction: Vector3, color: Vector3, strength: f64) -> Self {
+        Self {
+            direction: -1.0 * Vector3::calc_normalized_vector(&direction),
+            irradiance: strength * color,
+        }
+    }
+}
+
+/// An emissive quad. The same quad must be added to the Hittables with an emissive material so that rays can hit it.
+pub struct AreaLight {
+    quad: Quad,
+    object: usize,           // Handle to the quad in the Hittables
+    area: f64,               // The area of the quad
+    luminance_estimate: f64, // The luminance emitted at the center of the quad, used to estimate power
+}
+
+impl AreaLight {
+    /// Create an area light for a quad that was added to the Hittables with the handle object
+    pub fn new(quad: Quad, object: usize, materials: &[Material]) -> Self {
+        let area = calc_cross_product(&quad.u, &quad.v).magnitude();
+
+        let center = quad.q + 0.5 * quad.u + 0.5 * quad.v;
+        let ray_to_center = Ray {
+            origin: center + quad.normal,
+            direction: -1.0 * quad.normal,
+            time: 0.0,
+        };
+        let record = HitRecord::new(&ray_to_center, quad.normal, 1.0, quad.material, 0.5, 0.5);
+        let luminance_estimate = luminance(&get_emitted(&materials[quad.material], &record));
+
+        Self {
+            quad,
+            object,
+            area,
+            luminance_estimate,
+        }
+    }
+}
+
+/// A light sampled from a point
+pub struct LightSample {
+    pub direction: Vector3, // Unit vector towards the light
+    pub distance: f64,      // The distance to the light, which is infinite for directional lights
+    pub incident: Vector3,  // The light arriving along direction
+    // The probability density in solid angle of sampling direction. None for point, spot, and directional lights
+    // since they only ever arrive from a single direction.
+    pub pdf: Option<f64>,
+}
+
+/// How Lights picks a light to sample at a shading point
+pub enum LightSampling {
+    Uniform, // Every light is equally likely
+    Power,   // Lights are picked in proportion to their power with an alias table
+    Bvh, // A bounding volume hierarchy over the lights estimates each light's contribution at the shading point
+}
+
+/// The bounds of one or more lights, used to estimate their importance at a shading point.
+/// This follows the light bounds in pbrt-v4's light BVH.
+#[derive(Clone)]
+struct LightBounds {
+    bounds: Aabb,
+    phi: f64,         // An estimate of the power emitted
+    axis: Vector3,    // The average direction of emission
+    cos_theta_o: f64, // The cosine of the spread of the normals around the axis
+    cos_theta_e: f64, // The cosine of the angle past theta_o that light is emitted
+}
+
+impl LightBounds {
+    fn from_boxes(a: &Self, b: &Self) -> Self {
+        if a.phi <= 0.0 {
+            return b.clone();
+        }
+        if b.phi <= 0.0 {
+            return a.clone();
+        }
+
+        let (axis, cos_theta_o) =
+            union_direction_cones(&a.axis, a.cos_theta_o, &b.axis, b.cos_theta_o);
+        Self {
+            bounds: Aabb::from_boxes(&a.bounds, &b.bounds),
+            phi: a.phi + b.phi,
+            axis,
+            cos_theta_o,
+            cos_theta_e: f64::min(a.cos_theta_e, b.cos_theta_e),
+        }
+    }
+
+    fn centroid(&self) -> Vector3 {
+        Vector3 {
+            x: 0.5 * (self.bounds.x0 + self.bounds.x1),
+            y: 0.5 * (self.bounds.y0 + self.bounds.y1),
+            z: 0.5 * (self.bounds.z0 + self.bounds.z1),
+        }
+    }
+
+    /// Estimate how much light from the bounds reaches a point with an optional surface normal. The estimate is
+    /// conservative in angle, so it never rules out light that could arrive.
+    fn importance(&self, point: &Vector3, normal: Option<&Vector3>) -> f64 {
+        if self.phi <= 0.0 {
+            return 0.0;
+        }
+
+        // Clamp the distance so points near or inside of the bounds don't blow up
+        let center = self.centroid();
+        let diagonal = Vector3 {
+            x: self.bounds.x1 - self.bounds.x0,
+            y: self.bounds.y1 - self.bounds.y0,
+            z: self.bounds.z1 - self.bounds.z0,
+        };
+        let to_point = *point - center;
+        let distance_squared = f64::max(to_point.magnitude_squared(), 0.5 * diagonal.magnitude());
+
+        // The angle between the emission axis and the direction to the point
+        let direction = if to_point.magnitude_squared() > 0.0 {
+            Vector3::calc_normalized_vector(&to_point)
+        } else {
+            self.axis
+        };
+        let cos_theta_w = Vector3::dot_product(&self.axis, &direction);
+        let sin_theta_w = safe_sqrt(1.0 - cos_theta_w * cos_theta_w);
+
+        // The angle the bounds subtend from the point
+        let radius_squared = 0.25 * diagonal.magnitude_squared();
+        let center_distance_squared = to_point.magnitude_squared();
+        let (sin_theta_b, cos_theta_b) = if center_distance_squared < radius_squared {
+            (0.0, -1.0)
+        } else {
+            let sin_squared = radius_squared / center_distance_squared;
+            (sin_squared.sqrt(), safe_sqrt(1.0 - sin_squared))
+        };
+
+        // The smallest possible angle between an emitting normal and the direction to the point
+        let sin_theta_o = safe_sqrt(1.0 - self.cos_theta_o * self.cos_theta_o);
+        let (sin_theta_x, cos_theta_x) =
+            sin_cos_subtract_clamped(sin_theta_w, cos_theta_w, sin_theta_o, self.cos_theta_o);
+        let (_, cos_theta_p) =
+            sin_cos_subtract_clamped(sin_theta_x, cos_theta_x, sin_theta_b, cos_theta_b);
+        if cos_theta_p <= self.cos_theta_e {
+            return 0.0;
+        }
+
+        let mut importance = self.phi * cos_theta_p / distance_squared;
+
+        // The smallest possible angle between the surface normal and the direction to the light
+        if let Some(normal) = normal {
+            let cos_theta_i = Vector3::dot_product(&direction, normal).abs();
+            let sin_theta_i = safe_sqrt(1.0 - cos_theta_i * cos_theta_i);
+            let (_, cos_theta_i) =
+                sin_cos_subtract_clamped(sin_theta_i, cos_theta_i, sin_theta_b, cos_theta_b);
+            importance *= cos_theta_i;
+        }
+
+        f64::max(importance, 0.0)
+    }
+}
+
+enum LightBvhNode {
+    Node(usize, usize, LightBounds), // left child, right child, bounds of both
+    Light(usize, LightBounds),       // handle to the light, bounds of the light
+}
+
+/// Vose's alias method for picking from a discrete distribution in constant time
+struct AliasTable {
+    probabilities: Vec<f64>, // The normalized probability of each entry
+    thresholds: Vec<f64>,    // The probability of keeping an entry rather than using its alias
+    aliases: Vec<usize>,
+}
+
+impl AliasTable {
+    fn new(weights: &[f64]) -> Self {
+        let count = weights.len();
+        let total: f64 = weights.iter().sum();
+        let probabilities: Vec<f64> = if total > 0.0 {
+            weights.iter().map(|weight| weight / total).collect()
+        } else {
+            vec![1.0 / count as f64; count]
+        };
+
+        // Split entries into those with less and more than the average probability, then pair each small entry with a
+        // large one that fills the rest of its bucket
+        let mut scaled: Vec<f64> = probabilities.iter().map(|p| p * count as f64).collect();
+        let mut small: Vec<usize> = vec![];
+        let mut large: Vec<usize> = vec![];
+        for (index, value) in scaled.iter().enumerate() {
+            if *value < 1.0 {
+                small.push(index);
+            } else {
+                large.push(index);
+            }
+        }
+
+        let mut thresholds = vec![1.0; count];
+        let mut aliases: Vec<usize> = (0..count).collect();
+        while let (Some(less), Some(more)) = (small.pop(), large.pop()) {
+            thresholds[less] = scaled[less];
+            aliases[less] = more;
+
+            scaled[more] = scaled[more] + scaled[less] - 1.0;
+            if scaled[more] < 1.0 {
+                small.push(more);
+            } else {
+                large.push(more);
+            }
+        }
+
+        Self {
+            probabilities,
+            thresholds,
+            aliases,
+        }
+    }
+
+    /// Pick an entry with a uniform value in [0, 1)
+    fn sample(&self, u: f64) -> usize {
+        let count = self.probabilities.len();
+        let x = u * count as f64;
+        let index = usize::min(x as usize, count - 1);
+        if x - (index as f64) < self.thresholds[index] {
+            index
+        } else {
+            self.aliases[index]
+        }
+    }
+}
+
+/// The collection of lights in a scene
+pub struct Lights {
+    lights: Vec<Light>,
+    sampling: LightSampling,
+    object_lights: HashMap<usize, usize>, // Hittables object handles of area lights to their light handles
+
+    // Acceleration structures for sampling, constructed just-in-time
+    is_prepared: bool,
+    infinite_lights: Vec<usize>, // Directional lights, which can't be bounded
+    finite_lights: Vec<usize>,
+    alias_table: Option<AliasTable>, // Over the finite lights
+    bvh_nodes: Vec<LightBvhNode>,
+    light_trails: HashMap<usize, Vec<bool>>, // The path from the root to each finite light, true for the right child
+}
+
+impl Lights {
+    pub fn new() -> Self {
+        Self {
+            lights: vec![],
+            sampling: LightSampling::Bvh,
+            object_lights: HashMap::new(),
+            is_prepared: false,
+            infinite_lights: vec![],
+            finite_lights: vec![],
+            alias_table: None,
+            bvh_nodes: vec![],
+            light_trails: HashMap::new(),
+        }
+    }
+
+    pub fn add_light(&mut self, light: Light) -> usize {
+        let handle = self.lights.len();
+        if let Light::Area(area_light) = &light {
+            self.object_lights.insert(area_light.object, handle);
+        }
+        self.lights.push(light);
+
+        // The sampling structures need to be reconstructed
+        self.is_prepared = false;
+
+        handle
+    }
+
+    pub fn set_light_sampling(&mut self, sampling: LightSampling) {
+        self.sampling = sampling;
+        self.is_prepared = false;
+    }
+
+    pub fn lights(&self) -> &[Light] {
+        &self.lights
+    }
+
+    /// Get the handle of the area light for an object in the Hittables, if it is one
+    pub fn get_object_light(&self, object: usize) -> Option<usize> {
+        self.object_lights.get(&object).copied()
+    }
+
+    /// Pick a light to sample for a shading point with an optional surface normal, using a uniform value in [0, 1).
+    /// Returns the light's handle and the probability of picking it.
+    pub fn sample_light_handle(
+        &mut self,
+        point: &Vector3,
+        normal: Option<&Vector3>,
+        u: f64,
+    ) -> Option<(usize, f64)> {
+        if self.lights.is_empty() {
+            return None;
+        }
+        self.prepare();
+
+        if let LightSampling::Uniform = self.sampling {
+            let handle = pick_index(u, self.lights.len());
+            return Some((handle, 1.0 / self.lights.len() as f64));
+        }
+
+        // Infinite lights can't be bounded, so they are picked uniformly against the finite lights as a whole.
+        // Each choice rescales u back to [0, 1) so that it can make the next choice.
+        let infinite_probability = self.infinite_probability();
+        if u < infinite_probability {
+            let index = pick_index(u / infinite_probability, self.infinite_lights.len());
+            return Some((
+                self.infinite_lights[index],
+                infinite_probability / self.infinite_lights.len() as f64,
+            ));
+        }
+        let finite_probability = 1.0 - infinite_probability;
+        let mut u = remap_above(u, infinite_probability);
+
+        match self.sampling {
+            LightSampling::Power => {
+                let alias_table = self.alias_table.as_ref()?;
+                let index = alias_table.sample(u);
+                Some((
+                    self.finite_lights[index],
+                    finite_probability * alias_table.probabilities[index],
+                ))
+            }
+            _ => {
+                // Walk down the tree, picking each child in proportion to its importance
+                let mut node_handle = 0;
+                let mut probability = finite_probability;
+                loop {
+                    match &self.bvh_nodes[node_handle] {
+                        LightBvhNode::Node(left, right, _) => {
+                            let left_importance = self.node_importance(*left, point, normal);
+                            let right_importance = self.node_importance(*right, point, normal);
+                            let total = left_importance + right_importance;
+                            if total <= 0.0 {
+                                return None;
+                            }
+
+                            let left_probability = left_importance / total;
+                            if u < left_probability {
+                                u = f64::min(u / left_probability, ONE_MINUS_EPSILON);
+                                probability *= left_probability;
+                                node_handle = *left;
+                            } else {
+                                u = remap_above(u, left_probability);
+                                probability *= 1.0 - left_probability;
+                                node_handle = *right;
+                            }
+                        }
+                        LightBvhNode::Light(handle, bounds) => {
+                            // A light at the root hasn't been checked against the point yet
+                            if node_handle == 0 && bounds.importance(point, normal) <= 0.0 {
+                                return None;
+                            }
+                            return Some((*handle, probability));
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// The probability of sample_light_handle picking a light for a shading point with an optional surface normal
+    pub fn light_handle_probability(
+        &mut self,
+        point: &Vector3,
+        normal: Option<&Vector3>,
+        light_handle: usize,
+    ) -> f64 {
+        if self.lights.is_empty() {
+            return 0.0;
+        }
+        self.prepare();
+
+        if let LightSampling::Uniform = self.sampling {
+            return 1.0 / self.lights.len() as f64;
+        }
+
+        let infinite_probability = self.infinite_probability();
+        if let Light::Directional(_) = self.lights[light_handle] {
+            return infinite_probability / self.infinite_lights.len() as f64;
+        }
+        let finite_probability = 1.0 - infinite_probability;
+
+        match self.sampling {
+            LightSampling::Power => {
+                let alias_table = match &self.alias_table {
+                    Some(alias_table) => alias_table,
+                    None => return 0.0,
+                };
+                match self
+                    .finite_lights
+                    .iter()
+                    .position(|handle| *handle == light_handle)
+                {
+                    Some(index) => finite_probability * alias_table.probabilities[index],
+                    None => 0.0,
+                }
+            }
+            _ => {
+                let trail = match self.light_trails.get(&light_handle) {
+                    Some(trail) => trail,
+                    None => return 0.0,
+                };
+
+                // Follow the light's path from the root, applying the same choices that sampling would make
+                let mut node_handle = 0;
+                let mut probability = finite_probability;
+                for goes_right in trail {
+                    match &self.bvh_nodes[node_handle] {
+                        LightBvhNode::Node(left, right, _) => {
+                            let left_importance = self.node_importance(*left, point, normal);
+                            let right_importance = self.node_importance(*right, point, normal);
+                            let total = left_importance + right_importance;
+                            if total <= 0.0 {
+                                return 0.0;
+                            }
+
+                            if *goes_right {
+                                probability *= right_importance / total;
+                                node_handle = *right;
+                            } else {
+                                probability *= left_importance / total;
+                                node_handle = *left;
+                            }
+                        }
+                        LightBvhNode::Light(_, _) => break,
+                    }
+                }
+
+                // A light at the root hasn't been checked against the point yet
+                if let LightBvhNode::Light(_, bounds) = &self.bvh_nodes[node_handle]
+                    && node_handle == 0
+                    && bounds.importance(point, normal) <= 0.0
+                {
+                    return 0.0;
+                }
+
+                probability
+            }
+        }
+    }
+
+    /// The probability of picking one of the infinite lights rather than one of the finite lights
+    fn infinite_probability(&self) -> f64 {
+        let finite_count = if self.finite_lights.is_empty() { 0 } else { 1 };
+        self.infinite_lights.len() as f64 / (self.infinite_lights.len() + finite_count) as f64
+    }
+
+    fn node_importance(
+        &self,
+        node_handle: usize,
+        point: &Vector3,
+        normal: Option<&Vector3>,
+    ) -> f64 {
+        match &self.bvh_nodes[node_handle] {
+            LightBvhNode::Node(_, _, bounds) => bounds.importance(point, normal),
+            LightBvhNode::Light(_, bounds) => bounds.importance(point, normal),
+        }
+    }
+
+    /// Construct the sampling structures if the lights changed since they were last constructed
+    fn prepare(&mut self) {
+        if self.is_prepared {
+            return;
+        }
+
+        self.infinite_lights.clear();
+        self.finite_lights.clear();
+        let mut finite_bounds: Vec<(usize, LightBounds)> = vec![];
+        for (handle, light) in self.lights.iter().enumerate() {
+            match get_light_bounds(light) {
+                Some(bounds) => {
+                    self.finite_lights.push(handle);
+                    finite_bounds.push((handle, bounds));
+                }
+                None => self.infinite_lights.push(handle),
+            }
+        }
+
+        self.alias_table = if finite_bounds.is_empty() {
+            None
+        } else {
+            let powers: Vec<f64> = finite_bounds.iter().map(|(_, bounds)| bounds.phi).collect();
+            Some(AliasTable::new(&powers))
+        };
+
+        self.bvh_nodes.clear();
+        self.light_trails.clear();
+        if !finite_bounds.is_empty() {
+            build_light_bvh(&mut self.bvh_nodes, &mut finite_bounds);
+
+            // Record the path to each light so that its probability can be found later
+            let mut stack: Vec<(usize, Vec<bool>)> = vec![(0, vec![])];
+            while let Some((node_handle, trail)) = stack.pop() {
+                match &self.bvh_nodes[node_handle] {
+                    LightBvhNode::Node(left, right, _) => {
+                        let mut left_trail = trail.clone();
+                        left_trail.push(false);
+                        let mut right_trail = trail;
+                        right_trail.push(true);
+                        stack.push((*left, left_trail));
+                        stack.push((*right, right_trail));
+                    }
+                    LightBvhNode::Light(handle, _) => {
+                        self.light_trails.insert(*handle, trail);
+                    }
+                }
+            }
+        }
+
+        self.is_prepared = true;
+    }
+}
+
+/// Recursively build a light BVH by splitting the lights in half along the longest axis of their centroids.
+/// Returns the handle of the subtree's root node.
+fn build_light_bvh(nodes: &mut Vec<LightBvhNode>, entries: &mut [(usize, LightBounds)]) -> usize {
+    let node_handle = nodes.len();
+    if entries.len() == 1 {
+        nodes.push(LightBvhNode::Light(entries[0].0, entries[0].1.clone()));
+        return node_handle;
+    }
+
+    // Reserve this node so that it stays the parent of its children
+    let mut bounds = entries[0].1.clone();
+    for (_, entry_bounds) in entries.iter().skip(1) {
+        bounds = LightBounds::from_boxes(&bounds, entry_bounds);
+    }
+    nodes.push(LightBvhNode::Light(0, bounds.clone()));
+
+    let centroids: Vec<Vector3> = entries
+        .iter()
+        .map(|(_, bounds)| bounds.centroid())
+        .collect();
+    let extent = |component: fn(&Vector3) -> f64| -> f64 {
+        let min = centroids
+            .iter()
+            .map(component)
+            .fold(f64::INFINITY, f64::min);
+        let max = centroids
+            .iter()
+            .map(component)
+            .fold(f64::NEG_INFINITY, f64::max);
+        max - min
+    };
+    let x_extent = extent(|v| v.x);
+    let y_extent = extent(|v| v.y);
+    let z_extent = extent(|v| v.z);
+    let axis: fn(&Vector3) -> f64 = if x_extent >= y_extent && x_extent >= z_extent {
+        |v| v.x
+    } else if y_extent >= z_extent {
+        |v| v.y
+    } else {
+        |v| v.z
+    };
+    entries.sort_by(|a, b| axis(&a.1.centroid()).total_cmp(&axis(&b.1.centroid())));
+
+    let middle = entries.len() / 2;
+    let (left_entries, right_entries) = entries.split_at_mut(middle);
+    let left = build_light_bvh(nodes, left_entries);
+    let right = build_light_bvh(nodes, right_entries);
+    nodes[node_handle] = LightBvhNode::Node(left, right, bounds);
+
+    node_handle
+}
+
+/// Get the bounds of a light for the light BVH. Returns None for lights that are infinitely far away.
+fn get_light_bounds(light: &Light) -> Option<LightBounds> {
+    match light {
+        Light::Point(point_light) => Some(LightBounds {
+            bounds: Aabb::new(point_light.position, point_light.position),
+            phi: 4.0 * PI * luminance(&point_light.intensity),
+            axis: Vector3 {
+                x: 0.0,
+                y: 0.0,
+                z: 1.0,
+            },
+            cos_theta_o: -1.0, // Emits in every direction
+            cos_theta_e: 0.0,
+        }),
+        Light::Spot(spot_light) => {
+            // Light spreads up to the inner angle at full strength and falls off until the outer angle
+            let theta_e = spot_light.cos_outer.acos() - spot_light.cos_inner.acos();
+            Some(LightBounds {
+                bounds: Aabb::new(spot_light.position, spot_light.position),
+                phi: 4.0 * PI * luminance(&spot_light.intensity),
+                axis: spot_light.direction,
+                cos_theta_o: spot_light.cos_inner,
+                cos_theta_e: theta_e.cos(),
+            })
+        }
+        Light::Directional(_) => None,
+        Light::Area(area_light) => Some(LightBounds {
+            bounds: area_light.quad.bounding_box.clone(),
+            phi: area_light.luminance_estimate * area_light.area,
+            axis: area_light.quad.normal,
+            cos_theta_o: 1.0, // Every point has the same normal
+            cos_theta_e: 0.0, // Emits over the hemisphere
+        }),
+    }
+}
+
+/// The smallest cone containing two cones of directions, each given by an axis and the cosine of its spread.
+/// Returns the axis and cosine of the spread of the combined cone.
+fn union_direction_cones(
+    axis_a: &Vector3,
+    cos_theta_a: f64,
+    axis_b: &Vector3,
+    cos_theta_b: f64,
+) -> (Vector3, f64) {
+    let theta_a = cos_theta_a.clamp(-1.0, 1.0).acos();
+    let theta_b = cos_theta_b.clamp(-1.0, 1.0).acos();
+    let theta_d = Vector3::dot_product(axis_a, axis_b).clamp(-1.0, 1.0).acos();
+
+    // One cone already contains the other
+    if f64::min(theta_d + theta_b, PI) <= theta_a {
+        return (*axis_a, cos_theta_a);
+    }
+    if f64::min(theta_d + theta_a, PI) <= theta_b {
+        return (*axis_b, cos_theta_b);
+    }
+
+    let theta_o = 0.5 * (theta_a + theta_d + theta_b);
+    if theta_o >= PI {
+        return (*axis_a, -1.0);
+    }
+
+    // Rotate axis a towards axis b so that the new cone just touches the far sides of both cones
+    let theta_r = theta_o - theta_a;
+    let rotation_axis = calc_cross_product(axis_a, axis_b);
+    if rotation_axis.magnitude_squared() <= 0.0 {
+        return (*axis_a, -1.0);
+    }
+    let rotation_axis = Vector3::calc_normalized_vector(&rotation_axis);
+    let axis = theta_r.cos() * *axis_a
+        + theta_r.sin() * calc_cross_product(&rotation_axis, axis_a)
+        + ((1.0 - theta_r.cos()) * Vector3::dot_product(&rotation_axis, axis_a)) * rotation_axis;
+
+    (axis, theta_o.cos())
+}
+
+/// Pick an index in [0, count) with a uniform value in [0, 1)
+fn pick_index(u: f64, count: usize) -> usize {
+    usize::min((u * count as f64) as usize, count - 1)
+}
+
+/// Rescale a uniform value in [threshold, 1) back to [0, 1)
+fn remap_above(u: f64, threshold: f64) -> f64 {
+    ((u - threshold) / (1.0 - threshold)).clamp(0.0, ONE_MINUS_EPSILON)
+}
+
+/// The sine and cosine of max(0, a - b) given the sines and cosines of a and b
+fn sin_cos_subtract_clamped(sin_a: f64, cos_a: f64, sin_b: f64, cos_b: f64) -> (f64, f64) {
+    if cos_a > cos_b {
+        return (0.0, 1.0);
+    }
+    (sin_a * cos_b - cos_a * sin_b, cos_a * cos_b + sin_a * sin_b)
+}
+
+fn safe_sqrt(x: f64) -> f64 {
+    f64::max(x, 0.0).sqrt()
+}
+
+/// Sample the light arriving at a point from a light, using two uniform values in [0, 1) to pick a point on area lights.
+///
+/// Returns None if the light doesn't reach the point. For point, spot, and directional lights there is only one
+/// direction, so the incident light is already integrated over solid angle and multiplies a material's scattering
+/// without a pdf. Area lights are sampled uniformly by area and include the pdf of the direction in solid angle.
+pub fn sample_light(
+    light: &Light,
+    point: &Vector3,
+    materials: &[Material],
+    u: &Vector2,
+) -> Option<LightSample> {
+    match light {
+        Light::Point(point_light) => {
+            let to_light = point_light.position - *point;
+            let distance_squared = to_light.magnitude_squared();
+            if distance_squared <= 0.0 {
+                return None;
+            }
+            let distance = distance_squared.sqrt();
+            let direction = (1.0 / distance) * to_light;
+
+            let profile_intensity = match &point_light.profile {
+                Some(profile) => profile.relative_intensity(&(-1.0 * direction)),
+                None => 1.0,
+            };
+            if profile_intensity <= 0.0 {
+                return None;
+            }
+
+            Some(LightSample {
+                direction,
+                distance,
+                incident: (profile_intensity / distance_squared) * point_light.intensity,
+                pdf: None,
+            })
+        }
+        Light::Spot(spot_light) => {
+            let to_light = spot_light.position - *point;
+            let distance_squared = to_light.magnitude_squared();
+            if distance_squared <= 0.0 {
+                return None;
+            }
+            let distance = distance_squared.sqrt();
+            let direction = (1.0 / distance) * to_light;
+
+            let cos_angle = -Vector3::dot_product(&direction, &spot_light.direction);
+            let falloff = smoothstep(spot_light.cos_outer, spot_light.cos_inner, cos_angle);
+            let falloff = match &spot_light.profile {
+                Some(profile) => falloff * profile.relative_intensity(&(-1.0 * direction)),
+                None => falloff,
+            };
+            if falloff <= 0.0 {
+                return None;
+            }
+
+            Some(LightSample {
+                direction,
+                distance,
+                incident: (falloff / distance_squared) * spot_light.intensity,
+                pdf: None,
+            })
+        }
+        Light::Directional(directional_light) => Some(LightSample {
+            direction: directional_light.direction,
+            distance: f64::INFINITY,
+            incident: directional_light.irradiance,
+            pdf: None,
+        }),
+        Light::Area(area_light) => {
+            let quad = &area_light.quad;
+            let (a, b) = (u.x, u.y);
+            let light_point = quad.q + a * quad.u + b * quad.v;
+
+            let pdf = area_light_pdf(light, point, &light_point);
+            if pdf <= 0.0 {
+                return None;
+            }
+
+            let to_light = light_point - *point;
+            let distance = to_light.magnitude();
+            let direction = (1.0 / distance) * to_light;
+
+            // Only the front face emits
+            let ray_to_light = Ray {
+                origin: *point,
+                direction,
+                time: 0.0,
+            };
+            let record = HitRecord::new(&ray_to_light, quad.normal, distance, quad.material, a, b);
+            let incident = get_emitted(&materials[quad.material], &record);
+
+            Some(LightSample {
+                direction,
+                distance,
+                incident,
+                pdf: Some(pdf),
+            })
+        }
+    }
+}
+
+/// The probability density in solid angle of sample_light picking a point on an area light, as seen from a point.
+/// Always 0.0 for the other lights since they can't be hit.
+pub fn area_light_pdf(light: &Light, point: &Vector3, light_point: &Vector3) -> f64 {
+    match light {
+        Light::Area(area_light) => {
+            let to_light = *light_point - *point;
+            let distance_squared = to_light.magnitude_squared();
+            if distance_squared <= 0.0 {
+                return 0.0;
+            }
+
+            // Light is only emitted towards the front
+            let cos_light =
+                -Vector3::dot_product(&area_light.quad.normal, &to_light) / distance_squared.sqrt();
+            if cos_light <= 0.0 {
+                return 0.0;
+            }
+
+            distance_squared / (cos_light * area_light.area)
+        }
+        _ => 0.0,
+    }
+}
+
+/// Smoothly goes from 0.0 at a to 1.0 at b
+fn smoothstep(a: f64, b: f64, x: f64) -> f64 {
+    if a == b {
+        return if x < a { 0.0 } else { 1.0 };
+    }
+
+    let t = ((x - a) / (b - a)).clamp(0.0, 1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::map::Map;
+
+    fn vector(x: f64, y: f64, z: f64) -> Vector3 {
+        Vector3 { x, y, z }
+    }
+
+    fn make_lights() -> Lights {
+        let white = vector(1.0, 1.0, 1.0);
+        let materials = vec![Material::Emissive(Map::Color(vector(4.0, 2.0, 1.0)))];
+        let quad = Quad::new(
+            vector(-0.5, 2.0, -0.5),
+            vector(1.0, 0.0, 0.0),
+            vector(0.0, 0.0, 1.0),
+            0,
+        );
+
+        let mut lights = Lights::new();
+        lights.add_light(Light::Point(PointLight::new(
+            vector(1.0, 1.0, 0.0),
+            white,
+            5.0,
+        )));
+        lights.add_light(Light::Point(PointLight::new(
+            vector(-3.0, 0.5, 2.0),
+            white,
+            20.0,
+        )));
+        lights.add_light(Light::Spot(SpotLight::new(
+            vector(0.0, 3.0, 3.0),
+            vector(0.0, 0.0, 0.0),
+            white,
+            10.0,
+            20.0,
+            30.0,
+        )));
+        lights.add_light(Light::Directional(DirectionalLight::new(
+            vector(0.2, -1.0, 0.1),
+            white,
+            2.0,
+        )));
+        lights.add_light(Light::Area(AreaLight::new(quad, 0, &materials)));
+        lights.add_light(Light::Point(PointLight::new(
+            vector(4.0, 0.2, -1.0),
+            white,
+            1.0,
+        )));
+        lights
+    }
+
+    #[test]
+    fn alias_table_frequencies_match_weights() {
+        let weights = [1.0, 0.0, 3.0, 6.0, 0.5, 2.5];
+        let total: f64 = weights.iter().sum();
+        let alias_table = AliasTable::new(&weights);
+
+        let sample_count = 100_000;
+        let mut counts = vec![0; weights.len()];
+        for i in 0..sample_count {
+            counts[alias_table.sample((i as f64 + 0.5) / sample_count as f64)] += 1;
+        }
+        for (index, weight) in weights.iter().enumerate() {
+            assert!((alias_table.probabilities[index] - weight / total).abs() < 1e-12);
+            let frequency = counts[index] as f64 / sample_count as f64;
+            assert!(
+                (frequency - weight / total).abs() < 1e-3,
+                "entry {} was picked {} of the time rather than {}",
+                index,
+                frequency,
+                weight / total
+            );
+        }
+    }
+
+    #[test]
+    fn sampled_light_probabilities_match_and_sum_to_one() {
+        let shading_points = [
+            (vector(0.0, 0.0, 0.0), Some(vector(0.0, 1.0, 0.0))),
+            (vector(2.0, 1.0, -3.0), Some(vector(-1.0, 0.0, 0.0))),
+            (vector(-1.0, 4.0, 1.0), None),
+        ];
+        for sampling in [
+            LightSampling::Uniform,
+            LightSampling::Power,
+            LightSampling::Bvh,
+        ] {
+            let mut lights = make_lights();
+            lights.set_light_sampling(sampling);
+            let light_count = lights.lights().len();
+
+            for (point, normal) in &shading_points {
+                let normal = normal.as_ref();
+                let probabilities: Vec<f64> = (0..light_count)
+                    .map(|handle| lights.light_handle_probability(point, normal, handle))
+                    .collect();
+                let total: f64 = probabilities.iter().sum();
+                assert!((total - 1.0).abs() < 1e-9, "{:?}", probabilities);
+
+                let sample_count = 20_000;
+                let mut counts = vec![0; light_count];
+                for i in 0..sample_count {
+                    let u = (i as f64 + 0.5) / sample_count as f64;
+                    let (handle, probability) =
+                        lights.sample_light_handle(point, normal, u).unwrap();
+                    assert!((probability - probabilities[handle]).abs() < 1e-12);
+                    counts[handle] += 1;
+                }
+                for (count, probability) in counts.iter().zip(&probabilities) {
+                    let frequency = *count as f64 / sample_count as f64;
+                    assert!((frequency - probability).abs() < 2e-3);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn light_bvh_holds_every_finite_light_once() {
+        let mut lights = make_lights();
+        lights.prepare();
+
+        // The directional light can't be bounded, so it stays out of the tree
+        assert_eq!(lights.infinite_lights, vec![3]);
+        assert_eq!(lights.finite_lights, vec![0, 1, 2, 4, 5]);
+        assert_eq!(lights.bvh_nodes.len(), 2 * lights.finite_lights.len() - 1);
+
+        let mut leaves: Vec<usize> = lights
+            .bvh_nodes
+            .iter()
+            .filter_map(|node| match node {
+                LightBvhNode::Light(handle, _) => Some(*handle),
+                LightBvhNode::Node(_, _, _) => None,
+            })
+            .collect();
+        leaves.sort();
+        assert_eq!(leaves, lights.finite_lights);
+
+        // Each trail leads from the root to its light
+        for (handle, trail) in &lights.light_trails {
+            let mut node_handle = 0;
+            for goes_right in trail {
+                node_handle = match &lights.bvh_nodes[node_handle] {
+                    LightBvhNode::Node(left, right, _) => {
+                        if *goes_right {
+                            *right
+                        } else {
+                            *left
+                        }
+                    }
+                    LightBvhNode::Light(_, _) => panic!("The trail to {} is too long", handle),
+                };
+            }
+            let leaf = match &lights.bvh_nodes[node_handle] {
+                LightBvhNode::Light(leaf, _) => *leaf,
+                LightBvhNode::Node(_, _, _) => panic!("The trail to {} is too short", handle),
+            };
+            assert_eq!(leaf, *handle);
+        }
+    }
+
+    #[test]
+    fn point_light_falls_off_with_the_square_of_the_distance() {
+        let position = vector(1.0, 2.0, 3.0);
+        let light = Light::Point(PointLight::new(position, vector(1.0, 0.5, 0.25), 8.0));
+        let offset = Vector3::calc_normalized_vector(&vector(0.3, -1.0, 0.2));
+        for distance in [0.5, 1.0, 2.0, 10.0] {
+            let point = position + distance * offset;
+            let sample = sample_light(&light, &point, &[], &Vector2::default()).unwrap();
+            assert!((sample.distance - distance).abs() < 1e-12);
+            assert!((sample.direction + offset).magnitude() < 1e-12);
+            let expected = (8.0 / (distance * distance)) * vector(1.0, 0.5, 0.25);
+            assert!((sample.incident - expected).magnitude() < 1e-12 * expected.magnitude());
+            assert!(sample.pdf.is_none());
+        }
+    }
+
+    #[test]
+    fn spot_light_is_full_inside_of_the_inner_angle_and_dark_outside_of_the_outer() {
+        let light = Light::Spot(SpotLight::new(
+            vector(0.0, 0.0, 0.0),
+            vector(0.0, -1.0, 0.0),
+            vector(1.0, 1.0, 1.0),
+            4.0,
+            20.0,
+            30.0,
+        ));
+        // Points 2 units away at angles from the axis, where the full intensity of 4 spreads over 2 * 2 square units
+        let falloff = |angle: f64| -> f64 {
+            let angle = angle.to_radians();
+            let point = vector(2.0 * angle.sin(), -2.0 * angle.cos(), 0.0);
+            sample_light(&light, &point, &[], &Vector2::default())
+                .map_or(0.0, |sample| sample.incident.y)
+        };
+        for angle in [0.0, 10.0, 19.9] {
+            assert!((falloff(angle) - 1.0).abs() < 1e-12, "{}", angle);
+        }
+        for angle in [30.1, 45.0, 90.0, 180.0] {
+            assert_eq!(falloff(angle), 0.0, "{}", angle);
+        }
+        let middle = falloff(25.0);
+        assert!(middle > 0.0 && middle < 1.0);
+        assert!(falloff(22.0) > middle && middle > falloff(28.0));
+    }
+
+    #[test]
+    fn directional_light_arrives_from_the_same_direction_everywhere() {
+        let light = Light::Directional(DirectionalLight::new(
+            vector(0.2, -1.0, 0.1),
+            vector(1.0, 1.0, 1.0),
+            3.0,
+        ));
+        let towards_light = Vector3::calc_normalized_vector(&vector(-0.2, 1.0, -0.1));
+        for point in [
+            vector(0.0, 0.0, 0.0),
+            vector(100.0, -5.0, 3.0),
+            vector(-2.0, 40.0, -70.0),
+        ] {
+            let sample = sample_light(&light, &point, &[], &Vector2::default()).unwrap();
+            assert!((sample.direction - towards_light).magnitude() < 1e-12);
+            assert_eq!(sample.distance, f64::INFINITY);
+            assert!((sample.incident - vector(3.0, 3.0, 3.0)).magnitude() < 1e-12);
+        }
+    }
+}
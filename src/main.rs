@@ -1,22 +1,51 @@
-use std::{env, rc::Rc};
+use std::{
+    collections::HashMap,
+    env,
+    net::TcpListener,
+    process::{Command, Stdio},
+    rc::Rc,
+    time::Duration,
+};
 
-use rand::{Rng, rngs::ThreadRng};
+use learn_raycasting::denoise::DenoiseSettings;
+use rand::{Rng, SeedableRng, rngs::SmallRng};
 
 use crate::{
-    camera::{Camera, render},
+    aov::{parse_aov, parse_render_pass},
+    background::{Background, EnvironmentMap, PhysicalSky},
+    camera::{Camera, Projection, StereoLayout, render, render_tile, write_ppm},
+    farm::{FarmJob, FarmTile, WorkerConnection, run_coordinator},
+    filter::Filter,
     hittables::{Hittable, Hittables},
-    map::{CheckerData, ImageData},
-    material::Material,
+    ies::IesProfile,
+    lens::{Aperture, ApertureMask, LensSystem},
+    light::{AreaLight, DirectionalLight, Light, LightSampling, Lights, PointLight, SpotLight},
+    map::{CheckerData, ColorSpace, FilterMode, ImageData, TextureSampler, WrapMode},
+    material::{Material, SubsurfaceData},
     perlin::Perlin,
     quad::Quad,
+    sampler::SamplePattern,
     sphere::Sphere,
+    tile::merge_tiles,
     vector::Vector3,
+    volume::{DensityField, Volume, VoxelGrid},
 };
 
 mod aabb;
+mod aov;
+mod background;
 mod camera;
+mod checkpoint;
+mod farm;
+mod film;
+mod filter;
 mod hit_record;
 mod hittables;
+mod ies;
+mod image_decoding;
+mod image_encoding;
+mod lens;
+mod light;
 mod map;
 mod material;
 mod math;
@@ -24,12 +53,31 @@ mod perlin;
 mod quad;
 mod ray;
 mod raytrace_vector;
+mod sampler;
 mod sphere;
+mod tile;
 mod vector;
+mod volume;
 
 // We use a right-handed coordinate system
 
-fn bouncing_spheres() -> (Camera, Vec<Material>, Hittables, i32) {
+/// The white to light blue sky that most scenes are lit by
+fn sky_gradient() -> Background {
+    Background::Gradient(
+        Vector3 {
+            x: 1.0,
+            y: 1.0,
+            z: 1.0,
+        },
+        Vector3 {
+            x: 0.5,
+            y: 0.7,
+            z: 1.0,
+        },
+    )
+}
+
+fn bouncing_spheres() -> (Camera, Vec<Material>, Hittables, Lights, Background, i32) {
     // Initialize camera
     let aspect_ratio = 16.0 / 9.0;
     let image_width = 400;
@@ -58,7 +106,8 @@ fn bouncing_spheres() -> (Camera, Vec<Material>, Hittables, i32) {
     );
     let max_depth = 50;
 
-    let mut world_rng = ThreadRng::default();
+    // A fixed seed keeps the scene the same between runs, which resuming a render relies on
+    let mut world_rng = SmallRng::seed_from_u64(0);
     let mut materials: Vec<Material> = vec![];
     let mut hittables = Hittables::new();
 
@@ -209,10 +258,17 @@ fn bouncing_spheres() -> (Camera, Vec<Material>, Hittables, i32) {
         )));
     }
 
-    (camera, materials, hittables, max_depth)
+    (
+        camera,
+        materials,
+        hittables,
+        Lights::new(),
+        sky_gradient(),
+        max_depth,
+    )
 }
 
-fn checkered_spheres() -> (Camera, Vec<Material>, Hittables, i32) {
+fn checkered_spheres() -> (Camera, Vec<Material>, Hittables, Lights, Background, i32) {
     // Initialize camera
     let aspect_ratio = 16.0 / 9.0;
     let image_width = 400;
@@ -279,10 +335,20 @@ fn checkered_spheres() -> (Camera, Vec<Material>, Hittables, i32) {
         checker,
     )));
 
-    (camera, materials, hittables, max_depth)
+    (
+        camera,
+        materials,
+        hittables,
+        Lights::new(),
+        sky_gradient(),
+        max_depth,
+    )
 }
 
-fn globe(file_path: &str) -> (Camera, Vec<Material>, Hittables, i32) {
+fn globe(
+    file_path: &str,
+    options: &HashMap<String, String>,
+) -> (Camera, Vec<Material>, Hittables, Lights, Background, i32) {
     // Initialize camera
     let aspect_ratio = 16.0 / 9.0;
     let image_width = 400;
@@ -316,9 +382,11 @@ fn globe(file_path: &str) -> (Camera, Vec<Material>, Hittables, i32) {
     let mut hittables = Hittables::new();
 
     let earth_texture = materials.len();
-    materials.push(Material::Diffuse(map::Map::Image(ImageData::new(
-        file_path,
-    ))));
+    // The earth wraps around in longitude, and mipmaps keep it from aliasing when it's far away
+    materials.push(Material::Diffuse(map::Map::Image(
+        ImageData::new(file_path, ColorSpace::Srgb),
+        get_texture_sampler(options, WrapMode::Repeat, FilterMode::Trilinear),
+    )));
     hittables.add_object(Hittable::Sphere(Sphere::new(
         Vector3 {
             x: 0.0,
@@ -329,10 +397,17 @@ fn globe(file_path: &str) -> (Camera, Vec<Material>, Hittables, i32) {
         earth_texture,
     )));
 
-    (camera, materials, hittables, max_depth)
+    (
+        camera,
+        materials,
+        hittables,
+        Lights::new(),
+        sky_gradient(),
+        max_depth,
+    )
 }
 
-fn perlin_spheres() -> (Camera, Vec<Material>, Hittables, i32) {
+fn perlin_spheres() -> (Camera, Vec<Material>, Hittables, Lights, Background, i32) {
     // Initialize camera
     let camera = Camera::new(
         Vector3 {
@@ -364,7 +439,7 @@ fn perlin_spheres() -> (Camera, Vec<Material>, Hittables, i32) {
     let mut hittables = Hittables::new();
 
     let pertext = materials.len();
-    materials.push(Material::Diffuse(map::Map::Noise(Perlin::new(), 4.0)));
+    materials.push(Material::Diffuse(map::Map::Noise(Perlin::new(1), 4.0)));
 
     hittables.add_object(Hittable::Sphere(Sphere::new(
         Vector3 {
@@ -385,10 +460,17 @@ fn perlin_spheres() -> (Camera, Vec<Material>, Hittables, i32) {
         pertext,
     )));
 
-    (camera, materials, hittables, max_depth)
+    (
+        camera,
+        materials,
+        hittables,
+        Lights::new(),
+        sky_gradient(),
+        max_depth,
+    )
 }
 
-fn quads() -> (Camera, Vec<Material>, Hittables, i32) {
+fn quads() -> (Camera, Vec<Material>, Hittables, Lights, Background, i32) {
     // Initialize camera
     let camera = Camera::new(
         Vector3 {
@@ -548,32 +630,1863 @@ fn quads() -> (Camera, Vec<Material>, Hittables, i32) {
         lower_teal,
     )));
 
-    (camera, materials, hittables, max_depth)
+    (
+        camera,
+        materials,
+        hittables,
+        Lights::new(),
+        sky_gradient(),
+        max_depth,
+    )
 }
 
-fn main() {
-    let args: Vec<String> = env::args().collect();
+fn clouds(
+    voxel_grid_path: Option<&String>,
+) -> (Camera, Vec<Material>, Hittables, Lights, Background, i32) {
+    // Initialize camera
+    let camera = Camera::new(
+        Vector3 {
+            x: 0.0,
+            y: 2.0,
+            z: 14.0,
+        },
+        Vector3 {
+            x: 0.0,
+            y: 2.0,
+            z: 0.0,
+        },
+        Vector3 {
+            x: 0.0,
+            y: 1.0,
+            z: 0.0,
+        },
+        0.0,
+        10.0,
+        16.0 / 9.0,
+        400,
+        40.0,
+        100,
+    );
+    let max_depth = 50;
 
-    let scene: i32 = if args.len() == 1 {
-        0
-    } else {
-        args[1].parse().expect("Unable to parse scene arg")
+    let mut materials: Vec<Material> = vec![];
+    let mut hittables = Hittables::new();
+
+    let ground = materials.len();
+    materials.push(Material::Diffuse(map::Map::Color(Vector3 {
+        x: 0.4,
+        y: 0.5,
+        z: 0.3,
+    })));
+    hittables.add_object(Hittable::Sphere(Sphere::new(
+        Vector3 {
+            x: 0.0,
+            y: -1000.0,
+            z: 0.0,
+        },
+        1000.0,
+        ground,
+    )));
+
+    // Clouds scatter mostly forward
+    let cloud = materials.len();
+    materials.push(Material::HenyeyGreenstein(
+        map::Map::Color(Vector3 {
+            x: 0.95,
+            y: 0.95,
+            z: 0.95,
+        }),
+        0.6,
+    ));
+
+    // Use a loaded voxel grid if we have one, otherwise build a puffy cloud from noise with a spherical falloff
+    let cloud_grid = match voxel_grid_path {
+        Some(path) => VoxelGrid::load(path),
+        None => {
+            let noise = Perlin::new(2);
+            let resolution = 48;
+            let mut densities: Vec<f64> = Vec::with_capacity(resolution * resolution * resolution);
+            for k in 0..resolution {
+                for j in 0..resolution {
+                    for i in 0..resolution {
+                        // Position of the voxel center in [-1, 1]^3
+                        let p = Vector3 {
+                            x: 2.0 * ((i as f64) + 0.5) / (resolution as f64) - 1.0,
+                            y: 2.0 * ((j as f64) + 0.5) / (resolution as f64) - 1.0,
+                            z: 2.0 * ((k as f64) + 0.5) / (resolution as f64) - 1.0,
+                        };
+                        let falloff = f64::max(1.0 - p.magnitude(), 0.0);
+                        let density = 8.0 * falloff * (0.5 + noise.turbulence(&(3.0 * p), 5));
+                        densities.push(density);
+                    }
+                }
+            }
+            VoxelGrid::new(resolution, resolution, resolution, densities)
+        }
     };
+    hittables.add_object(Hittable::Volume(Volume::new(
+        Vector3 {
+            x: -5.5,
+            y: 1.0,
+            z: -2.0,
+        },
+        Vector3 {
+            x: -0.5,
+            y: 6.0,
+            z: 3.0,
+        },
+        Rc::new(DensityField::Grid(cloud_grid)),
+        cloud,
+    )));
 
-    // Generate scene
-    let (mut camera, materials, mut hittables, max_depth) = if scene == 0 {
-        bouncing_spheres()
-    } else if scene == 1 {
-        checkered_spheres()
-    } else if scene == 2 {
-        let earth_image_path = &args[2];
-        globe(earth_image_path)
-    } else if scene == 3 {
-        perlin_spheres()
-    } else {
-        quads()
+    // Smoke is closer to isotropic and absorbs more light
+    let smoke = materials.len();
+    materials.push(Material::HenyeyGreenstein(
+        map::Map::Color(Vector3 {
+            x: 0.3,
+            y: 0.3,
+            z: 0.3,
+        }),
+        0.1,
+    ));
+    hittables.add_object(Hittable::Volume(Volume::new(
+        Vector3 {
+            x: 1.0,
+            y: 0.0,
+            z: -1.0,
+        },
+        Vector3 {
+            x: 4.0,
+            y: 5.0,
+            z: 2.0,
+        },
+        Rc::new(DensityField::Turbulence(Perlin::new(3), 1.5, 7, 3.0)),
+        smoke,
+    )));
+
+    // A thin layer of ground fog
+    let fog = materials.len();
+    materials.push(Material::HenyeyGreenstein(
+        map::Map::Color(Vector3 {
+            x: 0.9,
+            y: 0.9,
+            z: 0.9,
+        }),
+        0.0,
+    ));
+    hittables.add_object(Hittable::Volume(Volume::new(
+        Vector3 {
+            x: -12.0,
+            y: 0.0,
+            z: -6.0,
+        },
+        Vector3 {
+            x: 12.0,
+            y: 0.6,
+            z: 6.0,
+        },
+        Rc::new(DensityField::Noise(Perlin::new(4), 2.0, 0.4)),
+        fog,
+    )));
+
+    (
+        camera,
+        materials,
+        hittables,
+        Lights::new(),
+        sky_gradient(),
+        max_depth,
+    )
+}
+
+fn subsurface_spheres() -> (Camera, Vec<Material>, Hittables, Lights, Background, i32) {
+    // Initialize camera
+    let camera = Camera::new(
+        Vector3 {
+            x: 0.0,
+            y: 3.0,
+            z: 12.0,
+        },
+        Vector3 {
+            x: 0.0,
+            y: 1.0,
+            z: 0.0,
+        },
+        Vector3 {
+            x: 0.0,
+            y: 1.0,
+            z: 0.0,
+        },
+        0.0,
+        10.0,
+        16.0 / 9.0,
+        400,
+        30.0,
+        100,
+    );
+    let max_depth = 50;
+
+    let mut materials: Vec<Material> = vec![];
+    let mut hittables = Hittables::new();
+
+    let ground = materials.len();
+    materials.push(Material::Diffuse(map::Map::Checker(CheckerData::new(
+        1.0,
+        Rc::new(map::Map::Color(Vector3 {
+            x: 0.2,
+            y: 0.2,
+            z: 0.2,
+        })),
+        Rc::new(map::Map::Color(Vector3 {
+            x: 0.8,
+            y: 0.8,
+            z: 0.8,
+        })),
+    ))));
+    hittables.add_object(Hittable::Sphere(Sphere::new(
+        Vector3 {
+            x: 0.0,
+            y: -1000.0,
+            z: 0.0,
+        },
+        1000.0,
+        ground,
+    )));
+
+    // Each entry is the sphere's x position, albedo, and mean free path.
+    // Red light travels further than blue light in skin, which gives it a warm glow.
+    let subsurface_materials = [
+        (
+            // Skin
+            -4.5,
+            Vector3 {
+                x: 0.99,
+                y: 0.9,
+                z: 0.8,
+            },
+            Vector3 {
+                x: 0.35,
+                y: 0.12,
+                z: 0.06,
+            },
+        ),
+        (
+            // Wax
+            -1.5,
+            Vector3 {
+                x: 0.99,
+                y: 0.95,
+                z: 0.7,
+            },
+            Vector3 {
+                x: 0.3,
+                y: 0.3,
+                z: 0.2,
+            },
+        ),
+        (
+            // Marble
+            1.5,
+            Vector3 {
+                x: 0.999,
+                y: 0.999,
+                z: 0.995,
+            },
+            Vector3 {
+                x: 0.08,
+                y: 0.08,
+                z: 0.07,
+            },
+        ),
+        (
+            // Milk
+            4.5,
+            Vector3 {
+                x: 0.999,
+                y: 0.999,
+                z: 0.998,
+            },
+            Vector3 {
+                x: 0.03,
+                y: 0.03,
+                z: 0.025,
+            },
+        ),
+    ];
+
+    for (x, albedo, mean_free_path) in subsurface_materials {
+        let material = materials.len();
+        materials.push(Material::Subsurface(Box::new(SubsurfaceData::new(
+            map::Map::Color(albedo),
+            map::Map::Color(mean_free_path),
+            1.4,
+        ))));
+        hittables.add_object(Hittable::Sphere(Sphere::new(
+            Vector3 { x, y: 1.2, z: 0.0 },
+            1.2,
+            material,
+        )));
+    }
+
+    (
+        camera,
+        materials,
+        hittables,
+        Lights::new(),
+        sky_gradient(),
+        max_depth,
+    )
+}
+
+fn bump_mapping(
+    normal_map_path: Option<&String>,
+    options: &HashMap<String, String>,
+) -> (Camera, Vec<Material>, Hittables, Lights, Background, i32) {
+    // Initialize camera
+    let camera = Camera::new(
+        Vector3 {
+            x: 0.0,
+            y: 4.0,
+            z: 10.0,
+        },
+        Vector3 {
+            x: 0.0,
+            y: 1.0,
+            z: 0.0,
+        },
+        Vector3 {
+            x: 0.0,
+            y: 1.0,
+            z: 0.0,
+        },
+        0.0,
+        10.0,
+        16.0 / 9.0,
+        400,
+        40.0,
+        100,
+    );
+    let max_depth = 50;
+
+    let mut materials: Vec<Material> = vec![];
+    let mut hittables = Hittables::new();
+
+    // Use a loaded tangent space normal map for the floor if we have one. Otherwise make tiles that tilt in
+    // alternating directions.
+    let floor_normals = match normal_map_path {
+        Some(path) => map::Map::Image(
+            ImageData::new(path, ColorSpace::Linear),
+            get_texture_sampler(options, WrapMode::Repeat, FilterMode::Bilinear),
+        ),
+        None => map::Map::Checker(CheckerData::new(
+            1.0,
+            Rc::new(map::Map::Color(Vector3 {
+                x: 0.8,
+                y: 0.5,
+                z: 0.95,
+            })),
+            Rc::new(map::Map::Color(Vector3 {
+                x: 0.5,
+                y: 0.8,
+                z: 0.95,
+            })),
+        )),
     };
+    let floor = materials.len();
+    materials.push(Material::NormalMap(
+        Box::new(Material::Diffuse(map::Map::Color(Vector3 {
+            x: 0.6,
+            y: 0.6,
+            z: 0.6,
+        }))),
+        floor_normals,
+    ));
+    hittables.add_object(Hittable::Quad(Quad::new(
+        Vector3 {
+            x: -8.0,
+            y: 0.0,
+            z: 6.0,
+        },
+        Vector3 {
+            x: 16.0,
+            y: 0.0,
+            z: 0.0,
+        },
+        Vector3 {
+            x: 0.0,
+            y: 0.0,
+            z: -12.0,
+        },
+        floor,
+    )));
 
-    // Render
-    render(&mut camera, &mut hittables, &materials, max_depth);
+    let bumpy_diffuse = materials.len();
+    materials.push(Material::Bump(
+        Box::new(Material::Diffuse(map::Map::Color(Vector3 {
+            x: 0.8,
+            y: 0.3,
+            z: 0.2,
+        }))),
+        map::Map::Noise(Perlin::new(5), 4.0),
+        0.1,
+    ));
+    hittables.add_object(Hittable::Sphere(Sphere::new(
+        Vector3 {
+            x: -2.2,
+            y: 1.0,
+            z: 0.0,
+        },
+        1.0,
+        bumpy_diffuse,
+    )));
+
+    let hammered_metal = materials.len();
+    materials.push(Material::Bump(
+        Box::new(Material::Metal(
+            Vector3 {
+                x: 0.8,
+                y: 0.8,
+                z: 0.8,
+            },
+            0.0,
+        )),
+        map::Map::Noise(Perlin::new(6), 10.0),
+        0.02,
+    ));
+    hittables.add_object(Hittable::Sphere(Sphere::new(
+        Vector3 {
+            x: 0.0,
+            y: 1.0,
+            z: 0.0,
+        },
+        1.0,
+        hammered_metal,
+    )));
+
+    let rippled_glass = materials.len();
+    materials.push(Material::Bump(
+        Box::new(Material::Dielectric(1.5)),
+        map::Map::Noise(Perlin::new(7), 2.0),
+        0.05,
+    ));
+    hittables.add_object(Hittable::Sphere(Sphere::new(
+        Vector3 {
+            x: 2.2,
+            y: 1.0,
+            z: 0.0,
+        },
+        1.0,
+        rippled_glass,
+    )));
+
+    (
+        camera,
+        materials,
+        hittables,
+        Lights::new(),
+        sky_gradient(),
+        max_depth,
+    )
+}
+
+fn cutouts(
+    mask_path: Option<&String>,
+    options: &HashMap<String, String>,
+) -> (Camera, Vec<Material>, Hittables, Lights, Background, i32) {
+    // Initialize camera
+    let camera = Camera::new(
+        Vector3 {
+            x: 0.0,
+            y: 2.0,
+            z: 10.0,
+        },
+        Vector3 {
+            x: 0.0,
+            y: 1.5,
+            z: 0.0,
+        },
+        Vector3 {
+            x: 0.0,
+            y: 1.0,
+            z: 0.0,
+        },
+        0.0,
+        10.0,
+        16.0 / 9.0,
+        400,
+        40.0,
+        100,
+    );
+    let max_depth = 50;
+
+    let mut materials: Vec<Material> = vec![];
+    let mut hittables = Hittables::new();
+
+    let ground = materials.len();
+    materials.push(Material::Diffuse(map::Map::Color(Vector3 {
+        x: 0.5,
+        y: 0.45,
+        z: 0.35,
+    })));
+    hittables.add_object(Hittable::Sphere(Sphere::new(
+        Vector3 {
+            x: 0.0,
+            y: -1000.0,
+            z: 0.0,
+        },
+        1000.0,
+        ground,
+    )));
+
+    let ball = materials.len();
+    materials.push(Material::Diffuse(map::Map::Color(Vector3 {
+        x: 0.8,
+        y: 0.2,
+        z: 0.2,
+    })));
+    hittables.add_object(Hittable::Sphere(Sphere::new(
+        Vector3 {
+            x: 0.0,
+            y: 1.0,
+            z: -3.0,
+        },
+        1.0,
+        ball,
+    )));
+
+    // A chain-link style fence made from a checker pattern of opaque and transparent squares
+    let fence = materials.len();
+    materials.push(Material::Cutout(
+        Box::new(Material::Metal(
+            Vector3 {
+                x: 0.6,
+                y: 0.6,
+                z: 0.6,
+            },
+            0.3,
+        )),
+        map::Map::Checker(CheckerData::new(
+            0.1,
+            Rc::new(map::Map::Color(Vector3 {
+                x: 1.0,
+                y: 1.0,
+                z: 1.0,
+            })),
+            Rc::new(map::Map::Color(Vector3 {
+                x: 0.0,
+                y: 0.0,
+                z: 0.0,
+            })),
+        )),
+    ));
+    hittables.add_object(Hittable::Quad(Quad::new(
+        Vector3 {
+            x: -4.5,
+            y: 0.0,
+            z: -1.05,
+        },
+        Vector3 {
+            x: 3.5,
+            y: 0.0,
+            z: 0.0,
+        },
+        Vector3 {
+            x: 0.0,
+            y: 2.5,
+            z: 0.0,
+        },
+        fence,
+    )));
+
+    // Leaf cards use a loaded mask if we have one, otherwise the holes come from noise
+    let leaf_mask = match mask_path {
+        Some(path) => map::Map::Image(
+            ImageData::new(path, ColorSpace::Linear),
+            get_texture_sampler(options, WrapMode::Clamp, FilterMode::Bilinear),
+        ),
+        None => map::Map::Noise(Perlin::new(8), 6.0),
+    };
+    let leaf = materials.len();
+    materials.push(Material::Cutout(
+        Box::new(Material::Diffuse(map::Map::Color(Vector3 {
+            x: 0.2,
+            y: 0.6,
+            z: 0.15,
+        }))),
+        leaf_mask,
+    ));
+    hittables.add_object(Hittable::Quad(Quad::new(
+        Vector3 {
+            x: 1.0,
+            y: 0.5,
+            z: -0.5,
+        },
+        Vector3 {
+            x: 2.5,
+            y: 0.0,
+            z: -1.0,
+        },
+        Vector3 {
+            x: 0.0,
+            y: 2.5,
+            z: 0.0,
+        },
+        leaf,
+    )));
+
+    // A sheer curtain that lets half of the light through
+    let curtain = materials.len();
+    materials.push(Material::Cutout(
+        Box::new(Material::Diffuse(map::Map::Color(Vector3 {
+            x: 0.9,
+            y: 0.9,
+            z: 0.9,
+        }))),
+        map::Map::Color(Vector3 {
+            x: 0.5,
+            y: 0.5,
+            z: 0.5,
+        }),
+    ));
+    hittables.add_object(Hittable::Quad(Quad::new(
+        Vector3 {
+            x: -1.2,
+            y: 0.0,
+            z: -1.5,
+        },
+        Vector3 {
+            x: 2.4,
+            y: 0.0,
+            z: 0.0,
+        },
+        Vector3 {
+            x: 0.0,
+            y: 3.0,
+            z: 0.0,
+        },
+        curtain,
+    )));
+
+    (
+        camera,
+        materials,
+        hittables,
+        Lights::new(),
+        sky_gradient(),
+        max_depth,
+    )
+}
+
+fn environment_lighting(
+    environment_path: Option<&String>,
+) -> (Camera, Vec<Material>, Hittables, Lights, Background, i32) {
+    // Initialize camera
+    let aspect_ratio = 16.0 / 9.0;
+    let image_width = 400;
+    let camera = Camera::new(
+        Vector3 {
+            x: 0.0,
+            y: 2.0,
+            z: 9.0,
+        },
+        Vector3 {
+            x: 0.0,
+            y: 0.8,
+            z: 0.0,
+        },
+        Vector3 {
+            x: 0.0,
+            y: 1.0,
+            z: 0.0,
+        },
+        0.0,
+        9.0,
+        aspect_ratio,
+        image_width,
+        30.0,
+        64,
+    );
+    let max_depth = 50;
+
+    // Initialize world
+    let mut materials: Vec<Material> = vec![];
+    let mut hittables = Hittables::new();
+
+    let ground = materials.len();
+    materials.push(Material::Diffuse(map::Map::Color(Vector3 {
+        x: 0.5,
+        y: 0.5,
+        z: 0.5,
+    })));
+    hittables.add_object(Hittable::Quad(Quad::new(
+        Vector3 {
+            x: -20.0,
+            y: 0.0,
+            z: 20.0,
+        },
+        Vector3 {
+            x: 40.0,
+            y: 0.0,
+            z: 0.0,
+        },
+        Vector3 {
+            x: 0.0,
+            y: 0.0,
+            z: -40.0,
+        },
+        ground,
+    )));
+
+    let diffuse = materials.len();
+    materials.push(Material::Diffuse(map::Map::Color(Vector3 {
+        x: 0.8,
+        y: 0.3,
+        z: 0.2,
+    })));
+    let metal = materials.len();
+    materials.push(Material::Metal(
+        Vector3 {
+            x: 0.9,
+            y: 0.9,
+            z: 0.9,
+        },
+        0.05,
+    ));
+    let glass = materials.len();
+    materials.push(Material::Dielectric(1.5));
+
+    for (x, material) in [(-2.2, diffuse), (0.0, metal), (2.2, glass)] {
+        hittables.add_object(Hittable::Sphere(Sphere::new(
+            Vector3 { x, y: 1.0, z: 0.0 },
+            1.0,
+            material,
+        )));
+    }
+
+    // Use a loaded equirectangular image if we have one, otherwise a simple sky with a small, bright sun. The sun is
+    // where importance sampling pays off, since scattered rays would rarely find it on their own.
+    let image = match environment_path {
+        Some(path) => ImageData::new(path, ColorSpace::Srgb),
+        None => {
+            let width = 256;
+            let height = 128;
+            let mut pixels: Vec<f32> = Vec::with_capacity(3 * width * height);
+            for j in 0..height {
+                for i in 0..width {
+                    let sun_distance = ((i as f32 - 0.62 * width as f32).powi(2)
+                        + (j as f32 - 0.3 * height as f32).powi(2))
+                    .sqrt();
+                    let color = if sun_distance < 2.0 {
+                        [400.0, 360.0, 300.0]
+                    } else if j < height / 2 {
+                        let t = j as f32 / (height / 2) as f32;
+                        [0.2 + 0.3 * t, 0.35 + 0.3 * t, 0.7 + 0.2 * t]
+                    } else {
+                        [0.15, 0.12, 0.1]
+                    };
+                    pixels.extend_from_slice(&color);
+                }
+            }
+            ImageData::from_pixels(width, height, 3, pixels)
+        }
+    };
+    let background = Background::Environment(EnvironmentMap::new(image, 0.0, 1.0));
+
+    (
+        camera,
+        materials,
+        hittables,
+        Lights::new(),
+        background,
+        max_depth,
+    )
+}
+
+fn daylight(
+    sun_elevation: Option<&String>,
+) -> (Camera, Vec<Material>, Hittables, Lights, Background, i32) {
+    // Initialize camera
+    let aspect_ratio = 16.0 / 9.0;
+    let image_width = 400;
+    let camera = Camera::new(
+        Vector3 {
+            x: 0.0,
+            y: 1.5,
+            z: 9.0,
+        },
+        Vector3 {
+            x: 0.0,
+            y: 1.5,
+            z: 0.0,
+        },
+        Vector3 {
+            x: 0.0,
+            y: 1.0,
+            z: 0.0,
+        },
+        0.0,
+        9.0,
+        aspect_ratio,
+        image_width,
+        40.0,
+        64,
+    );
+    let max_depth = 50;
+
+    // Initialize world
+    let mut materials: Vec<Material> = vec![];
+    let mut hittables = Hittables::new();
+
+    let ground = materials.len();
+    materials.push(Material::Diffuse(map::Map::Color(Vector3 {
+        x: 0.4,
+        y: 0.4,
+        z: 0.35,
+    })));
+    hittables.add_object(Hittable::Quad(Quad::new(
+        Vector3 {
+            x: -10.0,
+            y: 0.0,
+            z: 10.0,
+        },
+        Vector3 {
+            x: 20.0,
+            y: 0.0,
+            z: 0.0,
+        },
+        Vector3 {
+            x: 0.0,
+            y: 0.0,
+            z: -20.0,
+        },
+        ground,
+    )));
+
+    // A wall to cast a long shadow when the sun is low
+    let wall = materials.len();
+    materials.push(Material::Diffuse(map::Map::Color(Vector3 {
+        x: 0.7,
+        y: 0.7,
+        z: 0.7,
+    })));
+    hittables.add_object(Hittable::Quad(Quad::new(
+        Vector3 {
+            x: -4.0,
+            y: 0.0,
+            z: -3.0,
+        },
+        Vector3 {
+            x: 8.0,
+            y: 0.0,
+            z: 0.0,
+        },
+        Vector3 {
+            x: 0.0,
+            y: 3.0,
+            z: 0.0,
+        },
+        wall,
+    )));
+
+    let white = materials.len();
+    materials.push(Material::Diffuse(map::Map::Color(Vector3 {
+        x: 0.8,
+        y: 0.8,
+        z: 0.8,
+    })));
+    let metal = materials.len();
+    materials.push(Material::Metal(
+        Vector3 {
+            x: 0.8,
+            y: 0.6,
+            z: 0.3,
+        },
+        0.1,
+    ));
+    for (x, material) in [(-1.5, white), (1.5, metal)] {
+        hittables.add_object(Hittable::Sphere(Sphere::new(
+            Vector3 { x, y: 1.0, z: 0.0 },
+            1.0,
+            material,
+        )));
+    }
+
+    // The sun sits behind and to the right of the camera's view at the given elevation in degrees
+    let elevation: f64 = match sun_elevation {
+        Some(elevation) => elevation.parse().expect("Unable to parse sun elevation"),
+        None => 35.0,
+    };
+    let elevation = elevation.to_radians();
+    let azimuth = 40.0_f64.to_radians();
+    let sun_direction = Vector3 {
+        x: elevation.cos() * azimuth.sin(),
+        y: elevation.sin(),
+        z: elevation.cos() * azimuth.cos(),
+    };
+    let background = Background::Sky(PhysicalSky::new(
+        sun_direction,
+        3.0,
+        Vector3 {
+            x: 0.3,
+            y: 0.3,
+            z: 0.3,
+        },
+    ));
+
+    (
+        camera,
+        materials,
+        hittables,
+        Lights::new(),
+        background,
+        max_depth,
+    )
+}
+
+fn studio_lights(
+    ies_path: Option<&String>,
+) -> (Camera, Vec<Material>, Hittables, Lights, Background, i32) {
+    // Initialize camera
+    let aspect_ratio = 16.0 / 9.0;
+    let image_width = 400;
+    let camera = Camera::new(
+        Vector3 {
+            x: 0.0,
+            y: 2.5,
+            z: 9.0,
+        },
+        Vector3 {
+            x: 0.0,
+            y: 1.0,
+            z: 0.0,
+        },
+        Vector3 {
+            x: 0.0,
+            y: 1.0,
+            z: 0.0,
+        },
+        0.0,
+        9.0,
+        aspect_ratio,
+        image_width,
+        35.0,
+        64,
+    );
+    let max_depth = 50;
+
+    // Initialize world
+    let mut materials: Vec<Material> = vec![];
+    let mut hittables = Hittables::new();
+    let mut lights = Lights::new();
+
+    // A floor and a backdrop
+    let backdrop = materials.len();
+    materials.push(Material::Diffuse(map::Map::Color(Vector3 {
+        x: 0.6,
+        y: 0.6,
+        z: 0.6,
+    })));
+    hittables.add_object(Hittable::Quad(Quad::new(
+        Vector3 {
+            x: -8.0,
+            y: 0.0,
+            z: 6.0,
+        },
+        Vector3 {
+            x: 16.0,
+            y: 0.0,
+            z: 0.0,
+        },
+        Vector3 {
+            x: 0.0,
+            y: 0.0,
+            z: -10.0,
+        },
+        backdrop,
+    )));
+    hittables.add_object(Hittable::Quad(Quad::new(
+        Vector3 {
+            x: -8.0,
+            y: 0.0,
+            z: -4.0,
+        },
+        Vector3 {
+            x: 16.0,
+            y: 0.0,
+            z: 0.0,
+        },
+        Vector3 {
+            x: 0.0,
+            y: 8.0,
+            z: 0.0,
+        },
+        backdrop,
+    )));
+
+    let red = materials.len();
+    materials.push(Material::Diffuse(map::Map::Color(Vector3 {
+        x: 0.7,
+        y: 0.15,
+        z: 0.1,
+    })));
+    let metal = materials.len();
+    materials.push(Material::Metal(
+        Vector3 {
+            x: 0.9,
+            y: 0.9,
+            z: 0.9,
+        },
+        0.0,
+    ));
+    let white = materials.len();
+    materials.push(Material::Diffuse(map::Map::Color(Vector3 {
+        x: 0.8,
+        y: 0.8,
+        z: 0.8,
+    })));
+    for (x, material) in [(-2.2, red), (0.0, metal), (2.2, white)] {
+        hittables.add_object(Hittable::Sphere(Sphere::new(
+            Vector3 { x, y: 1.0, z: 0.0 },
+            1.0,
+            material,
+        )));
+    }
+
+    // A warm key light, a spotlight on the white sphere, and a dim, cool fill light from above.
+    // The key light and the spotlight are fixtures with a photometric profile if we have one.
+    let profile = ies_path.map(|path| Rc::new(IesProfile::load(path)));
+    let warm = Vector3 {
+        x: 1.0,
+        y: 0.85,
+        z: 0.6,
+    };
+    let key_light = match &profile {
+        Some(profile) => PointLight::new_ies(
+            Vector3 {
+                x: -2.2,
+                y: 5.0,
+                z: 1.0,
+            },
+            Vector3 {
+                x: 0.0,
+                y: -1.0,
+                z: 0.0,
+            },
+            warm,
+            40.0,
+            profile.clone(),
+        ),
+        None => PointLight::new(
+            Vector3 {
+                x: -4.0,
+                y: 5.0,
+                z: 4.0,
+            },
+            warm,
+            40.0,
+        ),
+    };
+    lights.add_light(Light::Point(key_light));
+
+    let spot_position = Vector3 {
+        x: 4.0,
+        y: 6.0,
+        z: 2.0,
+    };
+    let spot_target = Vector3 {
+        x: 2.2,
+        y: 0.0,
+        z: 0.0,
+    };
+    let spot_color = Vector3 {
+        x: 1.0,
+        y: 1.0,
+        z: 1.0,
+    };
+    let spot_light = match &profile {
+        Some(profile) => SpotLight::new_ies(
+            spot_position,
+            spot_target,
+            spot_color,
+            60.0,
+            10.0,
+            18.0,
+            profile.clone(),
+        ),
+        None => SpotLight::new(spot_position, spot_target, spot_color, 60.0, 10.0, 18.0),
+    };
+    lights.add_light(Light::Spot(spot_light));
+    lights.add_light(Light::Directional(DirectionalLight::new(
+        Vector3 {
+            x: 0.2,
+            y: -1.0,
+            z: -0.3,
+        },
+        Vector3 {
+            x: 0.6,
+            y: 0.7,
+            z: 1.0,
+        },
+        0.3,
+    )));
+
+    // A nearly black studio
+    let background = Background::Gradient(
+        Vector3 {
+            x: 0.0,
+            y: 0.0,
+            z: 0.0,
+        },
+        Vector3 {
+            x: 0.02,
+            y: 0.02,
+            z: 0.03,
+        },
+    );
+
+    (camera, materials, hittables, lights, background, max_depth)
+}
+
+fn office(
+    light_sampling: Option<&String>,
+) -> (Camera, Vec<Material>, Hittables, Lights, Background, i32) {
+    // Initialize camera
+    let aspect_ratio = 16.0 / 9.0;
+    let image_width = 400;
+    let mut camera = Camera::new(
+        Vector3 {
+            x: 0.0,
+            y: 1.6,
+            z: 9.0,
+        },
+        Vector3 {
+            x: 0.0,
+            y: 1.2,
+            z: 0.0,
+        },
+        Vector3 {
+            x: 0.0,
+            y: 1.0,
+            z: 0.0,
+        },
+        0.0,
+        9.0,
+        aspect_ratio,
+        image_width,
+        60.0,
+        16,
+    );
+    let max_depth = 50;
+    // Most of the light has bounced off of the walls a few times, so only start ending paths after that
+    camera.set_russian_roulette_depth(5);
+
+    // Initialize world
+    let mut materials: Vec<Material> = vec![];
+    let mut hittables = Hittables::new();
+    let mut lights = Lights::new();
+    match light_sampling.map(|name| name.as_str()) {
+        Some("uniform") => lights.set_light_sampling(LightSampling::Uniform),
+        Some("power") => lights.set_light_sampling(LightSampling::Power),
+        Some("bvh") | None => lights.set_light_sampling(LightSampling::Bvh),
+        Some(name) => panic!(
+            "Unknown light sampling {}, expected uniform, power, or bvh",
+            name
+        ),
+    }
+
+    // A long room with a floor, ceiling, and walls
+    let (half_width, height, front, back) = (6.0, 3.0, 10.0, -30.0);
+    let floor = materials.len();
+    materials.push(Material::Diffuse(map::Map::Color(Vector3 {
+        x: 0.45,
+        y: 0.4,
+        z: 0.35,
+    })));
+    let wall = materials.len();
+    materials.push(Material::Diffuse(map::Map::Color(Vector3 {
+        x: 0.75,
+        y: 0.75,
+        z: 0.72,
+    })));
+    let room = [
+        // Floor
+        (
+            Vector3 {
+                x: -half_width,
+                y: 0.0,
+                z: front,
+            },
+            Vector3 {
+                x: 2.0 * half_width,
+                y: 0.0,
+                z: 0.0,
+            },
+            Vector3 {
+                x: 0.0,
+                y: 0.0,
+                z: back - front,
+            },
+            floor,
+        ),
+        // Ceiling
+        (
+            Vector3 {
+                x: -half_width,
+                y: height,
+                z: front,
+            },
+            Vector3 {
+                x: 2.0 * half_width,
+                y: 0.0,
+                z: 0.0,
+            },
+            Vector3 {
+                x: 0.0,
+                y: 0.0,
+                z: back - front,
+            },
+            wall,
+        ),
+        // Left and right walls
+        (
+            Vector3 {
+                x: -half_width,
+                y: 0.0,
+                z: front,
+            },
+            Vector3 {
+                x: 0.0,
+                y: height,
+                z: 0.0,
+            },
+            Vector3 {
+                x: 0.0,
+                y: 0.0,
+                z: back - front,
+            },
+            wall,
+        ),
+        (
+            Vector3 {
+                x: half_width,
+                y: 0.0,
+                z: front,
+            },
+            Vector3 {
+                x: 0.0,
+                y: height,
+                z: 0.0,
+            },
+            Vector3 {
+                x: 0.0,
+                y: 0.0,
+                z: back - front,
+            },
+            wall,
+        ),
+        // Back wall
+        (
+            Vector3 {
+                x: -half_width,
+                y: 0.0,
+                z: back,
+            },
+            Vector3 {
+                x: 2.0 * half_width,
+                y: 0.0,
+                z: 0.0,
+            },
+            Vector3 {
+                x: 0.0,
+                y: height,
+                z: 0.0,
+            },
+            wall,
+        ),
+    ];
+    for (q, u, v, material) in room {
+        hittables.add_object(Hittable::Quad(Quad::new(q, u, v, material)));
+    }
+
+    // Rows of desks
+    let desk = materials.len();
+    materials.push(Material::Diffuse(map::Map::Color(Vector3 {
+        x: 0.5,
+        y: 0.3,
+        z: 0.15,
+    })));
+    for row in 0..8 {
+        for x in [-3.5, 1.5] {
+            let z = 4.0 - 4.5 * row as f64;
+            hittables.add_object(Hittable::Quad(Quad::new(
+                Vector3 { x, y: 0.75, z },
+                Vector3 {
+                    x: 2.0,
+                    y: 0.0,
+                    z: 0.0,
+                },
+                Vector3 {
+                    x: 0.0,
+                    y: 0.0,
+                    z: -1.0,
+                },
+                desk,
+            )));
+        }
+    }
+
+    // A grid of ceiling panels facing down. Most are dim, a few are much brighter, which is where picking lights by
+    // power pays off, and the far ones barely light the near desks, which is where the light BVH pays off.
+    let panel_color = Vector3 {
+        x: 1.0,
+        y: 0.95,
+        z: 0.85,
+    };
+    for row in 0..20 {
+        for column in 0..5 {
+            let strength = if (row + column) % 7 == 0 { 30.0 } else { 4.0 };
+            let panel = materials.len();
+            materials.push(Material::Emissive(map::Map::Color(strength * panel_color)));
+
+            let x = -half_width + 0.6 + 2.4 * column as f64;
+            let z = front - 2.1 - 2.0 * row as f64;
+            let quad = Quad::new(
+                Vector3 {
+                    x,
+                    y: height - 0.01,
+                    z,
+                },
+                Vector3 {
+                    x: 1.2,
+                    y: 0.0,
+                    z: 0.0,
+                },
+                Vector3 {
+                    x: 0.0,
+                    y: 0.0,
+                    z: 0.6,
+                },
+                panel,
+            );
+            let object = hittables.add_object(Hittable::Quad(quad.clone()));
+            lights.add_light(Light::Area(AreaLight::new(quad, object, &materials)));
+        }
+    }
+
+    // Nighttime outside
+    let background = Background::Gradient(
+        Vector3 {
+            x: 0.0,
+            y: 0.0,
+            z: 0.0,
+        },
+        Vector3 {
+            x: 0.0,
+            y: 0.0,
+            z: 0.0,
+        },
+    );
+
+    (camera, materials, hittables, lights, background, max_depth)
+}
+
+/// Split the command line into positional arguments and `--name value` options
+fn parse_args(args: &[String]) -> (Vec<String>, HashMap<String, String>) {
+    let mut positional: Vec<String> = vec![];
+    let mut options: HashMap<String, String> = HashMap::new();
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.strip_prefix("--") {
+            Some(name) => {
+                let value = args
+                    .next()
+                    .unwrap_or_else(|| panic!("Missing value for option --{}", name));
+                options.insert(name.to_string(), value.clone());
+            }
+            None => positional.push(arg.clone()),
+        }
+    }
+
+    (positional, options)
+}
+
+/// Use the wrap and filter modes given in the options for the images that a scene loads, otherwise the scene's own
+fn get_texture_sampler(
+    options: &HashMap<String, String>,
+    wrap_mode: WrapMode,
+    filter_mode: FilterMode,
+) -> TextureSampler {
+    let wrap_mode = match options.get("texture-wrap").map(|name| name.as_str()) {
+        Some("clamp") => WrapMode::Clamp,
+        Some("repeat") => WrapMode::Repeat,
+        Some("mirror") => WrapMode::Mirror,
+        Some(name) => panic!(
+            "Unknown texture wrap mode {}, expected clamp, repeat, or mirror",
+            name
+        ),
+        None => wrap_mode,
+    };
+    let filter_mode = match options.get("texture-filter").map(|name| name.as_str()) {
+        Some("nearest") => FilterMode::Nearest,
+        Some("bilinear") => FilterMode::Bilinear,
+        Some("trilinear") => FilterMode::Trilinear,
+        Some(name) => panic!(
+            "Unknown texture filter {}, expected nearest, bilinear, or trilinear",
+            name
+        ),
+        None => filter_mode,
+    };
+
+    TextureSampler::new(wrap_mode, filter_mode)
+}
+
+/// Build the scene picked by the positional arguments. Options can change how the scene's images are sampled.
+fn build_scene(
+    args: &[String],
+    options: &HashMap<String, String>,
+) -> (Camera, Vec<Material>, Hittables, Lights, Background, i32) {
+    let scene: i32 = if args.len() == 1 {
+        0
+    } else {
+        args[1].parse().expect("Unable to parse scene arg")
+    };
+
+    if scene == 0 {
+        bouncing_spheres()
+    } else if scene == 1 {
+        checkered_spheres()
+    } else if scene == 2 {
+        let earth_image_path = &args[2];
+        globe(earth_image_path, options)
+    } else if scene == 3 {
+        perlin_spheres()
+    } else if scene == 5 {
+        clouds(args.get(2))
+    } else if scene == 6 {
+        subsurface_spheres()
+    } else if scene == 7 {
+        bump_mapping(args.get(2), options)
+    } else if scene == 8 {
+        cutouts(args.get(2), options)
+    } else if scene == 9 {
+        environment_lighting(args.get(2))
+    } else if scene == 10 {
+        daylight(args.get(2))
+    } else if scene == 11 {
+        studio_lights(args.get(2))
+    } else if scene == 12 {
+        office(args.get(2))
+    } else {
+        quads()
+    }
+}
+
+/// Apply the options that change the samples that are taken, which must be the same for every tile of a render
+fn apply_sampling_options(camera: &mut Camera, options: &HashMap<String, String>) {
+    match options.get("sampler").map(|name| name.as_str()) {
+        Some("independent") => camera.set_sample_pattern(SamplePattern::Independent),
+        Some("stratified") => camera.set_sample_pattern(SamplePattern::Stratified),
+        Some("halton") => camera.set_sample_pattern(SamplePattern::Halton),
+        Some("sobol") | None => camera.set_sample_pattern(SamplePattern::Sobol),
+        Some(name) => panic!(
+            "Unknown sample pattern {}, expected independent, stratified, halton, or sobol",
+            name
+        ),
+    }
+
+    // Adaptive sampling is turned on by giving a noise threshold
+    if let Some(noise_threshold) = options.get("adaptive") {
+        let noise_threshold: f64 = noise_threshold
+            .parse()
+            .expect("Unable to parse adaptive noise threshold");
+        camera.set_adaptive_sampling(noise_threshold, options.get("heatmap").cloned());
+    }
+
+    if let Some(name) = options.get("filter") {
+        let radius: Option<f64> = options
+            .get("filter-radius")
+            .map(|radius| radius.parse().expect("Unable to parse filter radius"));
+        let filter = match name.as_str() {
+            "box" => Filter::Box(radius.unwrap_or(0.5)),
+            "tent" => Filter::Tent(radius.unwrap_or(1.0)),
+            "gaussian" => Filter::Gaussian(radius.unwrap_or(1.5), 0.5),
+            "mitchell" => Filter::MitchellNetravali(radius.unwrap_or(2.0), 1.0 / 3.0, 1.0 / 3.0),
+            "blackman-harris" => Filter::BlackmanHarris(radius.unwrap_or(2.0)),
+            _ => panic!(
+                "Unknown filter {}, expected box, tent, gaussian, mitchell, or blackman-harris",
+                name
+            ),
+        };
+        camera.set_filter(filter);
+    }
+
+    // A physical camera is given by its focal length in mm, with a full frame sensor unless another size is given
+    if let Some(focal_length) = options.get("focal-length") {
+        let focal_length: f64 = focal_length.parse().expect("Unable to parse focal length");
+        let sensor_size: Vec<f64> = options
+            .get("sensor")
+            .map_or("36x24", |size| size.as_str())
+            .split('x')
+            .map(|length| length.parse().expect("Unable to parse sensor size"))
+            .collect();
+        if sensor_size.len() != 2 {
+            panic!("The sensor size must be given as widthxheight in mm");
+        }
+        camera.set_sensor(focal_length, sensor_size[0], sensor_size[1]);
+    }
+    if let Some(f_number) = options.get("f-number") {
+        camera.set_f_number(f_number.parse().expect("Unable to parse f-number"));
+    }
+
+    // The shutter speed is in seconds, and can be given as a fraction like 1/60
+    if let Some(shutter_speed) = options.get("shutter") {
+        let shutter_speed = match shutter_speed.split_once('/') {
+            Some((numerator, denominator)) => {
+                let numerator: f64 = numerator.parse().expect("Unable to parse shutter speed");
+                let denominator: f64 = denominator.parse().expect("Unable to parse shutter speed");
+                numerator / denominator
+            }
+            None => shutter_speed
+                .parse()
+                .expect("Unable to parse shutter speed"),
+        };
+        let shutter_open: f64 = options.get("shutter-open").map_or(0.0, |time| {
+            time.parse().expect("Unable to parse shutter open time")
+        });
+        camera.set_shutter(shutter_open, shutter_open + shutter_speed);
+    }
+    if let Some(iso) = options.get("iso") {
+        let compensation: f64 = options.get("ev").map_or(0.0, |compensation| {
+            compensation
+                .parse()
+                .expect("Unable to parse exposure compensation")
+        });
+        camera.set_iso(iso.parse().expect("Unable to parse ISO"), compensation);
+    }
+
+    // Fisheyes take their field of view, and orthographic views take their width in world units
+    if let Some(name) = options.get("projection") {
+        let fov: Option<f64> = options
+            .get("fov")
+            .map(|fov| fov.parse().expect("Unable to parse field of view"));
+        let projection = match name.as_str() {
+            "perspective" => Projection::Perspective,
+            "orthographic" => Projection::Orthographic(
+                options
+                    .get("view-width")
+                    .expect("Missing option --view-width for the orthographic projection")
+                    .parse()
+                    .expect("Unable to parse view width"),
+            ),
+            "fisheye" => Projection::EquidistantFisheye(fov.unwrap_or(180.0)),
+            "equisolid" => Projection::EquisolidFisheye(fov.unwrap_or(180.0)),
+            "equirectangular" => Projection::Equirectangular,
+            _ => panic!(
+                "Unknown projection {}, expected perspective, orthographic, fisheye, equisolid, or equirectangular",
+                name
+            ),
+        };
+        camera.set_projection(projection);
+    }
+
+    // Stereo is turned on by giving the layout of the eyes' images
+    if let Some(layout) = options.get("stereo") {
+        let layout = match layout.as_str() {
+            "side-by-side" => StereoLayout::SideBySide,
+            "top-bottom" => StereoLayout::TopBottom,
+            _ => panic!(
+                "Unknown stereo layout {}, expected side-by-side or top-bottom",
+                layout
+            ),
+        };
+        let interocular_distance: f64 = options.get("interocular").map_or(0.064, |distance| {
+            distance
+                .parse()
+                .expect("Unable to parse interocular distance")
+        });
+        let convergence_distance: Option<f64> = options.get("convergence").map(|distance| {
+            distance
+                .parse()
+                .expect("Unable to parse convergence distance")
+        });
+        camera.set_stereo(interocular_distance, convergence_distance, layout);
+    }
+
+    // Polygonal apertures are given by their number of blades, and apertures shaped like an image by its path
+    if options.contains_key("aperture-blades") && options.contains_key("aperture-mask") {
+        panic!("The options --aperture-blades and --aperture-mask can't be used together");
+    }
+    if options.contains_key("aperture-rotation") && !options.contains_key("aperture-blades") {
+        panic!("The option --aperture-rotation needs --aperture-blades");
+    }
+    if let Some(blade_count) = options.get("aperture-blades") {
+        let blade_count: i32 = blade_count
+            .parse()
+            .expect("Unable to parse aperture blade count");
+        if blade_count < 3 {
+            panic!("An aperture needs at least 3 blades, got {}", blade_count);
+        }
+        let rotation: f64 = options.get("aperture-rotation").map_or(0.0, |rotation| {
+            rotation.parse().expect("Unable to parse aperture rotation")
+        });
+        camera.set_aperture(Aperture::Polygon(blade_count, rotation));
+    }
+    if let Some(mask_path) = options.get("aperture-mask") {
+        let mask = ApertureMask::new(&ImageData::new(mask_path, ColorSpace::Linear));
+        camera.set_aperture(Aperture::Mask(mask));
+    }
+
+    // A lens prescription replaces the projection. The film is full frame unless another diagonal in mm is given.
+    if let Some(lens_path) = options.get("lens") {
+        let film_diagonal: f64 = options.get("film-diagonal").map_or(43.27, |diagonal| {
+            diagonal.parse().expect("Unable to parse film diagonal")
+        });
+        camera.set_lens_system(LensSystem::load(lens_path, film_diagonal));
+    }
+
+    // Paths can be randomly ended once they've made this many reflections
+    if let Some(depth) = options.get("rr-depth") {
+        let depth: i32 = depth
+            .parse()
+            .expect("Unable to parse Russian roulette depth");
+        if depth < 1 {
+            panic!(
+                "The Russian roulette depth must be at least 1, got {}",
+                depth
+            );
+        }
+        camera.set_russian_roulette_depth(depth);
+    }
+
+    if let Some(seed) = options.get("seed") {
+        camera.set_seed(seed.parse().expect("Unable to parse seed"));
+    }
+}
+
+/// Render tiles for the coordinator at an address until it runs out of them
+fn run_worker(address: &str) {
+    let mut connection = WorkerConnection::connect(address);
+    let job = connection.receive_job();
+    // The job's files are removed when local_job is dropped at the end
+    let local_job = job.write_local();
+    let options: HashMap<String, String> = local_job.options.iter().cloned().collect();
+    let (mut camera, materials, mut hittables, mut lights, background, max_depth) =
+        build_scene(&local_job.args, &options);
+    apply_sampling_options(&mut camera, &options);
+
+    while let Some(region) = connection.receive_tile() {
+        let tile = render_tile(
+            &mut camera,
+            &mut hittables,
+            &mut lights,
+            &background,
+            &materials,
+            max_depth,
+            region,
+        );
+        connection.send_tile(&tile);
+    }
+}
+
+/// Split the image into tiles and render them on the workers that connect to an address, optionally starting some
+/// workers on this machine, then print the merged image
+fn run_farm(address: &str, camera: &Camera, args: Vec<String>, options: &HashMap<String, String>) {
+    let listener = TcpListener::bind(address)
+        .unwrap_or_else(|_| panic!("Unable to listen for workers at {}", address));
+    let address = listener.local_addr().unwrap().to_string();
+    eprintln!("Listening for workers at {}", address);
+
+    let tile_size: i32 = options
+        .get("tile-size")
+        .map_or(64, |size| size.parse().expect("Unable to parse tile size"));
+    let local_worker_count: i32 = options.get("workers").map_or(0, |count| {
+        count.parse().expect("Unable to parse worker count")
+    });
+    let program = env::current_exe().expect("Unable to find the program to start workers with");
+    let mut workers: Vec<_> = (0..local_worker_count)
+        .map(|_| {
+            Command::new(&program)
+                .args(["worker", &address])
+                .stdout(Stdio::null())
+                .spawn()
+                .expect("Unable to start a worker")
+        })
+        .collect();
+
+    // Workers that don't send back their tile in time are dropped, and their tile goes to another worker. The farm gives
+    // up if it goes that long without any workers.
+    let worker_timeout: f64 = options.get("worker-timeout").map_or(600.0, |seconds| {
+        seconds.parse().expect("Unable to parse worker timeout")
+    });
+
+    let tiles = camera
+        .render_region()
+        .split(tile_size)
+        .into_iter()
+        .map(|region| FarmTile {
+            region,
+            encoded_region: camera.get_tile_region(&region),
+        })
+        .collect();
+    // The lens prescription and aperture mask are files on this machine, so they go to the workers with the job
+    let job = FarmJob::new(
+        args,
+        options.clone().into_iter().collect(),
+        &["lens", "aperture-mask"],
+    );
+    let merger = run_coordinator(
+        listener,
+        &job,
+        tiles,
+        Duration::from_secs_f64(worker_timeout),
+    );
+    for worker in workers.iter_mut() {
+        let _ = worker.wait();
+    }
+
+    let (image_width, image_height, colors) = merger.get_image();
+    write_ppm(&colors, image_width, image_height, merger.get_exposure());
+}
+
+fn main() {
+    // The scene number and its own argument come first, and the render settings are options that can go anywhere
+    let (args, options) = parse_args(&env::args().collect::<Vec<String>>());
+
+    // Merging tiles takes the tile paths in place of a scene, and a worker takes its coordinator's address
+    match args.get(1).map(|arg| arg.as_str()) {
+        Some("merge") => {
+            let merger = merge_tiles(&args[2..]);
+            let (image_width, image_height, colors) = merger.get_image();
+            write_ppm(&colors, image_width, image_height, merger.get_exposure());
+            return;
+        }
+        Some("worker") => {
+            run_worker(&args[2]);
+            return;
+        }
+        _ => {}
+    }
+
+    // Generate scene
+    let (mut camera, materials, mut hittables, mut lights, background, max_depth) =
+        build_scene(&args, &options);
+    apply_sampling_options(&mut camera, &options);
+    // The crop window is given as x,y,width,height in pixels
+    if let Some(crop) = options.get("crop") {
+        let bounds: Vec<i32> = crop
+            .split(',')
+            .map(|bound| bound.parse().expect("Unable to parse crop window"))
+            .collect();
+        if bounds.len() != 4 {
+            panic!(
+                "Unable to parse crop window {}, expected x,y,width,height",
+                crop
+            );
+        }
+        camera.set_crop_window(bounds[0], bounds[1], bounds[2], bounds[3]);
+    }
+
+    // The tiles are rendered by workers when the coordinator is given an address to listen at. Tiles only carry the
+    // image, so the outputs that need every sample of the render on one machine can't be made.
+    if let Some(address) = options.get("farm") {
+        for name in [
+            "aov-path",
+            "aovs",
+            "passes",
+            "denoise",
+            "heatmap",
+            "checkpoint",
+            "checkpoint-interval",
+            "resume",
+            "tile",
+        ] {
+            if options.contains_key(name) {
+                panic!("The option --{} can't be used with --farm", name);
+            }
+        }
+        run_farm(address, &camera, args, &options);
+        return;
+    }
+
+    // Output variables and render passes are written when a path is given, with comma separated lists of which ones.
+    // All of the output variables are written if neither list is given.
+    if let Some(aov_path) = options.get("aov-path") {
+        let default_aovs = if options.contains_key("passes") {
+            ""
+        } else {
+            "albedo,normal,depth,position,uv,material,object"
+        };
+        let aovs = options
+            .get("aovs")
+            .map_or(default_aovs, |names| names.as_str())
+            .split(',')
+            .filter(|name| !name.is_empty())
+            .map(|name| {
+                parse_aov(name).unwrap_or_else(|| {
+                    panic!(
+                        "Unknown output variable {}, expected albedo, normal, depth, position, uv, material, or object",
+                        name
+                    )
+                })
+            })
+            .collect();
+        let passes = match options.get("passes").map(|names| names.as_str()) {
+            Some("all") => {
+                "diffuse_direct,diffuse_indirect,specular,transmission,emission,background"
+            }
+            Some(names) => names,
+            None => "",
+        }
+        .split(',')
+        .filter(|name| !name.is_empty())
+        .map(|name| {
+            parse_render_pass(name).unwrap_or_else(|| {
+                panic!(
+                    "Unknown render pass {}, expected diffuse_direct, diffuse_indirect, specular, transmission, emission, background, or all",
+                    name
+                )
+            })
+        })
+        .collect();
+        camera.set_aov_output(aovs, passes, aov_path.clone());
+    }
+
+    // Progress is saved every minute unless another interval is given
+    if let Some(checkpoint_path) = options.get("checkpoint") {
+        let interval_seconds: f64 = options.get("checkpoint-interval").map_or(60.0, |seconds| {
+            seconds
+                .parse()
+                .expect("Unable to parse checkpoint interval")
+        });
+        camera.set_checkpoint(checkpoint_path.clone(), interval_seconds);
+    }
+    if let Some(resume_path) = options.get("resume") {
+        camera.set_resume(resume_path.clone());
+    }
+
+    if let Some(tile_path) = options.get("tile") {
+        camera.set_tile_output(tile_path.clone());
+    }
+
+    // Denoising is turned on by giving the number of filter passes
+    if let Some(iterations) = options.get("denoise") {
+        let iterations: usize = iterations
+            .parse()
+            .expect("Unable to parse denoise iterations");
+        camera.set_denoise(DenoiseSettings {
+            iterations,
+            ..DenoiseSettings::default()
+        });
+    }
+
+    // Render
+    render(
+        &mut camera,
+        &mut hittables,
+        &mut lights,
+        &background,
+        &materials,
+        max_depth,
+    );
 }
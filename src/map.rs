@@ -1,13 +1,15 @@
-use std::{fs, rc::Rc};
+use std::rc::Rc;
 
-use zune_jpeg::{ImageInfo, JpegDecoder};
-
-use crate::{perlin::Perlin, vector::Vector3};
+use crate::{
+    image_decoding::decode_image,
+    perlin::Perlin,
+    vector::{Vector2, Vector3},
+};
 
 pub enum Map {
     Color(Vector3),
     Checker(CheckerData),
-    Image(ImageData),
+    Image(ImageData, TextureSampler),
     Noise(Perlin, f64),
 }
 
@@ -27,31 +29,329 @@ impl CheckerData {
     }
 }
 
+/// How the color channels of an image file are encoded
+pub enum ColorSpace {
+    Srgb,   // Colors that need to be decoded with the sRGB transfer function, like photographs
+    Linear, // Data that is used as is, like normal maps and masks
+}
+
+/// How texture coordinates outside of [0, 1] are mapped back onto the image
+#[derive(Clone, Copy)]
+pub enum WrapMode {
+    Clamp,  // Use the closest edge pixel
+    Repeat, // Tile the image
+    Mirror, // Tile the image, flipping every other tile
+}
+
+/// How pixels are combined when looking up an image
+#[derive(Clone, Copy)]
+pub enum FilterMode {
+    Nearest,   // Use the closest pixel
+    Bilinear,  // Blend the four closest pixels
+    Trilinear, // Blend bilinear lookups from the two mip levels that best match the lookup's footprint
+}
+
+#[derive(Clone, Copy)]
+pub struct TextureSampler {
+    pub wrap_mode: WrapMode,
+    pub filter_mode: FilterMode,
+}
+
+impl TextureSampler {
+    pub fn new(wrap_mode: WrapMode, filter_mode: FilterMode) -> Self {
+        Self {
+            wrap_mode,
+            filter_mode,
+        }
+    }
+}
+
+/// One level of an image's mip chain
+struct MipLevel {
+    width: usize,
+    height: usize,
+    pixels: Vec<f32>, // Interleaved channels, row by row from the top of the image
+}
+
+/// Image pixels stored as linear floating point values
 pub struct ImageData {
-    pixels: Vec<u8>,
-    image_info: ImageInfo,
+    width: usize,
+    height: usize,
+    channels: usize, // 1 (gray), 2 (gray, alpha), 3 (red, green, blue), or 4 (red, green, blue, alpha)
+    // The full resolution image followed by successively halved copies, down to a single pixel
+    levels: Vec<MipLevel>,
 }
 
 impl ImageData {
-    pub fn new(file_path: &str) -> Self {
-        let file_contents =
-            fs::read(file_path).expect(&format!("Unable to read file path at {}", file_path));
-        let mut decoder = JpegDecoder::new(&file_contents);
-        let pixels = decoder
-            .decode()
-            .expect(&format!("Unable to decode file contents of {}", file_path));
-        let info = decoder
-            .info()
-            .expect("Unable to get image info after decoding");
+    /// Load a JPEG, PNG, or Radiance HDR image.
+    /// The color space is ignored for HDR images since they are always linear. Alpha is never sRGB encoded.
+    pub fn new(file_path: &str, color_space: ColorSpace) -> Self {
+        let decoded = decode_image(file_path);
+
+        let mut pixels = decoded.pixels;
+        let is_srgb = matches!(color_space, ColorSpace::Srgb);
+        if is_srgb && !decoded.is_hdr {
+            let color_channels = if decoded.channels == 2 || decoded.channels == 4 {
+                decoded.channels - 1
+            } else {
+                decoded.channels
+            };
+            for pixel in pixels.chunks_exact_mut(decoded.channels) {
+                for value in pixel.iter_mut().take(color_channels) {
+                    *value = srgb_to_linear(*value);
+                }
+            }
+        }
+
+        Self::from_pixels(decoded.width, decoded.height, decoded.channels, pixels)
+    }
+
+    /// Create an image from linear pixel values. Channels are interleaved and rows go from the top of the image.
+    pub fn from_pixels(width: usize, height: usize, channels: usize, pixels: Vec<f32>) -> Self {
+        assert_eq!(pixels.len(), width * height * channels);
+
+        let levels = build_mip_levels(
+            MipLevel {
+                width,
+                height,
+                pixels,
+            },
+            channels,
+        );
 
         Self {
-            pixels,
-            image_info: info,
+            width,
+            height,
+            channels,
+            levels,
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn has_alpha(&self) -> bool {
+        self.channels == 2 || self.channels == 4
+    }
+
+    /// Get the color and alpha of the pixel in column i and row j of a mip level. Gray images have their value copied
+    /// to each color channel and images without alpha are opaque.
+    pub fn get_pixel(&self, level: usize, i: usize, j: usize) -> (Vector3, f64) {
+        let mip_level = &self.levels[level];
+        let index = self.channels * (j * mip_level.width + i);
+        let pixel = mip_level
+            .pixels
+            .get(index..index + self.channels)
+            .unwrap_or_else(|| panic!("Failure to get pixel at ({}, {})", i, j));
+
+        match self.channels {
+            1 | 2 => {
+                let gray = pixel[0] as f64;
+                let alpha = if self.channels == 2 {
+                    pixel[1] as f64
+                } else {
+                    1.0
+                };
+                (
+                    Vector3 {
+                        x: gray,
+                        y: gray,
+                        z: gray,
+                    },
+                    alpha,
+                )
+            }
+            _ => {
+                let alpha = if self.channels == 4 {
+                    pixel[3] as f64
+                } else {
+                    1.0
+                };
+                (
+                    Vector3 {
+                        x: pixel[0] as f64,
+                        y: pixel[1] as f64,
+                        z: pixel[2] as f64,
+                    },
+                    alpha,
+                )
+            }
         }
     }
+
+    /// Look up the color and alpha of the image at texture coordinates (u, v).
+    ///
+    /// footprint is how far the lookup spreads in u and v, for example the size of a camera pixel projected onto the
+    /// surface. It picks the mip level for trilinear filtering. A zero footprint uses the full resolution image.
+    pub fn sample(
+        &self,
+        u: f64,
+        v: f64,
+        sampler: &TextureSampler,
+        footprint: Vector2,
+    ) -> (Vector3, f64) {
+        // Flip v to image coordinates
+        let v = 1.0 - v;
+
+        match sampler.filter_mode {
+            FilterMode::Nearest => {
+                let i = wrap_index((u * self.width as f64).floor() as i64, self.width, sampler);
+                let j = wrap_index(
+                    (v * self.height as f64).floor() as i64,
+                    self.height,
+                    sampler,
+                );
+                self.get_pixel(0, i, j)
+            }
+            FilterMode::Bilinear => self.sample_bilinear(0, u, v, sampler),
+            FilterMode::Trilinear => {
+                // The footprint's width in full resolution pixels. Each mip level halves the resolution.
+                let pixel_width = f64::max(
+                    footprint.x.abs() * self.width as f64,
+                    footprint.y.abs() * self.height as f64,
+                );
+                let max_level = (self.levels.len() - 1) as f64;
+                let level = if pixel_width > 1.0 {
+                    f64::min(pixel_width.log2(), max_level)
+                } else {
+                    0.0
+                };
+
+                let lower_level = level.floor() as usize;
+                let upper_level = usize::min(lower_level + 1, self.levels.len() - 1);
+                let t = level - lower_level as f64;
+
+                let (lower_color, lower_alpha) = self.sample_bilinear(lower_level, u, v, sampler);
+                if t <= 0.0 {
+                    return (lower_color, lower_alpha);
+                }
+                let (upper_color, upper_alpha) = self.sample_bilinear(upper_level, u, v, sampler);
+
+                (
+                    (1.0 - t) * lower_color + t * upper_color,
+                    (1.0 - t) * lower_alpha + t * upper_alpha,
+                )
+            }
+        }
+    }
+
+    /// Blend the four pixels closest to image coordinates (x, y) in [0, 1] of a mip level
+    fn sample_bilinear(
+        &self,
+        level: usize,
+        x: f64,
+        y: f64,
+        sampler: &TextureSampler,
+    ) -> (Vector3, f64) {
+        let mip_level = &self.levels[level];
+
+        // Pixel centers sit at (index + 0.5) / size
+        let x = x * mip_level.width as f64 - 0.5;
+        let y = y * mip_level.height as f64 - 0.5;
+        let x0 = x.floor();
+        let y0 = y.floor();
+        let tx = x - x0;
+        let ty = y - y0;
+
+        let i0 = wrap_index(x0 as i64, mip_level.width, sampler);
+        let i1 = wrap_index(x0 as i64 + 1, mip_level.width, sampler);
+        let j0 = wrap_index(y0 as i64, mip_level.height, sampler);
+        let j1 = wrap_index(y0 as i64 + 1, mip_level.height, sampler);
+
+        let (c00, a00) = self.get_pixel(level, i0, j0);
+        let (c10, a10) = self.get_pixel(level, i1, j0);
+        let (c01, a01) = self.get_pixel(level, i0, j1);
+        let (c11, a11) = self.get_pixel(level, i1, j1);
+
+        let w00 = (1.0 - tx) * (1.0 - ty);
+        let w10 = tx * (1.0 - ty);
+        let w01 = (1.0 - tx) * ty;
+        let w11 = tx * ty;
+
+        (
+            w00 * c00 + w10 * c10 + w01 * c01 + w11 * c11,
+            w00 * a00 + w10 * a10 + w01 * a01 + w11 * a11,
+        )
+    }
+}
+
+/// Map a pixel index that may be outside of the image back into [0, size) using the sampler's wrap mode
+fn wrap_index(index: i64, size: usize, sampler: &TextureSampler) -> usize {
+    let size = size as i64;
+    let wrapped = match sampler.wrap_mode {
+        WrapMode::Clamp => index.clamp(0, size - 1),
+        WrapMode::Repeat => index.rem_euclid(size),
+        WrapMode::Mirror => {
+            let period_index = index.rem_euclid(2 * size);
+            if period_index >= size {
+                2 * size - 1 - period_index
+            } else {
+                period_index
+            }
+        }
+    };
+
+    wrapped as usize
 }
 
-pub fn get_map_value(map: &Map, u: f64, v: f64, p: Vector3) -> Vector3 {
+/// Build the mip chain for an image by repeatedly averaging 2x2 blocks of pixels
+fn build_mip_levels(base_level: MipLevel, channels: usize) -> Vec<MipLevel> {
+    let mut levels = vec![base_level];
+
+    loop {
+        let previous = &levels[levels.len() - 1];
+        if previous.width <= 1 && previous.height <= 1 {
+            break;
+        }
+
+        let width = usize::max(previous.width / 2, 1);
+        let height = usize::max(previous.height / 2, 1);
+        let mut pixels: Vec<f32> = Vec::with_capacity(width * height * channels);
+
+        for j in 0..height {
+            for i in 0..width {
+                // Odd sizes clamp to the last row or column
+                let i0 = usize::min(2 * i, previous.width - 1);
+                let i1 = usize::min(2 * i + 1, previous.width - 1);
+                let j0 = usize::min(2 * j, previous.height - 1);
+                let j1 = usize::min(2 * j + 1, previous.height - 1);
+
+                for channel in 0..channels {
+                    let get = |i: usize, j: usize| {
+                        previous.pixels[channels * (j * previous.width + i) + channel]
+                    };
+                    pixels.push(0.25 * (get(i0, j0) + get(i1, j0) + get(i0, j1) + get(i1, j1)));
+                }
+            }
+        }
+
+        levels.push(MipLevel {
+            width,
+            height,
+            pixels,
+        });
+    }
+
+    levels
+}
+
+/// Convert an sRGB encoded value in [0, 1] to a linear value
+fn srgb_to_linear(value: f32) -> f32 {
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+/// Get the value of a map. footprint is how far the lookup spreads in u and v, which is used to filter images.
+/// It can be zero if unknown.
+pub fn get_map_value(map: &Map, u: f64, v: f64, p: Vector3, footprint: Vector2) -> Vector3 {
     match map {
         Map::Color(color) => *color,
         Map::Checker(checker_data) => {
@@ -60,17 +360,14 @@ pub fn get_map_value(map: &Map, u: f64, v: f64, p: Vector3) -> Vector3 {
             let z_int = (checker_data.inv_scale * p.z).floor() as i64;
             let is_even = ((x_int + y_int + z_int) % 2) == 0;
             if is_even {
-                get_map_value(&checker_data.even, u, v, p)
+                get_map_value(&checker_data.even, u, v, p, footprint)
             } else {
-                get_map_value(&checker_data.odd, u, v, p)
+                get_map_value(&checker_data.odd, u, v, p, footprint)
             }
         }
-        Map::Image(image_data) => {
-            let pixels = &image_data.pixels;
-            let info = &image_data.image_info;
-
-            if info.height <= 0 {
-                // Debugging aid if info is invalid
+        Map::Image(image_data, sampler) => {
+            if image_data.height == 0 || image_data.width == 0 {
+                // Debugging aid if the image is empty
                 return Vector3 {
                     x: 1.0,
                     y: 0.0,
@@ -78,48 +375,7 @@ pub fn get_map_value(map: &Map, u: f64, v: f64, p: Vector3) -> Vector3 {
                 };
             }
 
-            // Clamp input texture coordinates to [0, 1]
-            let u = if u < 0.0 {
-                0.0
-            } else if u > 1.0 {
-                1.0
-            } else {
-                u
-            };
-            let v = {
-                let v = if v < 0.0 {
-                    0.0
-                } else if v > 1.0 {
-                    1.0
-                } else {
-                    v
-                };
-
-                // Flip v to image coordinates
-                1.0 - v
-            };
-
-            let i = (u * info.width as f64) as usize;
-            let j = (v * info.height as f64) as usize;
-
-            let r_index = (3 * j) * (info.width as usize) + (3 * i);
-            let pixel_r = *pixels
-                .get(r_index)
-                .expect(&format!("Failure to get pixel at ({}, {})", i, j));
-            let pixel_g = *pixels
-                .get(r_index + 1)
-                .expect(&format!("Failure to get pixel at ({}, {})", i, j));
-            let pixel_b = *pixels
-                .get(r_index + 2)
-                .expect(&format!("Failure to get pixel at ({}, {})", i, j));
-
-            let color_scale = 1.0 / 255.0; // For normalizing RGB values to [0.0, 1.0]
-
-            Vector3 {
-                x: color_scale * (pixel_r as f64),
-                y: color_scale * (pixel_g as f64),
-                z: color_scale * (pixel_b as f64),
-            }
+            image_data.sample(u, v, sampler, footprint).0
         }
         Map::Noise(noise, scale) => {
             (1.0 + (scale * p.z + 10.0 * noise.turbulence(&p, 7)).sin())
@@ -131,3 +387,112 @@ pub fn get_map_value(map: &Map, u: f64, v: f64, p: Vector3) -> Vector3 {
         }
     }
 }
+
+/// Get the opacity of a map in [0, 1]. Images with an alpha channel use it, otherwise the color channels are averaged.
+pub fn get_map_opacity(map: &Map, u: f64, v: f64, p: Vector3, footprint: Vector2) -> f64 {
+    match map {
+        Map::Image(image_data, sampler) if image_data.has_alpha() => image_data
+            .sample(u, v, sampler, footprint)
+            .1
+            .clamp(0.0, 1.0),
+        _ => {
+            let value = get_map_value(map, u, v, p, footprint);
+            ((value.x + value.y + value.z) / 3.0).clamp(0.0, 1.0)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn wrap_all(indices: &[i64], size: usize, wrap_mode: WrapMode) -> Vec<usize> {
+        let sampler = TextureSampler::new(wrap_mode, FilterMode::Nearest);
+        indices
+            .iter()
+            .map(|index| wrap_index(*index, size, &sampler))
+            .collect()
+    }
+
+    #[test]
+    fn indices_wrap_onto_the_image() {
+        let indices = [-7, -4, -3, -1, 0, 2, 3, 4, 6, 10];
+        assert_eq!(
+            wrap_all(&indices, 3, WrapMode::Clamp),
+            vec![0, 0, 0, 0, 0, 2, 2, 2, 2, 2]
+        );
+        assert_eq!(
+            wrap_all(&indices, 3, WrapMode::Repeat),
+            vec![2, 2, 0, 2, 0, 2, 0, 1, 0, 1]
+        );
+        // Mirroring goes 0 1 2 2 1 0 0 1 2 with period 6
+        assert_eq!(
+            wrap_all(&indices, 3, WrapMode::Mirror),
+            vec![0, 2, 2, 0, 0, 2, 2, 1, 0, 1]
+        );
+
+        // A single pixel image always uses that pixel
+        for wrap_mode in [WrapMode::Clamp, WrapMode::Repeat, WrapMode::Mirror] {
+            assert_eq!(wrap_all(&[-2, -1, 0, 1, 5], 1, wrap_mode), vec![0; 5]);
+        }
+    }
+
+    #[test]
+    fn mip_chain_halves_and_averages() {
+        // 5x3 with two channels, where odd sizes clamp to the last row and column
+        let width = 5;
+        let height = 3;
+        let pixels: Vec<f32> = (0..width * height)
+            .flat_map(|index| [index as f32, 1.0])
+            .collect();
+        let image = ImageData::from_pixels(width, height, 2, pixels);
+
+        let sizes: Vec<(usize, usize)> = image
+            .levels
+            .iter()
+            .map(|level| (level.width, level.height))
+            .collect();
+        assert_eq!(sizes, vec![(5, 3), (2, 1), (1, 1)]);
+
+        // Level 1 averages the blocks of rows 0 and 1
+        assert_eq!(
+            image.levels[1].pixels,
+            vec![
+                0.25 * (0.0 + 1.0 + 5.0 + 6.0),
+                1.0,
+                0.25 * (2.0 + 3.0 + 7.0 + 8.0),
+                1.0
+            ]
+        );
+        assert_eq!(image.levels[2].pixels, vec![0.5 * (3.0 + 5.0), 1.0]);
+
+        // A square power of two keeps the overall average all the way down
+        let pixels: Vec<f32> = (0..64).map(|index| (index * index) as f32).collect();
+        let average = pixels.iter().sum::<f32>() / 64.0;
+        let image = ImageData::from_pixels(8, 8, 1, pixels);
+        assert_eq!(image.levels.len(), 4);
+        for level in &image.levels {
+            assert_eq!(level.width, level.height);
+            let level_average =
+                level.pixels.iter().sum::<f32>() / (level.width * level.height) as f32;
+            assert!((level_average - average).abs() < 1e-3);
+        }
+    }
+
+    #[test]
+    fn nearest_and_mirror_lookups() {
+        let pixels: Vec<f32> = vec![0.0, 0.25, 0.5, 1.0];
+        let image = ImageData::from_pixels(4, 1, 1, pixels);
+        let nearest = TextureSampler::new(WrapMode::Mirror, FilterMode::Nearest);
+        let lookup = |u: f64| image.sample(u, 0.5, &nearest, Vector2::default()).0.x;
+
+        assert_eq!(lookup(0.1), 0.0);
+        assert_eq!(lookup(0.6), 0.5);
+        assert_eq!(lookup(0.99), 1.0);
+        // Past the right edge the image runs backwards, and before the left edge too
+        assert_eq!(lookup(1.1), 1.0);
+        assert_eq!(lookup(1.6), 0.25);
+        assert_eq!(lookup(-0.1), 0.0);
+        assert_eq!(lookup(-0.3), 0.25);
+    }
+}
@@ -1,16 +1,51 @@
-use rand::{Rng, rngs::ThreadRng};
-
 use crate::{
-    map::{self, get_map_value},
+    hit_record::HitRecord,
+    map::{self, get_map_opacity, get_map_value},
     ray::Ray,
-    raytrace_vector::{random_vector, reflect, refract},
-    vector::Vector3,
+    raytrace_vector::{orthonormal_basis, reflect, refract, sample_unit_sphere},
+    vector::{Vector2, Vector3, calc_cross_product},
 };
 
 pub enum Material {
-    Diffuse(map::Map),   // albedo
-    Metal(Vector3, f64), // albedo, fuzz radius
-    Dielectric(f64),     // The ratio of the enclosed media's eta to the enclosing media's eta
+    Diffuse(map::Map),               // albedo
+    Metal(Vector3, f64),             // albedo, fuzz radius
+    Dielectric(f64), // The ratio of the enclosed media's eta to the enclosing media's eta
+    HenyeyGreenstein(map::Map, f64), // albedo, anisotropy in [-1, 1]
+    Subsurface(Box<SubsurfaceData>),
+    Bump(Box<Material>, map::Map, f64), // base material, height map, bump scale
+    NormalMap(Box<Material>, map::Map), // base material, tangent space normal map
+    Cutout(Box<Material>, map::Map),    // base material, opacity map
+    Emissive(map::Map),                 // emitted radiance from the front face
+}
+
+/// A dielectric boundary enclosing a scattering medium. Light that refracts through the boundary takes a random walk
+/// through the medium until it is absorbed or escapes. The boundary must be a closed surface.
+pub struct SubsurfaceData {
+    // The probability of light scattering rather than being absorbed at each event, per color channel
+    albedo: map::Map,
+    // The average distance between scattering events, per color channel
+    mean_free_path: map::Map,
+    // The ratio of the enclosed media's eta to the enclosing media's eta
+    refraction_index: f64,
+}
+
+impl SubsurfaceData {
+    pub fn new(albedo: map::Map, mean_free_path: map::Map, refraction_index: f64) -> Self {
+        Self {
+            albedo,
+            mean_free_path,
+            refraction_index,
+        }
+    }
+}
+
+/// The uniform values in [0, 1) that a scattering event makes its random choices with. They come from the Sampler, so
+/// that the choices at every bounce are spread out over the samples of a pixel.
+pub struct ScatterValues {
+    pub choice: f64,        // Picks between reflecting and refracting
+    pub channel: f64, // Picks the color channel that a distance through a medium is sampled with
+    pub distance: f64, // Picks how far light travels through a medium
+    pub direction: Vector2, // Picks the scattered direction
 }
 
 /// Scatter a ray off of a material.
@@ -19,13 +54,15 @@ pub enum Material {
 pub fn scatter_ray(
     hit_material: &Material,
     ray_in: &Ray,
-    hit_point: Vector3,
-    hit_point_normal: Vector3,
-    front_face: bool,
-    u: f64,
-    v: f64,
-    rng: &mut ThreadRng,
+    hit_record: &HitRecord,
+    values: &ScatterValues,
 ) -> Option<(Vector3, Ray)> {
+    let hit_point = hit_record.point;
+    let hit_point_normal = hit_record.shading_normal;
+    let front_face = hit_record.front_face;
+    let u = hit_record.u;
+    let v = hit_record.v;
+
     match hit_material {
         Material::Diffuse(map_in) => {
             // We could either scatter with some probability, and if it doesn't scatter, it's absorbed
@@ -33,20 +70,25 @@ pub fn scatter_ray(
 
             // If the random vector is ~= -1.0 * hit_point_normal, this vector's magnitude
             // can be ~= 0.0. This can cause issues, so we treat that as if it were the normal
-            let scattered_direction = hit_point_normal + random_vector(rng);
+            let scattered_direction = hit_point_normal + sample_unit_sphere(&values.direction);
             let scattered_direction = if scattered_direction.magnitude() < 1e-8 {
                 hit_point_normal
             } else {
                 scattered_direction
             };
 
+            // A perturbed shading normal can send the ray into the surface, which would leak light through it
+            if Vector3::dot_product(&scattered_direction, &hit_record.normal) <= 0.0 {
+                return None;
+            }
+
             let scattered_ray = Ray {
                 origin: hit_point,
                 direction: scattered_direction,
                 time: ray_in.time,
             };
 
-            let attenuation = get_map_value(map_in, u, v, hit_point);
+            let attenuation = get_map_value(map_in, u, v, hit_point, hit_record.uv_footprint);
 
             Some((attenuation, scattered_ray))
         }
@@ -57,9 +99,14 @@ pub fn scatter_ray(
                 // Normalize the reflected ray's vector in order to have consistent magnitude
                 reflected.normalize();
                 // Add fuzz
-                reflected + (*fuzz * random_vector(rng))
+                reflected + (*fuzz * sample_unit_sphere(&values.direction))
             };
 
+            // Fuzz or a perturbed shading normal can send the ray into the surface, in which case it's absorbed
+            if Vector3::dot_product(&reflected, &hit_record.normal) <= 0.0 {
+                return None;
+            }
+
             let scattered_ray = Ray {
                 origin: hit_point,
                 direction: reflected,
@@ -74,44 +121,524 @@ pub fn scatter_ray(
                 z: 1.0,
             };
 
-            let refraction_index = if front_face {
-                // If we hit the front face, we need to switch the refraction index to have enclosing media's eta over the enclosed media's
-                1.0 / *ri
-            } else {
-                *ri
-            };
-
-            let unit_direction = Vector3::calc_normalized_vector(&ray_in.direction);
-
-            // Determine whether we need to reflect or refract
-            let direction = {
-                let cos_theta = f64::min(
-                    Vector3::dot_product(&(-1.0 * unit_direction), &hit_point_normal),
-                    1.0,
-                );
-                let sin_theta = (1.0 - (cos_theta * cos_theta)).sqrt();
+            let direction = scatter_dielectric_boundary(
+                *ri,
+                &ray_in.direction,
+                &hit_point_normal,
+                front_face,
+                values.choice,
+            );
 
-                let cannot_refract = (refraction_index * sin_theta) > 1.0;
-
-                if cannot_refract
-                    || reflectance(cos_theta, refraction_index) > rng.random_range(0.0..1.0)
-                {
-                    reflect(&unit_direction, &hit_point_normal)
-                } else {
-                    refract(&unit_direction, &hit_point_normal, refraction_index)
-                }
+            let scattered_ray = Ray {
+                origin: hit_point,
+                direction,
+                time: ray_in.time,
             };
+            Some((attenuation, scattered_ray))
+        }
+        Material::HenyeyGreenstein(map_in, g) => {
+            let forward = Vector3::calc_normalized_vector(&ray_in.direction);
+            let direction = sample_henyey_greenstein(&forward, *g, &values.direction);
 
             let scattered_ray = Ray {
                 origin: hit_point,
                 direction,
                 time: ray_in.time,
             };
+
+            let attenuation = get_map_value(map_in, u, v, hit_point, hit_record.uv_footprint);
+
             Some((attenuation, scattered_ray))
         }
+        Material::Subsurface(subsurface_data) => {
+            if front_face {
+                // Light arriving from outside either reflects off of the boundary or refracts into the medium
+                let direction = scatter_dielectric_boundary(
+                    subsurface_data.refraction_index,
+                    &ray_in.direction,
+                    &hit_point_normal,
+                    front_face,
+                    values.choice,
+                );
+
+                let attenuation = Vector3 {
+                    x: 1.0,
+                    y: 1.0,
+                    z: 1.0,
+                };
+                let scattered_ray = Ray {
+                    origin: hit_point,
+                    direction,
+                    time: ray_in.time,
+                };
+                return Some((attenuation, scattered_ray));
+            }
+
+            // The ray travelled through the medium from its origin to the inside of the boundary. Sample whether it
+            // scattered along the way.
+            let albedo = get_map_value(
+                &subsurface_data.albedo,
+                u,
+                v,
+                hit_point,
+                hit_record.uv_footprint,
+            );
+            let mean_free_path = get_map_value(
+                &subsurface_data.mean_free_path,
+                u,
+                v,
+                hit_point,
+                hit_record.uv_footprint,
+            );
+            let extinction = [
+                1.0 / mean_free_path.x,
+                1.0 / mean_free_path.y,
+                1.0 / mean_free_path.z,
+            ];
+
+            let unit_direction = Vector3::calc_normalized_vector(&ray_in.direction);
+            let boundary_distance = (hit_point - ray_in.origin).magnitude();
+
+            // Each color channel has its own extinction, so we pick one channel to sample the distance with and weight
+            // the result by the average pdf over all channels (the balance heuristic). This keeps the weights bounded.
+            let channel = usize::min((values.channel * 3.0) as usize, 2);
+            let distance = -(1.0 - values.distance).ln() / extinction[channel];
+
+            if distance < boundary_distance {
+                // Scatter inside the medium in a uniformly random direction
+                let transmittance = extinction.map(|sigma| (-sigma * distance).exp());
+                let pdf = (extinction[0] * transmittance[0]
+                    + extinction[1] * transmittance[1]
+                    + extinction[2] * transmittance[2])
+                    / 3.0;
+
+                let attenuation = Vector3 {
+                    x: albedo.x * extinction[0] * transmittance[0] / pdf,
+                    y: albedo.y * extinction[1] * transmittance[1] / pdf,
+                    z: albedo.z * extinction[2] * transmittance[2] / pdf,
+                };
+                let scattered_ray = Ray {
+                    origin: ray_in.origin + distance * unit_direction,
+                    direction: sample_unit_sphere(&values.direction),
+                    time: ray_in.time,
+                };
+                Some((attenuation, scattered_ray))
+            } else {
+                // Reached the boundary without scattering, so either leave the medium or reflect back into it
+                let transmittance = extinction.map(|sigma| (-sigma * boundary_distance).exp());
+                let probability = (transmittance[0] + transmittance[1] + transmittance[2]) / 3.0;
+
+                let attenuation = Vector3 {
+                    x: transmittance[0] / probability,
+                    y: transmittance[1] / probability,
+                    z: transmittance[2] / probability,
+                };
+                let direction = scatter_dielectric_boundary(
+                    subsurface_data.refraction_index,
+                    &ray_in.direction,
+                    &hit_point_normal,
+                    front_face,
+                    values.choice,
+                );
+                let scattered_ray = Ray {
+                    origin: hit_point,
+                    direction,
+                    time: ray_in.time,
+                };
+                Some((attenuation, scattered_ray))
+            }
+        }
+        Material::Bump(base_material, height_map, scale) => {
+            let mut bumped_record = hit_record.clone();
+            bumped_record.shading_normal = bump_shading_normal(height_map, *scale, hit_record);
+            scatter_ray(base_material, ray_in, &bumped_record, values)
+        }
+        Material::NormalMap(base_material, normal_map) => {
+            let mut mapped_record = hit_record.clone();
+            mapped_record.shading_normal = normal_map_shading_normal(normal_map, hit_record);
+            scatter_ray(base_material, ray_in, &mapped_record, values)
+        }
+        Material::Cutout(base_material, _) => {
+            // Transparent parts of the surface were already skipped when finding the hit record
+            scatter_ray(base_material, ray_in, hit_record, values)
+        }
+        Material::Emissive(_) => None, // Lights only emit
+    }
+}
+
+/// Evaluate how much light arriving from a direction scatters into ray_in at a hit point. This is used to sample
+/// lights directly rather than waiting for a scattered ray to hit them.
+///
+/// Returns the scattered light's attenuation including the cosine term, and the probability density of scatter_ray
+/// picking that direction in solid angle. Returns None for materials that only scatter in specific directions, like
+/// mirrors and glass, since a light sample can never line up with them.
+pub fn evaluate_scatter(
+    hit_material: &Material,
+    ray_in: &Ray,
+    hit_record: &HitRecord,
+    direction: &Vector3,
+) -> Option<(Vector3, f64)> {
+    match hit_material {
+        Material::Diffuse(map_in) => {
+            let zero = Vector3 {
+                x: 0.0,
+                y: 0.0,
+                z: 0.0,
+            };
+
+            // Match scatter_ray, which absorbs rays that a perturbed shading normal sends into the surface
+            if Vector3::dot_product(direction, &hit_record.normal) <= 0.0 {
+                return Some((zero, 0.0));
+            }
+
+            let unit_direction = Vector3::calc_normalized_vector(direction);
+            let cos_theta = Vector3::dot_product(&unit_direction, &hit_record.shading_normal);
+            if cos_theta <= 0.0 {
+                return Some((zero, 0.0));
+            }
+
+            // Lambertian reflection scatters with a cosine distribution around the shading normal
+            let albedo = get_map_value(
+                map_in,
+                hit_record.u,
+                hit_record.v,
+                hit_record.point,
+                hit_record.uv_footprint,
+            );
+            let pdf = cos_theta / std::f64::consts::PI;
+            Some((pdf * albedo, pdf))
+        }
+        Material::HenyeyGreenstein(map_in, g) => {
+            let forward = Vector3::calc_normalized_vector(&ray_in.direction);
+            let unit_direction = Vector3::calc_normalized_vector(direction);
+            let pdf = henyey_greenstein_phase(Vector3::dot_product(&forward, &unit_direction), *g);
+
+            let albedo = get_map_value(
+                map_in,
+                hit_record.u,
+                hit_record.v,
+                hit_record.point,
+                hit_record.uv_footprint,
+            );
+            Some((pdf * albedo, pdf))
+        }
+        Material::Bump(base_material, height_map, scale) => {
+            let mut bumped_record = hit_record.clone();
+            bumped_record.shading_normal = bump_shading_normal(height_map, *scale, hit_record);
+            evaluate_scatter(base_material, ray_in, &bumped_record, direction)
+        }
+        Material::NormalMap(base_material, normal_map) => {
+            let mut mapped_record = hit_record.clone();
+            mapped_record.shading_normal = normal_map_shading_normal(normal_map, hit_record);
+            evaluate_scatter(base_material, ray_in, &mapped_record, direction)
+        }
+        Material::Cutout(base_material, _) => {
+            evaluate_scatter(base_material, ray_in, hit_record, direction)
+        }
+        Material::Metal(_, _)
+        | Material::Dielectric(_)
+        | Material::Subsurface(_)
+        | Material::Emissive(_) => None,
+    }
+}
+
+/// Get the light emitted by a material at a hit point towards the ray's origin
+pub fn get_emitted(hit_material: &Material, hit_record: &HitRecord) -> Vector3 {
+    match hit_material {
+        Material::Emissive(map_in) => {
+            if hit_record.front_face {
+                get_map_value(
+                    map_in,
+                    hit_record.u,
+                    hit_record.v,
+                    hit_record.point,
+                    hit_record.uv_footprint,
+                )
+            } else {
+                Vector3 {
+                    x: 0.0,
+                    y: 0.0,
+                    z: 0.0,
+                }
+            }
+        }
+        Material::Bump(base_material, _, _)
+        | Material::NormalMap(base_material, _)
+        | Material::Cutout(base_material, _) => get_emitted(base_material, hit_record),
+        _ => Vector3 {
+            x: 0.0,
+            y: 0.0,
+            z: 0.0,
+        },
+    }
+}
+
+/// Get the opacity of a material at a hit point. 0.0 is fully transparent and 1.0 is fully opaque.
+pub fn get_opacity(hit_material: &Material, hit_record: &HitRecord) -> f64 {
+    match hit_material {
+        Material::Cutout(base_material, opacity_map) => {
+            let opacity = get_map_opacity(
+                opacity_map,
+                hit_record.u,
+                hit_record.v,
+                hit_record.point,
+                hit_record.uv_footprint,
+            );
+            opacity * get_opacity(base_material, hit_record)
+        }
+        Material::Bump(base_material, _, _) => get_opacity(base_material, hit_record),
+        Material::NormalMap(base_material, _) => get_opacity(base_material, hit_record),
+        _ => 1.0,
+    }
+}
+
+/// The kinds of scattering that a material can do
+#[derive(Clone, Copy, PartialEq)]
+pub enum Lobe {
+    Diffuse, // Scattering spread over many directions, including volumes and subsurface scattering
+    Specular, // Mirror-like reflection
+    Transmission, // Refraction through the surface
+}
+
+/// Get the kind of scattering that sent ray_in off in a direction from a hit point
+pub fn get_lobe(
+    hit_material: &Material,
+    ray_in: &Ray,
+    hit_record: &HitRecord,
+    direction: &Vector3,
+) -> Lobe {
+    // Whether the scattered ray leaves on the side of the surface that ray_in came from
+    let is_reflected = Vector3::dot_product(direction, &hit_record.normal).signum()
+        != Vector3::dot_product(&ray_in.direction, &hit_record.normal).signum();
+
+    match hit_material {
+        Material::Diffuse(_) | Material::HenyeyGreenstein(_, _) | Material::Emissive(_) => {
+            Lobe::Diffuse
+        }
+        Material::Metal(_, _) => Lobe::Specular,
+        Material::Dielectric(_) => {
+            if is_reflected {
+                Lobe::Specular
+            } else {
+                Lobe::Transmission
+            }
+        }
+        Material::Subsurface(_) => {
+            // Light that refracts in comes back out spread over many directions
+            if is_reflected {
+                Lobe::Specular
+            } else {
+                Lobe::Diffuse
+            }
+        }
+        Material::Bump(base_material, _, _)
+        | Material::NormalMap(base_material, _)
+        | Material::Cutout(base_material, _) => {
+            get_lobe(base_material, ray_in, hit_record, direction)
+        }
+    }
+}
+
+/// Get the overall color of a material at a hit point, without any lighting. Materials that don't tint the light
+/// passing through them, like glass and lights, are white.
+pub fn get_albedo(hit_material: &Material, hit_record: &HitRecord) -> Vector3 {
+    let white = Vector3 {
+        x: 1.0,
+        y: 1.0,
+        z: 1.0,
+    };
+    let map_value = |map_in: &map::Map| {
+        get_map_value(
+            map_in,
+            hit_record.u,
+            hit_record.v,
+            hit_record.point,
+            hit_record.uv_footprint,
+        )
+    };
+
+    match hit_material {
+        Material::Diffuse(map_in) => map_value(map_in),
+        Material::Metal(albedo, _) => *albedo,
+        Material::Dielectric(_) => white,
+        Material::HenyeyGreenstein(map_in, _) => map_value(map_in),
+        Material::Subsurface(data) => map_value(&data.albedo),
+        Material::Bump(base_material, _, _)
+        | Material::NormalMap(base_material, _)
+        | Material::Cutout(base_material, _) => get_albedo(base_material, hit_record),
+        Material::Emissive(_) => white,
+    }
+}
+
+/// Get the shading normal of a material at a hit point, including any bumps or normal maps
+pub fn get_shading_normal(hit_material: &Material, hit_record: &HitRecord) -> Vector3 {
+    match hit_material {
+        Material::Bump(base_material, height_map, scale) => {
+            let mut bumped_record = hit_record.clone();
+            bumped_record.shading_normal = bump_shading_normal(height_map, *scale, hit_record);
+            get_shading_normal(base_material, &bumped_record)
+        }
+        Material::NormalMap(base_material, normal_map) => {
+            let mut mapped_record = hit_record.clone();
+            mapped_record.shading_normal = normal_map_shading_normal(normal_map, hit_record);
+            get_shading_normal(base_material, &mapped_record)
+        }
+        Material::Cutout(base_material, _) => get_shading_normal(base_material, hit_record),
+        _ => hit_record.shading_normal,
+    }
+}
+
+/// Returns the shading normal of the hit record flipped to point out of the surface
+fn outward_shading_normal(hit_record: &HitRecord) -> Vector3 {
+    if hit_record.front_face {
+        hit_record.shading_normal
+    } else {
+        -1.0 * hit_record.shading_normal
+    }
+}
+
+/// Converts a normal pointing out of the surface back to the side the hit record's normals face
+fn facing_shading_normal(hit_record: &HitRecord, outward_normal: Vector3) -> Vector3 {
+    if hit_record.front_face {
+        outward_normal
+    } else {
+        -1.0 * outward_normal
+    }
+}
+
+/// Calculates the shading normal for a surface displaced along its normal by the height map.
+///
+/// The height map's channels are averaged to get the height. We use forward differences to find how the height
+/// changes with u and v, which tilts the tangents and therefore the normal.
+fn bump_shading_normal(height_map: &map::Map, scale: f64, hit_record: &HitRecord) -> Vector3 {
+    let height = |u: f64, v: f64, p: Vector3| -> f64 {
+        let value = get_map_value(height_map, u, v, p, hit_record.uv_footprint);
+        scale * (value.x + value.y + value.z) / 3.0
+    };
+
+    let u = hit_record.u;
+    let v = hit_record.v;
+    let p = hit_record.point;
+    let delta = 0.0005;
+
+    let base_height = height(u, v, p);
+    let dh_du = (height(u + delta, v, p + delta * hit_record.dpdu) - base_height) / delta;
+    let dh_dv = (height(u, v + delta, p + delta * hit_record.dpdv) - base_height) / delta;
+
+    let normal = outward_shading_normal(hit_record);
+    let bumped_dpdu = hit_record.dpdu + dh_du * normal;
+    let bumped_dpdv = hit_record.dpdv + dh_dv * normal;
+
+    let bumped_normal = calc_cross_product(&bumped_dpdu, &bumped_dpdv);
+    if bumped_normal.magnitude_squared() < 1e-16 {
+        return hit_record.shading_normal;
+    }
+    let bumped_normal = Vector3::calc_normalized_vector(&bumped_normal);
+
+    // Keep the bumped normal on the same side of the surface as the unperturbed one
+    let bumped_normal = if Vector3::dot_product(&bumped_normal, &normal) < 0.0 {
+        -1.0 * bumped_normal
+    } else {
+        bumped_normal
+    };
+
+    facing_shading_normal(hit_record, bumped_normal)
+}
+
+/// Calculates the shading normal from a tangent space normal map.
+///
+/// The map's red, green, and blue channels in [0, 1] are remapped to [-1, 1] and used as the coordinates of the normal
+/// along the u tangent, the v tangent, and the surface normal.
+fn normal_map_shading_normal(normal_map: &map::Map, hit_record: &HitRecord) -> Vector3 {
+    let normal = outward_shading_normal(hit_record);
+
+    // Build an orthonormal tangent frame by removing the normal's component from dpdu
+    let tangent = hit_record.dpdu - Vector3::dot_product(&hit_record.dpdu, &normal) * normal;
+    let tangent = if tangent.magnitude_squared() < 1e-16 {
+        orthonormal_basis(&normal).0
+    } else {
+        Vector3::calc_normalized_vector(&tangent)
+    };
+    let bitangent = calc_cross_product(&normal, &tangent);
+
+    let value = get_map_value(
+        normal_map,
+        hit_record.u,
+        hit_record.v,
+        hit_record.point,
+        hit_record.uv_footprint,
+    );
+    let mapped_normal = (2.0 * value.x - 1.0) * tangent
+        + (2.0 * value.y - 1.0) * bitangent
+        + (2.0 * value.z - 1.0) * normal;
+    if mapped_normal.magnitude_squared() < 1e-16 {
+        return hit_record.shading_normal;
+    }
+
+    facing_shading_normal(hit_record, Vector3::calc_normalized_vector(&mapped_normal))
+}
+
+/// Choose between reflection and refraction at the boundary of a dielectric with refraction index ri.
+/// Returns the direction of the scattered ray.
+fn scatter_dielectric_boundary(
+    ri: f64,
+    direction_in: &Vector3,
+    hit_point_normal: &Vector3,
+    front_face: bool,
+    choice: f64,
+) -> Vector3 {
+    let refraction_index = if front_face {
+        // If we hit the front face, we need to switch the refraction index to have enclosing media's eta over the enclosed media's
+        1.0 / ri
+    } else {
+        ri
+    };
+
+    let unit_direction = Vector3::calc_normalized_vector(direction_in);
+
+    // Determine whether we need to reflect or refract
+    let cos_theta = f64::min(
+        Vector3::dot_product(&(-1.0 * unit_direction), hit_point_normal),
+        1.0,
+    );
+    let sin_theta = (1.0 - (cos_theta * cos_theta)).sqrt();
+
+    let cannot_refract = (refraction_index * sin_theta) > 1.0;
+
+    if cannot_refract || reflectance(cos_theta, refraction_index) > choice {
+        reflect(&unit_direction, hit_point_normal)
+    } else {
+        refract(&unit_direction, hit_point_normal, refraction_index)
     }
 }
 
+/// Samples a scattered direction from the Henyey-Greenstein phase function.
+/// 'forward' is the unit direction the light was travelling in before scattering.
+fn sample_henyey_greenstein(forward: &Vector3, g: f64, u: &Vector2) -> Vector3 {
+    let xi = u.x;
+
+    // The cosine of the angle between the forward direction and the scattered direction
+    let cos_theta = if g.abs() < 1e-3 {
+        // Isotropic scattering. The general formula divides by g, so we special-case it.
+        1.0 - 2.0 * xi
+    } else {
+        let term = (1.0 - g * g) / (1.0 - g + 2.0 * g * xi);
+        (1.0 + g * g - term * term) / (2.0 * g)
+    };
+    let cos_theta = cos_theta.clamp(-1.0, 1.0);
+    let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
+    let phi = 2.0 * std::f64::consts::PI * u.y;
+
+    let (a, b) = orthonormal_basis(forward);
+    (sin_theta * phi.cos()) * a + (sin_theta * phi.sin()) * b + cos_theta * forward
+}
+
+/// The Henyey-Greenstein phase function for the cosine of the angle between the forward and scattered directions
+fn henyey_greenstein_phase(cos_theta: f64, g: f64) -> f64 {
+    let denominator = 1.0 + g * g - 2.0 * g * cos_theta;
+    (1.0 - g * g) / (4.0 * std::f64::consts::PI * denominator * denominator.sqrt())
+}
+
 /// Calculates the reflectance of a dielectric material using Schlick's approximation.
 fn reflectance(cos_theta: f64, refraction_index: f64) -> f64 {
     let r0 = (1.0 - refraction_index) / (1.0 + refraction_index);
@@ -119,3 +646,186 @@ fn reflectance(cos_theta: f64, refraction_index: f64) -> f64 {
 
     r0 + (1.0 - r0) * (1.0 - cos_theta).powi(5)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        map::{FilterMode, ImageData, TextureSampler, WrapMode},
+        quad::{Quad, hit_quad},
+        vector::calc_component_product,
+    };
+    use rand::{Rng, SeedableRng, rngs::SmallRng};
+
+    /// Follow light through a slab of a material between z = 0 and z = 1, returning the weight that it leaves the top
+    /// of the slab with and the weight that it leaves the bottom with
+    fn walk_through_slab(material: &Material, rng: &mut SmallRng) -> (Vector3, Vector3) {
+        let mut ray = Ray {
+            origin: Vector3 {
+                x: 0.0,
+                y: 0.0,
+                z: 2.0,
+            },
+            direction: Vector3 {
+                x: 0.3,
+                y: 0.0,
+                z: -1.0,
+            },
+            time: 0.0,
+        };
+        let mut throughput = Vector3 {
+            x: 1.0,
+            y: 1.0,
+            z: 1.0,
+        };
+        let mut inside = false;
+        loop {
+            if !inside {
+                if ray.direction.z > 0.0 && ray.origin.z > 0.5 {
+                    return (throughput, Vector3::default());
+                }
+                if ray.direction.z < 0.0 && ray.origin.z < 0.5 {
+                    return (Vector3::default(), throughput);
+                }
+            }
+
+            // The plane that the ray reaches next, with its normal pointing out of the slab
+            let plane_z = if (ray.direction.z > 0.0) == inside {
+                1.0
+            } else {
+                0.0
+            };
+            let outward = Vector3 {
+                x: 0.0,
+                y: 0.0,
+                z: 2.0 * plane_z - 1.0,
+            };
+            let t = (plane_z - ray.origin.z) / ray.direction.z;
+            let record = HitRecord::new(&ray, outward, t, 0, 0.0, 0.0);
+            let values = ScatterValues {
+                choice: rng.random_range(0.0..1.0),
+                channel: rng.random_range(0.0..1.0),
+                distance: rng.random_range(0.0..1.0),
+                direction: Vector2 {
+                    x: rng.random_range(0.0..1.0),
+                    y: rng.random_range(0.0..1.0),
+                },
+            };
+            let (attenuation, scattered) = scatter_ray(material, &ray, &record, &values)
+                .expect("Light was absorbed by a slab that doesn't absorb any");
+
+            throughput = calc_component_product(&throughput, &attenuation);
+            let on_boundary = (scattered.origin - record.point).magnitude() < 1e-12;
+            inside = !on_boundary || Vector3::dot_product(&scattered.direction, &outward) < 0.0;
+            ray = scattered;
+        }
+    }
+
+    #[test]
+    fn subsurface_slab_without_absorption_conserves_energy() {
+        // The channels scatter at different rates, so the walk is weighted by the balance heuristic. Their rates are
+        // close, since the weights multiply at every scattering event and far apart rates make them very noisy.
+        let material = Material::Subsurface(Box::new(SubsurfaceData::new(
+            map::Map::Color(Vector3 {
+                x: 1.0,
+                y: 1.0,
+                z: 1.0,
+            }),
+            map::Map::Color(Vector3 {
+                x: 0.4,
+                y: 0.5,
+                z: 0.6,
+            }),
+            1.4,
+        )));
+
+        let mut rng = SmallRng::seed_from_u64(0);
+        let path_count = 50000;
+        let mut reflectance = Vector3::default();
+        let mut transmittance = Vector3::default();
+        for _ in 0..path_count {
+            let (reflected, transmitted) = walk_through_slab(&material, &mut rng);
+            reflectance = reflectance + (1.0 / path_count as f64) * reflected;
+            transmittance = transmittance + (1.0 / path_count as f64) * transmitted;
+        }
+
+        let total = reflectance + transmittance;
+        for channel in [total.x, total.y, total.z] {
+            assert!((channel - 1.0).abs() < 0.02, "{}", channel);
+        }
+        // Thicker media reflect more of the light
+        assert!(reflectance.x > reflectance.z && transmittance.x < transmittance.z);
+    }
+
+    /// Hit a square in the xy plane facing +z, from in front of it or from behind it
+    fn hit_square(front: bool) -> HitRecord {
+        let quad = Quad::new(
+            Vector3 {
+                x: -1.0,
+                y: -1.0,
+                z: 0.0,
+            },
+            Vector3 {
+                x: 2.0,
+                y: 0.0,
+                z: 0.0,
+            },
+            Vector3 {
+                x: 0.0,
+                y: 2.0,
+                z: 0.0,
+            },
+            0,
+        );
+        let side = if front { 1.0 } else { -1.0 };
+        let ray = Ray {
+            origin: Vector3 {
+                x: 0.2,
+                y: 0.1,
+                z: side,
+            },
+            direction: Vector3 {
+                x: 0.0,
+                y: 0.0,
+                z: -side,
+            },
+            time: 0.0,
+        };
+        hit_quad(&ray, &quad, 0.001, f64::INFINITY).unwrap()
+    }
+
+    #[test]
+    fn constant_height_map_keeps_the_shading_normal() {
+        let height_map = map::Map::Color(Vector3 {
+            x: 0.7,
+            y: 0.7,
+            z: 0.7,
+        });
+        for front in [true, false] {
+            let record = hit_square(front);
+            let normal = bump_shading_normal(&height_map, 3.0, &record);
+            assert!((normal - record.shading_normal).magnitude() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn bumped_back_faces_keep_the_normal_on_their_side() {
+        // A ramp rising along u, steep enough to tilt the normal far from the geometric one
+        let image = ImageData::from_pixels(2, 1, 3, vec![0.0, 0.0, 0.0, 1.0, 1.0, 1.0]);
+        let height_map = map::Map::Image(
+            image,
+            TextureSampler::new(WrapMode::Clamp, FilterMode::Bilinear),
+        );
+        let front_record = hit_square(true);
+        let back_record = hit_square(false);
+        assert!(!back_record.front_face);
+        let front_normal = bump_shading_normal(&height_map, 2.0, &front_record);
+        let back_normal = bump_shading_normal(&height_map, 2.0, &back_record);
+
+        assert!(Vector3::dot_product(&back_normal, &back_record.normal) > 0.0);
+        assert!(Vector3::dot_product(&front_normal, &front_record.normal) > 0.0);
+        assert!(front_normal.x.abs() > 0.5);
+        // Both sides see the same bumped surface
+        assert!((front_normal + back_normal).magnitude() < 1e-9);
+    }
+}
@@ -1,4 +1,4 @@
-use rand::{Rng, rngs::ThreadRng};
+use rand::{Rng, SeedableRng, rngs::SmallRng};
 
 use crate::{raytrace_vector::random_vector, vector::Vector3};
 
@@ -12,8 +12,9 @@ pub struct Perlin {
 }
 
 impl Perlin {
-    pub fn new() -> Self {
-        let mut rng = ThreadRng::default();
+    /// Create noise with its own random gradients and permutations. The same seed always gives the same noise.
+    pub fn new(seed: u64) -> Self {
+        let mut rng = SmallRng::seed_from_u64(seed);
         let mut rand_vec: [Vector3; POINT_COUNT] = [Vector3 {
             x: 0.0,
             y: 0.0,
@@ -101,7 +102,7 @@ impl Perlin {
         accumulation.abs()
     }
 
-    fn generate_perm(rng: &mut ThreadRng) -> [usize; POINT_COUNT] {
+    fn generate_perm(rng: &mut SmallRng) -> [usize; POINT_COUNT] {
         let mut p: [usize; POINT_COUNT] = [0; POINT_COUNT];
 
         for index in 0..p.len() {
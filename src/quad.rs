@@ -49,7 +49,7 @@ pub fn hit_quad(ray_in: &Ray, quad_in: &Quad, tmin: f64, tmax: f64) -> Option<Hi
 
     // Return false if the hit point parameter t is outside the interval
     let t = (quad_in.d - Vector3::dot_product(&quad_in.normal, &ray_in.origin)) / denom;
-    if (t > tmax) || (t < tmin) {
+    if (t >= tmax) || (t <= tmin) {
         return None;
     }
 
@@ -73,12 +73,49 @@ pub fn hit_quad(ray_in: &Ray, quad_in: &Quad, tmin: f64, tmax: f64) -> Option<Hi
         }
     };
 
-    Some(HitRecord::new(
-        ray_in,
-        quad_in.normal,
-        t,
-        quad_in.material,
-        u,
-        v,
-    ))
+    let mut hit_record = HitRecord::new(ray_in, quad_in.normal, t, quad_in.material, u, v);
+    hit_record.dpdu = quad_in.u;
+    hit_record.dpdv = quad_in.v;
+
+    Some(hit_record)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tangents_cross_along_the_outward_normal() {
+        let quad = Quad::new(
+            Vector3 {
+                x: 0.0,
+                y: 1.0,
+                z: 0.0,
+            },
+            Vector3 {
+                x: 2.0,
+                y: 0.0,
+                z: 1.0,
+            },
+            Vector3 {
+                x: 0.0,
+                y: 3.0,
+                z: 0.5,
+            },
+            0,
+        );
+        let target = quad.q + 0.3 * quad.u + 0.6 * quad.v;
+        for side in [1.0, -1.0] {
+            let ray = Ray {
+                origin: target + (side * 5.0) * quad.normal,
+                direction: (-side) * quad.normal,
+                time: 0.0,
+            };
+            let record = hit_quad(&ray, &quad, 0.001, f64::INFINITY).unwrap();
+            assert_eq!(record.front_face, side > 0.0);
+            let tangent_normal =
+                Vector3::calc_normalized_vector(&calc_cross_product(&record.dpdu, &record.dpdv));
+            assert!((tangent_normal - quad.normal).magnitude() < 1e-9);
+        }
+    }
 }
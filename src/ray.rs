@@ -13,3 +13,12 @@ pub struct Ray {
 pub fn at(ray: &Ray, t: f64) -> Vector3 {
     ray.origin + t * ray.direction
 }
+
+/// The offset rays for neighboring pixels, which estimate how much of the scene a ray covers.
+/// Only camera rays carry differentials.
+pub struct RayDifferential {
+    pub rx_origin: Vector3,    // The origin of the ray one pixel to the right
+    pub rx_direction: Vector3, // The direction of the ray one pixel to the right
+    pub ry_origin: Vector3,    // The origin of the ray one pixel down
+    pub ry_direction: Vector3, // The direction of the ray one pixel down
+}
@@ -1,9 +1,11 @@
-use rand::{Rng, rngs::ThreadRng};
+use std::f64::consts::{FRAC_PI_2, FRAC_PI_4};
 
-use crate::vector::Vector3;
+use rand::{Rng, rngs::SmallRng};
+
+use crate::vector::{Vector2, Vector3, calc_cross_product};
 
 /// Returns a random unit vector
-pub fn random_vector(rng: &mut ThreadRng) -> Vector3 {
+pub fn random_vector(rng: &mut SmallRng) -> Vector3 {
     loop {
         let result = Vector3 {
             x: rng.random_range(-1.0..1.0),
@@ -19,23 +21,41 @@ pub fn random_vector(rng: &mut ThreadRng) -> Vector3 {
     }
 }
 
-pub fn random_vector_in_unit_disk(rng: &mut ThreadRng) -> Vector3 {
-    loop {
-        let result = Vector3 {
-            x: rng.random_range(-1.0..1.0),
-            y: rng.random_range(-1.0..1.0),
-            z: 0.0,
-        };
+/// Map two uniform values in [0, 1) to a point in the unit disk in the xy plane. The concentric mapping keeps
+/// neighboring values close together, so stratified values stay stratified over the disk.
+pub fn sample_unit_disk(u: &Vector2) -> Vector3 {
+    let a = 2.0 * u.x - 1.0;
+    let b = 2.0 * u.y - 1.0;
+    if a == 0.0 && b == 0.0 {
+        return Vector3::default();
+    }
 
-        // We need to make sure we don't square too small of a vector
-        if result.magnitude_squared() <= 1.0 {
-            return result;
-        }
+    let (radius, theta) = if a.abs() > b.abs() {
+        (a, FRAC_PI_4 * (b / a))
+    } else {
+        (b, FRAC_PI_2 - FRAC_PI_4 * (a / b))
+    };
+    Vector3 {
+        x: radius * theta.cos(),
+        y: radius * theta.sin(),
+        z: 0.0,
+    }
+}
+
+/// Map two uniform values in [0, 1) to a point on the unit sphere, evenly over its area
+pub fn sample_unit_sphere(u: &Vector2) -> Vector3 {
+    let z = 1.0 - 2.0 * u.x;
+    let radius = f64::max(1.0 - z * z, 0.0).sqrt();
+    let phi = 2.0 * std::f64::consts::PI * u.y;
+    Vector3 {
+        x: radius * phi.cos(),
+        y: radius * phi.sin(),
+        z,
     }
 }
 
 /// Returns a random unit vector that faces the same hemisphere as a surface normal
-pub fn random_on_hemisphere(rng: &mut ThreadRng, normal: Vector3) -> Vector3 {
+pub fn random_on_hemisphere(rng: &mut SmallRng, normal: Vector3) -> Vector3 {
     let vector = random_vector(rng);
 
     if Vector3::dot_product(&vector, &normal) > 0.0 {
@@ -62,3 +82,25 @@ pub fn refract(uv: &Vector3, n: &Vector3, etai_over_etat: f64) -> Vector3 {
     let r_out_parallel = -1.0 * (1.0 - r_out_perp.magnitude_squared()).abs().sqrt() * n;
     r_out_perp + r_out_parallel
 }
+
+/// Builds two unit vectors that form an orthonormal basis together with the unit vector n
+pub fn orthonormal_basis(n: &Vector3) -> (Vector3, Vector3) {
+    // Pick the axis that is least aligned with n so the cross product is well conditioned
+    let helper = if n.x.abs() > 0.9 {
+        Vector3 {
+            x: 0.0,
+            y: 1.0,
+            z: 0.0,
+        }
+    } else {
+        Vector3 {
+            x: 1.0,
+            y: 0.0,
+            z: 0.0,
+        }
+    };
+    let a = Vector3::calc_normalized_vector(&calc_cross_product(n, &helper));
+    let b = calc_cross_product(n, &a);
+
+    (a, b)
+}
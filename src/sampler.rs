@@ -0,0 +1,388 @@
+use crate::vector::Vector2;
+
+/// How the sample values for each pixel are spread over their dimensions
+#[derive(Clone, Copy)]
+pub enum SamplePattern {
+    Independent, // Every value is uniformly random on its own
+    Stratified,  // Values are jittered within separate strata so that they can't clump together
+    Halton,      // The Halton sequence with Owen scrambling for each pixel
+    Sobol,       // The Sobol sequence with Owen scrambling for each pixel
+}
+
+/// Supplies the values in [0, 1) that a pixel sample uses to pick its position, lens point, time, and the random
+/// choices at each bounce. Every value is a deterministic function of the pixel, the sample index, and the dimension,
+/// so the samples of a pixel are spread out over each dimension instead of being independent.
+///
+/// Each pixel sample starts at dimension 0 with start_pixel_sample, and each get_1d or get_2d call moves on to the
+/// next dimension. Callers should request the same dimensions in the same order for every sample of a pixel, even
+/// when a value ends up unused, so that matching dimensions line up across samples.
+pub struct Sampler {
+    pattern: SamplePattern,
+    sample_count: u32, // The number of samples for each pixel
+    grid_size: u32,    // The number of strata along each side of a 2D stratified grid
+
+    seed: u32,       // Changes every value, for renders that differ only in their noise
+    pixel_seed: u32, // A hash of the current pixel and the seed
+    sample_index: u32,
+    dimension: u32,
+    primes: Vec<u32>, // The bases of the Halton dimensions
+}
+
+// Halton dimensions past this many fall back to independent values, since the sequence in large bases has too few
+// digits to be useful at typical sample counts
+const HALTON_DIMENSION_COUNT: usize = 1000;
+
+impl Sampler {
+    pub fn new(pattern: SamplePattern, sample_count: i32) -> Self {
+        let sample_count = u32::max(sample_count as u32, 1);
+        let primes = match pattern {
+            SamplePattern::Halton => first_primes(HALTON_DIMENSION_COUNT),
+            _ => vec![],
+        };
+
+        Self {
+            pattern,
+            sample_count,
+            grid_size: u32::max((sample_count as f64).sqrt().ceil() as u32, 1),
+            seed: 0,
+            pixel_seed: 0,
+            sample_index: 0,
+            dimension: 0,
+            primes,
+        }
+    }
+
+    pub fn get_pattern(&self) -> SamplePattern {
+        self.pattern
+    }
+
+    /// Change the number of samples for each pixel, which decides the size of the strata
+    pub fn set_sample_count(&mut self, sample_count: i32) {
+        self.sample_count = u32::max(sample_count as u32, 1);
+        self.grid_size = u32::max((self.sample_count as f64).sqrt().ceil() as u32, 1);
+    }
+
+    /// Change the seed that every value depends on
+    pub fn set_seed(&mut self, seed: u32) {
+        self.seed = seed;
+    }
+
+    /// Start the values for one sample of a pixel
+    pub fn start_pixel_sample(&mut self, x: i32, y: i32, sample_index: i32) {
+        self.pixel_seed = hash(&[x as u32, y as u32, self.seed]);
+        self.sample_index = sample_index as u32;
+        self.dimension = 0;
+    }
+
+    /// Get the value of the next dimension
+    pub fn get_1d(&mut self) -> f64 {
+        let dimension = self.dimension;
+        self.dimension += 1;
+        let seed = hash(&[self.pixel_seed, dimension]);
+
+        match self.pattern {
+            SamplePattern::Independent => self.independent_value(seed, 0),
+            SamplePattern::Stratified => {
+                let stratum = permutation_element(self.sample_index, self.sample_count, seed);
+                (stratum as f64 + self.independent_value(seed, 0)) / self.sample_count as f64
+            }
+            SamplePattern::Halton => self.halton_value(dimension, seed),
+            SamplePattern::Sobol => {
+                let index = nested_uniform_scramble(self.sample_index, seed);
+                let value = nested_uniform_scramble(sobol(index, 0), hash(&[seed, 1]));
+                to_unit_float(value)
+            }
+        }
+    }
+
+    /// Get the values of the next two dimensions, which are stratified together
+    pub fn get_2d(&mut self) -> Vector2 {
+        let dimension = self.dimension;
+        self.dimension += 2;
+        let seed = hash(&[self.pixel_seed, dimension]);
+
+        match self.pattern {
+            SamplePattern::Independent => Vector2 {
+                x: self.independent_value(seed, 0),
+                y: self.independent_value(seed, 1),
+            },
+            SamplePattern::Stratified => {
+                // The sample count may not be square, so some cells of the grid can go unused
+                let cell_count = self.grid_size * self.grid_size;
+                let cell = permutation_element(self.sample_index, cell_count, seed);
+                let size = self.grid_size as f64;
+                Vector2 {
+                    x: ((cell % self.grid_size) as f64 + self.independent_value(seed, 0)) / size,
+                    y: ((cell / self.grid_size) as f64 + self.independent_value(seed, 1)) / size,
+                }
+            }
+            SamplePattern::Halton => Vector2 {
+                x: self.halton_value(dimension, seed),
+                y: self.halton_value(dimension + 1, hash(&[seed, 1])),
+            },
+            SamplePattern::Sobol => {
+                // Each pair of dimensions uses the first two Sobol dimensions with its own shuffle of the sample
+                // indices, which keeps the pairs from being correlated with each other
+                let index = nested_uniform_scramble(self.sample_index, seed);
+                Vector2 {
+                    x: to_unit_float(nested_uniform_scramble(sobol(index, 0), hash(&[seed, 1]))),
+                    y: to_unit_float(nested_uniform_scramble(sobol(index, 1), hash(&[seed, 2]))),
+                }
+            }
+        }
+    }
+
+    /// A uniform value for the current sample that only depends on the seed and an offset
+    fn independent_value(&self, seed: u32, offset: u32) -> f64 {
+        to_unit_float(hash(&[seed, self.sample_index, offset]))
+    }
+
+    /// The Halton sequence in the dimension's prime base, Owen scrambled for the pixel
+    fn halton_value(&self, dimension: u32, seed: u32) -> f64 {
+        match self.primes.get(dimension as usize) {
+            Some(base) => owen_scrambled_radical_inverse(self.sample_index, *base, seed),
+            None => self.independent_value(seed, 0),
+        }
+    }
+}
+
+/// Mirror the digits of an index in a base about the decimal point, randomly permuting each digit based on the digits
+/// before it. Without the scrambling, a few samples in a large base would all land near 0.0.
+fn owen_scrambled_radical_inverse(index: u32, base: u32, seed: u32) -> f64 {
+    let inverse_base = 1.0 / base as f64;
+    let mut index = index;
+    let mut value = 0.0;
+    let mut scale = 1.0; // The place value of the previous digit
+    let mut digit_seed = hash(&[seed, 0, 0]);
+
+    // Keep going past the index's last digit until the digits are too small to matter, since the scrambled zeros
+    // still shift the value. The digits are added up as floats, since in large bases they'd overflow an integer.
+    while 1.0 - (base - 1) as f64 * scale < 1.0 {
+        let digit = index % base;
+        index /= base;
+
+        let digit = permutation_element(digit, base, digit_seed);
+        scale *= inverse_base;
+        value += digit as f64 * scale;
+
+        // Each digit's permutation depends on all of the digits before it
+        digit_seed = hash(&[digit_seed, digit]);
+    }
+
+    f64::min(value, 1.0 - f64::EPSILON)
+}
+
+/// The first two dimensions of the Sobol sequence as 32-bit fixed point values
+fn sobol(index: u32, dimension: u32) -> u32 {
+    let mut result = 0;
+    let mut direction: u32 = 1 << 31;
+    for bit in 0..32 {
+        if (index >> bit) & 1 == 1 {
+            result ^= if dimension == 0 {
+                1 << (31 - bit)
+            } else {
+                direction
+            };
+        }
+        // The direction numbers of the second dimension come from the primitive polynomial x + 1
+        direction ^= direction >> 1;
+    }
+
+    result
+}
+
+/// Owen scramble the bits of a 32-bit fixed point value with a hash-based permutation. Each bit is flipped based on the
+/// bits above it, so values that share leading bits stay together and the stratification is kept.
+/// This follows Burley's "Practical Hash-based Owen Scrambling".
+fn nested_uniform_scramble(value: u32, seed: u32) -> u32 {
+    let mut x = value.reverse_bits();
+    x = x.wrapping_add(seed);
+    x ^= x.wrapping_mul(0x6c50b47c);
+    x ^= x.wrapping_mul(0xb82f1e52);
+    x ^= x.wrapping_mul(0xc7afe638);
+    x ^= x.wrapping_mul(0x8d22f6e6);
+    x.reverse_bits()
+}
+
+/// The element at an index of a random permutation of [0, length), picked by the seed.
+/// This is Kensler's hashed permutation from "Correlated Multi-Jittered Sampling".
+fn permutation_element(index: u32, length: u32, seed: u32) -> u32 {
+    let mut mask = length.wrapping_sub(1);
+    mask |= mask >> 1;
+    mask |= mask >> 2;
+    mask |= mask >> 4;
+    mask |= mask >> 8;
+    mask |= mask >> 16;
+
+    // Keep permuting within the next power of two until the result lands inside of the range
+    let mut i = index;
+    loop {
+        i ^= seed;
+        i = i.wrapping_mul(0xe170893d);
+        i ^= seed >> 16;
+        i ^= (i & mask) >> 4;
+        i ^= seed >> 8;
+        i = i.wrapping_mul(0x0929eb3f);
+        i ^= seed >> 23;
+        i ^= (i & mask) >> 1;
+        i = i.wrapping_mul(1 | (seed >> 27));
+        i = i.wrapping_mul(0x6935fa69);
+        i ^= (i & mask) >> 11;
+        i = i.wrapping_mul(0x74dcb303);
+        i ^= (i & mask) >> 2;
+        i = i.wrapping_mul(0x9e501cc3);
+        i ^= (i & mask) >> 2;
+        i = i.wrapping_mul(0xc860a3df);
+        i &= mask;
+        i ^= i >> 5;
+        if i < length {
+            return i.wrapping_add(seed) % length;
+        }
+    }
+}
+
+/// Mix values into a well distributed 32-bit hash
+pub fn hash(values: &[u32]) -> u32 {
+    let mut result: u32 = 0x9e3779b9;
+    for value in values {
+        result ^= value
+            .wrapping_add(0x9e3779b9)
+            .wrapping_add(result << 6)
+            .wrapping_add(result >> 2);
+
+        // The murmur3 finalizer
+        result ^= result >> 16;
+        result = result.wrapping_mul(0x85ebca6b);
+        result ^= result >> 13;
+        result = result.wrapping_mul(0xc2b2ae35);
+        result ^= result >> 16;
+    }
+
+    result
+}
+
+/// Map a 32-bit fixed point value to [0, 1)
+fn to_unit_float(value: u32) -> f64 {
+    value as f64 / 4294967296.0
+}
+
+/// The first count prime numbers
+fn first_primes(count: usize) -> Vec<u32> {
+    let mut primes: Vec<u32> = Vec::with_capacity(count);
+    let mut candidate = 2;
+    while primes.len() < count {
+        if primes
+            .iter()
+            .take_while(|prime| *prime * *prime <= candidate)
+            .all(|prime| candidate % prime != 0)
+        {
+            primes.push(candidate);
+        }
+        candidate += 1;
+    }
+
+    primes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Get the first count 2D values of a pixel from each sample
+    fn first_2d_values(pattern: SamplePattern, count: i32) -> Vec<Vector2> {
+        let mut sampler = Sampler::new(pattern, count);
+        (0..count)
+            .map(|index| {
+                sampler.start_pixel_sample(3, 7, index);
+                sampler.get_2d()
+            })
+            .collect()
+    }
+
+    /// Check that every cell of a size x size grid over [0, 1)^2 has exactly one value
+    fn assert_one_value_per_cell(values: &[Vector2], size: usize) {
+        let mut counts = vec![0; size * size];
+        for value in values {
+            assert!((0.0..1.0).contains(&value.x) && (0.0..1.0).contains(&value.y));
+            let column = (value.x * size as f64) as usize;
+            let row = (value.y * size as f64) as usize;
+            counts[row * size + column] += 1;
+        }
+        assert!(counts.iter().all(|count| *count == 1), "{:?}", counts);
+    }
+
+    #[test]
+    fn stratified_values_fill_every_stratum() {
+        let mut sampler = Sampler::new(SamplePattern::Stratified, 16);
+        let mut strata: Vec<usize> = (0..16)
+            .map(|index| {
+                sampler.start_pixel_sample(0, 0, index);
+                (sampler.get_1d() * 16.0) as usize
+            })
+            .collect();
+        strata.sort();
+        assert_eq!(strata, (0..16).collect::<Vec<usize>>());
+
+        assert_one_value_per_cell(&first_2d_values(SamplePattern::Stratified, 16), 4);
+    }
+
+    #[test]
+    fn sobol_values_are_stratified_in_two_dimensions() {
+        assert_one_value_per_cell(&first_2d_values(SamplePattern::Sobol, 16), 4);
+        assert_one_value_per_cell(&first_2d_values(SamplePattern::Sobol, 64), 8);
+    }
+
+    #[test]
+    fn sobol_scrambling_differs_between_dimensions() {
+        let mut sampler = Sampler::new(SamplePattern::Sobol, 16);
+        sampler.start_pixel_sample(0, 0, 0);
+        let first = sampler.get_2d();
+        let second = sampler.get_2d();
+        assert!(first.x != second.x && first.y != second.y);
+    }
+
+    #[test]
+    fn halton_values_are_stratified_in_each_base() {
+        // The first base^k values of the radical inverse land in different intervals of width 1 / base^k, and
+        // scrambling the digits keeps that true
+        for (base, count) in [(2, 16), (3, 9), (7, 49)] {
+            let mut intervals: Vec<u32> = (0..count)
+                .map(|index| {
+                    let value = owen_scrambled_radical_inverse(index, base, 99);
+                    assert!((0.0..1.0).contains(&value));
+                    (value * count as f64) as u32
+                })
+                .collect();
+            intervals.sort();
+            assert_eq!(intervals, (0..count).collect::<Vec<u32>>());
+        }
+    }
+
+    #[test]
+    fn halton_values_in_large_bases_stay_in_range() {
+        // The digits of a large index in a large base would overflow a 64-bit integer
+        for base in [97, 541] {
+            for index in [u32::MAX - 1, u32::MAX] {
+                let value = owen_scrambled_radical_inverse(index, base, 7);
+                assert!((0.0..1.0).contains(&value));
+            }
+
+            let mut intervals: Vec<u32> = (0..base)
+                .map(|index| (owen_scrambled_radical_inverse(index, base, 7) * base as f64) as u32)
+                .collect();
+            intervals.sort();
+            assert_eq!(intervals, (0..base).collect::<Vec<u32>>());
+        }
+    }
+
+    #[test]
+    fn permutation_visits_every_element() {
+        for length in [1, 5, 16, 17] {
+            let mut elements: Vec<u32> = (0..length)
+                .map(|index| permutation_element(index, length, 1234))
+                .collect();
+            elements.sort();
+            assert_eq!(elements, (0..length).collect::<Vec<u32>>());
+        }
+    }
+}
@@ -119,5 +119,83 @@ pub fn hit_sphere(ray_in: &Ray, sphere_in: &Sphere, tmin: f64, tmax: f64) -> Opt
         (u, v)
     };
 
-    return Some(HitRecord::new(ray_in, normal, t, sphere_in.material, u, v));
+    let mut hit_record = HitRecord::new(ray_in, normal, t, sphere_in.material, u, v);
+
+    // Differentiate the point with respect to u and v. u is 2 * pi times phi and v is pi times theta.
+    let sin_theta = (normal.x * normal.x + normal.z * normal.z).sqrt();
+    if sin_theta > 1e-8 {
+        hit_record.dpdu = (2.0 * std::f64::consts::PI * radius)
+            * Vector3 {
+                x: normal.z,
+                y: 0.0,
+                z: -normal.x,
+            };
+        hit_record.dpdv = (std::f64::consts::PI * radius)
+            * Vector3 {
+                x: -normal.y * normal.x / sin_theta,
+                y: sin_theta,
+                z: -normal.y * normal.z / sin_theta,
+            };
+    } else {
+        // The parameterization is degenerate at the poles, so we keep the default tangents
+    }
+
+    return Some(hit_record);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vector::calc_cross_product;
+
+    #[test]
+    fn tangents_cross_along_the_outward_normal() {
+        let center = Vector3 {
+            x: 1.0,
+            y: 2.0,
+            z: 3.0,
+        };
+        let sphere = Sphere::new(center, 2.0, 0);
+        let directions = [
+            Vector3 {
+                x: 1.0,
+                y: 0.3,
+                z: 0.2,
+            },
+            Vector3 {
+                x: -0.4,
+                y: -0.7,
+                z: 0.5,
+            },
+            Vector3 {
+                x: 0.1,
+                y: 0.5,
+                z: -1.0,
+            },
+        ];
+        for direction in directions {
+            // Hit the front face from outside of the sphere and the back face from its center
+            let rays = [
+                Ray {
+                    origin: center - 10.0 * direction,
+                    direction,
+                    time: 0.0,
+                },
+                Ray {
+                    origin: center,
+                    direction,
+                    time: 0.0,
+                },
+            ];
+            for ray in rays {
+                let record = hit_sphere(&ray, &sphere, 0.001, f64::INFINITY).unwrap();
+                let outward = 0.5 * (record.point - center);
+                let tangent_normal = Vector3::calc_normalized_vector(&calc_cross_product(
+                    &record.dpdu,
+                    &record.dpdv,
+                ));
+                assert!((tangent_normal - outward).magnitude() < 1e-9);
+            }
+        }
+    }
 }
@@ -0,0 +1,351 @@
+use std::fs;
+
+use crate::{
+    checkpoint::{CheckpointReader, CheckpointWriter},
+    film::Film,
+    vector::Vector3,
+};
+
+// Identifies tile files, with a version number at the end
+const TILE_MAGIC: &[u8; 8] = b"RTTILE02";
+
+/// A rectangle of pixels, from x0, y0 up to but not including x1, y1
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct PixelRegion {
+    pub x0: i32,
+    pub y0: i32,
+    pub x1: i32,
+    pub y1: i32,
+}
+
+impl PixelRegion {
+    pub fn contains(&self, x: i32, y: i32) -> bool {
+        x >= self.x0 && x < self.x1 && y >= self.y0 && y < self.y1
+    }
+
+    /// Split the region into tiles of at most tile_size by tile_size pixels, in rows from the top left
+    pub fn split(&self, tile_size: i32) -> Vec<PixelRegion> {
+        let mut tiles = vec![];
+        for y0 in (self.y0..self.y1).step_by(tile_size as usize) {
+            for x0 in (self.x0..self.x1).step_by(tile_size as usize) {
+                tiles.push(PixelRegion {
+                    x0,
+                    y0,
+                    x1: i32::min(x0 + tile_size, self.x1),
+                    y1: i32::min(y0 + tile_size, self.y1),
+                });
+            }
+        }
+        tiles
+    }
+
+    /// Grow the region by a radius in pixels on every side, staying inside of an image
+    pub fn expand(&self, radius: f64, image_width: i32, image_height: i32) -> PixelRegion {
+        let margin = radius.ceil() as i32;
+        PixelRegion {
+            x0: i32::max(self.x0 - margin, 0),
+            y0: i32::max(self.y0 - margin, 0),
+            x1: i32::min(self.x1 + margin, image_width),
+            y1: i32::min(self.y1 + margin, image_height),
+        }
+    }
+}
+
+/// Encode the part of the film that a region's samples reached as a tile. The tile keeps the film's sums rather than
+/// its colors, so that tiles which overlap, or which cover the same pixels with different samples, can be merged into
+/// exactly the image that one render taking all of their samples would have made. The exposure that the image is
+/// shown with goes along with it.
+pub fn encode_tile(
+    film: &Film,
+    region: &PixelRegion,
+    image_width: i32,
+    image_height: i32,
+    exposure: f64,
+) -> CheckpointWriter {
+    let mut writer = CheckpointWriter::new(TILE_MAGIC);
+    writer.write_i32(image_width);
+    writer.write_i32(image_height);
+    writer.write_f64(exposure);
+    for bound in [region.x0, region.y0, region.x1, region.y1] {
+        writer.write_i32(bound);
+    }
+
+    for y in region.y0..region.y1 {
+        for x in region.x0..region.x1 {
+            let (weighted_color, weight) = film.get_sums(x, y);
+            writer.write_vector3(&weighted_color);
+            writer.write_f64(weight);
+        }
+    }
+
+    writer
+}
+
+/// Write a tile of the film to a file. See encode_tile.
+pub fn write_tile(
+    file_path: &str,
+    film: &Film,
+    region: &PixelRegion,
+    image_width: i32,
+    image_height: i32,
+    exposure: f64,
+) {
+    encode_tile(film, region, image_width, image_height, exposure).save(file_path);
+}
+
+/// Adds up tiles into an image. Every tile must come from a render of the same image size and exposure.
+#[derive(Default)]
+pub struct TileMerger {
+    image_size: Option<(i32, i32)>,
+    exposure: Option<f64>,
+    weighted_colors: Vec<Vector3>,
+    weights: Vec<f64>,
+}
+
+impl TileMerger {
+    /// Add a tile's sums to the image. The tile is only added if it's from an image of the same size and exposure as
+    /// the tiles before it. The name says where the tile came from in error messages.
+    pub fn add_tile(&mut self, tile: &Tile, name: &str) -> Result<(), String> {
+        let tile_size = (tile.image_width, tile.image_height);
+        if let Some(size) = self.image_size
+            && size != tile_size
+        {
+            return Err(format!(
+                "The tile from {} is from a {}x{} image, expected {}x{}",
+                name, tile_size.0, tile_size.1, size.0, size.1
+            ));
+        }
+        if let Some(exposure) = self.exposure
+            && exposure != tile.exposure
+        {
+            return Err(format!(
+                "The tile from {} has an exposure of {}, expected {}",
+                name, tile.exposure, exposure
+            ));
+        }
+
+        if self.image_size.is_none() {
+            let pixel_count = tile.image_width as usize * tile.image_height as usize;
+            self.weighted_colors = vec![Vector3::default(); pixel_count];
+            self.weights = vec![0.0; pixel_count];
+            self.image_size = Some(tile_size);
+            self.exposure = Some(tile.exposure);
+        }
+
+        let region = &tile.region;
+        let mut sums = tile.sums.iter();
+        for y in region.y0..region.y1 {
+            for x in region.x0..region.x1 {
+                let index = (y * tile.image_width + x) as usize;
+                let (weighted_color, weight) = sums.next().unwrap();
+                self.weighted_colors[index] = self.weighted_colors[index] + *weighted_color;
+                self.weights[index] += weight;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Get the exposure that the merged image is shown with
+    pub fn get_exposure(&self) -> f64 {
+        self.exposure.expect("No tiles to merge")
+    }
+
+    /// Get the width, height, and colors of the merged image. Pixels that no tile covers are black.
+    pub fn get_image(&self) -> (i32, i32, Vec<Vector3>) {
+        let (image_width, image_height) = self.image_size.expect("No tiles to merge");
+        let uncovered_count = self.weights.iter().filter(|weight| **weight == 0.0).count();
+        if uncovered_count > 0 {
+            eprintln!("{} pixels weren't covered by any tile", uncovered_count);
+        }
+
+        // Match Film::get_color
+        let colors = self
+            .weighted_colors
+            .iter()
+            .zip(&self.weights)
+            .map(|(weighted_color, weight)| {
+                if weight.abs() < 1e-12 {
+                    Vector3::default()
+                } else {
+                    (1.0 / weight) * *weighted_color
+                }
+            })
+            .collect();
+
+        (image_width, image_height, colors)
+    }
+}
+
+/// Merge tile files into an image
+pub fn merge_tiles(file_paths: &[String]) -> TileMerger {
+    let mut merger = TileMerger::default();
+    for file_path in file_paths {
+        let bytes = fs::read(file_path)
+            .unwrap_or_else(|_| panic!("Unable to read file path at {}", file_path));
+        decode_tile(bytes, file_path)
+            .and_then(|tile| merger.add_tile(&tile, file_path))
+            .unwrap_or_else(|error| panic!("{}", error));
+    }
+    merger
+}
+
+/// A tile read back from its encoding
+pub struct Tile {
+    image_width: i32,
+    image_height: i32,
+    exposure: f64,
+    pub region: PixelRegion,
+    sums: Vec<(Vector3, f64)>, // The weighted color and weight sums of the region's pixels, in rows from the top left
+}
+
+/// Read an encoded tile, checking that it's a whole tile that stays inside of its image. The name says where it came
+/// from in error messages.
+pub fn decode_tile(bytes: Vec<u8>, name: &str) -> Result<Tile, String> {
+    let mut reader = CheckpointReader::from_bytes(bytes, TILE_MAGIC, name)?;
+
+    // The image size, the exposure, and the region
+    if reader.remaining_len() < 32 {
+        return Err(format!("The tile from {} ended early", name));
+    }
+    let (image_width, image_height) = (reader.read_i32(), reader.read_i32());
+    let exposure = reader.read_f64();
+    let region = PixelRegion {
+        x0: reader.read_i32(),
+        y0: reader.read_i32(),
+        x1: reader.read_i32(),
+        y1: reader.read_i32(),
+    };
+    if region.x0 < 0
+        || region.y0 < 0
+        || region.x1 < region.x0
+        || region.y1 < region.y0
+        || region.x1 > image_width
+        || region.y1 > image_height
+    {
+        return Err(format!(
+            "The tile from {} reaches outside of its image",
+            name
+        ));
+    }
+
+    // Each pixel has a weighted color and a weight
+    let pixel_count = (region.x1 - region.x0) as usize * (region.y1 - region.y0) as usize;
+    if pixel_count.checked_mul(32) != Some(reader.remaining_len()) {
+        return Err(format!(
+            "The tile from {} doesn't have the {} pixels of its region",
+            name, pixel_count
+        ));
+    }
+    let sums = (0..pixel_count)
+        .map(|_| (reader.read_vector3(), reader.read_f64()))
+        .collect();
+
+    Ok(Tile {
+        image_width,
+        image_height,
+        exposure,
+        region,
+        sums,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{filter::Filter, vector::Vector2};
+
+    #[test]
+    fn merged_tiles_match_one_film() {
+        let (width, height) = (6, 4);
+        let filter = Filter::Tent(1.5);
+        let mut whole = Film::new(width, height, filter);
+        let mut left = Film::new(width, height, filter);
+        let mut right = Film::new(width, height, filter);
+
+        // The filter splats samples from each half onto the pixels just across the split
+        for y in 0..height {
+            for x in 0..width {
+                let offset = Vector2 { x: 0.3, y: 0.8 };
+                let color = Vector3 {
+                    x: x as f64,
+                    y: y as f64,
+                    z: 1.0,
+                };
+                whole.add_sample(x, y, &offset, &color);
+                if x < 3 {
+                    left.add_sample(x, y, &offset, &color);
+                } else {
+                    right.add_sample(x, y, &offset, &color);
+                }
+            }
+        }
+
+        let left_region = PixelRegion {
+            x0: 0,
+            y0: 0,
+            x1: 3,
+            y1: height,
+        };
+        let right_region = PixelRegion {
+            x0: 3,
+            y0: 0,
+            x1: width,
+            y1: height,
+        };
+        let directory = std::env::temp_dir();
+        let left_path = directory.join("tile_test_left.tile");
+        let right_path = directory.join("tile_test_right.tile");
+        let left_path = left_path.to_str().unwrap().to_string();
+        let right_path = right_path.to_str().unwrap().to_string();
+        write_tile(
+            &left_path,
+            &left,
+            &left_region.expand(1.5, width, height),
+            width,
+            height,
+            1.0,
+        );
+        write_tile(
+            &right_path,
+            &right,
+            &right_region.expand(1.5, width, height),
+            width,
+            height,
+            1.0,
+        );
+
+        let (merged_width, merged_height, colors) =
+            merge_tiles(&[left_path, right_path]).get_image();
+        assert_eq!((merged_width, merged_height), (width, height));
+        for y in 0..height {
+            for x in 0..width {
+                let expected = whole.get_color(x, y);
+                let merged = colors[(y * width + x) as usize];
+                assert!((expected - merged).magnitude() < 1e-12);
+            }
+        }
+    }
+
+    #[test]
+    fn expanded_regions_stay_inside_of_the_image() {
+        let region = PixelRegion {
+            x0: 1,
+            y0: 5,
+            x1: 4,
+            y1: 8,
+        };
+        let expanded = region.expand(1.5, 10, 9);
+        assert_eq!(
+            expanded,
+            PixelRegion {
+                x0: 0,
+                y0: 3,
+                x1: 6,
+                y1: 9,
+            }
+        );
+        assert!(expanded.contains(0, 3));
+        assert!(!expanded.contains(6, 3));
+    }
+}
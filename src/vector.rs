@@ -316,6 +316,15 @@ pub fn calc_cross_product(a: &Vector3, b: &Vector3) -> Vector3 {
     }
 }
 
+/// Multiplies each component of a by the matching component of b, e.g. to attenuate a color
+pub fn calc_component_product(a: &Vector3, b: &Vector3) -> Vector3 {
+    Vector3 {
+        x: a.x * b.x,
+        y: a.y * b.y,
+        z: a.z * b.z,
+    }
+}
+
 #[derive(Clone, Default, PartialEq, Copy)]
 pub struct Vector4 {
     pub x: f64,
@@ -0,0 +1,386 @@
+use std::{fs, rc::Rc};
+
+use rand::{Rng, rngs::SmallRng};
+
+use crate::{
+    aabb::{Aabb, hit_aabb_interval},
+    hit_record::HitRecord,
+    perlin::Perlin,
+    ray::{self, Ray},
+    vector::Vector3,
+};
+
+/// The field that determines the density of a volume at each point in space
+pub enum DensityField {
+    Noise(Perlin, f64, f64),           // noise, frequency scale, maximum density
+    Turbulence(Perlin, f64, i32, f64), // noise, frequency scale, octave count, maximum density
+    Grid(VoxelGrid),
+}
+
+/// A dense grid of density values that is stretched over the bounding box of its volume
+pub struct VoxelGrid {
+    nx: usize,
+    ny: usize,
+    nz: usize,
+    densities: Vec<f64>, // Stored with x varying fastest, then y, then z
+    max_density: f64,
+}
+
+impl VoxelGrid {
+    pub fn new(nx: usize, ny: usize, nz: usize, densities: Vec<f64>) -> Self {
+        assert!(nx > 0 && ny > 0 && nz > 0);
+        assert_eq!(densities.len(), nx * ny * nz);
+        assert!(densities.iter().all(|density| *density >= 0.0));
+
+        let max_density = densities
+            .iter()
+            .fold(0.0, |max, density| f64::max(max, *density));
+
+        Self {
+            nx,
+            ny,
+            nz,
+            densities,
+            max_density,
+        }
+    }
+
+    /// Load a voxel grid from disk
+    pub fn load(file_path: &str) -> Self {
+        let file_contents = fs::read(file_path)
+            .unwrap_or_else(|_| panic!("Unable to read file path at {}", file_path));
+
+        Self::parse(&file_contents)
+            .unwrap_or_else(|message| panic!("Unable to parse {}: {}", file_path, message))
+    }
+
+    /// Parse the contents of a voxel grid file.
+    ///
+    /// The file starts with the grid dimensions nx, ny, and nz as little-endian u32 values. They are followed by
+    /// nx * ny * nz little-endian f32 densities with x varying fastest, then y, then z. Densities must be finite and
+    /// non-negative since delta tracking treats density / majorant as a probability.
+    pub fn parse(file_contents: &[u8]) -> Result<Self, String> {
+        let read_u32 = |offset: usize| -> Result<u32, String> {
+            let bytes = file_contents
+                .get(offset..offset + 4)
+                .ok_or_else(|| "the file is truncated".to_string())?;
+            Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+        };
+
+        let nx = read_u32(0)? as usize;
+        let ny = read_u32(4)? as usize;
+        let nz = read_u32(8)? as usize;
+        if nx == 0 || ny == 0 || nz == 0 {
+            return Err(format!("the dimensions {}x{}x{} are empty", nx, ny, nz));
+        }
+        let voxel_count = nx
+            .checked_mul(ny)
+            .and_then(|count| count.checked_mul(nz))
+            .filter(|count| *count <= (file_contents.len() - 12) / 4)
+            .ok_or_else(|| format!("the file is too short for {}x{}x{} voxels", nx, ny, nz))?;
+        if file_contents.len() != 12 + 4 * voxel_count {
+            return Err(format!(
+                "the file is longer than {}x{}x{} voxels",
+                nx, ny, nz
+            ));
+        }
+
+        let mut densities: Vec<f64> = Vec::with_capacity(voxel_count);
+        for index in 0..voxel_count {
+            let density = f32::from_bits(read_u32(12 + 4 * index)?) as f64;
+            if !density.is_finite() || density < 0.0 {
+                return Err(format!(
+                    "voxel {} has the invalid density {}",
+                    index, density
+                ));
+            }
+            densities.push(density);
+        }
+
+        Ok(Self::new(nx, ny, nz, densities))
+    }
+
+    fn get_voxel(&self, i: usize, j: usize, k: usize) -> f64 {
+        self.densities[(k * self.ny + j) * self.nx + i]
+    }
+
+    /// Trilinearly interpolate the density at local coordinates in [0, 1]^3
+    fn lookup(&self, local: Vector3) -> f64 {
+        // Voxel centers sit at (index + 0.5) / n, so shift by half a voxel before interpolating
+        let to_grid = |value: f64, n: usize| -> (usize, usize, f64) {
+            let x = (value * n as f64 - 0.5).clamp(0.0, (n - 1) as f64);
+            let i0 = x.floor() as usize;
+            let i1 = usize::min(i0 + 1, n - 1);
+            (i0, i1, x - i0 as f64)
+        };
+
+        let (i0, i1, tx) = to_grid(local.x, self.nx);
+        let (j0, j1, ty) = to_grid(local.y, self.ny);
+        let (k0, k1, tz) = to_grid(local.z, self.nz);
+
+        let lerp = |a: f64, b: f64, t: f64| (1.0 - t) * a + t * b;
+
+        let c00 = lerp(self.get_voxel(i0, j0, k0), self.get_voxel(i1, j0, k0), tx);
+        let c10 = lerp(self.get_voxel(i0, j1, k0), self.get_voxel(i1, j1, k0), tx);
+        let c01 = lerp(self.get_voxel(i0, j0, k1), self.get_voxel(i1, j0, k1), tx);
+        let c11 = lerp(self.get_voxel(i0, j1, k1), self.get_voxel(i1, j1, k1), tx);
+
+        lerp(lerp(c00, c10, ty), lerp(c01, c11, ty), tz)
+    }
+}
+
+/// Structure for participating media with spatially varying density inside of a box
+#[derive(Clone)]
+pub struct Volume {
+    pub density: Rc<DensityField>,
+    pub majorant: f64, // An upper bound on the density anywhere in the volume
+    pub material: usize,
+    pub bounding_box: Aabb,
+}
+
+impl Volume {
+    /// Create a volume filling the box with corners a and b
+    pub fn new(a: Vector3, b: Vector3, density: Rc<DensityField>, material: usize) -> Self {
+        let majorant = match density.as_ref() {
+            DensityField::Noise(_, _, max_density) => *max_density,
+            DensityField::Turbulence(_, _, _, max_density) => *max_density,
+            DensityField::Grid(grid) => grid.max_density,
+        };
+
+        Self {
+            density,
+            majorant,
+            material,
+            bounding_box: Aabb::new(a, b),
+        }
+    }
+}
+
+/// Get the density of the volume at a point in world space
+fn get_density(volume: &Volume, p: Vector3) -> f64 {
+    match volume.density.as_ref() {
+        DensityField::Noise(noise, scale, max_density) => {
+            // Map the noise from [-1, 1] to [0, max_density]
+            0.5 * max_density * (1.0 + noise.noise(&(*scale * p)))
+        }
+        DensityField::Turbulence(noise, scale, octave_count, max_density) => {
+            max_density * f64::min(noise.turbulence(&(*scale * p), *octave_count), 1.0)
+        }
+        DensityField::Grid(grid) => {
+            let bbox = &volume.bounding_box;
+            let local = Vector3 {
+                x: (p.x - bbox.x0) / (bbox.x1 - bbox.x0),
+                y: (p.y - bbox.y0) / (bbox.y1 - bbox.y0),
+                z: (p.z - bbox.z0) / (bbox.z1 - bbox.z0),
+            };
+            grid.lookup(local)
+        }
+    }
+}
+
+/// Sample a distance along the ray using the majorant. Distances are measured in units of t.
+fn sample_majorant_step(volume: &Volume, ray_in: &Ray, rng: &mut SmallRng) -> f64 {
+    // The ray direction is not necessarily a unit vector, so we convert from world space distance to t
+    let ray_length = ray_in.direction.magnitude();
+    let xi: f64 = rng.random_range(0.0..1.0);
+    -(1.0 - xi).ln() / (volume.majorant * ray_length)
+}
+
+/// Determines whether the ray scatters inside of the volume within [tmin, tmax] using delta tracking.
+///
+/// Tentative collisions are sampled against the majorant. Each tentative collision is a real collision with probability
+/// density / majorant, otherwise it is a null collision and tracking continues. This produces collisions distributed
+/// exactly as they would be for the heterogeneous density.
+///
+/// Returns None if the ray passes through the volume or Some(HitRecord) at the collision.
+pub fn hit_volume(
+    ray_in: &Ray,
+    volume_in: &Volume,
+    tmin: f64,
+    tmax: f64,
+    rng: &mut SmallRng,
+) -> Option<HitRecord> {
+    if volume_in.majorant <= 0.0 {
+        return None;
+    }
+
+    let (t_enter, t_exit) = hit_aabb_interval(&volume_in.bounding_box, ray_in, tmin, tmax)?;
+
+    let mut t = t_enter;
+    loop {
+        t += sample_majorant_step(volume_in, ray_in, rng);
+        if t >= t_exit {
+            return None;
+        }
+
+        let point = ray::at(ray_in, t);
+        if rng.random_range(0.0..1.0) * volume_in.majorant < get_density(volume_in, point) {
+            // The normal is arbitrary for a volume since scattering does not depend on it
+            let normal = Vector3 {
+                x: 1.0,
+                y: 0.0,
+                z: 0.0,
+            };
+            return Some(HitRecord::new(
+                ray_in,
+                normal,
+                t,
+                volume_in.material,
+                0.0,
+                0.0,
+            ));
+        }
+    }
+}
+
+/// Estimates the fraction of light that makes it through the volume along the ray within [tmin, tmax] using ratio
+/// tracking.
+///
+/// Rather than stopping at the first real collision like delta tracking, ratio tracking weights the estimate by the
+/// probability of a null collision at every tentative collision. This has less variance than testing for a hit.
+pub fn volume_transmittance(
+    ray_in: &Ray,
+    volume_in: &Volume,
+    tmin: f64,
+    tmax: f64,
+    rng: &mut SmallRng,
+) -> f64 {
+    if volume_in.majorant <= 0.0 {
+        return 1.0;
+    }
+
+    let (t_enter, t_exit) = match hit_aabb_interval(&volume_in.bounding_box, ray_in, tmin, tmax) {
+        Some(interval) => interval,
+        None => return 1.0,
+    };
+
+    let mut transmittance = 1.0;
+    let mut t = t_enter;
+    loop {
+        t += sample_majorant_step(volume_in, ray_in, rng);
+        if t >= t_exit {
+            return transmittance;
+        }
+
+        let point = ray::at(ray_in, t);
+        transmittance *= 1.0 - get_density(volume_in, point) / volume_in.majorant;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+
+    use super::*;
+
+    fn grid_bytes(nx: u32, ny: u32, nz: u32, densities: &[f32]) -> Vec<u8> {
+        let mut bytes = vec![];
+        for value in [nx, ny, nz] {
+            bytes.extend(value.to_le_bytes());
+        }
+        for density in densities {
+            bytes.extend(density.to_le_bytes());
+        }
+        bytes
+    }
+
+    fn assert_close(a: f64, b: f64, tolerance: f64) {
+        assert!((a - b).abs() < tolerance, "{} is not close to {}", a, b);
+    }
+
+    #[test]
+    fn voxel_grid_parses_and_interpolates() {
+        let densities = [0.0, 1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0];
+        let grid = VoxelGrid::parse(&grid_bytes(2, 2, 2, &densities)).unwrap();
+        assert_eq!((grid.nx, grid.ny, grid.nz), (2, 2, 2));
+        assert_eq!(grid.max_density, 7.0);
+
+        // x varies fastest, then y, then z
+        let at = |x: f64, y: f64, z: f64| grid.lookup(Vector3 { x, y, z });
+        assert_close(at(0.25, 0.25, 0.25), 0.0, 1e-12);
+        assert_close(at(0.75, 0.25, 0.25), 1.0, 1e-12);
+        assert_close(at(0.25, 0.75, 0.25), 2.0, 1e-12);
+        assert_close(at(0.25, 0.25, 0.75), 4.0, 1e-12);
+        assert_close(at(0.5, 0.5, 0.5), 3.5, 1e-12);
+
+        // Lookups past the outer voxel centers clamp to the edge
+        assert_close(at(0.0, 0.0, 0.0), 0.0, 1e-12);
+        assert_close(at(1.0, 1.0, 1.0), 7.0, 1e-12);
+        assert_close(at(1.0, 0.5, 0.0), 2.0, 1e-12);
+    }
+
+    #[test]
+    fn voxel_grid_rejects_bad_files() {
+        let bytes = grid_bytes(2, 1, 1, &[1.0, 2.0]);
+        assert!(VoxelGrid::parse(&bytes).is_ok());
+        assert!(VoxelGrid::parse(&bytes[..bytes.len() - 1]).is_err());
+        assert!(VoxelGrid::parse(&bytes[..8]).is_err());
+        assert!(VoxelGrid::parse(&[bytes.clone(), vec![0; 4]].concat()).is_err());
+        assert!(VoxelGrid::parse(&grid_bytes(0, 1, 1, &[])).is_err());
+        assert!(VoxelGrid::parse(&grid_bytes(u32::MAX, u32::MAX, u32::MAX, &[1.0])).is_err());
+        assert!(VoxelGrid::parse(&grid_bytes(2, 1, 1, &[1.0, -0.5])).is_err());
+        assert!(VoxelGrid::parse(&grid_bytes(2, 1, 1, &[f32::NAN, 1.0])).is_err());
+        assert!(VoxelGrid::parse(&grid_bytes(2, 1, 1, &[1.0, f32::INFINITY])).is_err());
+    }
+
+    #[test]
+    fn tracking_matches_transmittance_of_constant_medium() {
+        // A unit cube of constant density, crossed by a ray whose direction isn't a unit vector
+        let density = 1.5;
+        let grid = VoxelGrid::new(1, 1, 1, vec![density]);
+        let mut volume = Volume::new(
+            Vector3::default(),
+            Vector3 {
+                x: 1.0,
+                y: 1.0,
+                z: 1.0,
+            },
+            Rc::new(DensityField::Grid(grid)),
+            0,
+        );
+        let ray = Ray {
+            origin: Vector3 {
+                x: -1.0,
+                y: 0.5,
+                z: 0.5,
+            },
+            direction: Vector3 {
+                x: 2.0,
+                y: 0.0,
+                z: 0.0,
+            },
+            time: 0.0,
+        };
+        let expected = f64::exp(-density);
+
+        // A loose majorant makes both estimators take null collisions
+        for majorant in [density, 2.0 * density] {
+            volume.majorant = majorant;
+            let mut rng = SmallRng::seed_from_u64(7);
+            let sample_count = 200_000;
+            let mut miss_count = 0;
+            let mut transmittance_sum = 0.0;
+            for _ in 0..sample_count {
+                if hit_volume(&ray, &volume, 0.0, f64::INFINITY, &mut rng).is_none() {
+                    miss_count += 1;
+                }
+                transmittance_sum +=
+                    volume_transmittance(&ray, &volume, 0.0, f64::INFINITY, &mut rng);
+            }
+            assert_close(miss_count as f64 / sample_count as f64, expected, 5e-3);
+            assert_close(transmittance_sum / sample_count as f64, expected, 5e-3);
+        }
+
+        // Rays that end partway through only see that much of the medium
+        let mut rng = SmallRng::seed_from_u64(7);
+        let sample_count = 200_000;
+        let transmittance_sum: f64 = (0..sample_count)
+            .map(|_| volume_transmittance(&ray, &volume, 0.0, 0.75, &mut rng))
+            .sum();
+        assert_close(
+            transmittance_sum / sample_count as f64,
+            f64::exp(-0.5 * density),
+            5e-3,
+        );
+    }
+}